@@ -1,6 +1,6 @@
 use anyhow::Result;
 use std::net::ToSocketAddrs;
-use wksocket::{sleep, tick_count};
+use wksocket::{sleep, Tick};
 use wksocket::{MessageSND, WkSender};
 use wksocket::{WkAuth, WkSession};
 
@@ -26,7 +26,7 @@ fn main() -> Result<()> {
                 sender.send(MessageSND::PosEdge(slot))?;
                 slot += 10;
             }
-            sender.send(MessageSND::SendPacket(tick_count()))?;
+            sender.send(MessageSND::SendPacket(Tick::now()))?;
             sleep(500);
         }
         sender.send(MessageSND::CloseSession)?;
@@ -9,6 +9,10 @@ const peerAddress = document.getElementById('peer-address');
 const wpmValue = document.getElementById('wpm-value');
 const pktValue = document.getElementById('pkt-value');
 const rttValue = document.getElementById('rtt-value');
+const retxValue = document.getElementById('retx-value');
+const linkItem = document.getElementById('link-item');
+const wpmItem = document.getElementById('wpm-item');
+const cwTranscript = document.getElementById('cw-transcript');
 const atuBtn = document.getElementById('atu-btn');
 const killBtn = document.getElementById('kill-btn');
 const killBanner = document.getElementById('kill-banner');
@@ -133,8 +137,21 @@ async function updateStats() {
         sessionStart.textContent = stats.session_start || '-';
         peerAddress.textContent = stats.peer_address || '-';
         wpmValue.textContent = stats.wpm.toFixed(1);
+        wpmItem.title = stats.wpm > 0
+            ? `dah:dit ${stats.dah_ratio ? stats.dah_ratio.toFixed(1) : '-'}` +
+              ` / weight ${stats.weight ? stats.weight.toFixed(0) + '%' : '-'}\n` +
+              `Farnsworth ${stats.farnsworth_wpm.toFixed(1)} wpm`
+            : '';
         pktValue.textContent = stats.pkt_per_sec;
+        cwTranscript.textContent = stats.transcript;
         rttValue.textContent = stats.rtt_ms;
+        retxValue.textContent = stats.retransmissions;
+        linkItem.title = `srtt ${stats.srtt_ms}ms / rto ${stats.rto_ms}ms\n` +
+            `dup ${stats.duplicates} / ooo ${stats.out_of_order} / queue ${stats.send_queue}\n` +
+            `last packet ${stats.idle_ms}ms ago\n` +
+            `playout ${stats.playout_delay_ms}ms / late edges ${stats.late_edges}\n` +
+            `edge error p50 ${stats.edge_error_p50_us}us / p99 ${stats.edge_error_p99_us}us` +
+            ` / max ${stats.edge_error_max_us}us`;
 
         if (stats.auth_ok) {
             appTitle.classList.add('active');
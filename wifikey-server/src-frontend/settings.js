@@ -15,7 +15,12 @@ const serverPasswordInput = document.getElementById('server-password');
 const rigcontrolPortSelect = document.getElementById('rigcontrol-port');
 const keyingPortSelect = document.getElementById('keying-port');
 const useRtsCheckbox = document.getElementById('use-rts');
+const allowLegacyAuthCheckbox = document.getElementById('allow-legacy-auth');
 const rigScriptSelect = document.getElementById('rig-script');
+const playoutPercentileInput = document.getElementById('playout-percentile');
+const playoutMinInput = document.getElementById('playout-min');
+const playoutMaxInput = document.getElementById('playout-max');
+const lateEdgePolicySelect = document.getElementById('late-edge-policy');
 
 // State
 let currentConfig = null;
@@ -67,6 +72,11 @@ function populateForm(config, ports, scripts) {
     serverNameInput.value = config.server_name || '';
     serverPasswordInput.value = config.server_password || '';
     useRtsCheckbox.checked = config.use_rts_for_keying || false;
+    allowLegacyAuthCheckbox.checked = config.allow_legacy_auth || false;
+    playoutPercentileInput.value = config.playout_percentile ?? 95;
+    playoutMinInput.value = config.playout_min_ms ?? 0;
+    playoutMaxInput.value = config.playout_max_ms ?? 250;
+    lateEdgePolicySelect.value = config.late_edge_policy || 'keep_length';
     populatePortSelect(rigcontrolPortSelect, ports, config.rigcontrol_port);
     populatePortSelect(keyingPortSelect, ports, config.keying_port);
     populateScriptSelect(scripts, config.rig_script);
@@ -129,6 +139,13 @@ async function saveSettings() {
             keying_port: keyingPortSelect.value,
             use_rts_for_keying: useRtsCheckbox.checked,
             rig_script: rigScriptSelect.value,
+            // Kept as-is; the backend regenerates it when the password changes
+            server_verifier: currentConfig?.server_verifier || '',
+            allow_legacy_auth: allowLegacyAuthCheckbox.checked,
+            playout_percentile: parseInt(playoutPercentileInput.value, 10),
+            playout_min_ms: parseInt(playoutMinInput.value, 10),
+            playout_max_ms: parseInt(playoutMaxInput.value, 10),
+            late_edge_policy: lateEdgePolicySelect.value,
         };
         settingsSave.disabled = true;
         settingsSave.textContent = 'Saving...';
@@ -2,11 +2,24 @@ use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::PathBuf;
+use wksocket::{AuthVerifier, JitterBufferConfig, LatePolicy};
 
 fn default_rig_script() -> String {
     "yaesu_ft891.lua".to_string()
 }
 
+fn default_playout_percentile() -> u8 {
+    JitterBufferConfig::default().percentile
+}
+
+fn default_playout_max_ms() -> u32 {
+    JitterBufferConfig::default().max_delay
+}
+
+fn default_late_edge_policy() -> String {
+    LatePolicy::default().to_string()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppConfig {
     pub server_name: String,
@@ -16,6 +29,26 @@ pub struct AppConfig {
     pub use_rts_for_keying: bool,
     #[serde(default = "default_rig_script")]
     pub rig_script: String,
+    /// SPAKE2+ verifier derived from `server_password`. Sessions are authenticated
+    /// against this; the password itself is still needed for the MQTT rendezvous.
+    #[serde(default)]
+    pub server_verifier: String,
+    /// Accept the legacy MD5 challenge from clients running old firmware
+    #[serde(default)]
+    pub allow_legacy_auth: bool,
+    /// Percentile of the measured lateness the playout delay covers
+    #[serde(default = "default_playout_percentile")]
+    pub playout_percentile: u8,
+    /// Lower bound of the playout delay in milliseconds
+    #[serde(default)]
+    pub playout_min_ms: u32,
+    /// Upper bound of the playout delay in milliseconds
+    #[serde(default = "default_playout_max_ms")]
+    pub playout_max_ms: u32,
+    /// What to do with an edge that arrives after it was due:
+    /// "stretch", "drop" or "keep_length"
+    #[serde(default = "default_late_edge_policy")]
+    pub late_edge_policy: String,
 }
 
 impl Default for AppConfig {
@@ -27,6 +60,12 @@ impl Default for AppConfig {
             keying_port: "COM6".to_string(),
             use_rts_for_keying: true,
             rig_script: default_rig_script(),
+            server_verifier: String::new(),
+            allow_legacy_auth: false,
+            playout_percentile: default_playout_percentile(),
+            playout_min_ms: 0,
+            playout_max_ms: default_playout_max_ms(),
+            late_edge_policy: default_late_edge_policy(),
         }
     }
 }
@@ -44,12 +83,45 @@ impl AppConfig {
         let content = fs::read_to_string(&config_path)
             .with_context(|| format!("Failed to read config file: {:?}", config_path))?;
 
-        let config: AppConfig =
+        let mut config: AppConfig =
             toml::from_str(&content).with_context(|| "Failed to parse config file")?;
 
+        if config.refresh_verifier() {
+            config.save()?;
+        }
+
         Ok(config)
     }
 
+    /// Regenerate `server_verifier` if it is missing or no longer matches
+    /// `server_password`. Returns true when the verifier was replaced.
+    pub fn refresh_verifier(&mut self) -> bool {
+        let current = self.server_verifier.parse::<AuthVerifier>();
+        if matches!(current, Ok(ref v) if v.verify_password(&self.server_password)) {
+            return false;
+        }
+        self.server_verifier = AuthVerifier::new(&self.server_password).to_string();
+        true
+    }
+
+    /// Verifier used to authenticate sessions
+    pub fn auth_verifier(&self) -> AuthVerifier {
+        self.server_verifier
+            .parse()
+            .unwrap_or_else(|_| AuthVerifier::new(&self.server_password))
+    }
+
+    /// Playout delay and late-edge policy of the keyer. An unknown policy
+    /// falls back to the default.
+    pub fn jitter_buffer(&self) -> JitterBufferConfig {
+        JitterBufferConfig {
+            percentile: self.playout_percentile.min(100),
+            min_delay: self.playout_min_ms,
+            max_delay: self.playout_max_ms.max(self.playout_min_ms),
+            late: self.late_edge_policy.parse().unwrap_or_default(),
+        }
+    }
+
     /// Save configuration to cfg.toml file
     pub fn save(&self) -> Result<()> {
         let config_path = Self::config_path()?;
@@ -103,5 +175,46 @@ mod tests {
         let config = AppConfig::default();
         assert_eq!(config.server_name, "your_callsign/keyer_name");
         assert!(config.use_rts_for_keying);
+        assert!(!config.allow_legacy_auth);
+    }
+
+    #[test]
+    fn test_refresh_verifier() {
+        let mut config = AppConfig::default();
+        assert!(config.refresh_verifier());
+        assert!(config.auth_verifier().verify_password("keyer_passwd"));
+        // unchanged password keeps the stored verifier
+        assert!(!config.refresh_verifier());
+        config.server_password = "new_passwd".to_string();
+        assert!(config.refresh_verifier());
+        assert!(config.auth_verifier().verify_password("new_passwd"));
+    }
+
+    #[test]
+    fn test_jitter_buffer() {
+        let mut config = AppConfig::default();
+        assert_eq!(config.jitter_buffer(), JitterBufferConfig::default());
+        config.late_edge_policy = "drop".to_string();
+        config.playout_min_ms = 300;
+        let buffer = config.jitter_buffer();
+        assert_eq!(buffer.late, LatePolicy::Drop);
+        assert_eq!(buffer.max_delay, 300);
+        config.late_edge_policy = "bogus".to_string();
+        assert_eq!(config.jitter_buffer().late, LatePolicy::default());
+    }
+
+    #[test]
+    fn test_playout_defaults_for_old_config() {
+        let config: AppConfig = toml::from_str(
+            r#"
+            server_name = "a"
+            server_password = "b"
+            rigcontrol_port = "COM5"
+            keying_port = "COM6"
+            use_rts_for_keying = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.jitter_buffer(), JitterBufferConfig::default());
     }
 }
@@ -0,0 +1,290 @@
+use crate::keyer::MORSE_TABLE;
+use std::collections::HashMap;
+use std::fmt;
+use wksocket::{SpeedEstimator, Tick, MSPERWPM};
+
+// 速さが推定できるまでは 20 WPM とみなす
+const DEFAULT_WPM: f32 = 20.0;
+// これより長い符号は打ち損じとしてまとめて捨てる
+const MAX_ELEMENTS: u8 = 16;
+// PARIS の 50 単位のうち、文字間と語間の分
+const PARIS_GAPS: f32 = 19.0;
+
+/// Prosigns that are not characters of `MORSE_TABLE`, as dots and dashes
+const PROSIGNS: &[(&str, &str)] = &[
+    ("<SK>", "...-.-"),
+    ("<KN>", "-.--."),
+    ("<AS>", ".-..."),
+    ("<KA>", "-.-.-"),
+    ("<VE>", "...-."),
+    ("<BK>", "-...-.-"),
+    ("<CL>", "-.-..-.."),
+    ("<SOS>", "...---..."),
+    ("<HH>", "........"),
+];
+
+/// What the decoder made of the keying.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CwEvent {
+    Char(char),
+    /// Elements sent as one character, such as `<SK>`
+    Prosign(&'static str),
+    /// A pattern that is neither, as dots and dashes
+    Unknown(String),
+    /// The gap between two words
+    WordSpace,
+}
+
+impl fmt::Display for CwEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CwEvent::Char(c) => write!(f, "{c}"),
+            CwEvent::Prosign(p) => f.write_str(p),
+            CwEvent::Unknown(_) => f.write_str("*"),
+            CwEvent::WordSpace => f.write_str(" "),
+        }
+    }
+}
+
+// 符号の長さと、i 番目の要素を i ビット目に置いた値 (1 が長点)
+fn pattern_code(pattern: &str) -> (u8, u16) {
+    pattern
+        .bytes()
+        .enumerate()
+        .fold((0, 0), |(len, code), (i, b)| {
+            (len + 1, code | ((b == b'-') as u16) << i)
+        })
+}
+
+// 各間隔のしきい値 (ms)
+struct Thresholds {
+    dah: f32,
+    letter: f32,
+    word: f32,
+}
+
+/// Streaming Morse decoder for the keying received from the remote operator.
+///
+/// Fed with the key edges as they are played, it tells dits from dahs and
+/// the gaps between elements, characters and words by the speed, weighting
+/// and spacing a `SpeedEstimator` finds in the same keying, so it follows
+/// the operator's speed. Patterns are looked up in `MORSE_TABLE`, then in
+/// the prosigns.
+pub struct CwDecoder {
+    table: HashMap<(u8, u16), CwEvent>,
+    speed: SpeedEstimator,
+    // 組み立て中の符号
+    len: u8,
+    code: u16,
+    down_at: Option<Tick>,
+    up_at: Option<Tick>,
+    // 前の語間から文字を出したか
+    in_word: bool,
+}
+
+impl Default for CwDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CwDecoder {
+    pub fn new() -> Self {
+        let mut table: HashMap<_, _> = MORSE_TABLE
+            .iter()
+            .map(|&(c, len, code)| ((len, code as u16), CwEvent::Char(c)))
+            .collect();
+        for &(name, pattern) in PROSIGNS {
+            table.insert(pattern_code(pattern), CwEvent::Prosign(name));
+        }
+        Self {
+            table,
+            speed: SpeedEstimator::new(),
+            len: 0,
+            code: 0,
+            down_at: None,
+            up_at: None,
+            in_word: false,
+        }
+    }
+
+    /// Account for the key going down or up at `at`. Returns what the
+    /// space before a key-down completed.
+    pub fn key(&mut self, down: bool, at: Tick) -> Vec<CwEvent> {
+        let mut events = Vec::new();
+        if down {
+            if self.down_at.is_some() {
+                return events;
+            }
+            if let Some(up_at) = self.up_at.take() {
+                let space = at.since(up_at);
+                self.speed.space(space);
+                events = self.close(space);
+            }
+            self.down_at = Some(at);
+        } else if let Some(down_at) = self.down_at.take() {
+            let mark = at.since(down_at);
+            self.speed.mark(mark);
+            if self.len < MAX_ELEMENTS {
+                let dah = mark as f32 >= self.thresholds().dah;
+                self.code |= (dah as u16) << self.len;
+            }
+            self.len = self.len.saturating_add(1);
+            self.up_at = Some(at);
+        }
+        events
+    }
+
+    /// Finish the character or word the key has been up long enough for
+    /// by `now`
+    pub fn idle(&mut self, now: Tick) -> Vec<CwEvent> {
+        match (self.down_at, self.up_at) {
+            (None, Some(up_at)) => self.close(now.since(up_at)),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Finish whatever has been sent, as at the end of a session
+    pub fn finish(&mut self) -> Vec<CwEvent> {
+        self.down_at = None;
+        self.close(u32::MAX)
+    }
+
+    // key-up が space ms 続いたところで終わった文字と語を返す
+    fn close(&mut self, space: u32) -> Vec<CwEvent> {
+        let t = self.thresholds();
+        let mut events = Vec::new();
+        if space as f32 >= t.letter && self.len > 0 {
+            events.push(self.take_char());
+            self.in_word = true;
+        }
+        if space as f32 >= t.word && self.in_word {
+            events.push(CwEvent::WordSpace);
+            self.in_word = false;
+        }
+        events
+    }
+
+    fn take_char(&mut self) -> CwEvent {
+        let (len, code) = (self.len, self.code);
+        self.len = 0;
+        self.code = 0;
+        if let Some(event) = self.table.get(&(len, code)) {
+            return event.clone();
+        }
+        let pattern = (0..len.min(MAX_ELEMENTS))
+            .map(|i| if code >> i & 1 == 1 { '-' } else { '.' })
+            .collect();
+        CwEvent::Unknown(pattern)
+    }
+
+    fn thresholds(&self) -> Thresholds {
+        let estimate = self.speed.estimate();
+        let wpm = estimate.map_or(DEFAULT_WPM, |e| e.wpm);
+        let unit = MSPERWPM as f32 / wpm;
+        // 重みがあれば短点とスペースは 1 単位から増減する
+        let dit = estimate
+            .and_then(|e| e.weight)
+            .map_or(unit, |w| 2.0 * unit * w / 100.0);
+        let element = 2.0 * unit - dit;
+        let dah = dit * (1.0 + estimate.and_then(|e| e.dah_ratio).unwrap_or(3.0)) / 2.0;
+        // Farnsworth では文字間と語間だけが 1 単位より広い単位で測られる
+        let stretch = estimate.map_or(1.0, |e| e.wpm / e.farnsworth_wpm);
+        let spacing = unit * ((50.0 * stretch - (50.0 - PARIS_GAPS)) / PARIS_GAPS).max(1.0);
+        Thresholds {
+            dah,
+            letter: (element + 3.0 * spacing) / 2.0,
+            word: 5.0 * spacing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // text を '.' '-' と ' ' (文字間) '/' (語間) で送り、出てきたものを並べる
+    fn send(decoder: &mut CwDecoder, at: &mut Tick, unit: u32, spacing: u32, text: &str) -> String {
+        let mut out = String::new();
+        let mut gap = 0;
+        for c in text.chars() {
+            match c {
+                '.' | '-' => {
+                    *at += gap;
+                    for e in decoder.key(true, *at) {
+                        out += &e.to_string();
+                    }
+                    *at += if c == '.' { unit } else { 3 * unit };
+                    decoder.key(false, *at);
+                    gap = unit;
+                }
+                ' ' => gap = 3 * spacing,
+                _ => gap = 7 * spacing,
+            }
+        }
+        *at += gap;
+        for e in decoder.idle(*at) {
+            out += &e.to_string();
+        }
+        out
+    }
+
+    #[test]
+    fn test_decode_text() {
+        let mut decoder = CwDecoder::new();
+        let mut at = Tick::from_ms(u32::MAX - 1000);
+        let text = send(
+            &mut decoder,
+            &mut at,
+            60,
+            60,
+            "-.-. --.-/-.. ./.--- .- .---- -..-. .--./",
+        );
+        assert_eq!(text, "CQ DE JA1/P ");
+    }
+
+    #[test]
+    fn test_prosigns_and_unknown() {
+        let mut decoder = CwDecoder::new();
+        let mut at = Tick::ZERO;
+        let text = send(&mut decoder, &mut at, 60, 60, "-.--. ...-.- .-.-. ..--.-/");
+        assert_eq!(text, "<KN><SK>+* ");
+        assert_eq!(decoder.finish(), []);
+    }
+
+    #[test]
+    fn test_follows_speed() {
+        // 20 WPM の初期値のままでは 35 WPM の長点 (103 ms) が短点に見える
+        let mut decoder = CwDecoder::new();
+        let mut at = Tick::ZERO;
+        send(&mut decoder, &mut at, 34, 34, "-- -- -- ../");
+        let text = send(&mut decoder, &mut at, 34, 34, "- . ... -/");
+        assert_eq!(text, "TEST ");
+        // 12 WPM に落としても追いつく
+        send(&mut decoder, &mut at, 100, 100, ".--. .- .-. .. .../");
+        let text = send(&mut decoder, &mut at, 100, 100, ".--. .- .-. .. .../");
+        assert_eq!(text, "PARIS ");
+    }
+
+    #[test]
+    fn test_farnsworth_gaps() {
+        // 文字は 20 WPM、文字間と語間は全体で 10 WPM の広さ
+        let mut decoder = CwDecoder::new();
+        let mut at = Tick::ZERO;
+        let spacing = 60 * 69 / 19;
+        send(&mut decoder, &mut at, 60, spacing, ".--. .- .-. .. .../");
+        let text = send(&mut decoder, &mut at, 60, spacing, ".--. .- .-. .. .../");
+        assert_eq!(text, "PARIS ");
+    }
+
+    #[test]
+    fn test_finish_flushes() {
+        let mut decoder = CwDecoder::new();
+        let mut at = Tick::ZERO;
+        // 最後の文字はまだ閉じていない
+        assert_eq!(send(&mut decoder, &mut at, 60, 60, "... -.-"), "S");
+        assert_eq!(decoder.idle(at), []);
+        assert_eq!(decoder.finish(), [CwEvent::Char('K'), CwEvent::WordSpace]);
+        assert_eq!(decoder.finish(), []);
+    }
+}
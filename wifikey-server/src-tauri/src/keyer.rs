@@ -1,17 +1,64 @@
+use crate::decoder::{CwDecoder, CwEvent};
 use crate::rigcontrol::RigControl;
 use crate::server::RemoteStats;
 use anyhow::Result;
 use log::{info, trace};
-use std::sync::atomic::Ordering;
-use std::sync::{
-    atomic::{AtomicBool, AtomicU32},
-    Arc,
-};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
-use wksocket::{sleep, tick_count, MessageRCV, MessageSND, WkReceiver, WkSender, WkSession};
+use wksocket::{
+    sleep, Capabilities, JitterBufferConfig, KeyOutput, KeyPlayer, KeyerHooks, KeyerStats,
+    MessageRCV, MessageSND, Tick, WkReceiver, WkSender, WkSession, MSPERWPM,
+};
 
-pub const MAX_ASSERT_DURATION: u32 = 10000;
-pub const MSPERWPM: u32 = 1200; /* PARIS = 50 tick */
+/// Morse code of each character: (character, elements, code). Element `i`
+/// is bit `i` of the code, 1 for a dah.
+pub const MORSE_TABLE: &[(char, u8, u8)] = &[
+    ('0', 5, 0x1f), // '0' : -----
+    ('1', 5, 0x1e), // '1' : .----
+    ('2', 5, 0x1c), // '2' : ..---
+    ('3', 5, 0x18), // '3' : ...--
+    ('4', 5, 0x10), // '4' : ....-
+    ('5', 5, 0x00), // '5' : .....
+    ('6', 5, 0x01), // '6' : -....
+    ('7', 5, 0x03), // '7' : --...
+    ('8', 5, 0x07), // '8' : ---..
+    ('9', 5, 0x0f), // '9' : ----.
+    ('A', 2, 0x02), // 'A' : .-
+    ('B', 4, 0x01), // 'B' : -...
+    ('C', 4, 0x05), // 'C' : -.-.
+    ('D', 3, 0x01), // 'D' : -..
+    ('E', 1, 0x00), // 'E' : .
+    ('F', 4, 0x04), // 'F' : ..-.
+    ('G', 3, 0x03), // 'G' : --.
+    ('H', 4, 0x00), // 'H' : ....
+    ('I', 2, 0x00), // 'I' : ..
+    ('J', 4, 0x0e), // 'J' : .---
+    ('K', 3, 0x05), // 'K' : -.-
+    ('L', 4, 0x02), // 'L' : .-..
+    ('M', 2, 0x03), // 'M' : --
+    ('N', 2, 0x01), // 'N' : -.
+    ('O', 3, 0x07), // 'O' : ---
+    ('P', 4, 0x06), // 'P' : .--.
+    ('Q', 4, 0x0b), // 'Q' : --.-
+    ('R', 3, 0x02), // 'R' : .-.
+    ('S', 3, 0x00), // 'S' : ...
+    ('T', 1, 0x01), // 'T' : -
+    ('U', 3, 0x04), // 'U' : ..-
+    ('V', 4, 0x08), // 'V' : ...-
+    ('W', 3, 0x06), // 'W' : .--
+    ('X', 4, 0x09), // 'X' : -..-
+    ('Y', 4, 0x0d), // 'Y' : -.--
+    ('Z', 4, 0x03), // 'Z' : --..
+    ('/', 5, 0x09), // '/' : -..-.
+    ('?', 6, 0x0c), // '?' : ..--..
+    ('.', 6, 0x2a), // '.' : .-.-.-
+    (',', 6, 0x33), // ',' : --..--
+    ('=', 5, 0x11), // '=' : -...-
+    ('!', 6, 0x35), // '!' : -.-.--
+    ('+', 5, 0x0a), // '+' : .-.-.
+    ('-', 6, 0x21), // '-' : -....-
+];
 
 #[allow(dead_code)]
 pub struct Keyer {
@@ -32,52 +79,7 @@ impl Keyer {
             word_space: 7,
             letter_space: 3,
             tick: MSPERWPM / 20,
-            morse_table: vec![
-                ('0', 5, 0x1f), // '0' : -----
-                ('1', 5, 0x1e), // '1' : .----
-                ('2', 5, 0x1c), // '2' : ..---
-                ('3', 5, 0x18), // '3' : ...--
-                ('4', 5, 0x10), // '4' : ....-
-                ('5', 5, 0x00), // '5' : .....
-                ('6', 5, 0x01), // '6' : -....
-                ('7', 5, 0x03), // '7' : --...
-                ('8', 5, 0x07), // '8' : ---..
-                ('9', 5, 0x0f), // '9' : ----.
-                ('A', 2, 0x02), // 'A' : .-
-                ('B', 4, 0x01), // 'B' : -...
-                ('C', 4, 0x05), // 'C' : -.-.
-                ('D', 3, 0x01), // 'D' : -..
-                ('E', 1, 0x00), // 'E' : .
-                ('F', 4, 0x04), // 'F' : ..-.
-                ('G', 3, 0x03), // 'G' : --.
-                ('H', 4, 0x00), // 'H' : ....
-                ('I', 2, 0x00), // 'I' : ..
-                ('J', 4, 0x0e), // 'J' : .---
-                ('K', 3, 0x05), // 'K' : -.-
-                ('L', 4, 0x02), // 'L' : .-..
-                ('M', 2, 0x03), // 'M' : --
-                ('N', 2, 0x01), // 'N' : -.
-                ('O', 3, 0x07), // 'O' : ---
-                ('P', 4, 0x06), // 'P' : .--.
-                ('Q', 4, 0x0b), // 'Q' : --.-
-                ('R', 3, 0x02), // 'R' : .-.
-                ('S', 3, 0x00), // 'S' : ...
-                ('T', 1, 0x01), // 'T' : -
-                ('U', 3, 0x04), // 'U' : ..-
-                ('V', 4, 0x08), // 'V' : ...-
-                ('W', 3, 0x06), // 'W' : .--
-                ('X', 4, 0x09), // 'X' : -..-
-                ('Y', 4, 0x0d), // 'Y' : -.--
-                ('Z', 4, 0x03), // 'Z' : --..
-                ('/', 5, 0x09), // '/' : -..-.
-                ('?', 6, 0x0c), // '?' : ..--..
-                ('.', 6, 0x2a), // '.' : .-.-.-
-                (',', 6, 0x33), // ',' : --..--
-                ('=', 5, 0x11), // '=' : -...-
-                ('!', 6, 0x35), // '!' : -.-.--
-                ('+', 5, 0x0a), // '+' : .-.-.
-                ('-', 6, 0x21), // '-' : -....-
-            ],
+            morse_table: MORSE_TABLE.to_vec(),
         })
     }
 
@@ -146,9 +148,127 @@ impl Keyer {
     }
 }
 
+/// Keys the rig through `RigControl`.
+struct RigKey(Arc<RigControl>);
+
+impl KeyOutput for RigKey {
+    fn set_key(&mut self, down: bool) {
+        self.0.assert_key(down);
+    }
+}
+
+/// Hands the client's other messages to the rig and reports to `RemoteStats`.
+struct RigHooks {
+    stat: Arc<RemoteStats>,
+    rigcon: Arc<RigControl>,
+    link: Arc<WkSession>,
+    decoder: CwDecoder,
+    // セッションログに語ごとに残す
+    word: String,
+}
+
+impl RigHooks {
+    fn decoded(&mut self, events: Vec<CwEvent>) {
+        for event in events {
+            let text = event.to_string();
+            self.stat.push_transcript(&text);
+            match event {
+                CwEvent::WordSpace => {
+                    info!("CW: {}", self.word);
+                    self.word.clear();
+                }
+                _ => self.word.push_str(&text),
+            }
+        }
+    }
+}
+
+impl KeyerHooks for RigHooks {
+    fn resynced(&mut self, stats: &KeyerStats, _now: Tick) {
+        // RemoteStats は 1/10 WPM 単位で持つ
+        self.stat.set_stats((stats.wpm * 10.0) as usize, stats.pkt);
+        self.stat.set_speed(stats.speed);
+        self.stat
+            .set_playout(stats.playout_delay_ms as usize, stats.late_edges as usize);
+        self.stat.set_edge_error(stats.edge_error);
+        self.stat.set_link(self.link.stats().ok());
+        self.stat.set_session_active(true);
+    }
+
+    fn keyed(&mut self, down: bool, now: Tick) {
+        let events = self.decoder.key(down, now);
+        self.decoded(events);
+    }
+
+    fn tick(&mut self, now: Tick) {
+        // 文字間・語間の空白は次の打鍵を待たずに確定させる
+        let events = self.decoder.idle(now);
+        self.decoded(events);
+    }
+
+    fn message(&mut self, msg: MessageRCV, rx_port: &WkReceiver) {
+        match msg {
+            MessageRCV::StartATU => {
+                info!("---- START ATU ----");
+                self.stat.set_atu_start(true);
+                if let Err(e) = self.rigcon.start_atu_with_rigcontrol() {
+                    info!("Start ATU error = {} ", e);
+                };
+                self.stat.set_atu_start(false);
+            }
+            MessageRCV::EncoderEvent {
+                encoder_id,
+                direction,
+                steps,
+            } => {
+                // 溜まっているイベントをドレインして合算（コアレス）
+                let mut acc: i32 = direction as i32 * steps as i32;
+                while let Ok(msgs) = rx_port.try_recv() {
+                    for m in msgs {
+                        if let MessageRCV::EncoderEvent {
+                            encoder_id: eid,
+                            direction: d,
+                            steps: s,
+                        } = m
+                        {
+                            if eid == encoder_id {
+                                acc += d as i32 * s as i32;
+                            }
+                        }
+                    }
+                }
+                if acc != 0 {
+                    let dir = if acc > 0 { 1i8 } else { -1i8 };
+                    // EU0%02d; は2桁上限。99超え分は捨てる
+                    let s = acc.unsigned_abs().min(99) as u8;
+                    trace!("RCV EncoderEvent enc={encoder_id} acc={acc} → dir={dir} steps={s}");
+                    if let Err(e) = self.rigcon.on_encoder_event(encoder_id, dir, s) {
+                        log::warn!("encoder_event: {e}");
+                    }
+                }
+            }
+            MessageRCV::ButtonEvent {
+                button_id,
+                press_ms,
+            } => {
+                if let Err(e) = self.rigcon.on_button_event(button_id, press_ms) {
+                    log::warn!("button_event: {e}");
+                }
+            }
+            MessageRCV::Pong { origin, .. } => {
+                let rtt = rx_port.clock().now().since(origin);
+                self.stat.set_rtt(rtt as usize);
+                trace!("Pong RTT={}ms", rtt);
+            }
+            _ => {}
+        }
+    }
+}
+
 pub struct RemoteKeyer {
     remote_stats: Arc<RemoteStats>,
     rigcontrol: Arc<RigControl>,
+    playout: JitterBufferConfig,
     stop: Arc<AtomicBool>,
 }
 
@@ -160,10 +280,15 @@ impl Drop for RemoteKeyer {
 }
 
 impl RemoteKeyer {
-    pub fn new(remote_stats: Arc<RemoteStats>, rigcontrol: Arc<RigControl>) -> Self {
+    pub fn new(
+        remote_stats: Arc<RemoteStats>,
+        rigcontrol: Arc<RigControl>,
+        playout: JitterBufferConfig,
+    ) -> Self {
         Self {
             remote_stats,
             rigcontrol,
+            playout,
             stop: Arc::new(AtomicBool::new(false)),
         }
     }
@@ -175,198 +300,42 @@ impl RemoteKeyer {
 
     pub fn run(&self, rx_port: WkReceiver, session: Arc<WkSession>) {
         // Create sender for outgoing packets (Ping)
+        let link = session.clone();
+        let clock = rx_port.clock().clone();
+        let ping = session.peer_capabilities().contains(Capabilities::PING);
         let sender = Arc::new(WkSender::new(session).unwrap());
 
         // Spawn ping thread: sends Ping every 5 seconds to measure RTT
-        let sender_ping = sender.clone();
-        let stopfl_ping = self.stop.clone();
-        thread::spawn(move || loop {
-            sleep(5000);
-            if stopfl_ping.load(Ordering::Relaxed) {
-                break;
-            }
-            let ts = tick_count();
-            if sender_ping.send(MessageSND::Ping(ts)).is_err() {
-                break;
-            }
-        });
-
-        let mut rmt_epoch = 0u32;
-        let mut epoch = 0u32;
-        let asserted = Arc::new(AtomicU32::new(0u32));
-        let asserted_wdg = asserted.clone();
-        let mut pkt = 0usize;
-        let mut duration_max = 1usize;
-        let mut last_sync_local = 0u32;
-        let mut last_transit = 0i64; // 前回の transit = now_server - rmt_esp32
-        let mut jitter_x16 = 0i64;  // 固定小数点 1/16 ms 単位（精度維持）
-
-        let rigcon_wdg = self.rigcontrol.clone();
-        let rigcon = self.rigcontrol.clone();
-        let stopfl = self.stop.clone();
-
-        let stat = self.remote_stats.clone();
-        let handle = thread::spawn(move || 'restart: loop {
-            if rx_port.closed() || stopfl.load(Ordering::Relaxed) {
-                info!("session closed");
-                stopfl.store(true, Ordering::Relaxed);
-                stat.set_session_active(false);
-                break;
-            }
-            if let Ok(msgs) = rx_port.recv() {
-                for m in msgs {
-                    pkt += 1;
-                    match m {
-                        MessageRCV::Sync(rmt) => {
-                            // Sync remote/local time every 3 sec
-                            if rmt - rmt_epoch > 3000 {
-                                let now = tick_count();
-
-                                // 一方向遅延変動（ジッター）の計測
-                                // transit = now_server - rmt_esp32 = OWD + clock_offset
-                                // 連続する transit の差を取るとクロックオフセットが消え OWD 変化量 = ジッター が残る
-                                let transit = now as i64 - rmt as i64;
-                                if last_sync_local > 0 {
-                                    let d = (transit - last_transit).unsigned_abs() as i64;
-                                    // RFC 3550 スタイルの EMA: J += (|D| - J) / 16
-                                    // 1/16 ms 単位で精度を維持し、最後に /16 して ms に変換
-                                    jitter_x16 += d * 16 - jitter_x16 / 16;
-                                }
-                                last_transit = transit;
-                                last_sync_local = now;
-
-                                rmt_epoch = rmt;
-                                epoch = now;
-                                let jitter_ms = (jitter_x16 / 16).unsigned_abs() as usize;
-                                trace!(
-                                    "Sync rmt={} local={} jitter~{}ms",
-                                    rmt_epoch, epoch, jitter_ms
-                                );
-                                if duration_max == 0 {
-                                    stat.set_stats(0, pkt / 3);
-                                } else {
-                                    stat.set_stats(1000 / duration_max * 36, pkt / 3);
-                                };
-                                duration_max = 0;
-                                pkt = 0;
-                                stat.set_session_active(true);
-                            }
-                        }
-                        MessageRCV::StartATU => {
-                            info!("---- START ATU ----");
-                            stat.set_atu_start(true);
-                            if let Err(e) = rigcon.start_atu_with_rigcontrol() {
-                                info!("Start ATU error = {} ", e);
-                            };
-                            stat.set_atu_start(false);
-                            break;
-                        }
-                        MessageRCV::EncoderEvent { encoder_id, direction, steps } => {
-                            // 溜まっているイベントをドレインして合算（コアレス）
-                            let mut acc: i32 = direction as i32 * steps as i32;
-                            loop {
-                                match rx_port.try_recv() {
-                                    Ok(msgs) => {
-                                        for m in msgs {
-                                            if let MessageRCV::EncoderEvent { encoder_id: eid, direction: d, steps: s } = m {
-                                                if eid == encoder_id {
-                                                    acc += d as i32 * s as i32;
-                                                }
-                                            }
-                                        }
-                                    }
-                                    Err(_) => break,
-                                }
-                            }
-                            if acc != 0 {
-                                let dir = if acc > 0 { 1i8 } else { -1i8 };
-                                // EU0%02d; は2桁上限。99超え分は捨てる
-                                let s = acc.unsigned_abs().min(99) as u8;
-                                trace!("RCV EncoderEvent enc={encoder_id} acc={acc} → dir={dir} steps={s}");
-                                if let Err(e) = rigcon.on_encoder_event(encoder_id, dir, s) {
-                                    log::warn!("encoder_event: {e}");
-                                }
-                            }
-                        }
-                        MessageRCV::ButtonEvent { button_id, press_ms } => {
-                            if let Err(e) = rigcon.on_button_event(button_id, press_ms) {
-                                log::warn!("button_event: {e}");
-                            }
-                        }
-                        MessageRCV::Pong(ts) => {
-                            let rtt = tick_count().wrapping_sub(ts);
-                            stat.set_rtt(rtt as usize);
-                            trace!("Pong RTT={}ms", rtt);
-                        }
-                        m => {
-                            let mut tm = 0u32;
-                            let mut keydown = false;
-                            match m {
-                                MessageRCV::Keydown(rmt) => {
-                                    tm = rmt;
-                                    keydown = true;
-                                }
-                                MessageRCV::Keyup(rmt) => {
-                                    tm = rmt;
-                                    keydown = false;
-                                }
-                                MessageRCV::SessionClosed => {
-                                    rigcon.assert_key(false);
-                                    break 'restart;
-                                }
-                                _ => {}
-                            }
-                            // Got Key mesg before sync
-                            if rmt_epoch == 0 {
-                                rmt_epoch = tm;
-                                epoch = tick_count()
-                            }
-
-                            // Calculate remote elapse time.
-                            let elapse_rmt = tm - rmt_epoch;
-                            loop {
-                                // calculate local eplapse time
-                                let now = tick_count();
-                                let elapse = now - epoch;
-                                if elapse >= elapse_rmt {
-                                    if keydown {
-                                        rigcon.assert_key(true);
-                                        asserted.store(now, Ordering::Relaxed);
-                                        trace!("down");
-                                    } else {
-                                        rigcon.assert_key(false);
-                                        let duration = now - asserted.load(Ordering::Relaxed);
-                                        if duration > duration_max as u32 {
-                                            duration_max = duration as usize;
-                                        }
-                                        asserted.store(0, Ordering::Relaxed);
-                                        trace!("up");
-                                    }
-                                    break;
-                                }
-                                sleep(1);
-                            }
-                        }
-                    }
+        // Pong を返さないクライアントには送らない（RTT は KCP の統計で見られる）
+        if ping {
+            let sender_ping = sender.clone();
+            let stopfl_ping = self.stop.clone();
+            thread::spawn(move || loop {
+                clock.sleep(5000);
+                if stopfl_ping.load(Ordering::Relaxed) {
+                    break;
                 }
-            } else {
-                info!("session closed");
-                stopfl.store(true, Ordering::Relaxed);
-            }
-        });
+                let ts = clock.now();
+                if sender_ping.send(MessageSND::Ping(ts)).is_err() {
+                    break;
+                }
+            });
+        }
 
-        let stopfl = self.stop.clone();
-        let _watchdog = thread::spawn(move || loop {
-            if stopfl.load(Ordering::Relaxed) {
-                break;
-            }
-            let asserted = asserted_wdg.load(Ordering::Relaxed);
-            if asserted != 0 && tick_count() - asserted > MAX_ASSERT_DURATION {
-                rigcon_wdg.assert_key(false);
-                asserted_wdg.store(0, Ordering::Relaxed);
-            }
-            sleep(1000);
-        });
-        handle.join().unwrap();
+        let player = KeyPlayer::new(RigKey(self.rigcontrol.clone()))
+            .jitter_buffer(self.playout)
+            .realtime_priority(true)
+            .stop_flag(self.stop.clone());
+        let mut hooks = RigHooks {
+            stat: self.remote_stats.clone(),
+            rigcon: self.rigcontrol.clone(),
+            link,
+            decoder: CwDecoder::new(),
+            word: String::new(),
+        };
+        player.run(&rx_port, &mut hooks);
+        let events = hooks.decoder.finish();
+        hooks.decoded(events);
+        self.remote_stats.set_session_active(false);
     }
 }
@@ -2,6 +2,7 @@
 
 pub mod commands;
 pub mod config;
+pub mod decoder;
 pub mod keyer;
 pub mod rigcontrol;
 pub mod server;
@@ -8,6 +8,7 @@ use tokio::sync::Mutex;
 
 mod commands;
 mod config;
+mod decoder;
 mod keyer;
 mod rigcontrol;
 mod server;
@@ -26,8 +27,38 @@ pub struct SessionStats {
     pub atu_active: bool,
     pub wpm: f32,
     pub pkt_per_sec: usize,
+    /// Dah length over dit length, 0 if not known yet
+    pub dah_ratio: f32,
+    /// Weighting in percent, 0 if not known yet
+    pub weight: f32,
+    /// Overall speed with the character and word gaps as sent
+    pub farnsworth_wpm: f32,
     /// Round-trip time in milliseconds
     pub rtt_ms: usize,
+    /// Playout delay of the keyer in milliseconds
+    pub playout_delay_ms: usize,
+    /// Key edges that arrived after they were due
+    pub late_edges: usize,
+    /// Median lateness of the played key edges in microseconds
+    pub edge_error_p50_us: u32,
+    /// 99th percentile of the lateness in microseconds
+    pub edge_error_p99_us: u32,
+    pub edge_error_max_us: u32,
+    /// Played edges per bucket of `EDGE_ERROR_BOUNDS_US`
+    pub edge_error_buckets: Vec<u32>,
+    /// Recent text decoded from the received keying
+    pub transcript: String,
+    /// KCP smoothed RTT in milliseconds
+    pub srtt_ms: u32,
+    /// KCP retransmission timeout in milliseconds
+    pub rto_ms: u32,
+    pub retransmissions: u64,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+    /// Segments waiting to be sent or acknowledged
+    pub send_queue: usize,
+    /// Milliseconds since the last packet from the client
+    pub idle_ms: u64,
     /// 緊急停止が有効かどうか
     pub emergency_stopped: bool,
 }
@@ -37,6 +68,10 @@ pub struct SessionStats {
 fn get_session_stats(state: State<'_, AppState>) -> SessionStats {
     let stats = state.remote_stats.get_session_stats();
     let (auth, atu, wpm, pkt, rtt) = state.remote_stats.get_misc_stats();
+    let speed = state.remote_stats.get_speed();
+    let (playout_delay_ms, late_edges) = state.remote_stats.get_playout();
+    let edge_error = state.remote_stats.get_edge_error();
+    let link = state.remote_stats.get_link().unwrap_or_default();
     let stopped = {
         let guard = state.server.blocking_lock();
         guard.as_ref().map(|s| s.is_stopped()).unwrap_or(false)
@@ -49,7 +84,24 @@ fn get_session_stats(state: State<'_, AppState>) -> SessionStats {
         atu_active: atu,
         wpm: wpm as f32 / 10.0,
         pkt_per_sec: pkt,
+        dah_ratio: speed.and_then(|s| s.dah_ratio).unwrap_or(0.0),
+        weight: speed.and_then(|s| s.weight).unwrap_or(0.0),
+        farnsworth_wpm: speed.map_or(0.0, |s| s.farnsworth_wpm),
         rtt_ms: rtt,
+        playout_delay_ms,
+        late_edges,
+        edge_error_p50_us: edge_error.percentile_us(50),
+        edge_error_p99_us: edge_error.percentile_us(99),
+        edge_error_max_us: edge_error.max_us(),
+        edge_error_buckets: edge_error.buckets().to_vec(),
+        transcript: state.remote_stats.get_transcript(),
+        srtt_ms: link.srtt_ms,
+        rto_ms: link.rto_ms,
+        retransmissions: link.retransmissions,
+        duplicates: link.duplicates,
+        out_of_order: link.out_of_order,
+        send_queue: link.send_queue,
+        idle_ms: link.since_last_recv.as_millis() as u64,
         emergency_stopped: stopped,
     }
 }
@@ -134,7 +186,10 @@ async fn get_config(state: State<'_, AppState>) -> Result<AppConfig, String> {
 
 /// Save configuration
 #[tauri::command]
-async fn save_config(state: State<'_, AppState>, new_config: AppConfig) -> Result<(), String> {
+async fn save_config(state: State<'_, AppState>, mut new_config: AppConfig) -> Result<(), String> {
+    // パスワードが変わっていれば verifier を作り直す
+    new_config.refresh_verifier();
+
     // Save to file
     new_config.save().map_err(|e| e.to_string())?;
 
@@ -311,7 +366,12 @@ async fn restart_server_internal(
     state.remote_stats.set_session_active(false);
     state.remote_stats.set_auth_ok(false);
     state.remote_stats.set_stats(0, 0);
+    state.remote_stats.set_speed(None);
     state.remote_stats.set_rtt(0);
+    state.remote_stats.set_playout(0, 0);
+    state.remote_stats.set_edge_error(Default::default());
+    state.remote_stats.set_link(None);
+    state.remote_stats.clear_transcript();
 
     // Create new server configuration
     let wk_config = Arc::new(WiFiKeyConfig::new(
@@ -321,6 +381,9 @@ async fn restart_server_internal(
         config.keying_port.clone(),
         config.use_rts_for_keying,
         config.rig_script.clone(),
+        config.auth_verifier(),
+        config.allow_legacy_auth,
+        config.jitter_buffer(),
     ));
 
     // Create new server
@@ -344,6 +407,9 @@ fn init_server(
         config.keying_port.clone(),
         config.use_rts_for_keying,
         config.rig_script.clone(),
+        config.auth_verifier(),
+        config.allow_legacy_auth,
+        config.jitter_buffer(),
     ));
 
     let server = WifiKeyServer::new(wk_config, remote_stats)
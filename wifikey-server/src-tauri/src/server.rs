@@ -7,14 +7,99 @@ use mdns_sd::{ServiceDaemon, ServiceInfo};
 use mqttstunclient::MQTTStunClient;
 use socket2::{Domain, Protocol, Socket, Type};
 use std::collections::HashMap;
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::sync::atomic::Ordering;
 use std::sync::{
     atomic::{AtomicBool, AtomicUsize},
-    mpsc, Arc, Mutex,
+    mpsc, Arc, Mutex, OnceLock,
 };
 use std::thread::{self, JoinHandle};
-use wksocket::{challenge, WkListener, WkReceiver, WkSession, MDNS_SERVICE_TYPE};
+use std::time::{Duration, Instant};
+use wksocket::{
+    challenge, AuthVerifier, Capabilities, Capture, CloseReason, EdgeErrorHistogram,
+    JitterBufferConfig, SpeedEstimate, WkError, WkListener, WkReceiver, WkSession, WkSessionConfig,
+    WkSessionStats, MDNS_SERVICE_TYPE,
+};
+
+// 受信側のリグは1台なので、LAN で同時に受け付けるセッションは1つ
+const LAN_MAX_SESSIONS: usize = 1;
+// 画面に残す解読結果の文字数
+const TRANSCRIPT_LEN: usize = 120;
+// 同じアドレスからパスワード違いがこの回数続いたら、しばらく認証させない
+const MAX_AUTH_FAILURES: u32 = 5;
+const AUTH_LOCKOUT: Duration = Duration::from_secs(300);
+
+/// Wrong passwords per client address.
+///
+/// Only `WkError::AuthFailed` counts; timeouts and dropped links are the
+/// network's fault. An address is locked out after `MAX_AUTH_FAILURES` and
+/// forgiven `AUTH_LOCKOUT` after its last failure.
+#[derive(Default)]
+struct AuthLockout {
+    failures: HashMap<IpAddr, (u32, Instant)>,
+}
+
+impl AuthLockout {
+    fn locked(&mut self, ip: IpAddr, now: Instant) -> bool {
+        match self.failures.get(&ip) {
+            Some(&(_, last)) if now.duration_since(last) >= AUTH_LOCKOUT => {
+                self.failures.remove(&ip);
+                false
+            }
+            Some(&(count, _)) => count >= MAX_AUTH_FAILURES,
+            None => false,
+        }
+    }
+
+    fn failed(&mut self, ip: IpAddr, now: Instant) {
+        let entry = self.failures.entry(ip).or_insert((0, now));
+        entry.0 += 1;
+        entry.1 = now;
+    }
+
+    fn succeeded(&mut self, ip: IpAddr) {
+        self.failures.remove(&ip);
+    }
+}
+
+// hello でクライアントに知らせる、このサーバーが受け取れるメッセージ
+fn session_config() -> WkSessionConfig {
+    let config = WkSessionConfig::default()
+        .software(concat!("wifikey-server ", env!("CARGO_PKG_VERSION")))
+        .capabilities(
+            Capabilities::KEYING
+                | Capabilities::KEYING_DATAGRAMS
+                | Capabilities::ATU
+                | Capabilities::ENCODER_EVENTS
+                | Capabilities::BUTTON_EVENTS
+                | Capabilities::TLV_MESSAGES,
+        );
+    match capture() {
+        Some(capture) => config.capture(capture),
+        None => config,
+    }
+}
+
+// WIFIKEY_CAPTURE にファイル名を指定すると全セッションのパケットを記録する。
+// WAN のリスナーは接続ごとに作り直すので、ファイルは起動中 1 つだけ開く
+fn capture() -> Option<Capture> {
+    static CAPTURE: OnceLock<Option<Capture>> = OnceLock::new();
+    CAPTURE
+        .get_or_init(|| {
+            let path = std::env::var("WIFIKEY_CAPTURE").ok()?;
+            match Capture::create(&path) {
+                Ok(capture) => {
+                    info!("capturing sessions to {path}");
+                    Some(capture)
+                }
+                Err(e) => {
+                    warn!("can't create capture file {path}: {e}");
+                    None
+                }
+            }
+        })
+        .clone()
+}
 
 pub struct WiFiKeyConfig {
     server_name: String,
@@ -23,9 +108,13 @@ pub struct WiFiKeyConfig {
     keying_port: String,
     use_rts_for_keying: bool,
     pub rig_script: String,
+    server_verifier: AuthVerifier,
+    allow_legacy_auth: bool,
+    playout: JitterBufferConfig,
 }
 
 impl WiFiKeyConfig {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         server_name: String,
         server_password: String,
@@ -33,6 +122,9 @@ impl WiFiKeyConfig {
         keying_port: String,
         use_rts_for_keying: bool,
         rig_script: String,
+        server_verifier: AuthVerifier,
+        allow_legacy_auth: bool,
+        playout: JitterBufferConfig,
     ) -> Self {
         Self {
             server_name,
@@ -41,6 +133,9 @@ impl WiFiKeyConfig {
             keying_port,
             use_rts_for_keying,
             rig_script,
+            server_verifier,
+            allow_legacy_auth,
+            playout,
         }
     }
 }
@@ -54,8 +149,20 @@ pub struct RemoteStats {
     pub atu_start: Arc<AtomicBool>,
     pub wpm: Arc<AtomicUsize>,
     pub pkt: Arc<AtomicUsize>,
+    /// Dah:dit ratio, weighting and Farnsworth speed of the recent keying
+    pub speed: Arc<Mutex<Option<SpeedEstimate>>>,
     /// Round-trip time in milliseconds (estimated from sync timing)
     pub rtt_ms: Arc<AtomicUsize>,
+    /// Current playout delay of the keyer in milliseconds
+    pub playout_delay_ms: Arc<AtomicUsize>,
+    /// Key edges that arrived after they were due in this session
+    pub late_edges: Arc<AtomicUsize>,
+    /// Lateness of the played key edges against their schedule
+    pub edge_error: Arc<Mutex<EdgeErrorHistogram>>,
+    /// Latest link-quality snapshot of the active session
+    pub link: Arc<Mutex<Option<WkSessionStats>>>,
+    /// Last `TRANSCRIPT_LEN` characters decoded from the keying
+    pub transcript: Arc<Mutex<String>>,
 }
 
 impl Default for RemoteStats {
@@ -68,7 +175,13 @@ impl Default for RemoteStats {
             atu_start: Arc::new(AtomicBool::new(false)),
             wpm: Arc::new(AtomicUsize::new(0)),
             pkt: Arc::new(AtomicUsize::new(0)),
+            speed: Arc::new(Mutex::new(None)),
             rtt_ms: Arc::new(AtomicUsize::new(0)),
+            playout_delay_ms: Arc::new(AtomicUsize::new(0)),
+            late_edges: Arc::new(AtomicUsize::new(0)),
+            edge_error: Arc::new(Mutex::new(EdgeErrorHistogram::default())),
+            link: Arc::new(Mutex::new(None)),
+            transcript: Arc::new(Mutex::new(String::new())),
         }
     }
 }
@@ -127,11 +240,76 @@ impl RemoteStats {
         self.pkt.store(pkt, Ordering::Relaxed);
     }
 
+    #[allow(dead_code)]
+    pub fn set_speed(&self, speed: Option<SpeedEstimate>) {
+        *self.speed.lock().expect("lock failed") = speed;
+    }
+
+    #[allow(dead_code)]
+    pub fn get_speed(&self) -> Option<SpeedEstimate> {
+        *self.speed.lock().expect("lock failed")
+    }
+
     #[allow(dead_code)]
     pub fn set_rtt(&self, rtt_ms: usize) {
         self.rtt_ms.store(rtt_ms, Ordering::Relaxed);
     }
 
+    #[allow(dead_code)]
+    pub fn set_playout(&self, delay_ms: usize, late_edges: usize) {
+        self.playout_delay_ms.store(delay_ms, Ordering::Relaxed);
+        self.late_edges.store(late_edges, Ordering::Relaxed);
+    }
+
+    #[allow(dead_code)]
+    pub fn get_playout(&self) -> (usize, usize) {
+        (
+            self.playout_delay_ms.load(Ordering::Relaxed),
+            self.late_edges.load(Ordering::Relaxed),
+        )
+    }
+
+    #[allow(dead_code)]
+    pub fn set_edge_error(&self, errors: EdgeErrorHistogram) {
+        *self.edge_error.lock().expect("lock failed") = errors;
+    }
+
+    #[allow(dead_code)]
+    pub fn get_edge_error(&self) -> EdgeErrorHistogram {
+        *self.edge_error.lock().expect("lock failed")
+    }
+
+    #[allow(dead_code)]
+    pub fn set_link(&self, link: Option<WkSessionStats>) {
+        *self.link.lock().expect("lock failed") = link;
+    }
+
+    #[allow(dead_code)]
+    pub fn get_link(&self) -> Option<WkSessionStats> {
+        *self.link.lock().expect("lock failed")
+    }
+
+    /// Append decoded text, dropping the oldest beyond `TRANSCRIPT_LEN`
+    #[allow(dead_code)]
+    pub fn push_transcript(&self, text: &str) {
+        let mut transcript = self.transcript.lock().expect("lock failed");
+        transcript.push_str(text);
+        let excess = transcript.chars().count().saturating_sub(TRANSCRIPT_LEN);
+        if let Some((i, _)) = transcript.char_indices().nth(excess) {
+            transcript.drain(..i);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn get_transcript(&self) -> String {
+        self.transcript.lock().expect("lock failed").clone()
+    }
+
+    #[allow(dead_code)]
+    pub fn clear_transcript(&self) {
+        self.transcript.lock().expect("lock failed").clear();
+    }
+
     #[allow(dead_code)]
     pub fn get_misc_stats(&self) -> (bool, bool, usize, usize, usize) {
         (
@@ -177,7 +355,7 @@ impl Drop for WifiKeyServer {
         self.stop.store(true, Ordering::Relaxed);
         if let Ok(mut guard) = self.active_session.lock() {
             if let Some(session) = guard.take() {
-                let _ = session.close();
+                let _ = session.close_with(CloseReason::Shutdown);
             }
         }
         if let Some(handle) = self.handle.take() {
@@ -255,72 +433,91 @@ impl WifiKeyServer {
                 fullname, addrs, lan_port
             );
 
+            // WAN 側の WkListener はセッション終了まで保持する (recvスレッドがパケットを供給し続ける)
+            let (tx, rx) =
+                mpsc::channel::<(Arc<WkSession>, std::net::SocketAddr, Option<WkListener>)>();
+
+            // LAN listener (dual-stack: IPv4 and IPv6 via single socket)
+            // ソケットとリスナーは1つだけ。キーイング中の2台目には busy を返す
+            let tx_lan = tx.clone();
+            let quit_lan = quit_thread.clone();
+            let mut lan_listener =
+                WkListener::bind_with_config(lan_udp, LAN_MAX_SESSIONS, session_config()).unwrap();
+            thread::spawn(move || {
+                while !quit_lan.load(Ordering::Relaxed) {
+                    let (session, addr) =
+                        match lan_listener.accept_timeout(std::time::Duration::from_secs(1)) {
+                            Ok(Some(accepted)) => accepted,
+                            Ok(None) => continue,
+                            Err(_) => break,
+                        };
+                    info!("LAN: accepted connection from {}", addr);
+                    if tx_lan.send((session, addr, None)).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            let wan_pending = Arc::new(AtomicBool::new(false));
+            let mut lockout = AuthLockout::default();
             loop {
                 if quit_thread.load(Ordering::Relaxed) {
                     let _ = mdns.shutdown();
                     break;
                 }
 
-                // WkListenerをセッション終了まで保持する (recvスレッドがパケットを供給し続ける)
-                let (tx, rx) =
-                    mpsc::channel::<(Arc<WkSession>, std::net::SocketAddr, WkListener)>();
-
-                // LAN listener (dual-stack: IPv4 and IPv6 via single socket)
-                let tx_lan = tx.clone();
-                let lan_udp_clone = lan_udp.try_clone().unwrap();
-                let quit_lan = quit_thread.clone();
-                thread::spawn(move || {
-                    if quit_lan.load(Ordering::Relaxed) {
-                        return;
-                    }
-                    let mut listener = WkListener::bind(lan_udp_clone).unwrap();
-                    if let Ok((session, addr)) = listener.accept() {
-                        info!("LAN: accepted connection from {}", addr);
-                        let _ = tx_lan.send((session, addr, listener));
-                    }
-                });
-
                 // WAN listener (MQTT/STUN)
-                let tx_wan = tx.clone();
-                let server_name = config.server_name.clone();
-                let server_password = config.server_password.clone();
-                let quit_wan = quit_thread.clone();
-                thread::spawn(move || {
-                    if quit_wan.load(Ordering::Relaxed) {
-                        return;
-                    }
-                    // IPv4でバインド: WindowsのUdpSocket::bind("[::]:0")はIPV6_V6ONLY=trueに
-                    // なるためSTUN(IPv4)が失敗する。IPv4ソケットを使うことで確実にSTUNが動く。
-                    // IPv6クライアントにはtry_get_routable_ipv6()で取得したアドレスを通知する。
-                    let wan_udp = UdpSocket::bind("0.0.0.0:0").unwrap();
-                    let mut mqtt =
-                        MQTTStunClient::new(server_name, &server_password, None, None);
-                    let has_v6 = MQTTStunClient::try_get_routable_ipv6().is_some();
-                    let conn_result = match mqtt.get_client_addr(&wan_udp, has_v6) {
-                        Some(r) => r,
-                        None => {
-                            if let Ok(addr) = wan_udp.local_addr() {
-                                info!("WAN: local address = {}", addr);
-                            }
+                // 前回のスレッドがまだ待ち受け中なら新たに起動しない
+                if !wan_pending.swap(true, Ordering::Relaxed) {
+                    let tx_wan = tx.clone();
+                    let server_name = config.server_name.clone();
+                    let server_password = config.server_password.clone();
+                    let quit_wan = quit_thread.clone();
+                    let wan_pending = wan_pending.clone();
+                    thread::spawn(move || {
+                        if quit_wan.load(Ordering::Relaxed) {
+                            wan_pending.store(false, Ordering::Relaxed);
                             return;
                         }
-                    };
-                    info!("WAN: client address = {}", conn_result.peer_addr);
-                    let mut listener = WkListener::bind(wan_udp).unwrap();
-                    if let Ok((session, addr)) = listener.accept() {
-                        info!("WAN: accepted connection from {}", addr);
-                        let _ = tx_wan.send((session, addr, listener));
-                    }
-                });
+                        // IPv4でバインド: WindowsのUdpSocket::bind("[::]:0")はIPV6_V6ONLY=trueに
+                        // なるためSTUN(IPv4)が失敗する。IPv4ソケットを使うことで確実にSTUNが動く。
+                        // IPv6クライアントにはtry_get_routable_ipv6()で取得したアドレスを通知する。
+                        let wan_udp = UdpSocket::bind("0.0.0.0:0").unwrap();
+                        let mut mqtt =
+                            MQTTStunClient::new(server_name, &server_password, None, None);
+                        let has_v6 = MQTTStunClient::try_get_routable_ipv6().is_some();
+                        let conn_result = match mqtt.get_client_addr(&wan_udp, has_v6) {
+                            Some(r) => r,
+                            None => {
+                                if let Ok(addr) = wan_udp.local_addr() {
+                                    info!("WAN: local address = {}", addr);
+                                }
+                                wan_pending.store(false, Ordering::Relaxed);
+                                return;
+                            }
+                        };
+                        info!("WAN: client address = {}", conn_result.peer_addr);
+                        let mut listener =
+                            WkListener::bind_with_config(wan_udp, 1, session_config()).unwrap();
+                        if let Ok((session, addr)) = listener.accept() {
+                            info!("WAN: accepted connection from {}", addr);
+                            let _ = tx_wan.send((session, addr, Some(listener)));
+                        }
+                        wan_pending.store(false, Ordering::Relaxed);
+                    });
+                }
 
-                drop(tx);
                 let connection = loop {
                     if quit_thread.load(Ordering::Relaxed) {
                         break None;
                     }
                     match rx.recv_timeout(std::time::Duration::from_secs(1)) {
                         Ok(result) => break Some(result),
-                        Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                        Err(mpsc::RecvTimeoutError::Timeout) => {
+                            if !wan_pending.load(Ordering::Relaxed) {
+                                break None;
+                            }
+                        }
                         Err(mpsc::RecvTimeoutError::Disconnected) => break None,
                     }
                 };
@@ -329,31 +526,65 @@ impl WifiKeyServer {
                         let _ = mdns.shutdown();
                         break;
                     }
-                    warn!("No connection received, retrying...");
                     continue;
                 };
 
                 let local_time: DateTime<Local> = Local::now();
                 info!("{}: Accept new session from {}", local_time, addr);
+                // デュアルスタックでは IPv4 が ::ffff:a.b.c.d で届くので正規化して数える
+                let ip = addr.ip().to_canonical();
+                if lockout.locked(ip, Instant::now()) {
+                    warn!("Auth. refused: {ip} is locked out after repeated failures");
+                    let _ = session.close_with(CloseReason::AuthFailure);
+                    continue;
+                }
                 stat.set_peer(&addr.to_string());
                 stat.set_session_start(&local_time.format("%F %T").to_string());
-                let Ok(_magic) = challenge(session.clone(), &config.server_password) else {
-                    info!("Auth. failure.");
+                let legacy_passwd = config
+                    .allow_legacy_auth
+                    .then_some(config.server_password.as_str());
+                if let Err(e) = challenge(session.clone(), &config.server_verifier, legacy_passwd) {
+                    let reason = match e {
+                        WkError::AuthFailed => {
+                            info!("Auth. failure: wrong password from {ip}");
+                            lockout.failed(ip, Instant::now());
+                            CloseReason::AuthFailure
+                        }
+                        // 回線やクライアントの不具合はパスワード違いとして数えない
+                        e => {
+                            info!("Auth. failure: {e}");
+                            CloseReason::LinkLost
+                        }
+                    };
                     stat.set_auth_ok(false);
                     stat.clear_peer();
                     stat.clear_session_start();
-                    let _ = session.close();
+                    let _ = session.close_with(reason);
                     continue;
-                };
-                info!("Auth. Success.");
+                }
+                lockout.succeeded(ip);
+                if session.encrypted() {
+                    info!("Auth. Success. (encrypted)");
+                } else {
+                    warn!("Auth. Success. (legacy client, traffic is not encrypted)");
+                }
+                match session.peer_hello() {
+                    Some(hello) => info!(
+                        "Client {} (protocol {}) {:?}",
+                        hello.software, hello.protocol, hello.capabilities
+                    ),
+                    None => info!("Client sent no hello, assuming baseline capabilities"),
+                }
                 stat.set_auth_ok(true);
+                // 解読結果は切断後も次のセッションまで残す
+                stat.clear_transcript();
                 {
                     let mut guard = active_session_clone.lock().unwrap();
                     *guard = Some(session.clone());
                 }
                 let mesg = WkReceiver::new(session.clone()).unwrap();
                 stat.set_peer(&addr.to_string());
-                let remote = RemoteKeyer::new(stat.clone(), rig.clone());
+                let remote = RemoteKeyer::new(stat.clone(), rig.clone(), config.playout);
                 remote.run(mesg, session);
                 {
                     let mut guard = active_session_clone.lock().unwrap();
@@ -364,6 +595,10 @@ impl WifiKeyServer {
                 stat.clear_peer();
                 stat.clear_session_start();
                 stat.set_stats(0, 0);
+                stat.set_speed(None);
+                stat.set_playout(0, 0);
+                stat.set_edge_error(EdgeErrorHistogram::default());
+                stat.set_link(None);
             }
         });
         Ok(Self {
@@ -399,7 +634,7 @@ impl WifiKeyServer {
         self.rigcontrol.emergency_stop();
         if let Ok(mut guard) = self.active_session.lock() {
             if let Some(session) = guard.take() {
-                let _ = session.close();
+                let _ = session.close_with(CloseReason::EmergencyStop);
             }
         }
     }
@@ -413,3 +648,55 @@ impl WifiKeyServer {
         self.rigcontrol.is_stopped()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockout_after_repeated_failures() {
+        let mut lockout = AuthLockout::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let start = Instant::now();
+        for i in 0..MAX_AUTH_FAILURES {
+            assert!(!lockout.locked(ip, start));
+            lockout.failed(ip, start + Duration::from_secs(i as u64));
+        }
+        let last = start + Duration::from_secs(MAX_AUTH_FAILURES as u64 - 1);
+        assert!(lockout.locked(ip, last));
+        assert!(!lockout.locked(other, last));
+        // 最後の失敗から AUTH_LOCKOUT 経てば許す
+        assert!(lockout.locked(ip, last + AUTH_LOCKOUT - Duration::from_secs(1)));
+        assert!(!lockout.locked(ip, last + AUTH_LOCKOUT));
+        assert!(!lockout.locked(ip, last + AUTH_LOCKOUT));
+    }
+
+    #[test]
+    fn test_lockout_forgiven_on_success() {
+        let mut lockout = AuthLockout::default();
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let now = Instant::now();
+        for _ in 0..MAX_AUTH_FAILURES - 1 {
+            lockout.failed(ip, now);
+        }
+        lockout.succeeded(ip);
+        lockout.failed(ip, now);
+        assert!(!lockout.locked(ip, now));
+    }
+
+    #[test]
+    fn test_transcript_keeps_the_tail() {
+        let stats = RemoteStats::default();
+        stats.push_transcript("CQ CQ DE ");
+        assert_eq!(stats.get_transcript(), "CQ CQ DE ");
+        for _ in 0..TRANSCRIPT_LEN {
+            stats.push_transcript("<SK>");
+        }
+        let transcript = stats.get_transcript();
+        assert_eq!(transcript.chars().count(), TRANSCRIPT_LEN);
+        assert!(transcript.ends_with("<SK>"));
+        stats.clear_transcript();
+        assert_eq!(stats.get_transcript(), "");
+    }
+}
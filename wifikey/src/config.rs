@@ -213,6 +213,9 @@ pub struct WifiProfile {
     pub server_name: String,
     pub server_password: String,
     pub tethering: bool,
+    /// Speak the unencrypted MD5 challenge with peers that predate SPAKE2+:
+    /// old servers for a client, old clients for the server build
+    pub legacy_auth: bool,
 }
 
 impl WifiProfile {
@@ -224,6 +227,7 @@ impl WifiProfile {
             server_name: server_name.to_string(),
             server_password: server_password.to_string(),
             tethering: false,
+            legacy_auth: false,
         }
     }
 
@@ -244,8 +248,14 @@ impl WifiProfile {
         buf.push(self.server_password.len() as u8);
         buf.extend_from_slice(self.server_password.as_bytes());
 
-        // Flags byte: bit0 = tethering
-        let flags: u8 = if self.tethering { 0x01 } else { 0x00 };
+        // Flags byte: bit0 = tethering, bit1 = legacy_auth
+        let mut flags: u8 = 0;
+        if self.tethering {
+            flags |= 0x01;
+        }
+        if self.legacy_auth {
+            flags |= 0x02;
+        }
         buf.push(flags);
 
         buf
@@ -275,11 +285,9 @@ impl WifiProfile {
         let server_password = read_string(data, &mut offset)?;
 
         // Read optional flags byte (backward compatible with old format)
-        let tethering = if offset < data.len() {
-            data[offset] & 0x01 != 0
-        } else {
-            false
-        };
+        let flags = data.get(offset).copied().unwrap_or(0);
+        let tethering = flags & 0x01 != 0;
+        let legacy_auth = flags & 0x02 != 0;
 
         Ok(Self {
             ssid,
@@ -287,6 +295,7 @@ impl WifiProfile {
             server_name,
             server_password,
             tethering,
+            legacy_auth,
         })
     }
 
@@ -353,6 +362,7 @@ impl ConfigManager {
             server_name: CFG_SERVER_NAME.to_string(),
             server_password: CFG_SERVER_PASSWORD.to_string(),
             tethering: CFG_TETHERING == "true",
+            legacy_auth: false,
         }]
     }
 
@@ -4,6 +4,8 @@
 //! ../WiFiKey の RotaryEncoder ライブラリ (Matthias Hertel) の Rust 移植。
 //! LatchMode::FOUR3 / TWO03 をサポート。
 
+use wksocket::Tick;
+
 /// クアドラチャ状態テーブル: (prev_AB << 2 | curr_AB) → direction
 /// 1=時計回り(UP), -1=反時計回り(DOWN), 0=無効遷移
 const KNOBDIR: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
@@ -30,8 +32,8 @@ pub struct QuadratureDecoder {
     state: u8,
     /// ラッチ到達直前の方向を蓄積 (+1 or -1)
     pending_dir: i8,
-    /// 前回ステップのタイムスタンプ (ms)
-    last_step_ms: u32,
+    /// 前回ステップのタイムスタンプ
+    last_step: Tick,
     /// 前回ステップからの経過時間 (ms), 初回は0
     pub last_interval_ms: u32,
     initialized: bool,
@@ -43,7 +45,7 @@ impl QuadratureDecoder {
         QuadratureDecoder {
             state: LATCH3, // 起動時はラッチ位置にいると仮定
             pending_dir: 0,
-            last_step_ms: 0,
+            last_step: Tick::ZERO,
             last_interval_ms: 0,
             initialized: false,
             mode,
@@ -56,7 +58,7 @@ impl QuadratureDecoder {
     /// - 中間状態の遷移は pending_dir に蓄積するだけ
     /// - ラッチ位置に達したときだけ結果を確定・返却
     /// - DEBOUNCE_MS 以内の連続ラッチは無視
-    pub fn tick(&mut self, a: bool, b: bool, now_ms: u32) -> Option<i8> {
+    pub fn tick(&mut self, a: bool, b: bool, now: Tick) -> Option<i8> {
         let new_ab = ((a as u8) << 1) | (b as u8);
         let idx = ((self.state & 0x03) << 2) | new_ab;
         self.state = new_ab;
@@ -78,14 +80,14 @@ impl QuadratureDecoder {
             let resolved = if self.pending_dir > 0 { 1i8 } else { -1i8 };
             self.pending_dir = 0;
 
-            let interval = now_ms.wrapping_sub(self.last_step_ms);
+            let interval = now.since(self.last_step);
             if self.initialized && interval < DEBOUNCE_MS {
                 return None; // デバウンス
             }
             if self.initialized {
                 self.last_interval_ms = interval;
             }
-            self.last_step_ms = now_ms;
+            self.last_step = now;
             self.initialized = true;
             Some(resolved)
         } else {
@@ -45,10 +45,13 @@ use log::LevelFilter;
 use log::{error, info, trace, warn};
 use mqttstunclient::MQTTStunClient;
 #[cfg(feature = "server")]
-use wksocket::{challenge, WkListener, WkReceiver};
+use wksocket::{challenge, AuthVerifier, WkListener, WkReceiver};
 #[cfg(not(feature = "server"))]
-use wksocket::{response, tick_count, MessageRCV, MessageSND, WkReceiver, WkSender, WkSession, MAX_SLOTS};
-use wksocket::{sleep, MDNS_PROTO, MDNS_SERVICE_NAME};
+use wksocket::{
+    response, tick_us, Clock, MessageRCV, MessageSND, SystemClock, Tick, WkError, WkReceiver,
+    WkSender, WkSession,
+};
+use wksocket::{sleep, Capabilities, CloseReason, WkSessionConfig, MDNS_PROTO, MDNS_SERVICE_NAME};
 
 use config::GpioConfig;
 use config::{ConfigManager, WifiProfile};
@@ -65,6 +68,16 @@ const SLEEP_PERIOD: usize = 148_000; // Doze after empty packets sent
 const PKT_INTERVAL: usize = 50; // Send keying packet every 50ms
 #[cfg(not(feature = "server"))]
 const KEEP_ALIVE: u32 = 3_000; // Send Keep Alive Packet every 3sec
+// パスワード違いの再試行間隔: 5秒から倍々で最大60秒（サーバーのロックアウトを踏まない）
+#[cfg(not(feature = "server"))]
+const AUTH_RETRY_MS: u32 = 5_000;
+#[cfg(not(feature = "server"))]
+const AUTH_RETRY_MAX_MS: u32 = 60_000;
+// サーバーが他のクライアントと接続中なら長めに待つ
+#[cfg(not(feature = "server"))]
+const SERVER_BUSY_RETRY_MS: u32 = 30_000;
+// hello で相手に知らせるファームウェア名
+const SOFTWARE: &str = concat!("wifikey ", env!("CARGO_PKG_VERSION"));
 
 // GPIO interrupt flag (client only) — ISRでエッジを即時捕捉してポーリングを補完する
 #[cfg(not(feature = "server"))]
@@ -468,6 +481,8 @@ fn run_keying_loop<K: InputPin, B: InputPin>(
     let mut slot_count: usize = 0;
     // v6接続が失敗した場合に次回のリトライでIPv4を強制するフラグ
     let mut force_v4 = false;
+    // 連続したパスワード違いの回数（再試行間隔の倍率）
+    let mut auth_failures: u32 = 0;
     // WiFi 再接続中に使う青色（ローカル定数）
     #[cfg(feature = "board_m5atom")]
     let blue_color = std::iter::repeat(RGB8 { r: 0, g: 0, b: 5 }).take(1);
@@ -614,24 +629,60 @@ fn run_keying_loop<K: InputPin, B: InputPin>(
                 udp
             }
         };
-        let Ok(session) = WkSession::connect(remote_addr, udp) else {
+        // クライアントが受け取るのは Ping だけ（TLV 形式でも読める）
+        let config = WkSessionConfig::default()
+            .software(SOFTWARE)
+            .capabilities(Capabilities::PING | Capabilities::TLV_MESSAGES);
+        let Ok(session) = WkSession::connect_with_config(remote_addr, udp, config) else {
             error!("Failed to connect to server");
             sleep(5000);
             continue;
         };
-        if let Err(e) = response(session.clone(), &profile.server_password) {
-            let _ = session.close();
-            info!("Auth. failed: {e:?}");
-            // v6で接続失敗した場合は次回はIPv4を強制する
-            if remote_addr.is_ipv6() && !force_v4 {
-                warn!("v6 auth failed; forcing v4 on next attempt");
-                force_v4 = true;
+        // 旧サーバー (MD5 チャレンジのみ) への接続はプロファイルで許可したときだけ
+        if let Err(e) = response(session.clone(), &profile.server_password, profile.legacy_auth) {
+            match e {
+                // 経路は通っているのでIPv4に切り替えず、間隔を空けて再試行する
+                WkError::AuthFailed => {
+                    let _ = session.close_with(CloseReason::AuthFailure);
+                    let wait = AUTH_RETRY_MS
+                        .saturating_mul(1 << auth_failures.min(4))
+                        .min(AUTH_RETRY_MAX_MS);
+                    auth_failures += 1;
+                    error!("Auth. failed: wrong password? retry in {wait}ms");
+                    sleep(wait);
+                }
+                WkError::Closed {
+                    reason: Some(CloseReason::ServerBusy),
+                } => {
+                    warn!("Server busy; retry in {SERVER_BUSY_RETRY_MS}ms");
+                    sleep(SERVER_BUSY_RETRY_MS);
+                }
+                e => {
+                    info!("Auth. failed: {e}");
+                    // パスワード違いではないので、サーバーには回線断として伝える
+                    if !matches!(e, WkError::Closed { .. }) {
+                        let _ = session.close_with(CloseReason::LinkLost);
+                    }
+                    // v6で接続失敗した場合は次回はIPv4を強制する
+                    if remote_addr.is_ipv6() && !force_v4 {
+                        warn!("v6 auth failed; forcing v4 on next attempt");
+                        force_v4 = true;
+                    }
+                    sleep(5000);
+                }
             }
-            sleep(5000);
             continue;
         };
-        info!("Auth. Success");
+        if session.encrypted() {
+            info!("Auth. Success (encrypted)");
+        } else {
+            warn!("Auth. Success (legacy server, traffic is not encrypted)");
+        }
+        // サーバーが受け取れないメッセージは送らない（旧サーバーは BASELINE とみなす）
+        let server_caps = session.peer_capabilities();
+        info!("Server capabilities: {server_caps:?}");
         force_v4 = false; // 接続成功したのでフラグをリセット
+        auth_failures = 0;
         // 認証完了・待機: 消灯（接続後は邪魔しない）
         #[cfg(feature = "board_m5atom")]
         led.write(empty_color.clone()).unwrap();
@@ -649,14 +700,16 @@ fn run_keying_loop<K: InputPin, B: InputPin>(
             continue;
         };
         // Pong responder: echo Ping packets back to server for RTT measurement
+        // 届いた時刻も返し、サーバーがこちらの時計のずれを推定できるようにする
         let sender_pong = sender.clone();
         std::thread::Builder::new()
             .stack_size(4096)
             .spawn(move || loop {
                 if let Ok(msgs) = receiver.recv() {
+                    let received = SystemClock.now();
                     for m in msgs {
-                        if let MessageRCV::Ping(ts) = m {
-                            let _ = sender_pong.send(MessageSND::Pong(ts));
+                        if let MessageRCV::Ping(origin) = m {
+                            let _ = sender_pong.send(MessageSND::Pong { origin, received });
                         }
                     }
                 } else {
@@ -666,8 +719,8 @@ fn run_keying_loop<K: InputPin, B: InputPin>(
             .unwrap();
 
         // Reset timestamps after discovery/connect/auth to avoid stale values
-        let mut last_sent: u32 = tick_count();
-        let mut last_stat: u32 = last_sent;
+        let mut last_sent = SystemClock.now();
+        let mut last_stat = last_sent;
         let mut dozing = false;
         let mut sleep_count = 0;
         // ポーリングによるエッジ検出
@@ -677,13 +730,13 @@ fn run_keying_loop<K: InputPin, B: InputPin>(
         let mut button_low_count: u32 = 0;
         // エンコーダー機能時のボタン押下タイムスタンプ
         #[cfg(feature = "encoder")]
-        let mut button_press_start: Option<u32> = None;
+        let mut button_press_start: Option<Tick> = None;
 
         loop {
             sleep(1);
-            let now = tick_count();
+            let now = SystemClock.now();
 
-            if KEEP_ALIVE != 0 && dozing && now - last_stat > KEEP_ALIVE {
+            if KEEP_ALIVE != 0 && dozing && now.since(last_stat) > KEEP_ALIVE {
                 if sender.send(MessageSND::SendPacket(now)).is_err() {
                     info!("Connection closed by peer.");
                     break;
@@ -696,7 +749,7 @@ fn run_keying_loop<K: InputPin, B: InputPin>(
                 }
             }
 
-            if !dozing && now - last_sent >= PKT_INTERVAL as u32 {
+            if !dozing && now.since(last_sent) >= PKT_INTERVAL as u32 {
                 if sender.send(MessageSND::SendPacket(last_sent)).is_err() {
                     info!("Connection closed by peer");
                     break;
@@ -726,7 +779,11 @@ fn run_keying_loop<K: InputPin, B: InputPin>(
                         #[cfg(not(feature = "board_m5atom"))]
                         led.set_high().unwrap();
 
-                        sender.send(MessageSND::StartATU).unwrap();
+                        if server_caps.contains(Capabilities::ATU) {
+                            sender.send(MessageSND::StartATU).unwrap();
+                        } else {
+                            info!("Server does not support ATU");
+                        }
                         sleep(500);
 
                         #[cfg(feature = "board_m5atom")]
@@ -750,9 +807,11 @@ fn run_keying_loop<K: InputPin, B: InputPin>(
                         button_press_start = Some(now);
                     }
                 } else if let Some(start) = button_press_start.take() {
-                    let press_ms = now.wrapping_sub(start).min(u16::MAX as u32) as u16;
+                    let press_ms = now.since(start).min(u16::MAX as u32) as u16;
                     info!("Button[0] press_ms={}", press_ms);
-                    if sender.send(MessageSND::ButtonEvent { button_id: 0, press_ms }).is_err() {
+                    if server_caps.contains(Capabilities::BUTTON_EVENTS)
+                        && sender.send(MessageSND::ButtonEvent { button_id: 0, press_ms }).is_err()
+                    {
                         info!("Connection closed by peer");
                         break;
                     }
@@ -769,13 +828,14 @@ fn run_keying_loop<K: InputPin, B: InputPin>(
                     if let Some(dir) = decoders[i].tick(a, b, now) {
                         let steps = 1u8;
                         trace!("Encoder[{}] dir={} steps={}", i, dir, steps);
-                        if sender
-                            .send(MessageSND::EncoderEvent {
-                                encoder_id: i as u8,
-                                direction: dir,
-                                steps,
-                            })
-                            .is_err()
+                        if server_caps.contains(Capabilities::ENCODER_EVENTS)
+                            && sender
+                                .send(MessageSND::EncoderEvent {
+                                    encoder_id: i as u8,
+                                    direction: dir,
+                                    steps,
+                                })
+                                .is_err()
                         {
                             info!("Connection closed by peer");
                             break;
@@ -803,28 +863,24 @@ fn run_keying_loop<K: InputPin, B: InputPin>(
                 }
                 sleep_count = 0;
 
-                let slot_pos = (now - last_sent) as usize;
-                if slot_pos >= PKT_INTERVAL || slot_count >= MAX_SLOTS {
-                    error!("Overflow interval={slot_pos} slots={slot_count}");
-                    last_sent = now;
-                    slot_count = 0;
-                } else if current_high {
+                // 打鍵はマイクロ秒の時刻で渡し、パケットへの詰め方は WkSender に任せる
+                let at_us = tick_us();
+                if current_high {
                     // キー OFF: 消灯
                     #[cfg(feature = "board_m5atom")]
                     led.write(empty_color.clone()).unwrap();
                     #[cfg(not(feature = "board_m5atom"))]
                     led.set_low().unwrap();
-                    sender.send(MessageSND::PosEdge(slot_pos as u8)).unwrap();
-                    slot_count += 1;
+                    sender.send(MessageSND::KeyEdge { at_us, down: false }).unwrap();
                 } else {
                     // キー ON: 白く点灯
                     #[cfg(feature = "board_m5atom")]
                     led.write(white_color.clone()).unwrap();
                     #[cfg(not(feature = "board_m5atom"))]
                     led.set_high().unwrap();
-                    sender.send(MessageSND::NegEdge(slot_pos as u8)).unwrap();
-                    slot_count += 1;
+                    sender.send(MessageSND::KeyEdge { at_us, down: true }).unwrap();
                 }
+                slot_count += 1;
             }
         }
     }
@@ -844,6 +900,8 @@ fn run_server_loop(
     #[cfg(not(feature = "board_m5atom"))] led: &mut PinDriver<'_, impl OutputPin, Output>,
 ) -> Result<()> {
     info!("Server starting for: {}", profile.server_name);
+    // パスワードはMQTTでも使うため保持しているが、セッション認証は verifier で行う（旧クライアントを許可したときを除く）
+    let verifier = AuthVerifier::new(&profile.server_password);
 
     loop {
         // Bind UDP socket
@@ -892,8 +950,14 @@ fn run_server_loop(
             }
         }
 
-        // Create listener
-        let mut listener = match WkListener::bind(udp) {
+        // Create listener (one keyer at a time; other clients get a busy reply)
+        // GpioKeyer はキーイングだけを扱う（ATU やエンコーダーには対応しない）
+        let config = WkSessionConfig::default()
+            .software(SOFTWARE)
+            .capabilities(
+                Capabilities::KEYING | Capabilities::KEYING_DATAGRAMS | Capabilities::TLV_MESSAGES,
+            );
+        let mut listener = match WkListener::bind_with_config(udp, 1, config) {
             Ok(l) => l,
             Err(e) => {
                 error!("Failed to bind listener: {e:?}");
@@ -922,13 +986,23 @@ fn run_server_loop(
                 led.set_high().unwrap();
 
                 // Authenticate
-                let Ok(_magic) = challenge(session.clone(), &profile.server_password) else {
-                    info!("Authentication failed");
-                    let _ = session.close();
+                // 旧クライアント (MD5 チャレンジのみ) はプロファイルで許可したときだけ受け入れる
+                let legacy = profile.legacy_auth.then_some(profile.server_password.as_str());
+                if let Err(e) = challenge(session.clone(), &verifier, legacy) {
+                    info!("Authentication failed: {e}");
+                    // パスワード違いのときだけ認証失敗として伝える
+                    let reason = match e {
+                        WkError::AuthFailed => CloseReason::AuthFailure,
+                        _ => CloseReason::LinkLost,
+                    };
+                    let _ = session.close_with(reason);
                     continue;
-                };
+                }
 
                 info!("Client authenticated");
+                if let Some(hello) = session.peer_hello() {
+                    info!("Client {} (protocol {})", hello.software, hello.protocol);
+                }
 
                 // Create receiver
                 let receiver = match WkReceiver::new(session) {
@@ -98,7 +98,7 @@ fn help_text() -> String {
         "WifiKey Serial Commands:\r\n\
          AT          - Test connection\r\n\
          AT+LIST     - List all profiles\r\n\
-         AT+ADD=<ssid>,<wifipass>,<server>,<serverpass>[,<tethering>] - Add profile\r\n\
+         AT+ADD=<ssid>,<wifipass>,<server>,<serverpass>[,<tethering>[,<legacy_auth>]] - Add profile\r\n\
          AT+DEL=<n>  - Delete profile at index n\r\n\
          AT+CLEAR    - Clear all profiles\r\n\
          AT+GPIO     - Show GPIO settings ({key_label})\r\n\
@@ -136,9 +136,10 @@ fn list_profiles(config_manager: &Arc<Mutex<ConfigManager>>) -> String {
     let mut response = String::new();
     for (i, p) in profiles.iter().enumerate() {
         let tether_mark = if p.tethering { " [T]" } else { "" };
+        let legacy_mark = if p.legacy_auth { " [L]" } else { "" };
         response.push_str(&format!(
-            "[{}] SSID={} SERVER={}{}\r\n",
-            i, p.ssid, p.server_name, tether_mark
+            "[{}] SSID={} SERVER={}{}{}\r\n",
+            i, p.ssid, p.server_name, tether_mark, legacy_mark
         ));
     }
     response.push_str(OK);
@@ -146,12 +147,12 @@ fn list_profiles(config_manager: &Arc<Mutex<ConfigManager>>) -> String {
 }
 
 fn add_profile(args: &str, config_manager: &Arc<Mutex<ConfigManager>>) -> String {
-    // Parse: ssid,wifipass,server,serverpass[,tethering]
-    let parts: Vec<&str> = args.splitn(5, ',').collect();
+    // Parse: ssid,wifipass,server,serverpass[,tethering[,legacy_auth]]
+    let parts: Vec<&str> = args.splitn(6, ',').collect();
 
     if parts.len() < 3 {
         return format!(
-            "Usage: AT+ADD=<ssid>,<wifipass>,<server>,<serverpass>[,<tethering>]\r\n{ERROR}"
+            "Usage: AT+ADD=<ssid>,<wifipass>,<server>,<serverpass>[,<tethering>[,<legacy_auth>]]\r\n{ERROR}"
         );
     }
 
@@ -159,10 +160,14 @@ fn add_profile(args: &str, config_manager: &Arc<Mutex<ConfigManager>>) -> String
     let password = parts.get(1).map(|s| s.trim()).unwrap_or("");
     let server_name = parts.get(2).map(|s| s.trim()).unwrap_or("");
     let server_password = parts.get(3).map(|s| s.trim()).unwrap_or("");
-    let tethering = parts
-        .get(4)
-        .map(|s| matches!(s.trim(), "1" | "true"))
-        .unwrap_or(false);
+    let flag = |i: usize| {
+        parts
+            .get(i)
+            .map(|s| matches!(s.trim(), "1" | "true"))
+            .unwrap_or(false)
+    };
+    let tethering = flag(4);
+    let legacy_auth = flag(5);
 
     if ssid.is_empty() || server_name.is_empty() {
         return format!("SSID and server name are required\r\n{ERROR}");
@@ -174,6 +179,7 @@ fn add_profile(args: &str, config_manager: &Arc<Mutex<ConfigManager>>) -> String
         server_name: server_name.to_string(),
         server_password: server_password.to_string(),
         tethering,
+        legacy_auth,
     };
 
     match config_manager.lock().unwrap().add_profile(profile) {
@@ -81,6 +81,10 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
                     <input type="checkbox" id="editTethering" style="width:auto;margin:0">
                     Mobile/Tethering (skip mDNS)
                 </label>
+                <label style="display:flex;align-items:center;gap:8px;margin:8px 0;color:#eee;cursor:pointer">
+                    <input type="checkbox" id="editLegacyAuth" style="width:auto;margin:0">
+                    Allow old peers (unencrypted)
+                </label>
                 <button type="submit" class="btn-primary">Save Changes</button>
                 <button type="button" class="btn-secondary" onclick="cancelEdit()">Cancel</button>
             </form>
@@ -107,6 +111,10 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
                     <input type="checkbox" id="tethering" style="width:auto;margin:0">
                     Mobile/Tethering (skip mDNS)
                 </label>
+                <label style="display:flex;align-items:center;gap:8px;margin:8px 0;color:#eee;cursor:pointer">
+                    <input type="checkbox" id="legacyAuth" style="width:auto;margin:0">
+                    Allow old peers (unencrypted)
+                </label>
 
                 <button type="submit" class="btn-primary">Add Profile</button>
             </form>
@@ -194,7 +202,7 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
                 } else {
                     container.innerHTML = currentProfiles.map((p, i) =>
                         `<div class="profile-item">
-                            <span>${p.ssid} → ${p.server_name}${p.tethering ? ' [T]' : ''}</span>
+                            <span>${p.ssid} → ${p.server_name}${p.tethering ? ' [T]' : ''}${p.legacy_auth ? ' [L]' : ''}</span>
                             <div style="display:flex;gap:6px">
                                 <button class="btn-secondary" onclick="editProfile(${i})">Edit</button>
                                 <button class="btn-danger" onclick="deleteProfile(${i})">Delete</button>
@@ -215,6 +223,7 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
             document.getElementById('editServer').value = p.server_name;
             document.getElementById('editServerpass').value = '';
             document.getElementById('editTethering').checked = p.tethering;
+            document.getElementById('editLegacyAuth').checked = p.legacy_auth;
             const card = document.getElementById('editFormCard');
             card.classList.remove('hidden');
             card.scrollIntoView({behavior: 'smooth'});
@@ -233,7 +242,8 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
                 password: document.getElementById('editWifipass').value,
                 server_name: document.getElementById('editServer').value,
                 server_password: document.getElementById('editServerpass').value,
-                tethering: document.getElementById('editTethering').checked
+                tethering: document.getElementById('editTethering').checked,
+                legacy_auth: document.getElementById('editLegacyAuth').checked
             };
             try {
                 const res = await fetch('/api/profiles/' + editingIndex, {
@@ -284,7 +294,8 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
                 password: document.getElementById('wifipass').value,
                 server_name: document.getElementById('server').value,
                 server_password: document.getElementById('serverpass').value,
-                tethering: document.getElementById('tethering').checked
+                tethering: document.getElementById('tethering').checked,
+                legacy_auth: document.getElementById('legacyAuth').checked
             };
             try {
                 const res = await fetch('/api/profiles', {
@@ -993,12 +1004,13 @@ fn profiles_to_json(profiles: &[WifiProfile]) -> String {
         .iter()
         .map(|p| {
             format!(
-                r#"{{"ssid":"{}","password":"{}","server_name":"{}","server_password":"{}","tethering":{}}}"#,
+                r#"{{"ssid":"{}","password":"{}","server_name":"{}","server_password":"{}","tethering":{},"legacy_auth":{}}}"#,
                 escape_json(&p.ssid),
                 escape_json(&p.password),
                 escape_json(&p.server_name),
                 escape_json(&p.server_password),
-                p.tethering
+                p.tethering,
+                p.legacy_auth
             )
         })
         .collect();
@@ -1032,6 +1044,7 @@ fn parse_profile_json(data: &[u8]) -> Option<WifiProfile> {
         server_name: extract("server_name")?,
         server_password: extract("server_password").unwrap_or_default(),
         tethering: s.contains(r#""tethering":true"#),
+        legacy_auth: s.contains(r#""legacy_auth":true"#),
     })
 }
 
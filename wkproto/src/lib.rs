@@ -0,0 +1,37 @@
+//! The WiFiKey2 protocol without threads or I/O.
+//!
+//! Message codecs, the handshake math and the keyer timing, usable on
+//! bare-metal and WASM targets. `wksocket` puts sessions around it.
+#![no_std]
+
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+
+pub use self::{
+    wkauth::{
+        legacy_digest, mac, verify_mac, AuthError, AuthVerifier, ClientPake, PakeKeys, MAC_LEN,
+        MAX_PBKDF2_ITERATIONS, PBKDF2_ITERATIONS, POINT_LEN, SALT_LEN,
+    },
+    wkcodec::{decode_message, edges_per_packet, encode_message, Codec, Edge, Packet, CODEC_MAGIC},
+    wkcontrol::CloseReason,
+    wkhello::{Capabilities, PROTOCOL_VERSION},
+    wkmessage::{DecodeError, EncodeError, MessageRCV, PacketKind, MAX_SLOTS, PKT_SIZE},
+    wkplayout::{
+        Jitter, JitterBuffer, JitterBufferConfig, LatePolicy, Playout, MAX_ASSERT_DURATION,
+        MSPERWPM, RESYNC_INTERVAL,
+    },
+    wkspeed::{SpeedEstimate, SpeedEstimator},
+    wksync::ClockSync,
+    wktime::{Clock, Tick},
+};
+
+mod wkauth;
+mod wkcodec;
+mod wkcontrol;
+mod wkhello;
+mod wkmessage;
+mod wkplayout;
+mod wkspeed;
+mod wksync;
+mod wktime;
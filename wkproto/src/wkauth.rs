@@ -0,0 +1,440 @@
+use alloc::string::String;
+use core::fmt;
+use core::str::FromStr;
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use rand_core::RngCore;
+use sha2::{Digest, Sha256, Sha512};
+use subtle::ConstantTimeEq;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const AUTH_CONTEXT: &[u8] = b"wifikey2 SPAKE2+ v1";
+const VERIFIER_TAG: &str = "spake2p";
+
+/// PBKDF2 iterations used for newly created verifiers
+pub const PBKDF2_ITERATIONS: u32 = 4096;
+/// Upper bound accepted from a server, so a rogue peer can't stall the client
+pub const MAX_PBKDF2_ITERATIONS: u32 = 1 << 20;
+pub const SALT_LEN: usize = 16;
+pub const POINT_LEN: usize = 32;
+pub const MAC_LEN: usize = 32;
+
+/// Why a handshake message or a stored verifier was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// Not the encoding of a usable Ristretto point
+    InvalidPoint,
+    /// Not a verifier string written by `AuthVerifier`'s `Display`
+    MalformedVerifier,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::InvalidPoint => write!(f, "invalid point encoding"),
+            AuthError::MalformedVerifier => write!(f, "malformed verifier"),
+        }
+    }
+}
+
+impl core::error::Error for AuthError {}
+
+fn point_m() -> RistrettoPoint {
+    RistrettoPoint::hash_from_bytes::<Sha512>(b"wifikey2 SPAKE2+ point M")
+}
+
+fn point_n() -> RistrettoPoint {
+    RistrettoPoint::hash_from_bytes::<Sha512>(b"wifikey2 SPAKE2+ point N")
+}
+
+fn random_scalar(rng: &mut impl RngCore) -> Scalar {
+    let mut wide = [0u8; 64];
+    rng.fill_bytes(&mut wide);
+    Scalar::from_bytes_mod_order_wide(&wide)
+}
+
+/// Derive the SPAKE2+ password scalars (w0, w1) from the password.
+fn derive_secrets(passwd: &str, salt: &[u8], iterations: u32) -> (Scalar, Scalar) {
+    let mut out = [0u8; 128];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passwd.as_bytes(), salt, iterations, &mut out);
+    let mut wide = [0u8; 64];
+    wide.copy_from_slice(&out[..64]);
+    let w0 = Scalar::from_bytes_mod_order_wide(&wide);
+    wide.copy_from_slice(&out[64..]);
+    let w1 = Scalar::from_bytes_mod_order_wide(&wide);
+    (w0, w1)
+}
+
+fn decode_point(buf: &[u8]) -> Result<RistrettoPoint, AuthError> {
+    let compressed = CompressedRistretto::from_slice(buf).map_err(|_| AuthError::InvalidPoint)?;
+    let point = compressed.decompress().ok_or(AuthError::InvalidPoint)?;
+    if point.is_identity() {
+        return Err(AuthError::InvalidPoint);
+    }
+    Ok(point)
+}
+
+/// HMAC-SHA256 of `data`, as used for the key confirmations
+pub fn mac(key: &[u8], data: &[u8]) -> [u8; MAC_LEN] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Check `tag` against the HMAC of `data` in constant time
+pub fn verify_mac(key: &[u8], data: &[u8], tag: &[u8]) -> bool {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.verify_slice(tag).is_ok()
+}
+
+/// Keys derived from a completed SPAKE2+ exchange
+pub struct PakeKeys {
+    /// Proves the client's side of the exchange, MACed over the server's share
+    pub confirm_client: [u8; 32],
+    /// Proves the server's side of the exchange, MACed over the client's share
+    pub confirm_server: [u8; 32],
+    /// Session key both sides end up with
+    pub shared: [u8; 32],
+}
+
+fn derive_keys(
+    x: &RistrettoPoint,
+    y: &RistrettoPoint,
+    z: &RistrettoPoint,
+    v: &RistrettoPoint,
+    w0: &Scalar,
+) -> PakeKeys {
+    let mut tt = Sha256::new();
+    let mut append = |data: &[u8]| {
+        tt.update((data.len() as u64).to_le_bytes());
+        tt.update(data);
+    };
+    append(AUTH_CONTEXT);
+    append(point_m().compress().as_bytes());
+    append(point_n().compress().as_bytes());
+    append(x.compress().as_bytes());
+    append(y.compress().as_bytes());
+    append(z.compress().as_bytes());
+    append(v.compress().as_bytes());
+    append(w0.as_bytes());
+    let k_main = tt.finalize();
+
+    let hk = Hkdf::<Sha256>::new(None, &k_main);
+    let mut confirm = [0u8; 64];
+    hk.expand(b"ConfirmationKeys", &mut confirm)
+        .expect("valid HKDF length");
+    let mut shared = [0u8; 32];
+    hk.expand(b"SharedKey", &mut shared)
+        .expect("valid HKDF length");
+
+    let mut keys = PakeKeys {
+        confirm_client: [0u8; 32],
+        confirm_server: [0u8; 32],
+        shared,
+    };
+    keys.confirm_client.copy_from_slice(&confirm[..32]);
+    keys.confirm_server.copy_from_slice(&confirm[32..]);
+    keys
+}
+
+/// Client half of SPAKE2+, from the server's salt and iteration count.
+///
+/// Send `share()`, then `finish` with the server's share to get the keys.
+pub struct ClientPake {
+    w0: Scalar,
+    w1: Scalar,
+    x: Scalar,
+    share: RistrettoPoint,
+}
+
+impl ClientPake {
+    pub fn new(passwd: &str, salt: &[u8], iterations: u32, rng: &mut impl RngCore) -> Self {
+        let (w0, w1) = derive_secrets(passwd, salt, iterations);
+        let x = random_scalar(rng);
+        Self {
+            w0,
+            w1,
+            x,
+            share: x * RISTRETTO_BASEPOINT_POINT + w0 * point_m(),
+        }
+    }
+
+    pub fn share(&self) -> [u8; POINT_LEN] {
+        self.share.compress().to_bytes()
+    }
+
+    pub fn finish(&self, share_y: &[u8]) -> Result<PakeKeys, AuthError> {
+        let share_y = decode_point(share_y)?;
+        let unblinded = share_y - self.w0 * point_n();
+        Ok(derive_keys(
+            &self.share,
+            &share_y,
+            &(self.x * unblinded),
+            &(self.w1 * unblinded),
+            &self.w0,
+        ))
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    use core::fmt::Write;
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(s, "{b:02x}");
+    }
+    s
+}
+
+fn from_hex<const N: usize>(s: &str) -> Result<[u8; N], AuthError> {
+    if s.len() != N * 2 || !s.is_ascii() {
+        return Err(AuthError::MalformedVerifier);
+    }
+    let mut out = [0u8; N];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+            .map_err(|_| AuthError::MalformedVerifier)?;
+    }
+    Ok(out)
+}
+
+/// Server-side credential for the SPAKE2+ handshake.
+///
+/// Holds only `w0` and `L = w1·G`, so a leaked verifier does not let anyone
+/// authenticate as a client. Serialized as
+/// `spake2p$<iterations>$<salt>$<w0>$<L>` for storage in configuration files.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthVerifier {
+    iterations: u32,
+    salt: [u8; SALT_LEN],
+    w0: Scalar,
+    l: RistrettoPoint,
+}
+
+impl AuthVerifier {
+    /// Create a verifier for `passwd` with a fresh random salt
+    #[cfg(feature = "std")]
+    pub fn new(passwd: &str) -> Self {
+        Self::with_rng(passwd, &mut rand::rng())
+    }
+
+    /// Create a verifier for `passwd`, salted from `rng`
+    pub fn with_rng(passwd: &str, rng: &mut impl RngCore) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        rng.fill_bytes(&mut salt);
+        Self::with_salt(passwd, salt, PBKDF2_ITERATIONS)
+    }
+
+    pub fn with_salt(passwd: &str, salt: [u8; SALT_LEN], iterations: u32) -> Self {
+        let (w0, w1) = derive_secrets(passwd, &salt, iterations);
+        Self {
+            iterations,
+            salt,
+            w0,
+            l: w1 * RISTRETTO_BASEPOINT_POINT,
+        }
+    }
+
+    pub fn iterations(&self) -> u32 {
+        self.iterations
+    }
+
+    pub fn salt(&self) -> &[u8; SALT_LEN] {
+        &self.salt
+    }
+
+    /// Server half of SPAKE2+: our share and the keys for the client's `share_x`
+    pub fn respond(
+        &self,
+        share_x: &[u8],
+        rng: &mut impl RngCore,
+    ) -> Result<([u8; POINT_LEN], PakeKeys), AuthError> {
+        let share_x = decode_point(share_x)?;
+        let y = random_scalar(rng);
+        let share_y = y * RISTRETTO_BASEPOINT_POINT + self.w0 * point_n();
+        let unblinded = share_x - self.w0 * point_m();
+        let keys = derive_keys(
+            &share_x,
+            &share_y,
+            &(y * unblinded),
+            &(y * self.l),
+            &self.w0,
+        );
+        Ok((share_y.compress().to_bytes(), keys))
+    }
+
+    /// Check whether this verifier was derived from `passwd`
+    pub fn verify_password(&self, passwd: &str) -> bool {
+        let other = Self::with_salt(passwd, self.salt, self.iterations);
+        let ok = self.w0.as_bytes().ct_eq(other.w0.as_bytes())
+            & self
+                .l
+                .compress()
+                .as_bytes()
+                .ct_eq(other.l.compress().as_bytes());
+        ok.into()
+    }
+}
+
+impl fmt::Debug for AuthVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthVerifier")
+            .field("iterations", &self.iterations)
+            .field("salt", &to_hex(&self.salt))
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for AuthVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{VERIFIER_TAG}${}${}${}${}",
+            self.iterations,
+            to_hex(&self.salt),
+            to_hex(self.w0.as_bytes()),
+            to_hex(self.l.compress().as_bytes())
+        )
+    }
+}
+
+impl FromStr for AuthVerifier {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self, AuthError> {
+        let mut fields = s.split('$');
+        if fields.next() != Some(VERIFIER_TAG) {
+            return Err(AuthError::MalformedVerifier);
+        }
+        let (Some(iterations), Some(salt), Some(w0), Some(l), None) = (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) else {
+            return Err(AuthError::MalformedVerifier);
+        };
+        let iterations: u32 = iterations
+            .parse()
+            .map_err(|_| AuthError::MalformedVerifier)?;
+        let salt = from_hex::<SALT_LEN>(salt)?;
+        let w0 = Option::<Scalar>::from(Scalar::from_canonical_bytes(from_hex::<32>(w0)?))
+            .ok_or(AuthError::MalformedVerifier)?;
+        let l = decode_point(&from_hex::<POINT_LEN>(l)?)?;
+        Ok(Self {
+            iterations,
+            salt,
+            w0,
+            l,
+        })
+    }
+}
+
+/// MD5 of the password with the challenge appended, as the old cleartext
+/// handshake sends it
+pub fn legacy_digest(salted: &str) -> [u8; 16] {
+    let mut hasher = Md5::new();
+    hasher.update(salted);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn test_legacy_digest_basic() {
+        // MD5("test") = 098f6bcd4621d373cade4e832627b4f6
+        assert_eq!(
+            legacy_digest("test"),
+            [
+                0x09, 0x8f, 0x6b, 0xcd, 0x46, 0x21, 0xd3, 0x73, 0xca, 0xde, 0x4e, 0x83, 0x26, 0x27,
+                0xb4, 0xf6
+            ]
+        );
+    }
+
+    #[test]
+    fn test_legacy_digest_empty() {
+        // MD5("") = d41d8cd98f00b204e9800998ecf8427e
+        assert_eq!(
+            legacy_digest(""),
+            [
+                0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04, 0xe9, 0x80, 0x09, 0x98, 0xec, 0xf8,
+                0x42, 0x7e
+            ]
+        );
+    }
+
+    #[test]
+    fn test_legacy_digest_with_salt() {
+        // Verify it produces a valid 16-byte hash
+        assert_ne!(legacy_digest("password12345"), [0u8; 16]);
+    }
+
+    /// Run both halves of the SPAKE2+ math without a network.
+    fn exchange(client_passwd: &str, verifier: &AuthVerifier) -> (PakeKeys, PakeKeys) {
+        let mut rng = rand::rng();
+        let client = ClientPake::new(
+            client_passwd,
+            verifier.salt(),
+            verifier.iterations(),
+            &mut rng,
+        );
+        let (share_y, server) = verifier.respond(&client.share(), &mut rng).unwrap();
+        (client.finish(&share_y).unwrap(), server)
+    }
+
+    #[test]
+    fn test_pake_same_password_agrees() {
+        let verifier = AuthVerifier::with_salt("keyer_passwd", [7u8; SALT_LEN], 16);
+        let (client, server) = exchange("keyer_passwd", &verifier);
+        assert_eq!(client.confirm_client, server.confirm_client);
+        assert_eq!(client.confirm_server, server.confirm_server);
+        assert_eq!(client.shared, server.shared);
+    }
+
+    #[test]
+    fn test_pake_wrong_password_disagrees() {
+        let verifier = AuthVerifier::with_salt("keyer_passwd", [7u8; SALT_LEN], 16);
+        let (client, server) = exchange("wrong_passwd", &verifier);
+        assert_ne!(client.confirm_server, server.confirm_server);
+        assert_ne!(client.shared, server.shared);
+    }
+
+    #[test]
+    fn test_pake_rejects_identity_share() {
+        let verifier = AuthVerifier::with_salt("keyer_passwd", [7u8; SALT_LEN], 16);
+        let identity = [0u8; POINT_LEN];
+        assert!(matches!(
+            verifier.respond(&identity, &mut rand::rng()),
+            Err(AuthError::InvalidPoint)
+        ));
+    }
+
+    #[test]
+    fn test_verifier_roundtrip() {
+        let verifier = AuthVerifier::with_salt("keyer_passwd", [1u8; SALT_LEN], 32);
+        let encoded = verifier.to_string();
+        assert!(encoded.starts_with("spake2p$32$"));
+        let decoded: AuthVerifier = encoded.parse().unwrap();
+        assert_eq!(decoded, verifier);
+        assert!(decoded.verify_password("keyer_passwd"));
+        assert!(!decoded.verify_password("other"));
+    }
+
+    #[test]
+    fn test_verifier_rejects_garbage() {
+        assert!("".parse::<AuthVerifier>().is_err());
+        assert!("spake2p$1$00".parse::<AuthVerifier>().is_err());
+        assert!("md5$1$00$00$00".parse::<AuthVerifier>().is_err());
+    }
+}
@@ -0,0 +1,1108 @@
+use crate::wkhello::Capabilities;
+use crate::wkmessage::{DecodeError, EncodeError, MessageRCV, PacketKind, MAX_SLOTS, PKT_SIZE};
+use crate::wktime::Tick;
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+use bytes::{Buf, BufMut, BytesMut};
+use log::{error, trace};
+
+// 旧形式の先頭バイトは種別 (0..=5) なので、それと重ならない値で見分ける
+pub const CODEC_MAGIC: u8 = 0xA5;
+const CODEC_VERSION: u8 = 1;
+// kind(1) + tm(4) + len(1)
+const MESSAGE_HEADER: usize = 6;
+// magic(1) + version(1)
+const CODEC_HEADER: usize = 2;
+// kind(1) + len(2)
+const RECORD_HEADER: usize = 3;
+
+// フィールドのタグ。種別をまたいで同じ意味で使う
+const TAG_TM: u8 = 1;
+const TAG_SLOTS: u8 = 2;
+const TAG_ID: u8 = 3;
+const TAG_DIRECTION: u8 = 4;
+const TAG_STEPS: u8 = 5;
+const TAG_PRESS_MS: u8 = 6;
+const TAG_EDGES: u8 = 7;
+// Pong を受けた側の時計で、Ping が届いた時刻と Pong を返した時刻
+const TAG_RECEIVED: u8 = 8;
+const TAG_SENT: u8 = 9;
+// 旧形式のスロットは tm からの 7bit ミリ秒
+const SLOT_OFFSET_MAX: i32 = 0x7f;
+
+/// A key transition at an absolute time on the `tick_us` clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub at_us: u64,
+    pub down: bool,
+}
+
+impl Edge {
+    /// The millisecond tick the edge falls in
+    pub fn tick(&self) -> Tick {
+        Tick::from_ms((self.at_us / 1000) as u32)
+    }
+}
+
+/// One message as put on the wire by `WkSender`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet<'a> {
+    Keyer {
+        tm: Tick,
+        slots: &'a [u8],
+        edges: &'a [Edge],
+    },
+    StartAtu,
+    Ping(Tick),
+    /// The peer times are dropped in the legacy layout
+    Pong {
+        origin: Tick,
+        peer: Option<(Tick, Tick)>,
+    },
+    Encoder {
+        encoder_id: u8,
+        direction: i8,
+        steps: u8,
+    },
+    Button {
+        button_id: u8,
+        press_ms: u16,
+    },
+}
+
+/// Wire layout of the messages sent to a peer.
+///
+/// `Legacy` is the fixed `[kind][u32 tm][u8 len][slots]` layout every
+/// client understands. `Tlv` is a versioned sequence of records, each a
+/// kind and a list of tagged fields, so fields can be added, omitted or
+/// grown and unknown kinds and tags are skipped by older receivers:
+///
+/// `[0xA5][version] ([kind][u16 len] ([tag][u8 len][value])*)*`
+///
+/// Edges go in their own field as a LEB128 base time in microseconds
+/// followed by one LEB128 `delta_us << 1 | up` per edge, so any gap and any
+/// number of edges can be sent. The legacy layout only holds 7-bit
+/// millisecond offsets from `tm` and drops edges that don't fit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Legacy,
+    Tlv,
+}
+
+impl Codec {
+    /// The richest layout `capabilities` can decode
+    pub fn for_peer(capabilities: Capabilities) -> Self {
+        if capabilities.contains(Capabilities::TLV_MESSAGES) {
+            Codec::Tlv
+        } else {
+            Codec::Legacy
+        }
+    }
+
+    pub fn encode(self, buf: &mut BytesMut, packet: &Packet) -> Result<(), EncodeError> {
+        match self {
+            Codec::Legacy => encode_legacy(buf, packet),
+            Codec::Tlv => encode_tlv(buf, packet),
+        }
+    }
+}
+
+/// Put one message in the legacy `[kind][u32 tm][u8 len][slots]` layout.
+pub fn encode_message(
+    buf: &mut BytesMut,
+    cmd: PacketKind,
+    tm: u32,
+    slots: &[u8],
+) -> Result<(), EncodeError> {
+    buf.clear();
+    buf.put_u8(cmd as u8);
+    buf.put_u32(tm);
+    if slots.len() > MAX_SLOTS {
+        return Err(EncodeError::TooManySlots {
+            slots: slots.len(),
+            max: MAX_SLOTS,
+        });
+    }
+    buf.put_u8(slots.len() as u8);
+    for s in slots.iter() {
+        buf.put_u8(*s);
+    }
+    Ok(())
+}
+
+fn encode_legacy(buf: &mut BytesMut, packet: &Packet) -> Result<(), EncodeError> {
+    match *packet {
+        Packet::Keyer { tm, slots, edges } => {
+            let slots = legacy_slots(tm, slots, edges);
+            encode_message(buf, PacketKind::KeyerMessage, tm.as_ms(), &slots)
+        }
+        Packet::StartAtu => encode_message(buf, PacketKind::StartATU, 0, &[]),
+        Packet::Ping(ts) => encode_message(buf, PacketKind::Ping, ts.as_ms(), &[]),
+        Packet::Pong { origin, .. } => encode_message(buf, PacketKind::Pong, origin.as_ms(), &[]),
+        Packet::Encoder {
+            encoder_id,
+            direction,
+            steps,
+        } => {
+            // tm = [encoder_id(8)] [dir+128(8)] [steps(8)] [0(8)]
+            let dir_byte = (direction as i16 + 128) as u8;
+            let tm =
+                ((encoder_id as u32) << 24) | ((dir_byte as u32) << 16) | ((steps as u32) << 8);
+            encode_message(buf, PacketKind::EncoderEvent, tm, &[])
+        }
+        Packet::Button {
+            button_id,
+            press_ms,
+        } => {
+            // tm = [0(8)] [button_id(8)] [press_ms(16)]
+            let tm = ((button_id as u32) << 16) | (press_ms as u32);
+            encode_message(buf, PacketKind::ButtonEvent, tm, &[])
+        }
+    }
+}
+
+// 絶対時刻の打鍵を tm からのスロットに直す。表せないものは捨てる
+fn legacy_slots<'a>(tm: Tick, slots: &'a [u8], edges: &[Edge]) -> Cow<'a, [u8]> {
+    if edges.is_empty() {
+        return Cow::Borrowed(slots);
+    }
+    let mut all = slots.to_vec();
+    for edge in edges {
+        // tm より前の打鍵も表せない
+        let offset = edge.tick().delta(tm);
+        if !(0..=SLOT_OFFSET_MAX).contains(&offset) || all.len() >= MAX_SLOTS {
+            error!("Overflow interval={offset} slots={}", all.len());
+            continue;
+        }
+        let up = if edge.down { 0 } else { 0x80 };
+        all.push(up | offset as u8);
+    }
+    Cow::Owned(all)
+}
+
+/// How many leading `edges` fit in one TLV keyer packet of at most
+/// `PKT_SIZE` bytes alongside `slots`; at least one if any are given.
+pub fn edges_per_packet(tm: Tick, slots: &[u8], edges: &[Edge]) -> usize {
+    let Some(first) = edges.first() else {
+        return 0;
+    };
+    // magic, version, レコードヘッダー, 各フィールドのタグと長さ
+    let mut header = CODEC_HEADER + RECORD_HEADER + 2 + uint_len(tm.as_ms()) + 2;
+    if !slots.is_empty() {
+        header += 2 + slots.len();
+    }
+    let mut value = varint_len(first.at_us);
+    let mut prev = first.at_us;
+    let mut n = 0;
+    for edge in edges {
+        value += varint_len(edge_delta(prev, edge));
+        if n > 0 && (header + value > PKT_SIZE || value > u8::MAX as usize) {
+            break;
+        }
+        prev = prev.max(edge.at_us);
+        n += 1;
+    }
+    n
+}
+
+// 単調クロックなので逆行はしないが、しても 0 に丸めて送る
+fn edge_delta(prev: u64, edge: &Edge) -> u64 {
+    (edge.at_us.saturating_sub(prev) << 1) | !edge.down as u64
+}
+
+fn encode_tlv(buf: &mut BytesMut, packet: &Packet) -> Result<(), EncodeError> {
+    buf.clear();
+    buf.put_u8(CODEC_MAGIC);
+    buf.put_u8(CODEC_VERSION);
+    let kind = match packet {
+        Packet::Keyer { .. } => PacketKind::KeyerMessage,
+        Packet::StartAtu => PacketKind::StartATU,
+        Packet::Ping(_) => PacketKind::Ping,
+        Packet::Pong { .. } => PacketKind::Pong,
+        Packet::Encoder { .. } => PacketKind::EncoderEvent,
+        Packet::Button { .. } => PacketKind::ButtonEvent,
+    };
+    buf.put_u8(kind as u8);
+    let len_at = buf.len();
+    buf.put_u16(0);
+    match *packet {
+        Packet::Keyer { tm, slots, edges } => {
+            put_uint(buf, TAG_TM, tm.as_ms());
+            // 打鍵のない同期パケットでは省く
+            if !slots.is_empty() {
+                put_bytes(buf, TAG_SLOTS, slots)?;
+            }
+            if let Some(first) = edges.first() {
+                let mut value = Vec::new();
+                put_varint(&mut value, first.at_us);
+                let mut prev = first.at_us;
+                for edge in edges.iter() {
+                    put_varint(&mut value, edge_delta(prev, edge));
+                    prev = prev.max(edge.at_us);
+                }
+                put_bytes(buf, TAG_EDGES, &value)?;
+            }
+        }
+        Packet::StartAtu => {}
+        Packet::Ping(ts) => put_uint(buf, TAG_TM, ts.as_ms()),
+        Packet::Pong { origin, peer } => {
+            put_uint(buf, TAG_TM, origin.as_ms());
+            if let Some((received, sent)) = peer {
+                put_uint(buf, TAG_RECEIVED, received.as_ms());
+                put_uint(buf, TAG_SENT, sent.as_ms());
+            }
+        }
+        Packet::Encoder {
+            encoder_id,
+            direction,
+            steps,
+        } => {
+            put_uint(buf, TAG_ID, encoder_id as u32);
+            put_bytes(buf, TAG_DIRECTION, &direction.to_be_bytes())?;
+            put_uint(buf, TAG_STEPS, steps as u32);
+        }
+        Packet::Button {
+            button_id,
+            press_ms,
+        } => {
+            put_uint(buf, TAG_ID, button_id as u32);
+            put_uint(buf, TAG_PRESS_MS, press_ms as u32);
+        }
+    }
+    let len = (buf.len() - len_at - 2) as u16;
+    buf[len_at..len_at + 2].copy_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+fn uint_len(value: u32) -> usize {
+    (4 - value.leading_zeros() as usize / 8).max(1)
+}
+
+// 整数は上位の 0 を省いた可変長で送る
+fn put_uint(buf: &mut BytesMut, tag: u8, value: u32) {
+    let len = uint_len(value);
+    buf.put_u8(tag);
+    buf.put_u8(len as u8);
+    buf.put_uint(value as u64, len);
+}
+
+fn put_bytes(buf: &mut BytesMut, tag: u8, value: &[u8]) -> Result<(), EncodeError> {
+    if value.len() > u8::MAX as usize {
+        return Err(EncodeError::FieldTooLong {
+            tag,
+            len: value.len(),
+        });
+    }
+    buf.put_u8(tag);
+    buf.put_u8(value.len() as u8);
+    buf.put_slice(value);
+    Ok(())
+}
+
+fn varint_len(value: u64) -> usize {
+    ((64 - value.leading_zeros() as usize).max(1)).div_ceil(7)
+}
+
+// LEB128: 下位 7bit ずつ、続きがあれば最上位ビットを立てる
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn get_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&b, rest) = buf.split_first()?;
+        *buf = rest;
+        let bits = (b & 0x7f) as u64;
+        // 10 バイト目は 1bit しか入らない
+        if bits << shift >> shift != bits {
+            return None;
+        }
+        value |= bits << shift;
+        if b & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Fields of one record, in wire order
+struct Fields<'a>(Vec<(u8, &'a [u8])>);
+
+impl<'a> Fields<'a> {
+    fn parse(mut body: &'a [u8]) -> Result<Self, DecodeError> {
+        let mut fields = Vec::new();
+        while let [tag, len, rest @ ..] = body {
+            let len = *len as usize;
+            if rest.len() < len {
+                return Err(DecodeError::Truncated);
+            }
+            fields.push((*tag, &rest[..len]));
+            body = &rest[len..];
+        }
+        if !body.is_empty() {
+            return Err(DecodeError::Truncated);
+        }
+        Ok(Self(fields))
+    }
+
+    fn get(&self, tag: u8) -> Option<&'a [u8]> {
+        self.0.iter().find(|(t, _)| *t == tag).map(|(_, v)| *v)
+    }
+
+    fn uint(&self, tag: u8, max: u32) -> Result<Option<u32>, DecodeError> {
+        let Some(mut value) = self.get(tag) else {
+            return Ok(None);
+        };
+        if value.is_empty() || value.len() > 4 {
+            return Err(DecodeError::InvalidField(tag));
+        }
+        let n = value.get_uint(value.len()) as u32;
+        if n > max {
+            return Err(DecodeError::InvalidField(tag));
+        }
+        Ok(Some(n))
+    }
+
+    fn require_uint(&self, tag: u8, max: u32) -> Result<u32, DecodeError> {
+        self.uint(tag, max)?.ok_or(DecodeError::MissingField(tag))
+    }
+}
+
+/// Decode a packet in either wire layout, see `Codec`.
+pub fn decode_message(buf: &[u8]) -> Result<Vec<MessageRCV>, DecodeError> {
+    if buf.first() == Some(&CODEC_MAGIC) {
+        return decode_tlv(buf);
+    }
+    if buf.len() < MESSAGE_HEADER {
+        return Err(DecodeError::Short(buf.len()));
+    }
+    let (mut header, edges) = buf.split_at(MESSAGE_HEADER);
+    let cmd = header.get_u8();
+    let tm = header.get_u32();
+    let len = header.get_u8() as usize;
+    let tick = Tick::from_ms(tm);
+    if edges.len() != len {
+        return Err(DecodeError::SlotCount {
+            declared: len,
+            present: edges.len(),
+        });
+    }
+    let mut slots = Vec::new();
+
+    if cmd == PacketKind::StartATU as u8 {
+        slots.push(MessageRCV::StartATU)
+    } else if cmd == PacketKind::Ping as u8 {
+        slots.push(MessageRCV::Ping(tick))
+    } else if cmd == PacketKind::Pong as u8 {
+        slots.push(MessageRCV::Pong {
+            origin: tick,
+            peer: None,
+        })
+    } else if cmd == PacketKind::EncoderEvent as u8 {
+        let encoder_id = (tm >> 24) as u8;
+        let dir_byte = (tm >> 16) as u8;
+        let direction = (dir_byte as i16 - 128) as i8;
+        let steps = (tm >> 8) as u8;
+        slots.push(MessageRCV::EncoderEvent {
+            encoder_id,
+            direction,
+            steps,
+        })
+    } else if cmd == PacketKind::ButtonEvent as u8 {
+        let button_id = (tm >> 16) as u8;
+        let press_ms = (tm & 0xFFFF) as u16;
+        slots.push(MessageRCV::ButtonEvent {
+            button_id,
+            press_ms,
+        })
+    } else if cmd != PacketKind::KeyerMessage as u8 {
+        return Err(DecodeError::UnknownKind(cmd));
+    } else if len == 0 {
+        trace!("Sync {tm}");
+        slots.push(MessageRCV::Sync(tick))
+    } else {
+        trace!("Edges {tm} {len} slots");
+        for &d in edges {
+            // tick_count() と同じく 49 日で一周する
+            let tm = tick + (d & 0x7fu8) as u32;
+            let keydown = d & 0x80u8 == 0;
+            if keydown {
+                slots.push(MessageRCV::Keydown { at: tm, sub_us: 0 })
+            } else {
+                slots.push(MessageRCV::Keyup { at: tm, sub_us: 0 })
+            }
+        }
+    }
+    Ok(slots)
+}
+
+/// Decode a packet in the TLV layout, skipping kinds and tags this version
+/// does not know.
+fn decode_tlv(pkt: &[u8]) -> Result<Vec<MessageRCV>, DecodeError> {
+    if pkt.len() < CODEC_HEADER {
+        return Err(DecodeError::Short(pkt.len()));
+    }
+    if pkt[1] != CODEC_VERSION {
+        return Err(DecodeError::UnsupportedVersion(pkt[1]));
+    }
+    let mut buf = &pkt[CODEC_HEADER..];
+    let mut msgs = Vec::new();
+    while buf.has_remaining() {
+        if buf.remaining() < RECORD_HEADER {
+            return Err(DecodeError::Truncated);
+        }
+        let kind = buf.get_u8();
+        let len = buf.get_u16() as usize;
+        if buf.remaining() < len {
+            return Err(DecodeError::Truncated);
+        }
+        let fields = Fields::parse(&buf[..len])?;
+        buf.advance(len);
+        decode_record(kind, &fields, &mut msgs)?;
+    }
+    Ok(msgs)
+}
+
+fn decode_record(kind: u8, fields: &Fields, msgs: &mut Vec<MessageRCV>) -> Result<(), DecodeError> {
+    match kind {
+        k if k == PacketKind::KeyerMessage as u8 => {
+            let tm = Tick::from_ms(fields.require_uint(TAG_TM, u32::MAX)?);
+            let slots = fields.get(TAG_SLOTS).unwrap_or_default();
+            for &d in slots {
+                let tm = tm + (d & 0x7f) as u32;
+                if d & 0x80 == 0 {
+                    msgs.push(MessageRCV::Keydown { at: tm, sub_us: 0 })
+                } else {
+                    msgs.push(MessageRCV::Keyup { at: tm, sub_us: 0 })
+                }
+            }
+            let edges = fields.get(TAG_EDGES).unwrap_or_default();
+            decode_edges(edges, msgs)?;
+            if slots.is_empty() && edges.is_empty() {
+                msgs.push(MessageRCV::Sync(tm))
+            }
+        }
+        k if k == PacketKind::StartATU as u8 => msgs.push(MessageRCV::StartATU),
+        k if k == PacketKind::Ping as u8 => {
+            let ts = fields.require_uint(TAG_TM, u32::MAX)?;
+            msgs.push(MessageRCV::Ping(Tick::from_ms(ts)))
+        }
+        k if k == PacketKind::Pong as u8 => {
+            let origin = Tick::from_ms(fields.require_uint(TAG_TM, u32::MAX)?);
+            // 片方だけでは使えないので、揃っているときだけ渡す
+            let received = fields.uint(TAG_RECEIVED, u32::MAX)?;
+            let sent = fields.uint(TAG_SENT, u32::MAX)?;
+            let peer = received
+                .zip(sent)
+                .map(|(r, s)| (Tick::from_ms(r), Tick::from_ms(s)));
+            msgs.push(MessageRCV::Pong { origin, peer })
+        }
+        k if k == PacketKind::EncoderEvent as u8 => {
+            let direction = match fields.get(TAG_DIRECTION) {
+                Some(&[d]) => d as i8,
+                Some(_) => return Err(DecodeError::InvalidField(TAG_DIRECTION)),
+                None => return Err(DecodeError::MissingField(TAG_DIRECTION)),
+            };
+            msgs.push(MessageRCV::EncoderEvent {
+                encoder_id: fields.uint(TAG_ID, u8::MAX as u32)?.unwrap_or(0) as u8,
+                direction,
+                steps: fields.uint(TAG_STEPS, u8::MAX as u32)?.unwrap_or(1) as u8,
+            })
+        }
+        k if k == PacketKind::ButtonEvent as u8 => msgs.push(MessageRCV::ButtonEvent {
+            button_id: fields.uint(TAG_ID, u8::MAX as u32)?.unwrap_or(0) as u8,
+            press_ms: fields.require_uint(TAG_PRESS_MS, u16::MAX as u32)? as u16,
+        }),
+        // 後の版で増えた種別は読み飛ばす
+        _ => trace!("unknown record kind {kind} skipped"),
+    }
+    Ok(())
+}
+
+// 時刻は tick_count() と同じミリ秒と、その中のマイクロ秒に分ける。ミリ秒は 49 日で一周する
+fn decode_edges(mut value: &[u8], msgs: &mut Vec<MessageRCV>) -> Result<(), DecodeError> {
+    if value.is_empty() {
+        return Ok(());
+    }
+    let mut at = get_varint(&mut value).ok_or(DecodeError::InvalidField(TAG_EDGES))?;
+    while !value.is_empty() {
+        let d = get_varint(&mut value).ok_or(DecodeError::InvalidField(TAG_EDGES))?;
+        at = at
+            .checked_add(d >> 1)
+            .ok_or(DecodeError::InvalidField(TAG_EDGES))?;
+        let tm = Tick::from_ms((at / 1000) as u32);
+        let sub_us = (at % 1000) as u16;
+        if d & 1 == 0 {
+            msgs.push(MessageRCV::Keydown { at: tm, sub_us })
+        } else {
+            msgs.push(MessageRCV::Keyup { at: tm, sub_us })
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use proptest::prelude::*;
+
+    // 旧形式の打鍵はミリ秒まで
+    fn down(ms: u32) -> MessageRCV {
+        MessageRCV::Keydown {
+            at: Tick::from_ms(ms),
+            sub_us: 0,
+        }
+    }
+
+    fn up(ms: u32) -> MessageRCV {
+        MessageRCV::Keyup {
+            at: Tick::from_ms(ms),
+            sub_us: 0,
+        }
+    }
+
+    // Packet は借用なので、スロットは持ち主を分けて生成する
+    #[derive(Debug, Clone)]
+    enum Sample {
+        Keyer(Tick, Vec<u8>),
+        Other(Packet<'static>),
+    }
+
+    impl Sample {
+        fn packet(&self) -> Packet<'_> {
+            match self {
+                Sample::Keyer(tm, slots) => Packet::Keyer {
+                    tm: *tm,
+                    slots,
+                    edges: &[],
+                },
+                Sample::Other(packet) => packet.clone(),
+            }
+        }
+    }
+
+    fn tick() -> impl Strategy<Value = Tick> {
+        any::<u32>().prop_map(Tick::from_ms)
+    }
+
+    // 旧形式でも表せるパケット
+    fn sample() -> impl Strategy<Value = Sample> {
+        prop_oneof![
+            (tick(), prop::collection::vec(any::<u8>(), 0..=MAX_SLOTS))
+                .prop_map(|(tm, slots)| Sample::Keyer(tm, slots)),
+            Just(Sample::Other(Packet::StartAtu)),
+            tick().prop_map(|ts| Sample::Other(Packet::Ping(ts))),
+            tick().prop_map(|origin| Sample::Other(Packet::Pong { origin, peer: None })),
+            any::<(u8, i8, u8)>().prop_map(|(encoder_id, direction, steps)| {
+                Sample::Other(Packet::Encoder {
+                    encoder_id,
+                    direction,
+                    steps,
+                })
+            }),
+            any::<(u8, u16)>().prop_map(|(button_id, press_ms)| {
+                Sample::Other(Packet::Button {
+                    button_id,
+                    press_ms,
+                })
+            }),
+        ]
+    }
+
+    // tm から gaps_us ずつ空けて上げ下げを繰り返す打鍵
+    fn edges(tm: Tick, gaps_us: &[u64]) -> Vec<Edge> {
+        let mut at_us = tm.as_ms() as u64 * 1000;
+        gaps_us
+            .iter()
+            .enumerate()
+            .map(|(i, gap)| {
+                at_us += gap;
+                Edge {
+                    at_us,
+                    down: i % 2 == 0,
+                }
+            })
+            .collect()
+    }
+
+    fn decoded(codec: Codec, packet: &Packet) -> Vec<MessageRCV> {
+        let mut buf = BytesMut::new();
+        codec.encode(&mut buf, packet).unwrap();
+        decode_message(&buf).unwrap()
+    }
+
+    proptest! {
+        #[test]
+        fn test_tlv_roundtrip_matches_legacy(sample in sample()) {
+            // どちらの形式で送っても同じメッセージになる
+            let packet = sample.packet();
+            prop_assert_eq!(decoded(Codec::Tlv, &packet), decoded(Codec::Legacy, &packet));
+        }
+
+        #[test]
+        fn test_tlv_truncation_never_panics(
+            sample in sample(),
+            at in any::<prop::sample::Index>(),
+            byte in any::<u8>(),
+        ) {
+            let mut buf = BytesMut::new();
+            Codec::Tlv.encode(&mut buf, &sample.packet()).unwrap();
+            for cut in 0..buf.len() {
+                let _ = decode_tlv(&buf[..cut]);
+            }
+            // 1 バイト壊しても落ちない
+            let mut broken = buf.to_vec();
+            broken[at.index(buf.len())] = byte;
+            let _ = decode_message(&broken);
+        }
+
+        #[test]
+        fn test_edges_match_legacy_slots(
+            tm in (0..u32::MAX / 2).prop_map(Tick::from_ms),
+            gaps_us in (1..20usize)
+                .prop_flat_map(|n| prop::collection::vec(0..127_000 / n as u64, n)),
+        ) {
+            // 旧形式でも表せる範囲の打鍵なら、どちらの形式でもミリ秒までは同じ時刻列になる
+            let edges = edges(tm, &gaps_us);
+            let packet = Packet::Keyer {
+                tm,
+                slots: &[],
+                edges: &edges,
+            };
+            let legacy = decoded(Codec::Legacy, &packet);
+            prop_assert_eq!(legacy.len(), edges.len());
+            let tlv: Vec<_> = decoded(Codec::Tlv, &packet)
+                .into_iter()
+                .map(|m| match m {
+                    MessageRCV::Keydown { at, .. } => down(at.as_ms()),
+                    MessageRCV::Keyup { at, .. } => up(at.as_ms()),
+                    m => m,
+                })
+                .collect();
+            prop_assert_eq!(tlv, legacy);
+        }
+
+        #[test]
+        fn test_edges_never_overflow(
+            tm in tick(),
+            // ときどき数秒の停止をはさみ、旧形式の MAX_SLOTS を超える数にもなる
+            gaps_us in prop::collection::vec(
+                prop_oneof![9 => 0..6_000u64, 1 => 0..10_000_000u64],
+                1..300,
+            ),
+        ) {
+            let edges = edges(tm, &gaps_us);
+            let expected: Vec<_> = edges
+                .iter()
+                .map(|e| {
+                    let (at, sub_us) = (e.tick(), (e.at_us % 1000) as u16);
+                    if e.down {
+                        MessageRCV::Keydown { at, sub_us }
+                    } else {
+                        MessageRCV::Keyup { at, sub_us }
+                    }
+                })
+                .collect();
+
+            let mut buf = BytesMut::new();
+            let mut rest = edges.as_slice();
+            let mut msgs = Vec::new();
+            while !rest.is_empty() {
+                let n = edges_per_packet(tm, &[], rest);
+                prop_assert!(n > 0);
+                let packet = Packet::Keyer {
+                    tm,
+                    slots: &[],
+                    edges: &rest[..n],
+                };
+                Codec::Tlv.encode(&mut buf, &packet).unwrap();
+                prop_assert!(buf.len() <= PKT_SIZE, "{} bytes", buf.len());
+                msgs.extend(decode_message(&buf).unwrap());
+                rest = &rest[n..];
+            }
+            prop_assert_eq!(msgs, expected);
+
+            // 旧形式では tm から 127ms を超えた打鍵は捨てるしかない
+            let last = edges[edges.len() - 1].tick();
+            if last.delta(tm) > SLOT_OFFSET_MAX {
+                let packet = Packet::Keyer {
+                    tm,
+                    slots: &[],
+                    edges: &edges,
+                };
+                prop_assert!(decoded(Codec::Legacy, &packet).len() < edges.len());
+            }
+        }
+
+        #[test]
+        fn test_varint_roundtrip(value in any::<u64>(), shift in 0..64u32) {
+            let value = value >> shift;
+            let mut buf = Vec::new();
+            put_varint(&mut buf, value);
+            prop_assert_eq!(buf.len(), varint_len(value));
+            prop_assert_eq!(get_varint(&mut buf.as_slice()), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_tlv_skips_unknown_kinds_and_tags() {
+        // Ping に未知のタグを足し、未知の種別のレコードを後ろに付ける
+        let pkt = [
+            CODEC_MAGIC,
+            CODEC_VERSION,
+            PacketKind::Ping as u8,
+            0,
+            6,
+            TAG_TM,
+            1,
+            7,
+            0x63,
+            1,
+            0xff,
+            0x42,
+            0,
+            3,
+            0x63,
+            1,
+            0xff,
+        ];
+        assert_eq!(
+            decode_tlv(&pkt).unwrap(),
+            vec![MessageRCV::Ping(Tick::from_ms(7))]
+        );
+    }
+
+    #[test]
+    fn test_pong_peer_times() {
+        let packet = Packet::Pong {
+            origin: Tick::from_ms(u32::MAX),
+            peer: Some((Tick::from_ms(5), Tick::from_ms(0x1_0000))),
+        };
+        assert_eq!(
+            decoded(Codec::Tlv, &packet),
+            vec![MessageRCV::Pong {
+                origin: Tick::from_ms(u32::MAX),
+                peer: Some((Tick::from_ms(5), Tick::from_ms(0x1_0000)))
+            }]
+        );
+        // 旧形式では送れない
+        assert_eq!(
+            decoded(Codec::Legacy, &packet),
+            vec![MessageRCV::Pong {
+                origin: Tick::from_ms(u32::MAX),
+                peer: None
+            }]
+        );
+        // 片方しかなければ使わない
+        let pkt = [
+            CODEC_MAGIC,
+            CODEC_VERSION,
+            3,
+            0,
+            6,
+            TAG_TM,
+            1,
+            7,
+            TAG_SENT,
+            1,
+            9,
+        ];
+        assert_eq!(
+            decode_tlv(&pkt).unwrap(),
+            vec![MessageRCV::Pong {
+                origin: Tick::from_ms(7),
+                peer: None
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tlv_optional_and_missing_fields() {
+        // steps と id を省いたエンコーダーイベント
+        let pkt = [CODEC_MAGIC, CODEC_VERSION, 4, 0, 3, TAG_DIRECTION, 1, 0xff];
+        assert_eq!(
+            decode_tlv(&pkt).unwrap(),
+            vec![MessageRCV::EncoderEvent {
+                encoder_id: 0,
+                direction: -1,
+                steps: 1
+            }]
+        );
+        let pkt = [CODEC_MAGIC, CODEC_VERSION, 5, 0, 3, TAG_ID, 1, 2];
+        assert_eq!(
+            decode_tlv(&pkt),
+            Err(DecodeError::MissingField(TAG_PRESS_MS))
+        );
+        let pkt = [CODEC_MAGIC, CODEC_VERSION, 5, 0, 4, TAG_PRESS_MS, 2, 1, 0];
+        assert_eq!(
+            decode_tlv(&pkt).unwrap(),
+            vec![MessageRCV::ButtonEvent {
+                button_id: 0,
+                press_ms: 256
+            }]
+        );
+        let pkt = [
+            CODEC_MAGIC,
+            CODEC_VERSION,
+            5,
+            0,
+            5,
+            TAG_PRESS_MS,
+            3,
+            1,
+            0,
+            0,
+        ];
+        assert_eq!(
+            decode_tlv(&pkt),
+            Err(DecodeError::InvalidField(TAG_PRESS_MS))
+        );
+        assert_eq!(
+            decode_tlv(&[CODEC_MAGIC, 2]),
+            Err(DecodeError::UnsupportedVersion(2))
+        );
+    }
+
+    #[test]
+    fn test_edges_keep_sub_millisecond_order() {
+        // 1ms 未満の間隔でも順序と上げ下げ、マイクロ秒まで保たれる
+        let edges = [
+            Edge {
+                at_us: 1_000_100,
+                down: true,
+            },
+            Edge {
+                at_us: 1_000_400,
+                down: false,
+            },
+            Edge {
+                at_us: 1_001_900,
+                down: true,
+            },
+        ];
+        let packet = Packet::Keyer {
+            tm: Tick::from_ms(1000),
+            slots: &[],
+            edges: &edges,
+        };
+        assert_eq!(
+            decoded(Codec::Tlv, &packet),
+            vec![
+                MessageRCV::Keydown {
+                    at: Tick::from_ms(1000),
+                    sub_us: 100
+                },
+                MessageRCV::Keyup {
+                    at: Tick::from_ms(1000),
+                    sub_us: 400
+                },
+                MessageRCV::Keydown {
+                    at: Tick::from_ms(1001),
+                    sub_us: 900
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_varint_rejects_malformed() {
+        // 64bit を超える値と途切れた値は受け付けない
+        assert_eq!(get_varint(&mut [0xff; 10].as_slice()), None);
+        assert_eq!(get_varint(&mut [0x80, 0x80].as_slice()), None);
+    }
+
+    #[test]
+    fn test_encode_sync_packet() {
+        let mut buf = BytesMut::with_capacity(128);
+        let slots: &[u8] = &[];
+        encode_message(&mut buf, PacketKind::KeyerMessage, 1000, slots).unwrap();
+
+        // Verify: cmd(1) + tm(4) + len(1) = 6 bytes
+        assert_eq!(buf.len(), 6);
+        assert_eq!(buf[0], PacketKind::KeyerMessage as u8);
+        // timestamp is big-endian
+        assert_eq!(&buf[1..5], &1000u32.to_be_bytes());
+        assert_eq!(buf[5], 0); // no slots
+    }
+
+    #[test]
+    fn test_encode_with_edges() {
+        let mut buf = BytesMut::with_capacity(128);
+        let slots: &[u8] = &[0x10, 0x90]; // keydown at +16, keyup at +16
+        encode_message(&mut buf, PacketKind::KeyerMessage, 1000, slots).unwrap();
+
+        assert_eq!(buf.len(), 8); // 6 + 2 slots
+        assert_eq!(buf[5], 2); // 2 slots
+        assert_eq!(buf[6], 0x10); // keydown (high bit = 0)
+        assert_eq!(buf[7], 0x90); // keyup (high bit = 1)
+    }
+
+    #[test]
+    fn test_encode_start_atu() {
+        let mut buf = BytesMut::with_capacity(128);
+        let slots: &[u8] = &[];
+        encode_message(&mut buf, PacketKind::StartATU, 0, slots).unwrap();
+
+        assert_eq!(buf[0], PacketKind::StartATU as u8);
+    }
+
+    #[test]
+    fn test_encode_too_many_slots() {
+        let mut buf = BytesMut::with_capacity(256);
+        let slots = vec![0u8; MAX_SLOTS + 1];
+        let result = encode_message(&mut buf, PacketKind::KeyerMessage, 0, &slots);
+
+        assert_eq!(
+            result,
+            Err(EncodeError::TooManySlots {
+                slots: 129,
+                max: MAX_SLOTS
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_sync_packet() {
+        let mut buf = BytesMut::with_capacity(128);
+        encode_message(&mut buf, PacketKind::KeyerMessage, 1000, &[]).unwrap();
+
+        let msgs = decode_message(&buf).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0], MessageRCV::Sync(Tick::from_ms(1000)));
+    }
+
+    #[test]
+    fn test_decode_keydown_keyup() {
+        let mut buf = BytesMut::with_capacity(128);
+        // keydown at offset 10, keyup at offset 20
+        let slots: &[u8] = &[10, 0x80 | 20];
+        encode_message(&mut buf, PacketKind::KeyerMessage, 1000, slots).unwrap();
+
+        let msgs = decode_message(&buf).unwrap();
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0], down(1010)); // 1000 + 10
+        assert_eq!(msgs[1], up(1020)); // 1000 + 20
+    }
+
+    #[test]
+    fn test_decode_start_atu() {
+        let mut buf = BytesMut::with_capacity(128);
+        encode_message(&mut buf, PacketKind::StartATU, 0, &[]).unwrap();
+
+        let msgs = decode_message(&buf).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0], MessageRCV::StartATU);
+    }
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let mut buf = BytesMut::with_capacity(128);
+        let original_tm = 5000u32;
+        let slots: &[u8] = &[5, 0x80 | 10, 15, 0x80 | 25];
+
+        encode_message(&mut buf, PacketKind::KeyerMessage, original_tm, slots).unwrap();
+        let msgs = decode_message(&buf).unwrap();
+
+        assert_eq!(msgs.len(), 4);
+        assert_eq!(msgs[0], down(5005));
+        assert_eq!(msgs[1], up(5010));
+        assert_eq!(msgs[2], down(5015));
+        assert_eq!(msgs[3], up(5025));
+    }
+
+    #[test]
+    fn test_encoder_event_roundtrip() {
+        let mut buf = BytesMut::with_capacity(128);
+        let dir_byte = (1i16 + 128) as u8; // direction = +1
+        let tm = ((2u32) << 24) | ((dir_byte as u32) << 16) | ((5u32) << 8);
+        encode_message(&mut buf, PacketKind::EncoderEvent, tm, &[]).unwrap();
+
+        let msgs = decode_message(&buf).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(
+            msgs[0],
+            MessageRCV::EncoderEvent {
+                encoder_id: 2,
+                direction: 1,
+                steps: 5
+            }
+        );
+    }
+
+    #[test]
+    fn test_encoder_event_negative_direction() {
+        let mut buf = BytesMut::with_capacity(128);
+        let dir_byte = (-1i16 + 128) as u8; // direction = -1 → 127
+        let tm = /* encoder_id=0 */ ((dir_byte as u32) << 16) | ((3u32) << 8);
+        encode_message(&mut buf, PacketKind::EncoderEvent, tm, &[]).unwrap();
+
+        let msgs = decode_message(&buf).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(
+            msgs[0],
+            MessageRCV::EncoderEvent {
+                encoder_id: 0,
+                direction: -1,
+                steps: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_button_event_roundtrip() {
+        let mut buf = BytesMut::with_capacity(128);
+        let tm = ((1u32) << 16) | 1500u32; // button_id=1, press_ms=1500
+        encode_message(&mut buf, PacketKind::ButtonEvent, tm, &[]).unwrap();
+
+        let msgs = decode_message(&buf).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(
+            msgs[0],
+            MessageRCV::ButtonEvent {
+                button_id: 1,
+                press_ms: 1500
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed() {
+        let mut buf = BytesMut::with_capacity(128);
+        encode_message(&mut buf, PacketKind::KeyerMessage, 0, &[1, 2]).unwrap();
+
+        assert_eq!(decode_message(&buf[..3]), Err(DecodeError::Short(3)));
+        assert_eq!(
+            decode_message(&buf[..7]),
+            Err(DecodeError::SlotCount {
+                declared: 2,
+                present: 1
+            })
+        );
+        let mut longer = buf.to_vec();
+        longer.push(3);
+        assert_eq!(
+            decode_message(&longer),
+            Err(DecodeError::SlotCount {
+                declared: 2,
+                present: 3
+            })
+        );
+        buf[0] = 0x7f;
+        assert_eq!(decode_message(&buf), Err(DecodeError::UnknownKind(0x7f)));
+    }
+
+    #[test]
+    fn test_decode_edge_wraps_tick() {
+        let mut buf = BytesMut::with_capacity(128);
+        encode_message(&mut buf, PacketKind::KeyerMessage, u32::MAX, &[0x02]).unwrap();
+
+        let msgs = decode_message(&buf).unwrap();
+        assert_eq!(msgs, vec![down(1)]);
+    }
+}
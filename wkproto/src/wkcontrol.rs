@@ -0,0 +1,57 @@
+/// Why a session ended, as carried by the close datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The user ended the session
+    UserQuit,
+    AuthFailure,
+    /// The server has no free session slot
+    ServerBusy,
+    EmergencyStop,
+    /// The application is shutting down
+    Shutdown,
+    /// The link broke: idle timeout, dead link or a tampered message
+    LinkLost,
+    /// A reason code this version does not know
+    Other(u8),
+}
+
+impl CloseReason {
+    /// Byte put on the wire
+    pub const fn code(self) -> u8 {
+        match self {
+            CloseReason::UserQuit => 1,
+            CloseReason::AuthFailure => 2,
+            CloseReason::ServerBusy => 3,
+            CloseReason::EmergencyStop => 4,
+            CloseReason::Shutdown => 5,
+            CloseReason::LinkLost => 6,
+            CloseReason::Other(code) => code,
+        }
+    }
+
+    pub const fn from_code(code: u8) -> Self {
+        match code {
+            1 => CloseReason::UserQuit,
+            2 => CloseReason::AuthFailure,
+            3 => CloseReason::ServerBusy,
+            4 => CloseReason::EmergencyStop,
+            5 => CloseReason::Shutdown,
+            6 => CloseReason::LinkLost,
+            code => CloseReason::Other(code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_reason_codes() {
+        for code in 0..=u8::MAX {
+            assert_eq!(CloseReason::from_code(code).code(), code);
+        }
+        assert_eq!(CloseReason::from_code(3), CloseReason::ServerBusy);
+        assert_eq!(CloseReason::from_code(0x42), CloseReason::Other(0x42));
+    }
+}
@@ -0,0 +1,98 @@
+use core::fmt;
+use core::ops::BitOr;
+
+/// Version of the message protocol spoken after authentication
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Message kinds a peer understands.
+///
+/// Each side announces what it can receive; check the peer's set before
+/// sending anything outside `BASELINE`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Keyer edges and sync packets
+    pub const KEYING: Self = Self(1 << 0);
+    /// Start ATU requests
+    pub const ATU: Self = Self(1 << 1);
+    /// Answers Ping with Pong
+    pub const PING: Self = Self(1 << 2);
+    pub const ENCODER_EVENTS: Self = Self(1 << 3);
+    pub const BUTTON_EVENTS: Self = Self(1 << 4);
+    /// Keyer messages on the unreliable datagram channel instead of KCP
+    pub const KEYING_DATAGRAMS: Self = Self(1 << 5);
+    /// Messages in the versioned TLV layout as well as the legacy one
+    pub const TLV_MESSAGES: Self = Self(1 << 6);
+
+    /// What every peer understood before capabilities were negotiated.
+    /// Assumed for peers that send no hello.
+    pub const BASELINE: Self = Self(Self::KEYING.0 | Self::ATU.0 | Self::PING.0);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [(Capabilities, &str); 7] = [
+            (Capabilities::KEYING, "KEYING"),
+            (Capabilities::ATU, "ATU"),
+            (Capabilities::PING, "PING"),
+            (Capabilities::ENCODER_EVENTS, "ENCODER_EVENTS"),
+            (Capabilities::BUTTON_EVENTS, "BUTTON_EVENTS"),
+            (Capabilities::KEYING_DATAGRAMS, "KEYING_DATAGRAMS"),
+            (Capabilities::TLV_MESSAGES, "TLV_MESSAGES"),
+        ];
+        let mut set = f.debug_set();
+        let mut known = 0;
+        for (cap, name) in NAMES {
+            if self.contains(cap) {
+                set.entry(&format_args!("{name}"));
+            }
+            known |= cap.0;
+        }
+        if self.0 & !known != 0 {
+            set.entry(&format_args!("{:#x}", self.0 & !known));
+        }
+        set.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn test_capabilities() {
+        let caps = Capabilities::BASELINE;
+        assert!(caps.contains(Capabilities::KEYING | Capabilities::PING));
+        assert!(!caps.contains(Capabilities::ENCODER_EVENTS));
+        assert_eq!(
+            format!("{:?}", caps | Capabilities::from_bits(1 << 31)),
+            "{KEYING, ATU, PING, 0x80000000}"
+        );
+    }
+}
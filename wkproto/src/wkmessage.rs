@@ -0,0 +1,118 @@
+use crate::wkcontrol::CloseReason;
+use crate::wktime::Tick;
+use core::fmt;
+
+/// Largest message a session carries in one packet
+pub const PKT_SIZE: usize = 128;
+/// Key edges one legacy keyer packet holds
+pub const MAX_SLOTS: usize = 128;
+
+/// First byte of a legacy packet and record kind of a TLV one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketKind {
+    KeyerMessage,
+    StartATU,
+    Ping,
+    Pong,
+    EncoderEvent = 4,
+    ButtonEvent = 5,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MessageRCV {
+    Sync(Tick),
+    /// Key pressed at `at` on the sender's clock, `sub_us` microseconds
+    /// into that millisecond. Only TLV edges carry the microseconds.
+    Keydown {
+        at: Tick,
+        sub_us: u16,
+    },
+    /// Key released, timed as `Keydown`
+    Keyup {
+        at: Tick,
+        sub_us: u16,
+    },
+    SessionClosed(CloseReason),
+    StartATU,
+    Ping(Tick),
+    /// Answer to the Ping sent at `origin`. Newer peers add their clock's
+    /// time when the Ping arrived and when they answered it.
+    Pong {
+        origin: Tick,
+        peer: Option<(Tick, Tick)>,
+    },
+    EncoderEvent {
+        encoder_id: u8,
+        direction: i8,
+        steps: u8,
+    },
+    ButtonEvent {
+        button_id: u8,
+        press_ms: u16,
+    },
+}
+
+/// Why a received packet is not a valid message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Shorter than the 6-byte header; holds the length
+    Short(usize),
+    UnknownKind(u8),
+    /// The slot count in the header disagrees with the bytes that follow
+    SlotCount {
+        declared: usize,
+        present: usize,
+    },
+    /// TLV packet of a layout version this side does not speak
+    UnsupportedVersion(u8),
+    /// A TLV record or field runs past the end of the packet
+    Truncated,
+    /// A TLV record lacks a field its kind requires; holds the tag
+    MissingField(u8),
+    /// A TLV field has the wrong length or an out-of-range value; holds the tag
+    InvalidField(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Short(len) => write!(f, "packet too short: {len} bytes"),
+            DecodeError::UnknownKind(kind) => write!(f, "unknown packet kind {kind}"),
+            DecodeError::SlotCount { declared, present } => {
+                write!(f, "{declared} slots declared but {present} present")
+            }
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported codec version {version}")
+            }
+            DecodeError::Truncated => write!(f, "truncated record"),
+            DecodeError::MissingField(tag) => write!(f, "missing field {tag}"),
+            DecodeError::InvalidField(tag) => write!(f, "invalid field {tag}"),
+        }
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+/// Why a message can't be put in a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    /// More key edges than one legacy packet holds
+    TooManySlots { slots: usize, max: usize },
+    /// A TLV field longer than its one-byte length allows
+    FieldTooLong { tag: u8, len: usize },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::TooManySlots { slots, max } => {
+                write!(f, "too many slots: {slots} > {max}")
+            }
+            EncodeError::FieldTooLong { tag, len } => {
+                write!(f, "field {tag} too long: {len} bytes")
+            }
+        }
+    }
+}
+
+impl core::error::Error for EncodeError {}
@@ -0,0 +1,471 @@
+use crate::wksync::ClockSync;
+use crate::wktime::Tick;
+use core::fmt;
+use core::str::FromStr;
+
+/// The key is released if held longer than this (ms), in case the link
+/// dies while it is down
+pub const MAX_ASSERT_DURATION: u32 = 10_000;
+/// Milliseconds per WPM (PARIS standard = 50 elements)
+pub const MSPERWPM: u32 = 1200;
+/// Keyer stats are reported on a Sync at most this often (ms)
+pub const RESYNC_INTERVAL: i32 = 3000;
+
+// 遅れの分布を取る打鍵の数
+const LATENESS_WINDOW: usize = 64;
+// 送り手の時刻でこれだけ空いた後の押下でだけ遅延を変える (ms)
+const ADAPT_GAP: i32 = 150;
+// 基準から相手の時刻でこれだけ経つごとに、1 ms 遅い Sync でも合わせ直す
+// (100 ppm)。水晶のずれより大きく取り、相手の時計が遅くても追いつける
+const DRIFT_ALLOWANCE: u32 = 10_000;
+
+/// Maps the sender's edge times onto the local clock.
+///
+/// Edges carry the sender's time. Once the sender answers Pings with its
+/// own timestamps, `ClockSync` maps them continuously and each edge is due
+/// one shortest one-way delay after it was keyed. Until then, and for
+/// peers that never send their timestamps, the clocks are paired by the
+/// Sync that came through fastest, or by the first edge, and each edge is
+/// due once as much time has passed here as there.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Playout {
+    // (相手の時刻, こちらの時刻) の対応。一番速く届いた Sync か最初の打鍵
+    anchor: Option<(Tick, Tick)>,
+    clock: ClockSync,
+}
+
+impl Playout {
+    pub const fn new() -> Self {
+        Self {
+            anchor: None,
+            clock: ClockSync::new(),
+        }
+    }
+
+    /// Account for a four-timestamp Ping/Pong exchange, see `ClockSync::update`
+    pub fn exchange(&mut self, origin: Tick, received: Tick, sent: Tick, arrived: Tick) -> bool {
+        self.clock.update(origin, received, sent, arrived)
+    }
+
+    /// The estimate of the sender's clock
+    pub fn clock_sync(&self) -> &ClockSync {
+        &self.clock
+    }
+
+    /// Pair `rmt` with `now` if this Sync came through faster than the one
+    /// the clocks are paired by, allowing for drift, so a Sync delayed on
+    /// the way never moves the playout later. True if it did.
+    pub fn sync(&mut self, rmt: Tick, now: Tick) -> bool {
+        if self.anchor.is_none_or(|(rmt_epoch, epoch)| {
+            let drift = (rmt.delta(rmt_epoch).max(0) as u32 / DRIFT_ALLOWANCE) as i32;
+            // オフセットは大きくても、届くまでの時間の差は小さい
+            now.delta(rmt).wrapping_sub(epoch.delta(rmt_epoch)) < drift
+        }) {
+            self.anchor = Some((rmt, now));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Our time at which the edge stamped `rmt` is due. Without a clock
+    /// estimate, an edge before any Sync pairs the clocks at `now`.
+    pub fn due(&mut self, rmt: Tick, now: Tick) -> Tick {
+        if let (Some(keyed), Some(delay)) = (self.clock.to_local(rmt), self.clock.delay()) {
+            // 届くのが一番早い場合に合わせる。遅れたものはすぐ鳴らす
+            return keyed + delay / 2;
+        }
+        let (rmt_epoch, epoch) = *self.anchor.get_or_insert((rmt, now));
+        // 基準より前の打鍵は遅れて届いたものとしてすぐ鳴らす
+        epoch + rmt.since(rmt_epoch)
+    }
+}
+
+/// Interarrival jitter of the Syncs, as in RFC 3550.
+///
+/// The transit time `now - rmt` holds the clock offset as well as the
+/// one-way delay; the difference of two transits leaves only the change in
+/// delay, which is smoothed by 1/16.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Jitter {
+    last_transit: Option<i32>,
+    // 固定小数点 1/16 ms 単位（精度維持）
+    jitter_x16: i64,
+}
+
+impl Jitter {
+    pub const fn new() -> Self {
+        Self {
+            last_transit: None,
+            jitter_x16: 0,
+        }
+    }
+
+    /// Account for a Sync stamped `rmt` that arrived at `now`
+    pub fn update(&mut self, rmt: Tick, now: Tick) {
+        // どちらの時計が一周しても差は小さいままなので wrapping で取る
+        let transit = now.delta(rmt);
+        if let Some(last) = self.last_transit {
+            let d = transit.wrapping_sub(last).unsigned_abs() as i64;
+            // J += (|D| - J) / 16 を 16 倍の単位で
+            self.jitter_x16 += d - self.jitter_x16 / 16;
+        }
+        self.last_transit = Some(transit);
+    }
+
+    /// Smoothed jitter in milliseconds
+    pub fn ms(&self) -> u32 {
+        (self.jitter_x16 / 16).unsigned_abs() as u32
+    }
+}
+
+/// What a `JitterBuffer` does with an edge that arrives after it is due.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LatePolicy {
+    /// Play it on arrival; the element it starts or ends changes length
+    Stretch,
+    /// Skip a late key-down and the key-up that ends it. A late key-up is
+    /// still played so the key is never left down.
+    Drop,
+    /// Play it on arrival and hold the following edges back by as much
+    /// until the next gap, so element lengths stay intact
+    #[default]
+    KeepLength,
+}
+
+impl fmt::Display for LatePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LatePolicy::Stretch => "stretch",
+            LatePolicy::Drop => "drop",
+            LatePolicy::KeepLength => "keep_length",
+        })
+    }
+}
+
+impl FromStr for LatePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "stretch" => Ok(LatePolicy::Stretch),
+            "drop" => Ok(LatePolicy::Drop),
+            "keep_length" => Ok(LatePolicy::KeepLength),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Tuning of a `JitterBuffer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JitterBufferConfig {
+    /// Share of the edges that should arrive before they are due, in percent
+    pub percentile: u8,
+    /// Bounds of the playout delay in milliseconds
+    pub min_delay: u32,
+    pub max_delay: u32,
+    pub late: LatePolicy,
+}
+
+impl JitterBufferConfig {
+    /// No added delay and late edges played on arrival, as before the buffer
+    pub const FIXED: Self = Self {
+        percentile: 0,
+        min_delay: 0,
+        max_delay: 0,
+        late: LatePolicy::Stretch,
+    };
+}
+
+impl Default for JitterBufferConfig {
+    fn default() -> Self {
+        Self {
+            percentile: 95,
+            min_delay: 0,
+            max_delay: 250,
+            late: LatePolicy::KeepLength,
+        }
+    }
+}
+
+/// Playout delay added to the edges' due times so that network jitter does
+/// not distort the elements.
+///
+/// Each edge records how late it arrived against its due time. At the
+/// first key-down after a gap the delay is set to the configured
+/// percentile of the recent lateness, so it never changes inside an
+/// element.
+#[derive(Debug, Clone, Copy)]
+pub struct JitterBuffer {
+    config: JitterBufferConfig,
+    lateness: [u32; LATENESS_WINDOW],
+    len: usize,
+    next: usize,
+    delay: u32,
+    // KeepLength で遅れた分だけ後ろにずらしている量
+    slip: u32,
+    late: u32,
+    // Drop で押下を捨てたので、対になる解放も捨てる
+    dropping: bool,
+    // 直前の打鍵の予定時刻
+    last: Option<Tick>,
+}
+
+impl JitterBuffer {
+    pub const fn new(config: JitterBufferConfig) -> Self {
+        Self {
+            config,
+            lateness: [0; LATENESS_WINDOW],
+            len: 0,
+            next: 0,
+            delay: config.min_delay,
+            slip: 0,
+            late: 0,
+            dropping: false,
+            last: None,
+        }
+    }
+
+    /// When to play an edge due at `due` that arrived at `now`, or None to
+    /// skip it
+    pub fn schedule(&mut self, due: Tick, down: bool, now: Tick) -> Option<Tick> {
+        self.lateness[self.next] = now.delta(due).max(0) as u32;
+        self.next = (self.next + 1) % LATENESS_WINDOW;
+        self.len = (self.len + 1).min(LATENESS_WINDOW);
+        let gap = self.last.is_none_or(|last| due.delta(last) >= ADAPT_GAP);
+        self.last = Some(due);
+        if down && gap {
+            self.delay = self.target();
+            self.slip = 0;
+        }
+        if !down && self.dropping {
+            self.dropping = false;
+            return None;
+        }
+
+        let at = due + self.delay + self.slip;
+        if !now.is_after(at) {
+            return Some(at);
+        }
+        self.late += 1;
+        match self.config.late {
+            LatePolicy::Stretch => Some(now),
+            LatePolicy::Drop if down => {
+                self.dropping = true;
+                None
+            }
+            LatePolicy::Drop => Some(now),
+            LatePolicy::KeepLength => {
+                self.slip += now.since(at);
+                Some(now)
+            }
+        }
+    }
+
+    fn target(&self) -> u32 {
+        let mut sorted = self.lateness;
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable();
+        let i = (self.len * self.config.percentile as usize / 100).min(self.len - 1);
+        sorted[i].clamp(self.config.min_delay, self.config.max_delay)
+    }
+
+    /// Delay added to the due times now, in milliseconds
+    pub fn delay(&self) -> u32 {
+        self.delay + self.slip
+    }
+
+    /// Edges that arrived after they were due
+    pub fn late(&self) -> u32 {
+        self.late
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_playout_due() {
+        let mut playout = Playout::new();
+        // 相手の時計はこちらと無関係で、途中で一周する
+        let rmt = Tick::from_ms(u32::MAX - 100);
+        let now = Tick::from_ms(50_000);
+        assert!(playout.sync(rmt, now));
+        assert_eq!(playout.due(rmt + 60, now + 20), Tick::from_ms(50_060));
+        // 基準より前の打鍵は基準の時刻、つまりすぐ鳴らす
+        assert_eq!(playout.due(rmt - 5, now + 20), now);
+
+        // 速く届いた Sync に合わせ直し、遅れた Sync では合わせ直さない
+        assert!(playout.sync(rmt + 1000, now + 990));
+        assert!(!playout.sync(rmt + 3001, Tick::from_ms(53_100)));
+        assert_eq!(playout.due(rmt + 3041, now), Tick::from_ms(53_031));
+    }
+
+    #[test]
+    fn test_playout_keeps_fastest_sync() {
+        let mut playout = Playout::new();
+        let rmt = Tick::from_ms(1000);
+        assert!(playout.sync(rmt, Tick::from_ms(80_000)));
+        // Pong が来なくても、遅れた Sync ごとに鳴らす時刻がずれたりしない
+        for i in 1..10 {
+            let delayed = (i % 3 + 1) * 20;
+            assert!(!playout.sync(rmt + i * 3000, Tick::from_ms(80_000 + i * 3000 + delayed)));
+        }
+        assert_eq!(
+            playout.due(rmt + 27_100, Tick::from_ms(107_050)),
+            Tick::from_ms(107_100)
+        );
+        // 時間が経てば、時計のずれの分だけ遅い Sync も受け入れる
+        assert!(!playout.sync(rmt + 30_000, Tick::from_ms(110_004)));
+        assert!(playout.sync(rmt + 30_000, Tick::from_ms(110_002)));
+        assert_eq!(
+            playout.due(rmt + 30_100, Tick::from_ms(110_050)),
+            Tick::from_ms(110_102)
+        );
+    }
+
+    #[test]
+    fn test_playout_edge_before_sync() {
+        let mut playout = Playout::new();
+        // Sync より先に届いた打鍵が基準になる
+        assert_eq!(
+            playout.due(Tick::from_ms(900), Tick::from_ms(7)),
+            Tick::from_ms(7)
+        );
+        assert_eq!(
+            playout.due(Tick::from_ms(955), Tick::from_ms(9)),
+            Tick::from_ms(62)
+        );
+        // 打鍵より遅れて届いた Sync では合わせ直さない
+        assert!(!playout.sync(Tick::from_ms(1000), Tick::from_ms(120)));
+        assert_eq!(
+            playout.due(Tick::from_ms(1010), Tick::from_ms(125)),
+            Tick::from_ms(117)
+        );
+    }
+
+    #[test]
+    fn test_playout_follows_clock_estimate() {
+        let mut playout = Playout::new();
+        // 遅れて届いた Sync でも、最初のものは基準になり打鍵もその分遅れる
+        let rmt = Tick::from_ms(90_000);
+        assert!(playout.sync(rmt, Tick::from_ms(1040)));
+        assert_eq!(
+            playout.due(rmt + 100, Tick::from_ms(1100)),
+            Tick::from_ms(1140)
+        );
+
+        // 相手の時計はこちらより 89_000 ms 進んでいて、片道 5 ms
+        let origin = Tick::from_ms(1200);
+        assert!(playout.exchange(origin, origin + 89_005, origin + 89_006, origin + 11));
+        assert_eq!(playout.clock_sync().delay(), Some(10));
+        // Sync で合わせ直さなくても、打った時刻 + 片道で鳴らす
+        assert!(!playout.sync(rmt + 5000, Tick::from_ms(6100)));
+        assert_eq!(
+            playout.due(rmt + 5100, Tick::from_ms(6050)),
+            Tick::from_ms(6105)
+        );
+    }
+
+    #[test]
+    fn test_jitter_ignores_clock_offset() {
+        let mut jitter = Jitter::new();
+        let offset = 0x8000_0000;
+        for i in 0..100 {
+            let rmt = Tick::from_ms(u32::MAX - 1000) + i * 50;
+            jitter.update(rmt, rmt + offset);
+        }
+        // 遅延が一定ならオフセットがいくら大きくてもジッターは 0
+        assert_eq!(jitter.ms(), 0);
+        for i in 0..200u32 {
+            let rmt = Tick::from_ms(i * 50);
+            jitter.update(rmt, rmt + offset + (i % 2) * 8);
+        }
+        // 8 ms ずつ揺れ続ければ 8 ms に近づく
+        assert!((7..=8).contains(&jitter.ms()), "{}", jitter.ms());
+    }
+
+    // 打鍵の予定時刻と到着の遅れ
+    fn play(buffer: &mut JitterBuffer, edges: &[(u32, bool, u32)]) -> Vec<Option<u32>> {
+        edges
+            .iter()
+            .map(|&(due, down, late)| {
+                let due = Tick::from_ms(due);
+                buffer.schedule(due, down, due + late).map(|t| t.as_ms())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_jitter_buffer_adapts_at_gaps() {
+        let mut buffer = JitterBuffer::new(JitterBufferConfig {
+            percentile: 90,
+            min_delay: 5,
+            max_delay: 100,
+            late: LatePolicy::Stretch,
+        });
+        // 最初は最小の遅延
+        assert_eq!(play(&mut buffer, &[(1000, true, 0)]), [Some(1005)]);
+        // 長点の途中では遅延を変えない
+        let got = play(
+            &mut buffer,
+            &[(1180, false, 40), (1240, true, 30), (1300, false, 0)],
+        );
+        assert_eq!(got, [Some(1220), Some(1270), Some(1305)]);
+        assert_eq!(buffer.late(), 2);
+        // 間が空いたら遅れに合わせて広げる
+        assert_eq!(play(&mut buffer, &[(2000, true, 0)]), [Some(2040)]);
+        assert_eq!(buffer.delay(), 40);
+        // 上限は超えない
+        for i in 0..LATENESS_WINDOW as u32 {
+            play(&mut buffer, &[(3000 + i * 200, true, 500)]);
+        }
+        assert_eq!(buffer.delay(), 100);
+    }
+
+    #[test]
+    fn test_jitter_buffer_late_policies() {
+        let edges = [
+            (1000, true, 0),
+            (1060, false, 0),
+            // 押下だけが 30 ms 遅れる
+            (1120, true, 30),
+            (1180, false, 0),
+            (1240, true, 0),
+            (1300, false, 0),
+        ];
+        let config = JitterBufferConfig {
+            percentile: 0,
+            min_delay: 10,
+            max_delay: 10,
+            late: LatePolicy::Stretch,
+        };
+        let mut buffer = JitterBuffer::new(config);
+        let got = play(&mut buffer, &edges);
+        // 短点が 20 ms 短くなる
+        let want = [1010, 1070, 1150, 1190, 1250, 1310].map(Some);
+        assert_eq!(got, want);
+
+        let mut buffer = JitterBuffer::new(JitterBufferConfig {
+            late: LatePolicy::Drop,
+            ..config
+        });
+        let got = play(&mut buffer, &edges);
+        // 遅れた短点を丸ごと捨てる
+        let want = [Some(1010), Some(1070), None, None, Some(1250), Some(1310)];
+        assert_eq!(got, want);
+
+        let mut buffer = JitterBuffer::new(JitterBufferConfig {
+            late: LatePolicy::KeepLength,
+            ..config
+        });
+        let got = play(&mut buffer, &edges);
+        // 以後の打鍵を 20 ms ずらして長さを保つ
+        let want = [1010, 1070, 1150, 1210, 1270, 1330].map(Some);
+        assert_eq!(got, want);
+        assert_eq!(buffer.delay(), 30);
+        assert_eq!(buffer.late(), 1);
+    }
+}
@@ -0,0 +1,300 @@
+use crate::wkplayout::MSPERWPM;
+
+// 直近これだけのマークとスペースから推定する
+const WINDOW: usize = 48;
+// マークがこれだけ集まるまでは推定しない
+const MIN_MARKS: usize = 6;
+// これより長いマークは押しっぱなし、長いスペースは休みとみなして使わない (ms)
+const MAX_MARK: u32 = 1500;
+const MAX_SPACE: u32 = 3000;
+// 長いほうのクラスタが短いほうのこれだけ倍あれば長点と短点に分かれている
+const SPLIT_RATIO: f64 = 2.0;
+const ITERATIONS: usize = 8;
+// PARIS の 50 単位のうち、文字の中（マークと符号間のスペース）の分
+const PARIS_IN_CHARS: f64 = 31.0;
+
+/// Speed and timing of the keying, see `SpeedEstimator`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpeedEstimate {
+    /// Character speed from the dit and the gap between elements (PARIS)
+    pub wpm: f32,
+    /// Dah length over dit length, 3 when standard. `None` until both
+    /// have been seen.
+    pub dah_ratio: Option<f32>,
+    /// Share of the dit-plus-gap period the key is down in percent, 50 when
+    /// standard. `None` until gaps between elements have been seen.
+    pub weight: Option<f32>,
+    /// Overall speed with the gaps between characters and words as sent.
+    /// Equal to `wpm` when they are standard; lower with Farnsworth spacing.
+    pub farnsworth_wpm: f32,
+}
+
+// 新しいものが古いものを押し出す固定長の窓
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    values: [u32; WINDOW],
+    len: usize,
+    next: usize,
+}
+
+impl Window {
+    const fn new() -> Self {
+        Self {
+            values: [0; WINDOW],
+            len: 0,
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, v: u32) {
+        self.values[self.next] = v;
+        self.next = (self.next + 1) % WINDOW;
+        self.len = (self.len + 1).min(WINDOW);
+    }
+
+    fn as_slice(&self) -> &[u32] {
+        &self.values[..self.len]
+    }
+}
+
+// 1 次元の k-means。centers を初期値から更新し、各クラスタの数を返す
+fn cluster<const K: usize>(
+    values: impl Iterator<Item = u32> + Clone,
+    centers: &mut [f64; K],
+) -> [usize; K] {
+    let mut counts = [0; K];
+    for _ in 0..ITERATIONS {
+        let mut sums = [0.0; K];
+        counts = [0; K];
+        for v in values.clone() {
+            let v = v as f64;
+            let mut nearest = 0;
+            for (i, c) in centers.iter().enumerate() {
+                if (v - c).abs() < (v - centers[nearest]).abs() {
+                    nearest = i;
+                }
+            }
+            sums[nearest] += v;
+            counts[nearest] += 1;
+        }
+        for i in 0..K {
+            if counts[i] > 0 {
+                centers[i] = sums[i] / counts[i] as f64;
+            }
+        }
+    }
+    counts
+}
+
+// 短いほうと長いほうの 2 つに分ける。分かれなければ長いほうは None
+fn split(values: impl Iterator<Item = u32> + Clone) -> Option<(f64, Option<f64>)> {
+    let (lo, hi) = values
+        .clone()
+        .fold((u32::MAX, 0), |(lo, hi), v| (lo.min(v), hi.max(v)));
+    if lo > hi {
+        return None;
+    }
+    let mut centers = [lo as f64, hi as f64];
+    let counts = cluster(values.clone(), &mut centers);
+    if counts[1] > 0 && centers[1] >= SPLIT_RATIO * centers[0] {
+        Some((centers[0], Some(centers[1])))
+    } else {
+        let mut mean = [lo as f64];
+        cluster(values, &mut mean);
+        Some((mean[0], None))
+    }
+}
+
+/// Estimates the speed and weighting of received keying.
+///
+/// Key-down times are clustered into dits and dahs, key-up times into the
+/// gaps between elements, characters and words. The dit and the element
+/// gap give the character speed and the weighting, the longer gaps the
+/// Farnsworth speed. Held keys and pauses are left out.
+#[derive(Debug, Clone, Copy)]
+pub struct SpeedEstimator {
+    marks: Window,
+    spaces: Window,
+}
+
+impl Default for SpeedEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpeedEstimator {
+    pub const fn new() -> Self {
+        Self {
+            marks: Window::new(),
+            spaces: Window::new(),
+        }
+    }
+
+    /// Account for the key being down for `ms`
+    pub fn mark(&mut self, ms: u32) {
+        if (1..=MAX_MARK).contains(&ms) {
+            self.marks.push(ms);
+        }
+    }
+
+    /// Account for the key being up for `ms` between two marks
+    pub fn space(&mut self, ms: u32) {
+        if (1..=MAX_SPACE).contains(&ms) {
+            self.spaces.push(ms);
+        }
+    }
+
+    /// The estimate from the recent marks and spaces, once there are enough
+    pub fn estimate(&self) -> Option<SpeedEstimate> {
+        let marks = self.marks.as_slice();
+        if marks.len() < MIN_MARKS {
+            return None;
+        }
+        let spaces = self.spaces.as_slice();
+        // 短点が dit ms なら、その倍より短いスペースが符号間、残りが文字間と語間
+        let gaps = |dit: f64| {
+            let short = move |s: &u32| (*s as f64) < SPLIT_RATIO * dit;
+            let element = split(spaces.iter().copied().filter(short)).map(|(e, _)| e);
+            let (letter, word) = split(spaces.iter().copied().filter(move |s| !short(s)))
+                .map_or((None, None), |(letter, word)| (Some(letter), word));
+            (element, letter, word)
+        };
+        let (dit, dah, (element, letter, word)) = match split(marks.iter().copied()) {
+            Some((dit, Some(dah))) => (dit, Some(dah), gaps(dit)),
+            // 長さがそろっているときは、長点と見て符号間のスペースが 1/3 ほどなら長点
+            Some((single, None)) => match gaps(single / 3.0) {
+                (Some(e), ..) if single >= SPLIT_RATIO * e => {
+                    (single / 3.0, None, gaps(single / 3.0))
+                }
+                _ => (single, None, gaps(single)),
+            },
+            None => return None,
+        };
+
+        let unit = element.map_or(dit, |e| (dit + e) / 2.0);
+        let letter = letter.unwrap_or(3.0 * unit);
+        let word = word.unwrap_or(letter * 7.0 / 3.0);
+        let paris_ms = PARIS_IN_CHARS * unit + 4.0 * letter + word;
+        Some(SpeedEstimate {
+            wpm: (MSPERWPM as f64 / unit) as f32,
+            dah_ratio: dah.map(|dah| (dah / dit) as f32),
+            weight: element.map(|e| (100.0 * dit / (dit + e)) as f32),
+            farnsworth_wpm: (60_000.0 / paris_ms) as f32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // '.' と '-' を符号間 element、' ' を文字間 letter、'/' を語間 word で送る
+    struct Fist {
+        dit: u32,
+        dah: u32,
+        element: u32,
+        letter: u32,
+        word: u32,
+    }
+
+    impl Fist {
+        fn standard(wpm: u32) -> Self {
+            let u = MSPERWPM / wpm;
+            Fist {
+                dit: u,
+                dah: 3 * u,
+                element: u,
+                letter: 3 * u,
+                word: 7 * u,
+            }
+        }
+
+        fn send(&self, est: &mut SpeedEstimator, text: &str) {
+            let mut gap = None;
+            for c in text.chars() {
+                match c {
+                    '.' | '-' => {
+                        if let Some(g) = gap {
+                            est.space(g);
+                        }
+                        est.mark(if c == '.' { self.dit } else { self.dah });
+                        gap = Some(self.element);
+                    }
+                    ' ' => gap = Some(self.letter),
+                    _ => gap = Some(self.word),
+                }
+            }
+        }
+    }
+
+    const PARIS: &str = ".--. .- .-. .. .../.--. .- .-. .. .../.--. .- .-. .. ...";
+
+    fn near(v: f32, expected: f32) -> bool {
+        (v - expected).abs() < expected * 0.03
+    }
+
+    #[test]
+    fn test_standard_keying() {
+        let mut est = SpeedEstimator::new();
+        assert_eq!(est.estimate(), None);
+        let fist = Fist::standard(20);
+        fist.send(&mut est, PARIS);
+        let e = est.estimate().unwrap();
+        assert!(near(e.wpm, 20.0), "{e:?}");
+        assert!(near(e.dah_ratio.unwrap(), 3.0), "{e:?}");
+        assert!(near(e.weight.unwrap(), 50.0), "{e:?}");
+        assert!(near(e.farnsworth_wpm, 20.0), "{e:?}");
+    }
+
+    #[test]
+    fn test_heavy_weighting() {
+        // 25 WPM の周期のまま、マークを伸ばしてスペースを詰めた 3.5:1 の送り
+        let mut est = SpeedEstimator::new();
+        let fist = Fist {
+            dit: 60,
+            dah: 210,
+            element: 36,
+            letter: 132,
+            word: 324,
+        };
+        fist.send(&mut est, PARIS);
+        let e = est.estimate().unwrap();
+        assert!(near(e.wpm, 25.0), "{e:?}");
+        assert!(near(e.dah_ratio.unwrap(), 3.5), "{e:?}");
+        assert!(near(e.weight.unwrap(), 62.5), "{e:?}");
+    }
+
+    #[test]
+    fn test_farnsworth_spacing() {
+        // 文字は 20 WPM、文字間と語間を広げて全体で 10 WPM
+        let mut est = SpeedEstimator::new();
+        let fist = Fist {
+            letter: 3 * 60 + 465,
+            word: 7 * 60 + 1085,
+            ..Fist::standard(20)
+        };
+        fist.send(&mut est, PARIS);
+        let e = est.estimate().unwrap();
+        assert!(near(e.wpm, 20.0), "{e:?}");
+        assert!(near(e.farnsworth_wpm, 10.0), "{e:?}");
+    }
+
+    #[test]
+    fn test_one_kind_of_element() {
+        let fist = Fist::standard(15);
+        // T と O だけでも、符号間のスペースから長点と分かる。押しっぱなしは数えない
+        let mut est = SpeedEstimator::new();
+        fist.send(&mut est, "- --- --- - ---/");
+        est.mark(5000);
+        let e = est.estimate().unwrap();
+        assert!(near(e.wpm, 15.0), "{e:?}");
+        assert_eq!(e.dah_ratio, None);
+        // E と S だけなら短点
+        let mut est = SpeedEstimator::new();
+        fist.send(&mut est, ". ... ... .");
+        let e = est.estimate().unwrap();
+        assert!(near(e.wpm, 15.0), "{e:?}");
+        assert!(near(e.weight.unwrap(), 50.0), "{e:?}");
+    }
+}
@@ -0,0 +1,225 @@
+use crate::wktime::Tick;
+
+// 直近これだけのやりとりから推定する（5 秒ごとの Ping で 80 秒分）
+const WINDOW: usize = 16;
+// 最小の遅延よりこれ以上遅いサンプルは片道の偏りが大きいので使わない (ms)
+const DELAY_TOLERANCE: u32 = 4;
+// これより長い往復はサンプルにしない (ms)
+const MAX_ROUND_TRIP: u32 = 5000;
+// 傾きを出すのに要るサンプルの時間幅 (ms)
+const MIN_SPAN: i32 = 10_000;
+// 水晶の誤差でありえない傾きは頭打ちにする
+const MAX_SKEW: f64 = 500e-6;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Sample {
+    // 往復の中間のこちらの時刻
+    at: Tick,
+    // Ping の到着時刻 - 送信時刻。片道の遅延を含んだオフセット
+    lead: u32,
+    // 往復から相手の処理時間を除いたもの
+    delay: u32,
+}
+
+impl Sample {
+    // base からのオフセットの差 (ms)
+    fn offset_from(&self, base: &Sample) -> f64 {
+        self.lead.wrapping_sub(base.lead) as i32 as f64
+            - (self.delay as f64 - base.delay as f64) / 2.0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Estimate {
+    // 最小遅延のサンプル。オフセットはここからの直線で表す
+    base: Sample,
+    // base の時刻でのオフセットの補正 (ms)
+    intercept: f64,
+    skew: f64,
+}
+
+/// Offset and skew of the peer's clock from four-timestamp Ping/Pong
+/// exchanges, as in NTP.
+///
+/// Each exchange gives the offset to within half its round trip, so only
+/// the exchanges whose delay is close to the shortest one in the window are
+/// kept, and a line fitted through them follows the drift between the two
+/// crystals.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClockSync {
+    samples: [Sample; WINDOW],
+    len: usize,
+    next: usize,
+    estimate: Option<Estimate>,
+}
+
+impl ClockSync {
+    pub const fn new() -> Self {
+        Self {
+            samples: [Sample {
+                at: Tick::ZERO,
+                lead: 0,
+                delay: 0,
+            }; WINDOW],
+            len: 0,
+            next: 0,
+            estimate: None,
+        }
+    }
+
+    /// Account for one exchange: `origin` when the Ping left and `arrived`
+    /// when its Pong came back on our clock, `received` and `sent` the
+    /// peer's clock when it got the Ping and answered it. Returns false if
+    /// the exchange is implausible and was dropped.
+    pub fn update(&mut self, origin: Tick, received: Tick, sent: Tick, arrived: Tick) -> bool {
+        let round_trip = arrived.delta(origin);
+        let turnaround = sent.delta(received);
+        if !(0..=MAX_ROUND_TRIP as i32).contains(&round_trip)
+            || !(0..=round_trip).contains(&turnaround)
+        {
+            return false;
+        }
+        self.samples[self.next] = Sample {
+            at: origin + round_trip as u32 / 2,
+            lead: received.as_ms().wrapping_sub(origin.as_ms()),
+            delay: (round_trip - turnaround) as u32,
+        };
+        self.next = (self.next + 1) % WINDOW;
+        self.len = (self.len + 1).min(WINDOW);
+        self.estimate = Some(self.fit());
+        true
+    }
+
+    // 遅延の小さいサンプルに最小二乗で直線を引く
+    fn fit(&self) -> Estimate {
+        let samples = &self.samples[..self.len];
+        let base = *samples.iter().min_by_key(|s| s.delay).unwrap();
+        let good = || {
+            samples
+                .iter()
+                .filter(move |s| s.delay <= base.delay + DELAY_TOLERANCE)
+                .map(move |s| (s.at.delta(base.at) as f64, s.offset_from(&base)))
+        };
+        let n = good().count() as f64;
+        let (sx, sy) = good().fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+        let (mx, my) = (sx / n, sy / n);
+        let (sxx, sxy) = good().fold((0.0, 0.0), |(sxx, sxy), (x, y)| {
+            (sxx + (x - mx) * (x - mx), sxy + (x - mx) * (y - my))
+        });
+        // base は x = 0 なので、両端は 0 から探せばよい
+        let (first, last) = good().fold((0.0, 0.0), |(lo, hi), (x, _)| (x.min(lo), x.max(hi)));
+        let skew = if last - first >= MIN_SPAN as f64 && sxx > 0.0 {
+            (sxy / sxx).clamp(-MAX_SKEW, MAX_SKEW)
+        } else {
+            0.0
+        };
+        Estimate {
+            base,
+            intercept: my - skew * mx,
+            skew,
+        }
+    }
+
+    /// Our time at which the peer's clock reads `rmt`, once an exchange has
+    /// been accounted for
+    pub fn to_local(&self, rmt: Tick) -> Option<Tick> {
+        let e = self.estimate?;
+        // rmt = local + lead - delay/2 + intercept + skew * (local - at) を local について解く
+        let from_base = (rmt - e.base.lead).delta(e.base.at) as f64;
+        let v = (from_base + e.base.delay as f64 / 2.0 - e.intercept) / (1.0 + e.skew);
+        let v = if v < 0.0 { v - 0.5 } else { v + 0.5 } as i32;
+        Some(Tick::from_ms(e.base.at.as_ms().wrapping_add(v as u32)))
+    }
+
+    /// Shortest round trip in the window without the peer's turnaround, in
+    /// milliseconds
+    pub fn delay(&self) -> Option<u32> {
+        self.estimate.map(|e| e.base.delay)
+    }
+
+    /// How much faster the peer's clock runs than ours, in parts per million
+    pub fn skew_ppm(&self) -> f64 {
+        self.estimate.map_or(0.0, |e| e.skew * 1e6)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 相手の時計は local * (1 + skew) + offset。exchange の片道は out / back ms
+    struct Link {
+        offset: u32,
+        skew: f64,
+    }
+
+    impl Link {
+        fn remote(&self, local: Tick) -> Tick {
+            let drift = (local.as_ms() as f64 * self.skew) as i64 as u32;
+            Tick::from_ms(local.as_ms().wrapping_add(self.offset).wrapping_add(drift))
+        }
+
+        fn exchange(&self, sync: &mut ClockSync, origin: Tick, out: u32, back: u32) -> bool {
+            let received = self.remote(origin + out);
+            let sent = self.remote(origin + out + 2);
+            sync.update(origin, received, sent, origin + out + 2 + back)
+        }
+    }
+
+    #[test]
+    fn test_offset_with_symmetric_delay() {
+        let link = Link {
+            offset: 0x9000_0000,
+            skew: 0.0,
+        };
+        let mut sync = ClockSync::new();
+        assert_eq!(sync.to_local(Tick::ZERO), None);
+        let origin = Tick::from_ms(u32::MAX - 3000);
+        assert!(link.exchange(&mut sync, origin, 10, 10));
+        assert_eq!(sync.delay(), Some(20));
+        // 相手の時計で 5 秒後はこちらでも 5 秒後
+        let t = origin + 5000;
+        assert_eq!(sync.to_local(link.remote(t)), Some(t));
+    }
+
+    #[test]
+    fn test_min_delay_filter() {
+        let link = Link {
+            offset: 12_345,
+            skew: 0.0,
+        };
+        let mut sync = ClockSync::new();
+        let mut origin = Tick::from_ms(1000);
+        // 片道だけ遅れるやりとりはオフセットを半分ずらすので使わない
+        for i in 0..12 {
+            let back = if i % 3 == 0 { 5 } else { 5 + 40 * i };
+            assert!(link.exchange(&mut sync, origin, 5, back));
+            origin += 5000;
+        }
+        assert_eq!(sync.delay(), Some(10));
+        assert_eq!(sync.to_local(link.remote(origin)), Some(origin));
+        // 往復が長すぎるもの、相手の時刻が逆行しているものは捨てる
+        assert!(!link.exchange(&mut sync, origin, 3000, 3000));
+        assert!(!sync.update(origin, Tick::from_ms(10), Tick::from_ms(5), origin + 20));
+    }
+
+    #[test]
+    fn test_skew_follows_drift() {
+        // 相手の水晶が 40 ppm 速い
+        let link = Link {
+            offset: 777,
+            skew: 40e-6,
+        };
+        let mut sync = ClockSync::new();
+        let mut origin = Tick::from_ms(100_000);
+        for _ in 0..WINDOW {
+            assert!(link.exchange(&mut sync, origin, 8, 8));
+            origin += 5000;
+        }
+        assert!((sync.skew_ppm() - 40.0).abs() < 5.0, "{}", sync.skew_ppm());
+        // 1 分先でも 1 ms 以内に合う
+        let t = origin + 60_000;
+        let local = sync.to_local(link.remote(t)).unwrap();
+        assert!(local.delta(t).abs() <= 1, "{} {}", local, t);
+    }
+}
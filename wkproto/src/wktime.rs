@@ -0,0 +1,139 @@
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub};
+
+/// A point on a wrapping millisecond clock such as `tick_count()`.
+///
+/// The clock is a `u32` and wraps about every 49.7 days, so ticks are
+/// compared by their wrapping difference: of two ticks less than ~24.8 days
+/// apart, the one the other has to count up to is the earlier. Plain `<` or
+/// `-` on the raw value goes wrong at the wrap, so `Tick` offers neither.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Tick(u32);
+
+impl Tick {
+    pub const ZERO: Tick = Tick(0);
+
+    pub const fn from_ms(ms: u32) -> Self {
+        Tick(ms)
+    }
+
+    /// Raw value as put on the wire
+    pub const fn as_ms(self) -> u32 {
+        self.0
+    }
+
+    /// Signed milliseconds from `earlier` to `self`; negative if `self` comes first.
+    pub const fn delta(self, earlier: Tick) -> i32 {
+        self.0.wrapping_sub(earlier.0) as i32
+    }
+
+    /// Milliseconds from `earlier` to `self`, or 0 if `self` comes first.
+    pub const fn since(self, earlier: Tick) -> u32 {
+        let d = self.delta(earlier);
+        if d < 0 {
+            0
+        } else {
+            d as u32
+        }
+    }
+
+    pub const fn is_before(self, other: Tick) -> bool {
+        self.delta(other) < 0
+    }
+
+    pub const fn is_after(self, other: Tick) -> bool {
+        self.delta(other) > 0
+    }
+
+    /// The later of the two
+    pub const fn max(self, other: Tick) -> Tick {
+        if self.is_before(other) {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl Add<u32> for Tick {
+    type Output = Tick;
+
+    fn add(self, ms: u32) -> Tick {
+        Tick(self.0.wrapping_add(ms))
+    }
+}
+
+impl AddAssign<u32> for Tick {
+    fn add_assign(&mut self, ms: u32) {
+        *self = *self + ms;
+    }
+}
+
+impl Sub<u32> for Tick {
+    type Output = Tick;
+
+    fn sub(self, ms: u32) -> Tick {
+        Tick(self.0.wrapping_sub(ms))
+    }
+}
+
+impl fmt::Display for Tick {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Source of time for sessions and keyers.
+///
+/// wksocket's `SystemClock` is `tick_us()` and `sleep()`. Its `SimClock` only
+/// moves when a test tells it to, so timeouts and key timing can be stepped
+/// through without waiting for them. Bare-metal targets implement it on
+/// their own timer.
+pub trait Clock: Send + Sync {
+    /// Microseconds since an arbitrary start, like `tick_us()`
+    fn now_us(&self) -> u64;
+
+    /// Block the calling thread for `ms` milliseconds of this clock
+    fn sleep(&self, ms: u32);
+
+    /// Block the calling thread until `now_us()` reaches `deadline_us`.
+    /// Clocks that can wait finer than a millisecond override this.
+    fn sleep_until_us(&self, deadline_us: u64) {
+        let now = self.now_us();
+        if now < deadline_us {
+            self.sleep((deadline_us - now).div_ceil(1000) as u32);
+        }
+    }
+
+    fn now(&self) -> Tick {
+        Tick::from_ms((self.now_us() / 1000) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tick_orders_across_wrap() {
+        let before = Tick::from_ms(u32::MAX - 5);
+        let after = before + 10;
+        assert_eq!(after, Tick::from_ms(4));
+        assert_eq!(after.delta(before), 10);
+        assert_eq!(before.delta(after), -10);
+        assert_eq!(after.since(before), 10);
+        assert_eq!(before.since(after), 0);
+        assert!(before.is_before(after));
+        assert!(after.is_after(before));
+        assert_eq!(before.max(after), after);
+        assert_eq!(after - 10, before);
+    }
+
+    #[test]
+    fn test_tick_half_range() {
+        // 約 24.8 日を超えて離れると前後が入れ替わる
+        let t = Tick::from_ms(1000);
+        assert_eq!((t + i32::MAX as u32).delta(t), i32::MAX);
+        assert!((t + i32::MAX as u32 + 1).is_before(t));
+    }
+}
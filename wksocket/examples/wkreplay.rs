@@ -0,0 +1,76 @@
+//! Replay a session capture and print the key timing the server would have played.
+//!
+//! usage: wkreplay <capture> [--pcap <out.pcap>] [--percentile <n>]
+//!        [--min-delay <ms>] [--max-delay <ms>] [--late <policy>]
+//!
+//! The playout options are those of the server's cfg.toml and default the same.
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::BufWriter;
+use wksocket::{replay, write_pcap, CaptureReader, JitterBufferConfig};
+
+const USAGE: &str = "usage: wkreplay <capture> [--pcap <out.pcap>] [--percentile <n>] \
+                     [--min-delay <ms>] [--max-delay <ms>] [--late <policy>]";
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let Some(path) = args.next() else {
+        bail!(USAGE);
+    };
+    let mut pcap = None;
+    let mut buffer = JitterBufferConfig::default();
+    while let Some(flag) = args.next() {
+        let value = args.next().context(USAGE)?;
+        match flag.as_str() {
+            "--pcap" => pcap = Some(value),
+            "--percentile" => buffer.percentile = value.parse()?,
+            "--min-delay" => buffer.min_delay = value.parse()?,
+            "--max-delay" => buffer.max_delay = value.parse()?,
+            "--late" => {
+                buffer.late = value
+                    .parse()
+                    .ok()
+                    .context("late policy is stretch, drop or keep_length")?
+            }
+            _ => bail!(USAGE),
+        }
+    }
+
+    let reader = CaptureReader::open(&path)?;
+    let started = reader.started();
+    let clock = reader.clock();
+    let records = reader.collect::<Result<Vec<_>>>()?;
+
+    if let Some(out) = pcap {
+        write_pcap(&records, started, BufWriter::new(File::create(&out)?))?;
+        println!("{} records written to {out}", records.len());
+    }
+
+    let convs: BTreeSet<u32> = records.iter().map(|r| r.conv).collect();
+    for conv in convs {
+        let keys = replay(&records, conv, clock, buffer);
+        if keys.is_empty() {
+            continue;
+        }
+        println!("session {conv}: {} edges", keys.len());
+        println!(
+            "{:>10} {:>10} {:>6} {:>10} {:>6}",
+            "arrived", "played", "key", "remote", "delay"
+        );
+        for key in keys {
+            // 届く予定から鳴るまで。プレイアウト遅延と、遅れて届いたぶん
+            let delay = key.played.saturating_sub(key.due);
+            println!(
+                "{:>10.3} {:>10.3} {:>6} {:>10} {:>6}",
+                key.arrived.as_secs_f64(),
+                key.played.as_secs_f64(),
+                if key.down { "down" } else { "up" },
+                key.remote,
+                delay.as_millis()
+            );
+        }
+    }
+    Ok(())
+}
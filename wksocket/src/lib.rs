@@ -1,11 +1,54 @@
 pub use self::{
-    wkmessage::{MessageRCV, MessageSND, WkReceiver, WkSender, MAX_SLOTS},
-    wksession::{challenge, response, WkListener, WkSession, PKT_SIZE},
-    wkutil::{sleep, tick_count},
+    wkauth::{challenge, response},
+    wkcapture::{
+        replay, write_pcap, Capture, CaptureKind, CaptureReader, CaptureRecord, ReplayedKey,
+    },
+    wkconfig::WkSessionConfig,
+    wkcore::{Transmit, WkSessionCore},
+    wkerror::{WkError, WkResult},
+    wkhello::Hello,
+    wkkeyer::{KeyOutput, KeyPlayer, KeyerHooks, KeyerStats},
+    wkmessage::{MessageSND, WkReceiver, WkSender},
+    wksched::{EdgeErrorHistogram, EDGE_ERROR_BOUNDS_US},
+    wksession::{WkListener, WkSession, MAX_SESSIONS},
+    wkstats::WkSessionStats,
+    wktime::{SharedClock, SimClock, SystemClock},
+    wkutil::{sleep, sleep_until_us, tick_count, tick_us},
 };
+// スレッドも I/O も使わない部分は wkproto にある
+pub use wkproto::{
+    AuthVerifier, Capabilities, Clock, ClockSync, CloseReason, DecodeError, Jitter,
+    JitterBufferConfig, LatePolicy, MessageRCV, Playout, SpeedEstimate, SpeedEstimator, Tick,
+    MAX_ASSERT_DURATION, MAX_SLOTS, MSPERWPM, PBKDF2_ITERATIONS, PKT_SIZE, PROTOCOL_VERSION,
+};
+
+#[cfg(feature = "tokio")]
+pub use self::wkasync::{AsyncWkListener, AsyncWkSession};
+#[cfg(feature = "impair")]
+pub use self::wkimpair::{ImpairConfig, ImpairProxy, ImpairStats, Latency};
 
+#[cfg(feature = "tokio")]
+mod wkasync;
+mod wkauth;
+mod wkcapture;
+mod wkconfig;
+mod wkcontrol;
+mod wkcore;
+mod wkcrypto;
+mod wkdatagram;
+mod wkerror;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod wkfuzz;
+mod wkhello;
+#[cfg(feature = "impair")]
+mod wkimpair;
+mod wkkeyer;
 mod wkmessage;
+mod wksched;
 mod wksession;
+mod wkstats;
+mod wktime;
 mod wkutil;
 
 /// mDNS service type for WiFiKey2 server discovery (mdns-sd crate format)
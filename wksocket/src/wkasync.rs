@@ -0,0 +1,501 @@
+use crate::wkauth::{challenge_on, response_on, AuthChannel};
+use crate::wkconfig::WkSessionConfig;
+use crate::wkcore::{Dispatch, ListenerSession, SessionTable, WkSessionCore};
+use crate::wkcrypto::{ResumeTicket, SessionCipher, CLOSE_TAG_LEN, RESUME_TAG_LEN};
+use crate::wkerror::{WkError, WkResult};
+use crate::wkhello::Hello;
+use crate::wksession::MAX_SESSIONS;
+use crate::wkstats::WkSessionStats;
+use anyhow::Result;
+use log::{info, trace};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use wkproto::{AuthVerifier, Capabilities, CloseReason, Tick};
+
+struct Shared {
+    core: Mutex<WkSessionCore>,
+    udp: Arc<UdpSocket>,
+    config: WkSessionConfig,
+    // データ到着・クローズを recv に知らせる
+    readable: Notify,
+    // 送信やクローズでタイマーの期限が変わったことをドライバに知らせる
+    wakeup: Notify,
+}
+
+impl Shared {
+    /// Run `f` on the core and send whatever it queued.
+    fn with_core<R>(&self, f: impl FnOnce(&mut WkSessionCore, Tick) -> R) -> WkResult<R> {
+        let mut core = self.core.lock().map_err(|_| WkError::Poisoned)?;
+        let res = f(&mut core, self.config.clock.now());
+        while let Some(t) = core.poll_transmit() {
+            // UDP の送信はほぼ待たされない。送れなければ KCP の再送に任せる
+            if let Err(e) = self.udp.try_send_to(&t.data, t.peer) {
+                log::warn!("UDP send error to {}: {}", t.peer, e);
+            }
+        }
+        Ok(res)
+    }
+
+    fn send(&self, buf: &[u8]) -> WkResult<usize> {
+        let n = self.with_core(|core, now| core.send(buf, now))??;
+        self.wakeup.notify_one();
+        Ok(n)
+    }
+
+    fn closed(&self) -> bool {
+        self.core.lock().map(|core| core.closed()).unwrap_or(true)
+    }
+
+    fn handle_timeout(&self) -> Option<u32> {
+        self.with_core(|core, now| {
+            if let Err(e) = core.handle_timeout(now) {
+                info!("kcp update failed. {e}");
+            }
+            (!core.closed()).then(|| core.next_timeout(now))
+        })
+        .ok()
+        .flatten()
+    }
+
+    fn close(&self, reason: CloseReason) {
+        let _ = self.with_core(|core, _| core.close_with(reason));
+        self.wakeup.notify_one();
+        self.readable.notify_waiters();
+    }
+
+    fn closed_by_peer(&self, reason: CloseReason, tag: Option<&[u8; CLOSE_TAG_LEN]>) -> bool {
+        let closed = self
+            .with_core(|core, _| core.closed_by_peer(reason, tag))
+            .unwrap_or(false);
+        if closed {
+            self.wakeup.notify_one();
+            self.readable.notify_waiters();
+        }
+        closed
+    }
+}
+
+// 認証は同期処理なので spawn_blocking のスレッドから呼ばれる
+impl AuthChannel for Shared {
+    fn send(&self, buf: &[u8]) -> WkResult<usize> {
+        Shared::send(self, buf)
+    }
+
+    fn recv_timeout(&self, buf: &mut [u8], timeout: u32) -> WkResult<usize> {
+        let clock = &self.config.clock;
+        let start = clock.now();
+        while clock.now().since(start) < timeout {
+            let n = self.with_core(|core, now| core.recv(buf, now))??;
+            if n > 0 {
+                return Ok(n);
+            }
+            clock.sleep(1);
+        }
+        Err(WkError::Timeout)
+    }
+
+    fn set_cipher(&self, cipher: SessionCipher) -> WkResult<()> {
+        self.with_core(|core, _| core.set_cipher(cipher))
+    }
+
+    fn set_resume(&self, ticket: ResumeTicket) -> WkResult<()> {
+        self.with_core(|core, _| core.set_resume(ticket))
+    }
+
+    fn set_peer_hello(&self, hello: Hello) -> WkResult<()> {
+        self.with_core(|core, _| core.set_peer_hello(hello))
+    }
+
+    fn config(&self) -> &WkSessionConfig {
+        &self.config
+    }
+}
+
+impl ListenerSession for Arc<Shared> {
+    fn closed(&self) -> bool {
+        Shared::closed(self)
+    }
+
+    fn handle_timeout(&self) -> Option<u32> {
+        Shared::handle_timeout(self)
+    }
+
+    fn input(&self, pkt: &[u8]) -> Result<()> {
+        self.with_core(|core, now| core.input(pkt, now))??;
+        self.readable.notify_waiters();
+        Ok(())
+    }
+
+    fn close(&self, reason: CloseReason) {
+        Shared::close(self, reason)
+    }
+
+    fn closed_by_peer(&self, reason: CloseReason, tag: Option<&[u8; CLOSE_TAG_LEN]>) -> bool {
+        Shared::closed_by_peer(self, reason, tag)
+    }
+
+    fn migrate(
+        &self,
+        peer: SocketAddr,
+        ticket: u32,
+        counter: u64,
+        tag: &[u8; RESUME_TAG_LEN],
+    ) -> bool {
+        self.with_core(|core, now| core.migrate(peer, ticket, counter, tag, now))
+            .unwrap_or(false)
+    }
+}
+
+/// Run KCP timers and, for clients, read the socket until the session closes.
+async fn drive(shared: Arc<Shared>, reader: bool) {
+    let mut buf = vec![0u8; shared.config.mtu];
+    while let Some(delay) = shared.handle_timeout() {
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(delay as u64)) => {}
+            _ = shared.wakeup.notified() => {}
+            res = shared.udp.recv_from(&mut buf), if reader => match res {
+                Ok((n, src)) => {
+                    let pkt = &mut buf[..n];
+                    let res = shared.with_core(|core, now| core.handle_datagram(src, pkt, now));
+                    if let Ok(Err(e)) = res {
+                        trace!("conv. id inconsistent. {e}");
+                    }
+                    shared.readable.notify_waiters();
+                }
+                Err(e) => trace!("connect: recv error {e}"),
+            },
+        }
+    }
+    info!("session closed. stop driver task.");
+    shared.readable.notify_waiters();
+}
+
+/// `WkSession` on a tokio `UdpSocket`.
+///
+/// A single task per session runs the KCP timers (and reads the socket on the
+/// client side) instead of the dedicated threads of the blocking API. Must be
+/// created inside a tokio runtime. Dropping it closes the session.
+pub struct AsyncWkSession {
+    shared: Arc<Shared>,
+}
+
+impl Drop for AsyncWkSession {
+    fn drop(&mut self) {
+        info!("session dropped. stop driver task.");
+        self.shared.close(CloseReason::UserQuit);
+    }
+}
+
+impl AsyncWkSession {
+    fn spawn(
+        udp: Arc<UdpSocket>,
+        peer: SocketAddr,
+        config: WkSessionConfig,
+        reader: bool,
+    ) -> WkResult<Self> {
+        let core = WkSessionCore::new(&config, peer, config.clock.now())?;
+        let shared = Arc::new(Shared {
+            core: Mutex::new(core),
+            udp,
+            config,
+            readable: Notify::new(),
+            wakeup: Notify::new(),
+        });
+        tokio::spawn(drive(shared.clone(), reader));
+        Ok(Self { shared })
+    }
+
+    pub fn connect(peer: SocketAddr, udp: UdpSocket) -> WkResult<Self> {
+        AsyncWkSession::connect_with_config(peer, udp, WkSessionConfig::default())
+    }
+
+    pub fn connect_with_config(
+        peer: SocketAddr,
+        udp: UdpSocket,
+        config: WkSessionConfig,
+    ) -> WkResult<Self> {
+        AsyncWkSession::spawn(Arc::new(udp), peer, config, true)
+    }
+
+    pub fn send(&self, buf: &[u8]) -> WkResult<usize> {
+        self.shared.send(buf)
+    }
+
+    /// Wait for the next message. Fails once the session is closed.
+    pub async fn recv(&self, buf: &mut [u8]) -> WkResult<usize> {
+        loop {
+            // 通知の取りこぼしを防ぐため、recv を試す前に待ち受けを登録する
+            let notified = self.shared.readable.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let n = self.shared.with_core(|core, now| core.recv(buf, now))??;
+            if n > 0 {
+                return Ok(n);
+            }
+            notified.await;
+        }
+    }
+
+    /// Send on the unreliable channel, see `WkSession::send_datagram`.
+    pub fn send_datagram(&self, buf: &[u8]) -> WkResult<usize> {
+        let n = self
+            .shared
+            .with_core(|core, now| core.send_datagram(buf, now))??;
+        self.shared.wakeup.notify_one();
+        Ok(n)
+    }
+
+    /// Wait for the next payload from the unreliable channel.
+    pub async fn recv_datagram(&self, buf: &mut [u8]) -> WkResult<usize> {
+        loop {
+            let notified = self.shared.readable.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let n = self.shared.with_core(|core, _| core.recv_datagram(buf))??;
+            if n > 0 {
+                return Ok(n);
+            }
+            notified.await;
+        }
+    }
+
+    pub async fn recv_timeout(&self, buf: &mut [u8], timeout: Duration) -> WkResult<usize> {
+        tokio::time::timeout(timeout, self.recv(buf))
+            .await
+            .map_err(|_| WkError::Timeout)?
+    }
+
+    /// Client side of the authentication handshake, see `response`.
+    pub async fn response(&self, passwd: &str, allow_legacy: bool) -> WkResult<u32> {
+        let shared = self.shared.clone();
+        let passwd = passwd.to_string();
+        tokio::task::spawn_blocking(move || response_on(&*shared, &passwd, allow_legacy))
+            .await
+            .map_err(|_| WkError::Poisoned)?
+    }
+
+    /// Server side of the authentication handshake, see `challenge`.
+    pub async fn challenge(
+        &self,
+        verifier: &AuthVerifier,
+        legacy_passwd: Option<&str>,
+    ) -> WkResult<u32> {
+        let shared = self.shared.clone();
+        let verifier = verifier.clone();
+        let legacy_passwd = legacy_passwd.map(str::to_string);
+        tokio::task::spawn_blocking(move || {
+            challenge_on(&*shared, &verifier, legacy_passwd.as_deref())
+        })
+        .await
+        .map_err(|_| WkError::Poisoned)?
+    }
+
+    /// Tell the server our address has changed, e.g. after switching networks.
+    pub fn request_resume(&self) -> WkResult<()> {
+        Ok(self.shared.with_core(|core, _| core.request_resume())??)
+    }
+
+    pub fn close(&self) {
+        self.shared.close(CloseReason::UserQuit);
+    }
+
+    /// Close the session and tell the peer why, see `WkSession::close_with`.
+    pub fn close_with(&self, reason: CloseReason) {
+        self.shared.close(reason);
+    }
+
+    pub fn closed(&self) -> bool {
+        self.shared.closed()
+    }
+
+    /// Why the session closed, locally or by the peer. None while it is open.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.shared
+            .with_core(|core, _| core.close_reason())
+            .ok()
+            .flatten()
+    }
+
+    /// True once the session has switched to ChaCha20-Poly1305 sealed payloads
+    pub fn encrypted(&self) -> bool {
+        self.shared
+            .with_core(|core, _| core.encrypted())
+            .unwrap_or(false)
+    }
+
+    pub fn peer(&self) -> WkResult<SocketAddr> {
+        self.shared.with_core(|core, _| core.peer())
+    }
+
+    /// What the peer announced after authentication, see `WkSession::peer_hello`.
+    pub fn peer_hello(&self) -> Option<Hello> {
+        self.shared
+            .with_core(|core, _| core.peer_hello().cloned())
+            .ok()
+            .flatten()
+    }
+
+    /// Message kinds the peer understands, `Capabilities::BASELINE` if it sent no hello
+    pub fn peer_capabilities(&self) -> Capabilities {
+        self.peer_hello()
+            .map_or(Capabilities::BASELINE, |hello| hello.capabilities)
+    }
+
+    pub fn conv(&self) -> WkResult<u32> {
+        self.shared.with_core(|core, _| core.conv())
+    }
+
+    /// Snapshot of RTT, retransmissions, queue depths and traffic counters
+    pub fn stats(&self) -> WkResult<WkSessionStats> {
+        self.shared.with_core(|core, now| core.stats(now))
+    }
+
+    pub fn config(&self) -> &WkSessionConfig {
+        &self.shared.config
+    }
+}
+
+/// `WkListener` on a tokio `UdpSocket`.
+pub struct AsyncWkListener {
+    rx: mpsc::UnboundedReceiver<(AsyncWkSession, SocketAddr)>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for AsyncWkListener {
+    fn drop(&mut self) {
+        info!("listener dropped. stop task.");
+        self.task.abort();
+    }
+}
+
+impl AsyncWkListener {
+    pub fn bind(udp: UdpSocket) -> WkResult<Self> {
+        AsyncWkListener::bind_with_config(udp, MAX_SESSIONS, WkSessionConfig::default())
+    }
+
+    /// Bind with at most `max_sessions` sessions, each created with `config`.
+    pub fn bind_with_config(
+        udp: UdpSocket,
+        max_sessions: usize,
+        config: WkSessionConfig,
+    ) -> WkResult<Self> {
+        let udp = Arc::new(udp);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0u8; config.mtu];
+            let mut sessions = SessionTable::new(max_sessions);
+            loop {
+                let (n, peer) = match udp.recv_from(&mut buf).await {
+                    Ok(r) => r,
+                    Err(e) => {
+                        trace!("listen: recv error {e}");
+                        continue;
+                    }
+                };
+                sessions.evict_closed();
+                let pkt = &mut buf[..n];
+                let conv = match sessions.dispatch(peer, pkt) {
+                    Dispatch::Done => continue,
+                    Dispatch::Reply(reply) => {
+                        let _ = udp.send_to(&reply, peer).await;
+                        continue;
+                    }
+                    Dispatch::Accept(conv) => conv,
+                };
+                let session = match AsyncWkSession::spawn(udp.clone(), peer, config.clone(), false)
+                {
+                    Ok(s) => s,
+                    Err(e) => {
+                        info!("failed to create session: {e}");
+                        continue;
+                    }
+                };
+                let shared = session.shared.clone();
+                if let Err(e) = shared.input(pkt) {
+                    info!("failed to input packet: {e}");
+                    continue;
+                }
+                if tx.send((session, peer)).is_err() {
+                    info!("listener channel closed");
+                    break;
+                }
+                sessions.insert(peer, conv, shared);
+            }
+        });
+        Ok(AsyncWkListener { rx, task })
+    }
+
+    pub async fn accept(&mut self) -> WkResult<(AsyncWkSession, SocketAddr)> {
+        self.rx.recv().await.ok_or(WkError::ListenerClosed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wkproto::PROTOCOL_VERSION;
+
+    #[tokio::test]
+    async fn test_async_loopback_auth_and_message() {
+        let server_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server_udp.local_addr().unwrap();
+        let mut listener = AsyncWkListener::bind(server_udp).unwrap();
+
+        let client_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = AsyncWkSession::connect(server_addr, client_udp).unwrap();
+        let verifier = AuthVerifier::with_salt("passwd", [3u8; 16], 16);
+
+        let client_auth = async { client.response("passwd", false).await };
+        let server_auth = async {
+            let (session, _) = listener.accept().await.unwrap();
+            session.challenge(&verifier, None).await.map(|_| session)
+        };
+        let (client_res, server_res) = tokio::join!(client_auth, server_auth);
+        client_res.unwrap();
+        let server = server_res.unwrap();
+        assert!(client.encrypted() && server.encrypted());
+        let hello = server.peer_hello().unwrap();
+        assert_eq!(hello.protocol, PROTOCOL_VERSION);
+        assert_eq!(client.peer_capabilities(), Capabilities::BASELINE);
+
+        client.send(b"cq").unwrap();
+        let mut buf = [0u8; 16];
+        let n = server
+            .recv_timeout(&mut buf, Duration::from_secs(2))
+            .await
+            .unwrap();
+        assert_eq!(&buf[..n], b"cq");
+
+        client.send_datagram(b"edge").unwrap();
+        let n = tokio::time::timeout(Duration::from_secs(2), server.recv_datagram(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], b"edge");
+    }
+
+    #[tokio::test]
+    async fn test_async_wrong_password_is_auth_failed() {
+        let server_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server_udp.local_addr().unwrap();
+        let mut listener = AsyncWkListener::bind(server_udp).unwrap();
+
+        let client_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = AsyncWkSession::connect(server_addr, client_udp).unwrap();
+        let verifier = AuthVerifier::with_salt("passwd", [3u8; 16], 16);
+
+        let client_auth = async { client.response("guess", false).await };
+        let server_auth = async {
+            let (session, _) = listener.accept().await.unwrap();
+            session.challenge(&verifier, None).await
+        };
+        let (client_res, server_res) = tokio::join!(client_auth, server_auth);
+        // サーバーは確認値の不一致、クライアントは 0 の結果で失敗を知る
+        assert!(matches!(server_res, Err(WkError::AuthFailed)));
+        assert!(matches!(client_res, Err(WkError::AuthFailed)));
+    }
+}
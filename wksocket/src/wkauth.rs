@@ -0,0 +1,294 @@
+use crate::wkconfig::WkSessionConfig;
+use crate::wkcrypto::{ResumeTicket, SessionCipher};
+use crate::wkerror::{WkError, WkResult};
+use crate::wkhello::{self, Hello, HELLO_OFFER};
+use crate::wksession::WkSession;
+use bytes::{Buf, BufMut, BytesMut};
+use log::{info, warn};
+use rand::random;
+use std::io::Cursor;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use wkproto::{
+    legacy_digest, mac, verify_mac, AuthVerifier, ClientPake, MAC_LEN, MAX_PBKDF2_ITERATIONS,
+    PKT_SIZE, POINT_LEN, SALT_LEN,
+};
+
+/// 認証要求の先頭バイト（PAKE）。旧クライアントは 0x00 の 1 バイトを送る。
+const AUTH_MAGIC: u8 = 0x57;
+const AUTH_VERSION: u8 = 1;
+
+/// What the handshake needs from a session, so it runs over blocking and async sessions.
+pub(crate) trait AuthChannel {
+    fn send(&self, buf: &[u8]) -> WkResult<usize>;
+    fn recv_timeout(&self, buf: &mut [u8], timeout: u32) -> WkResult<usize>;
+    fn set_cipher(&self, cipher: SessionCipher) -> WkResult<()>;
+    fn set_resume(&self, ticket: ResumeTicket) -> WkResult<()>;
+    fn set_peer_hello(&self, hello: Hello) -> WkResult<()>;
+    fn config(&self) -> &WkSessionConfig;
+}
+
+impl AuthChannel for WkSession {
+    fn send(&self, buf: &[u8]) -> WkResult<usize> {
+        WkSession::send(self, buf)
+    }
+
+    fn recv_timeout(&self, buf: &mut [u8], timeout: u32) -> WkResult<usize> {
+        WkSession::recv_timeout(self, buf, timeout)
+    }
+
+    fn set_cipher(&self, cipher: SessionCipher) -> WkResult<()> {
+        WkSession::set_cipher(self, cipher)
+    }
+
+    fn set_resume(&self, ticket: ResumeTicket) -> WkResult<()> {
+        WkSession::set_resume(self, ticket)
+    }
+
+    fn set_peer_hello(&self, hello: Hello) -> WkResult<()> {
+        WkSession::set_peer_hello(self, hello)
+    }
+
+    fn config(&self) -> &WkSessionConfig {
+        WkSession::config(self)
+    }
+}
+
+fn auth_timeout(session: &impl AuthChannel) -> u32 {
+    session.config().auth_timeout.as_millis() as u32
+}
+
+fn new_ticket() -> u32 {
+    random::<u32>().max(1)
+}
+
+/// Client side of the authentication handshake.
+///
+/// Runs SPAKE2+ and verifies the server's key confirmation before proving
+/// knowledge of the password; on success the session switches to encrypted
+/// payloads and both sides exchange a `Hello`. If the server only speaks the old MD5 challenge and
+/// `allow_legacy` is set, falls back to it and the session stays in cleartext.
+pub fn response(session: Arc<WkSession>, passwd: &str, allow_legacy: bool) -> WkResult<u32> {
+    response_on(&*session, passwd, allow_legacy)
+}
+
+pub(crate) fn response_on(
+    session: &impl AuthChannel,
+    passwd: &str,
+    allow_legacy: bool,
+) -> WkResult<u32> {
+    let mut buf = [0u8; PKT_SIZE];
+
+    // Request challenge from server
+    session.send(&[AUTH_MAGIC, AUTH_VERSION, HELLO_OFFER])?;
+
+    let n = session.recv_timeout(&mut buf, auth_timeout(session))?;
+
+    match &buf[..n] {
+        [AUTH_MAGIC, AUTH_VERSION, params @ ..] => response_pake(session, passwd, params),
+        [a, b, c, d] if allow_legacy => {
+            warn!("server does not support PAKE, falling back to legacy auth");
+            response_legacy(session, passwd, u32::from_be_bytes([*a, *b, *c, *d]))
+        }
+        [_, _, _, _] => Err(WkError::protocol(
+            "server requires legacy auth, which is disabled",
+        )),
+        _ => Err(WkError::protocol("malformed auth reply")),
+    }
+}
+
+fn response_pake(session: &impl AuthChannel, passwd: &str, params: &[u8]) -> WkResult<u32> {
+    if params.len() < 4 + SALT_LEN {
+        return Err(WkError::protocol("malformed auth parameters"));
+    }
+    let mut rcvbuf = Cursor::new(params);
+    let iterations = rcvbuf.get_u32();
+    if iterations == 0 || iterations > MAX_PBKDF2_ITERATIONS {
+        return Err(WkError::Protocol(format!(
+            "unacceptable PBKDF2 iterations {iterations}"
+        )));
+    }
+    let salt = &params[4..4 + SALT_LEN];
+    let pake = ClientPake::new(passwd, salt, iterations, &mut rand::rng());
+    let share_x = pake.share();
+    session.send(&share_x)?;
+
+    let mut buf = [0u8; PKT_SIZE];
+    let n = session.recv_timeout(&mut buf, auth_timeout(session))?;
+    if n != POINT_LEN + MAC_LEN {
+        return Err(WkError::protocol("malformed auth share"));
+    }
+    let keys = pake.finish(&buf[..POINT_LEN])?;
+
+    // サーバーが verifier を持っていることを確認してから自分の確認値を送る
+    if !verify_mac(&keys.confirm_server, &share_x, &buf[POINT_LEN..n]) {
+        warn!("server authentication failed");
+        // 確認値の代わりに 0 を送り、サーバーをタイムアウトまで待たせず失敗させる
+        let _ = session.send(&[0u8; MAC_LEN]);
+        return Err(WkError::AuthFailed);
+    }
+    session.send(&mac(&keys.confirm_client, &buf[..POINT_LEN]))?;
+
+    let n = session.recv_timeout(&mut buf, auth_timeout(session))?;
+    if n < 4 {
+        return Err(WkError::protocol("malformed auth result"));
+    }
+    let mut rcvbuf = Cursor::new(&buf[..n]);
+    let res = rcvbuf.get_u32();
+    if res == 0 {
+        Err(WkError::AuthFailed)
+    } else {
+        // 以降のペイロードはすべて共有鍵で暗号化する
+        session.set_cipher(SessionCipher::client(&keys.shared))?;
+        // res はアドレスが変わったときにセッションを再開するチケットになる
+        session.set_resume(ResumeTicket::new(res, &keys.shared))?;
+        // hello に対応していないサーバーは結果の後ろに何も付けない
+        if rcvbuf.remaining() > 0 && rcvbuf.get_u8() == HELLO_OFFER {
+            wkhello::exchange(session)?;
+        }
+        Ok(res)
+    }
+}
+
+fn response_legacy(session: &impl AuthChannel, passwd: &str, salt: u32) -> WkResult<u32> {
+    let mut buf = [0u8; PKT_SIZE];
+    buf[..16].copy_from_slice(&legacy_digest(&format!("{passwd}{salt}")));
+    session.send(&buf)?;
+
+    session.recv_timeout(&mut buf, auth_timeout(session))?;
+    let mut rcvbuf = Cursor::new(buf);
+    let res = rcvbuf.get_u32();
+    if res == 0 {
+        Err(WkError::AuthFailed)
+    } else {
+        Ok(res)
+    }
+}
+
+/// Server side of the authentication handshake.
+///
+/// Clients speaking SPAKE2+ are checked against `verifier` and the session
+/// is encrypted afterwards; clients that offer it get a `Hello` exchange
+/// on the encrypted session. The old MD5 challenge is only answered when
+/// `legacy_passwd` is given, and such sessions stay in cleartext.
+pub fn challenge(
+    session: Arc<WkSession>,
+    verifier: &AuthVerifier,
+    legacy_passwd: Option<&str>,
+) -> WkResult<u32> {
+    challenge_on(&*session, verifier, legacy_passwd)
+}
+
+pub(crate) fn challenge_on(
+    session: &impl AuthChannel,
+    verifier: &AuthVerifier,
+    legacy_passwd: Option<&str>,
+) -> WkResult<u32> {
+    let mut buf = [0u8; PKT_SIZE];
+
+    // Wait for client to initiate authentication
+    let n = session
+        .recv_timeout(&mut buf, auth_timeout(session))
+        .inspect_err(|e| info!("auth challenge: no init request: {e}"))?;
+
+    match (&buf[..n], legacy_passwd) {
+        ([AUTH_MAGIC, AUTH_VERSION, rest @ ..], _) => {
+            challenge_pake(session, verifier, rest.first() == Some(&HELLO_OFFER))
+        }
+        ([0], Some(passwd)) => {
+            warn!("client uses legacy MD5 auth");
+            challenge_legacy(session, passwd)
+        }
+        ([0], None) => {
+            info!("legacy auth requested but not allowed");
+            Err(WkError::protocol("legacy auth disabled"))
+        }
+        _ => {
+            info!("unknown auth request {:?}", &buf[..n]);
+            Err(WkError::protocol("unknown auth request"))
+        }
+    }
+}
+
+fn challenge_pake(
+    session: &impl AuthChannel,
+    verifier: &AuthVerifier,
+    hello: bool,
+) -> WkResult<u32> {
+    let mut sendbuf = BytesMut::with_capacity(PKT_SIZE);
+    let mut buf = [0u8; PKT_SIZE];
+
+    sendbuf.put_u8(AUTH_MAGIC);
+    sendbuf.put_u8(AUTH_VERSION);
+    sendbuf.put_u32(verifier.iterations());
+    sendbuf.put_slice(verifier.salt());
+    session.send(&sendbuf)?;
+
+    // ESP32 では PBKDF2 に数百 ms かかるため、クライアントの共有値を待つ時間は長めに取る
+    let pake_timeout = session.config().auth_pake_timeout.as_millis() as u32;
+    let n = session
+        .recv_timeout(&mut buf, pake_timeout)
+        .inspect_err(|e| info!("auth challenge: no client share: {e}"))?;
+    let (share_y, keys) = verifier.respond(&buf[..n], &mut rand::rng())?;
+
+    sendbuf.clear();
+    sendbuf.put_slice(&share_y);
+    sendbuf.put_slice(&mac(&keys.confirm_server, &buf[..POINT_LEN]));
+    session.send(&sendbuf)?;
+
+    let n = session
+        .recv_timeout(&mut buf, auth_timeout(session))
+        .inspect_err(|e| info!("auth challenge: no client confirmation: {e}"))?;
+    let ok = verify_mac(&keys.confirm_client, &share_y, &buf[..n]);
+    let res = if ok { new_ticket() } else { 0u32 };
+    sendbuf.clear();
+    sendbuf.put_u32(res);
+    if ok && hello {
+        sendbuf.put_u8(HELLO_OFFER);
+    }
+    session.send(&sendbuf)?;
+
+    if ok {
+        // res は平文で送った。ここから先のペイロードは暗号化する
+        session.set_cipher(SessionCipher::server(&keys.shared))?;
+        session.set_resume(ResumeTicket::new(res, &keys.shared))?;
+        if hello {
+            wkhello::exchange(session)?;
+        }
+        info!("auth challenge success {res}");
+        Ok(res)
+    } else {
+        info!("auth challenge failed: key confirmation mismatch");
+        Err(WkError::AuthFailed)
+    }
+}
+
+fn challenge_legacy(session: &impl AuthChannel, passwd: &str) -> WkResult<u32> {
+    let mut sendbuf = BytesMut::with_capacity(PKT_SIZE);
+    let mut buf = [0u8; PKT_SIZE];
+
+    let chl = random();
+    sendbuf.put_u32(chl);
+    session.send(&sendbuf)?;
+
+    session
+        .recv_timeout(&mut buf, auth_timeout(session))
+        .inspect_err(|e| info!("auth challenge: no legacy response: {e}"))?;
+
+    let response = &buf[..16];
+    let challenge = legacy_digest(&format!("{passwd}{chl}"));
+    // Use constant-time comparison to prevent timing attacks
+    let ok = response.ct_eq(&challenge).into();
+    let res = if ok { new_ticket() } else { 0u32 };
+    sendbuf.clear();
+    sendbuf.put_u32(res);
+    session.send(&sendbuf)?;
+
+    if ok {
+        info!("auth challenge success {res}");
+        Ok(res)
+    } else {
+        info!("auth challenge failed {response:?} {challenge:?}");
+        Err(WkError::AuthFailed)
+    }
+}
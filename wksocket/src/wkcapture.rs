@@ -0,0 +1,566 @@
+use crate::wkutil::tick_us;
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use log::trace;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use wkproto::{
+    decode_message, JitterBuffer, JitterBufferConfig, MessageRCV, PacketKind, Playout, Tick,
+    CODEC_MAGIC,
+};
+
+const CAPTURE_MAGIC: &[u8; 5] = b"WKCAP";
+const CAPTURE_VERSION: u8 = 2;
+// magic(5) + version(1) + started(8) + clock(8)
+const CAPTURE_HEADER: usize = 22;
+// kind(1) + at(8) + conv(4) + family(1)
+const RECORD_HEADER: usize = 14;
+// pcap に書くときの自分側のアドレス（記録していないので仮の値）
+const PCAP_LOCAL_V4: Ipv4Addr = Ipv4Addr::UNSPECIFIED;
+const PCAP_LOCAL_V6: Ipv6Addr = Ipv6Addr::UNSPECIFIED;
+// LINKTYPE_RAW: IPv4 と IPv6 を混在できる
+const PCAP_LINKTYPE_RAW: u32 = 101;
+
+/// What a capture record holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureKind {
+    /// Raw datagram received from the peer
+    Inbound = 1,
+    /// Raw datagram sent to the peer
+    Outbound = 2,
+    /// Decrypted message handed to the application by `recv`
+    Message = 3,
+    /// Decrypted payload handed to the application by `recv_datagram`
+    Datagram = 4,
+}
+
+impl CaptureKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(CaptureKind::Inbound),
+            2 => Some(CaptureKind::Outbound),
+            3 => Some(CaptureKind::Message),
+            4 => Some(CaptureKind::Datagram),
+            _ => None,
+        }
+    }
+}
+
+/// One datagram or message in a capture file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    /// Monotonic time since the capture was started
+    pub at: Duration,
+    pub kind: CaptureKind,
+    pub conv: u32,
+    pub peer: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+impl CaptureRecord {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.kind as u8);
+        buf.put_u64(self.at.as_micros() as u64);
+        buf.put_u32(self.conv);
+        match self.peer.ip() {
+            IpAddr::V4(ip) => {
+                buf.put_u8(4);
+                buf.put_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.put_u8(6);
+                buf.put_slice(&ip.octets());
+            }
+        }
+        buf.put_u16(self.peer.port());
+        buf.put_u16(self.data.len() as u16);
+        buf.put_slice(&self.data);
+    }
+
+    // ファイル末尾なら None
+    fn read_from(reader: &mut impl Read) -> Result<Option<Self>> {
+        let mut header = [0u8; RECORD_HEADER];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let mut buf = &header[..];
+        let Some(kind) = CaptureKind::from_u8(buf.get_u8()) else {
+            bail!("unknown capture record kind {}", header[0]);
+        };
+        let at = Duration::from_micros(buf.get_u64());
+        let conv = buf.get_u32();
+        let ip = match buf.get_u8() {
+            4 => {
+                let mut octets = [0u8; 4];
+                reader.read_exact(&mut octets)?;
+                IpAddr::from(octets)
+            }
+            6 => {
+                let mut octets = [0u8; 16];
+                reader.read_exact(&mut octets)?;
+                IpAddr::from(octets)
+            }
+            family => bail!("unknown address family {family}"),
+        };
+        let mut tail = [0u8; 4];
+        reader.read_exact(&mut tail)?;
+        let mut tail = &tail[..];
+        let port = tail.get_u16();
+        let mut data = vec![0u8; tail.get_u16() as usize];
+        reader.read_exact(&mut data)?;
+        Ok(Some(Self {
+            at,
+            kind,
+            conv,
+            peer: SocketAddr::new(ip, port),
+            data,
+        }))
+    }
+}
+
+struct CaptureWriter {
+    // 開始時のシステムクロック
+    start_us: u64,
+    out: Box<dyn Write + Send>,
+}
+
+/// Recorder for every datagram and message of the sessions it is given to.
+///
+/// Opt in with `WkSessionConfig::capture`; a listener shares one capture
+/// among all its sessions, told apart by conv. Cloning gives another handle
+/// to the same file. Write errors are logged and otherwise ignored so a full
+/// disk never breaks keying.
+///
+/// Records are timed on the system clock sessions keep time by, so the
+/// timestamps inside the messages, such as a Pong's, can be lined up with
+/// them.
+#[derive(Clone)]
+pub struct Capture {
+    writer: Arc<Mutex<CaptureWriter>>,
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Capture").finish_non_exhaustive()
+    }
+}
+
+// 設定の比較用。同じファイルへのハンドルなら等しい
+impl PartialEq for Capture {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.writer, &other.writer)
+    }
+}
+
+impl Eq for Capture {}
+
+impl Capture {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Capture::new(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn new(mut out: impl Write + Send + 'static) -> Result<Self> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let start_us = tick_us();
+        let mut header = BytesMut::new();
+        header.put_slice(CAPTURE_MAGIC);
+        header.put_u8(CAPTURE_VERSION);
+        header.put_u64(started.as_micros() as u64);
+        header.put_u64(start_us);
+        out.write_all(&header)?;
+        out.flush()?;
+        Ok(Self {
+            writer: Arc::new(Mutex::new(CaptureWriter {
+                start_us,
+                out: Box::new(out),
+            })),
+        })
+    }
+
+    pub(crate) fn record(&self, kind: CaptureKind, conv: u32, peer: SocketAddr, data: &[u8]) {
+        let Ok(mut writer) = self.writer.lock() else {
+            return;
+        };
+        let record = CaptureRecord {
+            at: Duration::from_micros(tick_us().saturating_sub(writer.start_us)),
+            kind,
+            conv,
+            peer,
+            data: data.to_vec(),
+        };
+        let mut buf = BytesMut::new();
+        record.encode(&mut buf);
+        // 落ちても残るよう 1 レコードごとに書き出す
+        if let Err(e) = writer.out.write_all(&buf).and_then(|_| writer.out.flush()) {
+            trace!("capture write error: {e}");
+        }
+    }
+}
+
+/// Reads the records of a capture file in the order they were written.
+pub struct CaptureReader<R> {
+    reader: R,
+    started: SystemTime,
+    clock: Duration,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        CaptureReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0u8; CAPTURE_HEADER];
+        reader.read_exact(&mut header)?;
+        if &header[..5] != CAPTURE_MAGIC {
+            bail!("not a capture file");
+        }
+        if header[5] != CAPTURE_VERSION {
+            bail!("unsupported capture version {}", header[5]);
+        }
+        let mut buf = &header[6..];
+        let started = UNIX_EPOCH + Duration::from_micros(buf.get_u64());
+        let clock = Duration::from_micros(buf.get_u64());
+        Ok(Self {
+            reader,
+            started,
+            clock,
+        })
+    }
+
+    /// Wall-clock time the capture was started
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    /// Reading of the sessions' system clock when the capture was started
+    pub fn clock(&self) -> Duration {
+        self.clock
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CaptureRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        CaptureRecord::read_from(&mut self.reader).transpose()
+    }
+}
+
+/// A key edge as the server's keyer would have played it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayedKey {
+    pub down: bool,
+    /// Timestamp of the edge on the client's clock
+    pub remote: Tick,
+    /// When the message carrying the edge arrived
+    pub arrived: Duration,
+    /// When the edge was due, before the playout delay
+    pub due: Duration,
+    /// When the key would have been asserted or released
+    pub played: Duration,
+}
+
+/// Replay the keyer messages received on session `conv` and return the key
+/// timing `KeyPlayer` would have produced with the jitter buffer `buffer`.
+///
+/// The edges go through the same `Playout` and `JitterBuffer`, with the
+/// Syncs and the client's Pongs mapping its clock as they arrived. `clock`
+/// is the session clock when the capture was started, see
+/// `CaptureReader::clock`. Keys are returned in the order they are played.
+pub fn replay<'a>(
+    records: impl IntoIterator<Item = &'a CaptureRecord>,
+    conv: u32,
+    clock: Duration,
+    buffer: JitterBufferConfig,
+) -> Vec<ReplayedKey> {
+    let mut playout = Playout::new();
+    let mut buffer = JitterBuffer::new(buffer);
+    let mut keys = Vec::new();
+    for record in records {
+        // 認証中のメッセージなどは飛ばす
+        if record.conv != conv
+            || !matches!(record.kind, CaptureKind::Message | CaptureKind::Datagram)
+            || !may_carry_keying(&record.data)
+        {
+            continue;
+        }
+        let Ok(msgs) = decode_message(&record.data) else {
+            continue;
+        };
+        // 受け取ったときのセッションの時刻
+        let now = Tick::from_ms((clock + record.at).as_millis() as u32);
+        let since_start = |t: Tick, sub_us: u16| {
+            shifted(record.at, t.delta(now)) + Duration::from_micros(sub_us as u64)
+        };
+        for msg in msgs {
+            let (remote, sub_us, down) = match msg {
+                MessageRCV::Sync(rmt) => {
+                    playout.sync(rmt, now);
+                    continue;
+                }
+                MessageRCV::Pong {
+                    origin,
+                    peer: Some((received, sent)),
+                } => {
+                    playout.exchange(origin, received, sent, now);
+                    continue;
+                }
+                MessageRCV::Keydown { at, sub_us } => (at, sub_us, true),
+                MessageRCV::Keyup { at, sub_us } => (at, sub_us, false),
+                _ => continue,
+            };
+            let due = playout.due(remote, now);
+            let Some(played) = buffer.schedule(due, down, now) else {
+                continue;
+            };
+            keys.push(ReplayedKey {
+                down,
+                remote,
+                arrived: record.at,
+                due: since_start(due, sub_us),
+                played: since_start(played, sub_us),
+            });
+        }
+    }
+    // スケジューラは鳴らす時刻の順に、同じなら積んだ順に鳴らす
+    keys.sort_by_key(|key| key.played);
+    keys
+}
+
+// 記録時刻から ms ずらした時刻。記録の開始より前にはしない
+fn shifted(at: Duration, ms: i32) -> Duration {
+    let offset = Duration::from_millis(ms.unsigned_abs() as u64);
+    if ms < 0 {
+        at.saturating_sub(offset)
+    } else {
+        at + offset
+    }
+}
+
+// 旧形式のキーイングか、キーイングや Pong を含みうる TLV 形式
+fn may_carry_keying(data: &[u8]) -> bool {
+    matches!(data.first(), Some(&k) if k == PacketKind::KeyerMessage as u8 || k == CODEC_MAGIC)
+}
+
+/// Write the raw datagrams of a capture as a pcap file for Wireshark.
+///
+/// The local end was not recorded and shows up as the unspecified address
+/// with port 0. Decode the peer's port as KCP to look into the frames.
+pub fn write_pcap<'a>(
+    records: impl IntoIterator<Item = &'a CaptureRecord>,
+    started: SystemTime,
+    mut out: impl Write,
+) -> Result<()> {
+    let mut buf = BytesMut::new();
+    buf.put_u32_le(0xa1b2c3d4);
+    buf.put_u16_le(2);
+    buf.put_u16_le(4);
+    buf.put_i32_le(0);
+    buf.put_u32_le(0);
+    buf.put_u32_le(u16::MAX as u32);
+    buf.put_u32_le(PCAP_LINKTYPE_RAW);
+    out.write_all(&buf)?;
+
+    let started = started.duration_since(UNIX_EPOCH).unwrap_or_default();
+    for record in records {
+        let (src, dst) = match record.kind {
+            CaptureKind::Inbound => (record.peer, local_for(record.peer)),
+            CaptureKind::Outbound => (local_for(record.peer), record.peer),
+            _ => continue,
+        };
+        let packet = ip_udp_packet(src, dst, &record.data);
+        let ts = started + record.at;
+        buf.clear();
+        buf.put_u32_le(ts.as_secs() as u32);
+        buf.put_u32_le(ts.subsec_micros());
+        buf.put_u32_le(packet.len() as u32);
+        buf.put_u32_le(packet.len() as u32);
+        buf.put_slice(&packet);
+        out.write_all(&buf)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn local_for(peer: SocketAddr) -> SocketAddr {
+    match peer {
+        SocketAddr::V4(_) => SocketAddr::new(PCAP_LOCAL_V4.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(PCAP_LOCAL_V6.into(), 0),
+    }
+}
+
+// UDP チェックサムは 0（IPv4 では省略、Wireshark も既定では検証しない）
+fn ip_udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> BytesMut {
+    let udp_len = 8 + payload.len();
+    let mut pkt = BytesMut::with_capacity(40 + udp_len);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            pkt.put_u8(0x45);
+            pkt.put_u8(0);
+            pkt.put_u16((20 + udp_len) as u16);
+            pkt.put_u32(0x0000_4000); // id 0, DF
+            pkt.put_u8(64);
+            pkt.put_u8(17);
+            pkt.put_u16(0);
+            pkt.put_slice(&s.octets());
+            pkt.put_slice(&d.octets());
+            let sum = ipv4_checksum(&pkt[..20]);
+            pkt[10..12].copy_from_slice(&sum.to_be_bytes());
+        }
+        (s, d) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            pkt.put_u32(0x6000_0000);
+            pkt.put_u16(udp_len as u16);
+            pkt.put_u8(17);
+            pkt.put_u8(64);
+            pkt.put_slice(&v6(s).octets());
+            pkt.put_slice(&v6(d).octets());
+        }
+    }
+    pkt.put_u16(src.port());
+    pkt.put_u16(dst.port());
+    pkt.put_u16(udp_len as u16);
+    pkt.put_u16(0);
+    pkt.put_slice(payload);
+    pkt
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], w[1]]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wkproto::encode_message;
+
+    // テストでは書き込んだバイト列を後から読む
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn keyer(tm: u32, slots: &[u8]) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        encode_message(&mut buf, PacketKind::KeyerMessage, tm, slots).unwrap();
+        buf.to_vec()
+    }
+
+    fn record(at_ms: u64, data: Vec<u8>) -> CaptureRecord {
+        CaptureRecord {
+            at: Duration::from_millis(at_ms),
+            kind: CaptureKind::Datagram,
+            conv: 7,
+            peer: "192.0.2.1:5000".parse().unwrap(),
+            data,
+        }
+    }
+
+    #[test]
+    fn test_capture_roundtrip() {
+        let shared = Shared::default();
+        let capture = Capture::new(shared.clone()).unwrap();
+        let v4: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6000".parse().unwrap();
+        capture.record(CaptureKind::Inbound, 7, v4, b"abc");
+        capture.record(CaptureKind::Message, 7, v6, b"");
+
+        let bytes = shared.0.lock().unwrap().clone();
+        let reader = CaptureReader::new(&bytes[..]).unwrap();
+        assert!(reader.clock() <= Duration::from_micros(tick_us()));
+        let records = reader.collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (records[0].kind, records[0].peer, &records[0].data[..]),
+            (CaptureKind::Inbound, v4, &b"abc"[..])
+        );
+        assert_eq!(
+            (records[1].kind, records[1].peer),
+            (CaptureKind::Message, v6)
+        );
+        assert!(records[0].at <= records[1].at);
+        assert!(CaptureReader::new(&b"PCAP\x01"[..]).is_err());
+    }
+
+    #[test]
+    fn test_replay_follows_keyer_playout() {
+        let records = [
+            // 認証メッセージは無視する
+            record(0, vec![0x02, 0x01]),
+            record(100, keyer(10_000, &[])),
+            // 打鍵が遅れて届くと到着時刻で鳴らす
+            record(150, keyer(10_020, &[0x00, 0x90])),
+            record(300, keyer(10_100, &[0x00])),
+        ];
+        let keys = replay(&records, 7, Duration::ZERO, JitterBufferConfig::FIXED);
+        let played: Vec<_> = keys.iter().map(|k| k.played.as_millis()).collect();
+        assert_eq!(played, vec![150, 150, 300]);
+        assert!(keys[0].down && !keys[1].down);
+        assert_eq!(keys[1].remote, Tick::from_ms(10_036));
+        assert_eq!(keys[1].due, Duration::from_millis(136));
+        assert!(replay(&records, 8, Duration::ZERO, JitterBufferConfig::FIXED).is_empty());
+    }
+
+    #[test]
+    fn test_replay_across_tick_wrap() {
+        // 相手の tick_count() がパケットの途中で一周する
+        let records = [
+            record(100, keyer(u32::MAX - 30, &[])),
+            record(150, keyer(u32::MAX - 10, &[0x05, 0x80 | 0x28])),
+            // 基準より前の時刻を持つ打鍵はすぐ鳴らす
+            record(200, keyer(u32::MAX - 100, &[0x00])),
+        ];
+        let keys = replay(&records, 7, Duration::ZERO, JitterBufferConfig::FIXED);
+        let due: Vec<_> = keys.iter().map(|k| k.due.as_millis()).collect();
+        assert_eq!(due, vec![125, 160, 100]);
+        assert_eq!(keys[1].remote, Tick::from_ms(29));
+        let played: Vec<_> = keys.iter().map(|k| k.played.as_millis()).collect();
+        assert_eq!(played, vec![150, 160, 200]);
+    }
+
+    #[test]
+    fn test_pcap_export() {
+        let mut inbound = record(1, vec![1, 2, 3]);
+        inbound.kind = CaptureKind::Inbound;
+        let records = [inbound, record(2, keyer(0, &[]))];
+        let mut out = Vec::new();
+        write_pcap(&records, UNIX_EPOCH, &mut out).unwrap();
+        // グローバルヘッダー + レコードヘッダー + IPv4 + UDP + データ。Datagram は書かない
+        assert_eq!(out.len(), 24 + 16 + 20 + 8 + 3);
+        assert_eq!(&out[..4], &0xa1b2c3d4u32.to_le_bytes());
+        let ip = &out[40..60];
+        assert_eq!(ipv4_checksum(ip), 0);
+        assert_eq!(&ip[12..16], &[192, 0, 2, 1]);
+    }
+}
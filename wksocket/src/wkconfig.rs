@@ -0,0 +1,180 @@
+use crate::wkcapture::Capture;
+use crate::wktime::SharedClock;
+use std::time::Duration;
+use wkproto::Capabilities;
+
+const DEFAULT_MTU: usize = 512;
+const DEFAULT_INTERVAL: i32 = 10;
+const DEFAULT_MAX_RESEND: u32 = 10;
+// KCP 自身の既定値
+const DEFAULT_SEND_WINDOW: u16 = 32;
+const DEFAULT_RECV_WINDOW: u16 = 128;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_AUTH_TIMEOUT: Duration = Duration::from_millis(1000);
+// ESP32 では PBKDF2 に数百 ms かかるため、クライアントの共有値を待つ時間は長めに取る
+const DEFAULT_AUTH_PAKE_TIMEOUT: Duration = Duration::from_millis(5000);
+// 新しいデータグラムに前の 2 つを重ねて送る
+const DEFAULT_DATAGRAM_REDUNDANCY: usize = 3;
+const DEFAULT_SOFTWARE: &str = concat!("wksocket ", env!("CARGO_PKG_VERSION"));
+
+/// KCP tuning and timeouts for a `WkSession`.
+///
+/// Accepted by `WkSession::connect_with_config` and
+/// `WkListener::bind_with_config`. The default is the low-latency profile
+/// used for LAN keying; raise the interval, resend limit and timeouts for
+/// lossy cellular links.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WkSessionConfig {
+    pub(crate) nodelay: bool,
+    pub(crate) interval: i32,
+    pub(crate) fast_resend: i32,
+    pub(crate) no_congestion: bool,
+    pub(crate) max_resend: u32,
+    pub(crate) send_window: u16,
+    pub(crate) recv_window: u16,
+    pub(crate) mtu: usize,
+    pub(crate) idle_timeout: Duration,
+    pub(crate) auth_timeout: Duration,
+    pub(crate) auth_pake_timeout: Duration,
+    pub(crate) software: String,
+    pub(crate) capabilities: Capabilities,
+    pub(crate) datagram_redundancy: usize,
+    pub(crate) capture: Option<Capture>,
+    pub(crate) clock: SharedClock,
+}
+
+impl Default for WkSessionConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            interval: DEFAULT_INTERVAL,
+            fast_resend: 1,
+            no_congestion: true,
+            max_resend: DEFAULT_MAX_RESEND,
+            send_window: DEFAULT_SEND_WINDOW,
+            recv_window: DEFAULT_RECV_WINDOW,
+            mtu: DEFAULT_MTU,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            auth_timeout: DEFAULT_AUTH_TIMEOUT,
+            auth_pake_timeout: DEFAULT_AUTH_PAKE_TIMEOUT,
+            software: DEFAULT_SOFTWARE.to_string(),
+            capabilities: Capabilities::BASELINE,
+            datagram_redundancy: DEFAULT_DATAGRAM_REDUNDANCY,
+            capture: None,
+            clock: SharedClock::default(),
+        }
+    }
+}
+
+impl WkSessionConfig {
+    /// KCP nodelay mode: shorter minimum RTO and no delayed ACKs
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Internal update interval in milliseconds
+    pub fn interval(mut self, interval: i32) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Retransmit after this many duplicate ACKs (0 disables fast resend)
+    pub fn fast_resend(mut self, fast_resend: i32) -> Self {
+        self.fast_resend = fast_resend;
+        self
+    }
+
+    /// Disable KCP congestion control
+    pub fn no_congestion(mut self, no_congestion: bool) -> Self {
+        self.no_congestion = no_congestion;
+        self
+    }
+
+    /// Retransmissions of one segment before the link is considered dead
+    pub fn max_resend(mut self, max_resend: u32) -> Self {
+        self.max_resend = max_resend;
+        self
+    }
+
+    /// Send and receive window sizes in segments
+    pub fn window(mut self, send: u16, recv: u16) -> Self {
+        self.send_window = send;
+        self.recv_window = recv;
+        self
+    }
+
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Close the session when nothing was sent or received for this long
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Time to wait for each authentication message
+    pub fn auth_timeout(mut self, timeout: Duration) -> Self {
+        self.auth_timeout = timeout;
+        self
+    }
+
+    /// Time the server waits for the client's SPAKE2+ share, which includes the client's PBKDF2
+    pub fn auth_pake_timeout(mut self, timeout: Duration) -> Self {
+        self.auth_pake_timeout = timeout;
+        self
+    }
+
+    /// Name and version announced to the peer in the hello, e.g. "wifikey 0.3.9"
+    pub fn software(mut self, software: impl Into<String>) -> Self {
+        self.software = software.into();
+        self
+    }
+
+    /// Message kinds this side understands, announced to the peer in the hello
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Payloads carried by each datagram, the new one plus this many minus one repeats
+    pub fn datagram_redundancy(mut self, redundancy: usize) -> Self {
+        self.datagram_redundancy = redundancy;
+        self
+    }
+
+    /// Record every datagram and message of the session, see `Capture`
+    pub fn capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Clock for KCP timers, timeouts and the keyers on the session, `SystemClock` by default
+    pub fn clock(mut self, clock: impl Into<SharedClock>) -> Self {
+        self.clock = clock.into();
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_overrides_defaults() {
+        let config = WkSessionConfig::default()
+            .interval(20)
+            .max_resend(30)
+            .window(64, 256)
+            .idle_timeout(Duration::from_secs(60));
+        assert_eq!(config.interval, 20);
+        assert_eq!(config.max_resend, 30);
+        assert_eq!((config.send_window, config.recv_window), (64, 256));
+        assert_eq!(config.idle_timeout, Duration::from_secs(60));
+        // 指定しなかった値は既定のまま
+        assert_eq!(config.mtu, DEFAULT_MTU);
+        assert_eq!(config.auth_timeout, DEFAULT_AUTH_TIMEOUT);
+    }
+}
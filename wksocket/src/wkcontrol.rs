@@ -0,0 +1,182 @@
+use crate::wkcrypto::{CLOSE_TAG_LEN, RESUME_TAG_LEN};
+use bytes::{Buf, BufMut, BytesMut};
+use wkproto::CloseReason;
+
+// KCP のコマンドは 81..=84 なので、それ以外の値で制御パケットを識別する
+const CONTROL_CMD: u8 = 0xF0;
+// conv(4) + cmd(1) + kind(1)
+const CONTROL_HEADER: usize = 6;
+
+const KIND_BUSY: u8 = 1;
+const KIND_RESUME_REQUIRED: u8 = 2;
+const KIND_RESUME: u8 = 3;
+const KIND_CLOSE: u8 = 4;
+
+/// Out-of-band datagrams sharing the UDP port with KCP.
+///
+/// Laid out like the start of a KCP segment (conv, cmd) with a command byte
+/// KCP never uses. Busy, ResumeRequired and Close are shorter than `KCP_OVERHEAD`,
+/// so older clients drop them as too short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Control {
+    /// The listener has no free session slot
+    Busy,
+    /// The listener got a packet for a known conv from an unknown address
+    ResumeRequired,
+    /// The client proves it owns the session from its new address
+    Resume {
+        ticket: u32,
+        counter: u64,
+        tag: [u8; RESUME_TAG_LEN],
+    },
+    /// The sender has closed the session. Sent a few times since nothing acknowledges it.
+    /// Encrypted sessions tag it, see `SessionCipher::close_tag`
+    Close {
+        reason: CloseReason,
+        tag: Option<[u8; CLOSE_TAG_LEN]>,
+    },
+}
+
+impl Control {
+    fn kind(&self) -> u8 {
+        match self {
+            Control::Busy => KIND_BUSY,
+            Control::ResumeRequired => KIND_RESUME_REQUIRED,
+            Control::Resume { .. } => KIND_RESUME,
+            Control::Close { .. } => KIND_CLOSE,
+        }
+    }
+
+    pub(crate) fn encode(&self, conv: u32) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(CONTROL_HEADER + 12 + RESUME_TAG_LEN);
+        buf.put_u32_le(conv);
+        buf.put_u8(CONTROL_CMD);
+        buf.put_u8(self.kind());
+        match self {
+            Control::Resume {
+                ticket,
+                counter,
+                tag,
+            } => {
+                buf.put_u32(*ticket);
+                buf.put_u64(*counter);
+                buf.put_slice(tag);
+            }
+            Control::Close { reason, tag } => {
+                buf.put_u8(reason.code());
+                if let Some(tag) = tag {
+                    buf.put_slice(tag);
+                }
+            }
+            Control::Busy | Control::ResumeRequired => {}
+        }
+        buf
+    }
+
+    /// Returns the conv and control message, or None if `pkt` is not a control datagram.
+    pub(crate) fn decode(pkt: &[u8]) -> Option<(u32, Control)> {
+        if pkt.len() < CONTROL_HEADER || pkt[4] != CONTROL_CMD {
+            return None;
+        }
+        let mut buf = pkt;
+        let conv = buf.get_u32_le();
+        buf.advance(1);
+        let ctrl = match buf.get_u8() {
+            KIND_BUSY => Control::Busy,
+            KIND_RESUME_REQUIRED => Control::ResumeRequired,
+            KIND_RESUME if buf.remaining() >= 12 + RESUME_TAG_LEN => {
+                let ticket = buf.get_u32();
+                let counter = buf.get_u64();
+                let mut tag = [0u8; RESUME_TAG_LEN];
+                buf.copy_to_slice(&mut tag);
+                Control::Resume {
+                    ticket,
+                    counter,
+                    tag,
+                }
+            }
+            KIND_CLOSE if buf.has_remaining() => {
+                let reason = CloseReason::from_code(buf.get_u8());
+                // タグ付きは暗号化されたセッションの Close。長さが合わないものは捨てる
+                let tag = match buf.remaining() {
+                    0 => None,
+                    CLOSE_TAG_LEN => {
+                        let mut tag = [0u8; CLOSE_TAG_LEN];
+                        buf.copy_to_slice(&mut tag);
+                        Some(tag)
+                    }
+                    _ => return None,
+                };
+                Control::Close { reason, tag }
+            }
+            _ => return None,
+        };
+        Some((conv, ctrl))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_roundtrip() {
+        let pkt = Control::Busy.encode(0x12345678);
+        assert!(pkt.len() < kcp::KCP_OVERHEAD);
+        // conv は KCP と同じくリトルエンディアン
+        assert_eq!(&pkt[..4], &0x12345678u32.to_le_bytes());
+        assert_eq!(Control::decode(&pkt), Some((0x12345678, Control::Busy)));
+
+        let resume = Control::Resume {
+            ticket: 42,
+            counter: 3,
+            tag: [9u8; RESUME_TAG_LEN],
+        };
+        let pkt = resume.encode(7);
+        assert_eq!(Control::decode(&pkt), Some((7, resume)));
+        // 途中で切れた Resume は捨てる
+        assert_eq!(Control::decode(&pkt[..pkt.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_close_reason_roundtrip() {
+        for reason in [
+            CloseReason::UserQuit,
+            CloseReason::AuthFailure,
+            CloseReason::ServerBusy,
+            CloseReason::EmergencyStop,
+            CloseReason::Shutdown,
+            CloseReason::Other(200),
+        ] {
+            for tag in [None, Some([9u8; CLOSE_TAG_LEN])] {
+                let close = Control::Close { reason, tag };
+                let pkt = close.encode(5);
+                assert!(pkt.len() < kcp::KCP_OVERHEAD);
+                assert_eq!(Control::decode(&pkt), Some((5, close)));
+            }
+        }
+        // 理由コードのない Close や途中で切れたタグは捨てる
+        let pkt = Control::Close {
+            reason: CloseReason::UserQuit,
+            tag: None,
+        }
+        .encode(5);
+        assert_eq!(Control::decode(&pkt[..pkt.len() - 1]), None);
+        let pkt = Control::Close {
+            reason: CloseReason::UserQuit,
+            tag: Some([9u8; CLOSE_TAG_LEN]),
+        }
+        .encode(5);
+        assert_eq!(Control::decode(&pkt[..pkt.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_kcp_segment_is_not_control() {
+        // conv=1, cmd=IKCP_CMD_PUSH(81)
+        let mut seg = [0u8; kcp::KCP_OVERHEAD];
+        seg[0] = 1;
+        seg[4] = 81;
+        assert_eq!(Control::decode(&seg), None);
+        assert_eq!(Control::decode(&[0u8; 3]), None);
+    }
+}
@@ -0,0 +1,1000 @@
+use crate::wkcapture::{Capture, CaptureKind};
+use crate::wkconfig::WkSessionConfig;
+use crate::wkcontrol::Control;
+use crate::wkcrypto::{ResumeTicket, SessionCipher, CLOSE_TAG_LEN, RESUME_TAG_LEN};
+use crate::wkdatagram::{is_datagram, DatagramReceiver, DatagramSender};
+use crate::wkerror::WkError;
+use crate::wkhello::Hello;
+use crate::wkstats::{LinkMonitor, WkSessionStats};
+use anyhow::{bail, Result};
+use kcp::Kcp;
+use log::{info, trace};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use wkproto::{CloseReason, Tick};
+
+// Close には ACK がないので、取りこぼしに備えて数回送る
+const CLOSE_REPEATS: usize = 3;
+// 読まれないデータグラムはこれを超えたら古いものから捨てる
+const MAX_DATAGRAMS: usize = 64;
+
+/// Datagram produced by `WkSessionCore` that the caller must send.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transmit {
+    pub peer: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+// KCP の出力先。送信はせずに呼び出し側が取り出すまで溜めておく
+#[derive(Clone, Default)]
+struct QueueOutput {
+    queue: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+impl Write for QueueOutput {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        trace!("{} byte packet queued", data.len());
+        self.queue.lock().unwrap().push_back(data.to_vec());
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sans-IO state machine of one session.
+///
+/// Owns KCP, the session cipher and the resume ticket but never touches a
+/// socket or a clock. Feed received datagrams to `handle_datagram` (client)
+/// or `input` (listener), call `handle_timeout` once `next_timeout` has
+/// elapsed, and send whatever `poll_transmit` returns. `now` is usually
+/// `SystemClock.now()`.
+pub struct WkSessionCore {
+    kcp: Kcp<QueueOutput>,
+    output: QueueOutput,
+    peer: SocketAddr,
+    mtu: usize,
+    idle_timeout: u32,
+    // KCP に渡す時刻の起点。KCP の時刻計算は tick_count() の一周を扱えない
+    epoch: Tick,
+    last_update: Tick,
+    closed: bool,
+    close_reason: Option<CloseReason>,
+    // 認証後に設定される。None の間は平文（認証中および旧クライアント）
+    cipher: Option<SessionCipher>,
+    resume: Option<ResumeTicket>,
+    // 旧版のピアや認証前は None
+    peer_hello: Option<Hello>,
+    datagram_tx: DatagramSender,
+    datagram_rx: DatagramReceiver,
+    datagrams: VecDeque<Vec<u8>>,
+    monitor: LinkMonitor,
+    capture: Option<Capture>,
+}
+
+impl WkSessionCore {
+    pub fn new(config: &WkSessionConfig, peer: SocketAddr, now: Tick) -> Result<Self> {
+        let output = QueueOutput::default();
+        let conv = 0;
+        let mut kcp = Kcp::new(conv, output.clone());
+
+        kcp.set_mtu(config.mtu)?;
+        kcp.set_nodelay(
+            config.nodelay,
+            config.interval,
+            config.fast_resend,
+            config.no_congestion,
+        );
+        kcp.set_wndsize(config.send_window, config.recv_window);
+        kcp.set_maximum_resend_times(config.max_resend);
+
+        if conv == 0 {
+            kcp.input_conv();
+        }
+
+        kcp.update(0)?;
+
+        Ok(Self {
+            kcp,
+            output,
+            peer,
+            mtu: config.mtu,
+            idle_timeout: config.idle_timeout.as_millis() as u32,
+            epoch: now,
+            last_update: now,
+            closed: false,
+            close_reason: None,
+            cipher: None,
+            resume: None,
+            peer_hello: None,
+            datagram_tx: DatagramSender::new(config.datagram_redundancy),
+            datagram_rx: DatagramReceiver::default(),
+            datagrams: VecDeque::new(),
+            monitor: LinkMonitor::new(config, now),
+            capture: config.capture.clone(),
+        })
+    }
+
+    /// Next datagram to send, if any.
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        let data = self.output.queue.lock().unwrap().pop_front()?;
+        self.monitor.on_transmit(&data);
+        self.record(CaptureKind::Outbound, &data);
+        Some(Transmit {
+            peer: self.peer,
+            data,
+        })
+    }
+
+    /// Client side: handle a datagram received from `src`.
+    ///
+    /// Control datagrams are answered here; a busy server or a close from
+    /// the server closes the session. Anything else from an address other
+    /// than the server's is dropped.
+    pub fn handle_datagram(&mut self, src: SocketAddr, pkt: &mut [u8], now: Tick) -> Result<()> {
+        self.record(CaptureKind::Inbound, pkt);
+        match Control::decode(pkt) {
+            Some((_, Control::Busy)) => {
+                // 断られるのは conv をもらう前だけ。ソケットは誰からでも受け取るので送り元も見る
+                if src == self.peer && self.kcp.waiting_conv() {
+                    info!("connect: server {src} is busy");
+                    self.shut(CloseReason::ServerBusy);
+                }
+                return Ok(());
+            }
+            Some((conv, Control::Close { reason, tag })) => {
+                if src == self.peer
+                    && conv == self.kcp.conv()
+                    && self.close_authentic(conv, reason, tag.as_ref())
+                {
+                    info!("connect: session {conv} closed by server: {reason:?}");
+                    self.shut(reason);
+                }
+                return Ok(());
+            }
+            Some((conv, Control::ResumeRequired)) => {
+                // サーバーから見た自分のアドレスが変わった（NAT の張り替えなど）
+                if src == self.peer && conv == self.kcp.conv() {
+                    info!("connect: server asks to resume session {conv}");
+                    if let Err(e) = self.request_resume() {
+                        info!("connect: can't resume session {conv}: {e}");
+                    }
+                }
+                return Ok(());
+            }
+            Some(_) => return Ok(()),
+            None => {}
+        }
+        // 開いたセグメントが認証に失敗するとセッションを閉じるので、他所からの注入は先に捨てる
+        if src != self.peer {
+            trace!("connect: packet from {src} dropped, not the server");
+            return Ok(());
+        }
+        if pkt.len() < kcp::KCP_OVERHEAD {
+            trace!(
+                "connect: packet too short {} bytes received from {src}",
+                pkt.len()
+            );
+            return Ok(());
+        }
+        if self.closed {
+            return Err(self.closed_error());
+        }
+        if self.kcp.waiting_conv() {
+            let conv = kcp::get_conv(pkt);
+            kcp::set_conv(pkt, conv);
+        }
+        self.feed(pkt, now)
+    }
+
+    /// Feed a KCP segment or datagram frame that is known to belong to this session.
+    pub fn input(&mut self, buf: &[u8], now: Tick) -> Result<()> {
+        self.record(CaptureKind::Inbound, buf);
+        self.feed(buf, now)
+    }
+
+    fn feed(&mut self, buf: &[u8], now: Tick) -> Result<()> {
+        if is_datagram(buf) {
+            self.input_datagram(buf, now);
+            return Ok(());
+        }
+        match self.kcp.input(buf) {
+            Ok(_) => {}
+            Err(err) => return Err(err.into()),
+        }
+        self.monitor.on_receive(buf, now);
+        self.last_update = now;
+        // flush_ack() は内部バッファを送信せずにエンコードだけする（kcp-0.5.3 のバグ）。
+        // flush() を呼ぶことで ACK を即座に送信キューに積む。
+        let _ = self.kcp.flush();
+        Ok(())
+    }
+
+    pub fn send(&mut self, buf: &[u8], now: Tick) -> Result<usize> {
+        if self.closed || self.kcp.is_dead_link() {
+            self.shut(CloseReason::LinkLost);
+            return Err(self.closed_error());
+        }
+        let n = match self.cipher.as_mut() {
+            Some(cipher) => {
+                // KCP が受け付けなかったときは nonce を進めない
+                let kcp = &mut self.kcp;
+                cipher.seal_with(buf, |sealed| Ok(kcp.send(sealed)?))?;
+                buf.len()
+            }
+            None => self.kcp.send(buf)?,
+        };
+        self.last_update = now;
+        self.kcp.flush()?;
+        Ok(n)
+    }
+
+    /// Copy the next message into `buf`. Returns 0 if none is complete yet.
+    pub fn recv(&mut self, buf: &mut [u8], now: Tick) -> Result<usize> {
+        if self.closed {
+            return Err(self.closed_error());
+        }
+        let Some(cipher) = self.cipher.as_mut() else {
+            return match self.kcp.recv(buf) {
+                Ok(n) => {
+                    self.last_update = now;
+                    self.record(CaptureKind::Message, &buf[..n]);
+                    Ok(n)
+                }
+                Err(kcp::Error::RecvQueueEmpty | kcp::Error::ExpectingFragment) => Ok(0),
+                Err(e) => Err(e.into()),
+            };
+        };
+        let size = match self.kcp.peeksize() {
+            Ok(size) => size,
+            Err(kcp::Error::RecvQueueEmpty | kcp::Error::ExpectingFragment) => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut sealed = vec![0u8; size];
+        let n = self.kcp.recv(&mut sealed)?;
+        self.last_update = now;
+        let plain = match cipher.open(&sealed[..n]) {
+            Ok(plain) => plain,
+            Err(e) => {
+                // 改ざん・注入されたメッセージ。以降の nonce が揃わないのでセッションを閉じる
+                self.shut(CloseReason::LinkLost);
+                return Err(e);
+            }
+        };
+        if plain.len() > buf.len() {
+            return Err(WkError::TooLarge(plain.len()).into());
+        }
+        buf[..plain.len()].copy_from_slice(&plain);
+        self.record(CaptureKind::Message, &plain);
+        Ok(plain.len())
+    }
+
+    // 不正なフレームで KCP のセッションまで閉じないよう、エラーは捨てるだけにする
+    fn input_datagram(&mut self, pkt: &[u8], now: Tick) {
+        let Some(cipher) = self.cipher.as_ref() else {
+            trace!("datagram before authentication dropped");
+            return;
+        };
+        match self.datagram_rx.accept(pkt, cipher) {
+            Ok(payloads) => {
+                self.monitor.on_receive(pkt, now);
+                self.last_update = now;
+                for payload in payloads {
+                    if self.datagrams.len() >= MAX_DATAGRAMS {
+                        self.datagrams.pop_front();
+                    }
+                    self.datagrams.push_back(payload);
+                }
+            }
+            Err(e) => trace!("datagram dropped: {e}"),
+        }
+    }
+
+    /// Send `buf` on the unreliable channel.
+    ///
+    /// Bypasses KCP, so a lost datagram never delays later ones; instead each
+    /// datagram repeats the last few payloads and the receiver drops what it
+    /// has already seen. Only encrypted sessions carry datagrams.
+    pub fn send_datagram(&mut self, buf: &[u8], now: Tick) -> Result<usize> {
+        if self.closed {
+            return Err(self.closed_error());
+        }
+        let Some(cipher) = self.cipher.as_ref() else {
+            bail!("datagrams need an encrypted session");
+        };
+        let frame = self
+            .datagram_tx
+            .frame(self.kcp.conv(), buf, self.mtu, cipher)?;
+        self.output.queue.lock().unwrap().push_back(frame);
+        self.last_update = now;
+        Ok(buf.len())
+    }
+
+    /// Copy the next datagram payload into `buf`. Returns 0 if none has arrived.
+    pub fn recv_datagram(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.closed {
+            return Err(self.closed_error());
+        }
+        let Some(payload) = self.datagrams.pop_front() else {
+            return Ok(0);
+        };
+        if payload.len() > buf.len() {
+            return Err(WkError::TooLarge(payload.len()).into());
+        }
+        buf[..payload.len()].copy_from_slice(&payload);
+        self.record(CaptureKind::Datagram, &payload);
+        Ok(payload.len())
+    }
+
+    /// Run KCP timers and close the session once it has been idle too long.
+    pub fn handle_timeout(&mut self, now: Tick) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.kcp.update(now.since(self.epoch))?;
+        if now.since(self.last_update) > self.idle_timeout {
+            trace!("session timed out.");
+            self.shut(CloseReason::LinkLost);
+        }
+        Ok(())
+    }
+
+    /// Milliseconds until `handle_timeout` should be called again.
+    pub fn next_timeout(&self, now: Tick) -> u32 {
+        self.kcp.check(now.since(self.epoch))
+    }
+
+    /// Link-quality snapshot
+    pub fn stats(&self, now: Tick) -> WkSessionStats {
+        self.monitor.snapshot(self.kcp.wait_snd(), now)
+    }
+
+    pub(crate) fn set_cipher(&mut self, cipher: SessionCipher) {
+        self.cipher = Some(cipher);
+    }
+
+    pub fn encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub(crate) fn set_resume(&mut self, ticket: ResumeTicket) {
+        self.resume = Some(ticket);
+    }
+
+    pub(crate) fn set_peer_hello(&mut self, hello: Hello) {
+        self.peer_hello = Some(hello);
+    }
+
+    /// What the peer announced after authentication. None for peers that predate the hello.
+    pub fn peer_hello(&self) -> Option<&Hello> {
+        self.peer_hello.as_ref()
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Client side: ask the server to move this session to our current address.
+    pub fn request_resume(&mut self) -> Result<()> {
+        let conv = self.kcp.conv();
+        let Some(resume) = self.resume.as_mut() else {
+            bail!("session has no resume ticket");
+        };
+        let (ticket, counter, tag) = resume.next(conv);
+        let req = Control::Resume {
+            ticket,
+            counter,
+            tag,
+        };
+        self.output
+            .queue
+            .lock()
+            .unwrap()
+            .push_back(req.encode(conv).to_vec());
+        Ok(())
+    }
+
+    /// Server side: move the session to `peer` if the request is valid.
+    pub(crate) fn migrate(
+        &mut self,
+        peer: SocketAddr,
+        ticket: u32,
+        counter: u64,
+        tag: &[u8; RESUME_TAG_LEN],
+        now: Tick,
+    ) -> bool {
+        let conv = self.kcp.conv();
+        let Some(resume) = self.resume.as_mut() else {
+            return false;
+        };
+        if self.closed || !resume.verify(conv, ticket, counter, tag) {
+            return false;
+        }
+        self.peer = peer;
+        self.last_update = now;
+        // 溜まっているセグメントと ACK を新しいアドレスへ送る
+        let _ = self.kcp.flush();
+        true
+    }
+
+    pub fn flush(&mut self, now: Tick) -> Result<()> {
+        self.kcp.flush()?;
+        self.last_update = now;
+        Ok(())
+    }
+
+    pub fn conv(&self) -> u32 {
+        self.kcp.conv()
+    }
+
+    pub fn waiting_conv(&self) -> bool {
+        self.kcp.waiting_conv()
+    }
+
+    /// Close the session as the user's decision, see `close_with`.
+    pub fn close(&mut self) {
+        self.close_with(CloseReason::UserQuit);
+    }
+
+    /// Close the session and queue a few close datagrams telling the peer why.
+    pub fn close_with(&mut self, reason: CloseReason) {
+        if self.closed {
+            return;
+        }
+        self.shut(reason);
+        let conv = self.kcp.conv();
+        let tag = self
+            .cipher
+            .as_ref()
+            .map(|cipher| cipher.close_tag(conv, reason.code()));
+        let pkt = Control::Close { reason, tag }.encode(conv);
+        let mut queue = self.output.queue.lock().unwrap();
+        for _ in 0..CLOSE_REPEATS {
+            queue.push_back(pkt.to_vec());
+        }
+    }
+
+    /// Server side: the peer has closed the session. Returns false, leaving
+    /// the session open, if the close lacks the tag an encrypted session needs.
+    pub(crate) fn closed_by_peer(
+        &mut self,
+        reason: CloseReason,
+        tag: Option<&[u8; CLOSE_TAG_LEN]>,
+    ) -> bool {
+        if !self.close_authentic(self.kcp.conv(), reason, tag) {
+            return false;
+        }
+        self.shut(reason);
+        true
+    }
+
+    // 暗号化したあとは鍵を知らない第三者の Close で切られないようにタグを確かめる
+    fn close_authentic(
+        &self,
+        conv: u32,
+        reason: CloseReason,
+        tag: Option<&[u8; CLOSE_TAG_LEN]>,
+    ) -> bool {
+        match (&self.cipher, tag) {
+            (None, _) => true,
+            (Some(cipher), Some(tag)) => cipher.verify_close(conv, reason.code(), tag),
+            (Some(_), None) => false,
+        }
+    }
+
+    // 最初に閉じた理由だけを残す
+    fn shut(&mut self, reason: CloseReason) {
+        self.closed = true;
+        self.close_reason.get_or_insert(reason);
+    }
+
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Why the session closed, whether locally or by the peer. None while open.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
+
+    fn closed_error(&self) -> anyhow::Error {
+        WkError::Closed {
+            reason: self.close_reason,
+        }
+        .into()
+    }
+
+    pub fn last_update(&self) -> Tick {
+        self.last_update
+    }
+
+    fn record(&self, kind: CaptureKind, data: &[u8]) {
+        if let Some(capture) = &self.capture {
+            capture.record(kind, self.kcp.conv(), self.peer, data);
+        }
+    }
+}
+
+/// A session as seen by the listener's demultiplexer.
+pub(crate) trait ListenerSession {
+    fn closed(&self) -> bool;
+    /// Run the session's timers. Returns milliseconds until they are due
+    /// again, or None once the session has closed.
+    fn handle_timeout(&self) -> Option<u32>;
+    fn input(&self, pkt: &[u8]) -> Result<()>;
+    fn close(&self, reason: CloseReason);
+    fn closed_by_peer(&self, reason: CloseReason, tag: Option<&[u8; CLOSE_TAG_LEN]>) -> bool;
+    fn migrate(
+        &self,
+        peer: SocketAddr,
+        ticket: u32,
+        counter: u64,
+        tag: &[u8; RESUME_TAG_LEN],
+    ) -> bool;
+}
+
+/// What the listener has to do with a received datagram.
+pub(crate) enum Dispatch {
+    /// Handled by an existing session, or dropped
+    Done,
+    /// Send this control datagram back to the sender
+    Reply(Vec<u8>),
+    /// Create a session with this conv and feed it the (rewritten) packet
+    Accept(u32),
+}
+
+/// Sessions of a listener keyed by peer and conv, shared by the blocking and async listeners.
+pub(crate) struct SessionTable<S> {
+    sessions: HashMap<(SocketAddr, u32), S>,
+    max_sessions: usize,
+}
+
+impl<S: ListenerSession> SessionTable<S> {
+    pub(crate) fn new(max_sessions: usize) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            max_sessions,
+        }
+    }
+
+    /// 閉じた・タイムアウトしたセッションを解放
+    pub(crate) fn evict_closed(&mut self) {
+        self.sessions.retain(|(peer, conv), session| {
+            if session.closed() {
+                trace!("evict session {conv} from {peer}");
+            }
+            !session.closed()
+        });
+    }
+
+    /// Run the timers of every session. Returns milliseconds until the
+    /// earliest is due again, or None without an open session.
+    pub(crate) fn handle_timeouts(&self) -> Option<u32> {
+        self.sessions
+            .values()
+            .filter_map(|session| session.handle_timeout())
+            .min()
+    }
+
+    pub(crate) fn insert(&mut self, peer: SocketAddr, conv: u32, session: S) {
+        self.sessions.insert((peer, conv), session);
+    }
+
+    pub(crate) fn dispatch(&mut self, peer: SocketAddr, pkt: &mut [u8]) -> Dispatch {
+        let n = pkt.len();
+        trace!("received {n}bytes from {peer}");
+
+        match Control::decode(pkt) {
+            Some((
+                conv,
+                Control::Resume {
+                    ticket,
+                    counter,
+                    tag,
+                },
+            )) => {
+                let Some(old) = self
+                    .sessions
+                    .iter()
+                    .find(|((_, c), s)| *c == conv && !s.closed())
+                    .map(|(key, _)| *key)
+                else {
+                    trace!("resume for unknown session {conv} from {peer}");
+                    return Dispatch::Done;
+                };
+                if old.0 == peer {
+                    return Dispatch::Done;
+                }
+                if !self.sessions[&old].migrate(peer, ticket, counter, &tag) {
+                    info!("listen: invalid resume of session {conv} from {peer}");
+                    return Dispatch::Done;
+                }
+                info!("listen: session {conv} moved from {} to {peer}", old.0);
+                if let Some(session) = self.sessions.remove(&old) {
+                    self.sessions.insert((peer, conv), session);
+                }
+                return Dispatch::Done;
+            }
+            Some((conv, Control::Close { reason, tag })) => {
+                // 登録済みのアドレスからの Close だけを受け付ける
+                let key = (peer, conv);
+                let Some(session) = self.sessions.get(&key) else {
+                    return Dispatch::Done;
+                };
+                if session.closed_by_peer(reason, tag.as_ref()) {
+                    info!("listen: session {conv} closed by {peer}: {reason:?}");
+                    self.sessions.remove(&key);
+                } else {
+                    info!("listen: unauthenticated close of session {conv} from {peer} dropped");
+                }
+                return Dispatch::Done;
+            }
+            _ => {}
+        }
+
+        if n < kcp::KCP_OVERHEAD {
+            info!("listen: packet too short {n} bytes received from {peer}");
+            return Dispatch::Done;
+        }
+
+        let mut conv = kcp::get_conv(pkt);
+        if conv != 0 {
+            let key = (peer, conv);
+            let Some(session) = self.sessions.get(&key) else {
+                // 既知の conv が別アドレスから届いたらクライアントに再開を促す
+                if self
+                    .sessions
+                    .iter()
+                    .any(|((_, c), s)| *c == conv && !s.closed())
+                {
+                    trace!("session {conv} seen from new address {peer}");
+                    return Dispatch::Reply(Control::ResumeRequired.encode(conv).to_vec());
+                }
+                trace!("discard packet for unknown session {conv} from {peer}");
+                return Dispatch::Done;
+            };
+            if !session.closed() {
+                trace!("input session {conv} {n} bytes");
+                if session.input(pkt).is_ok() {
+                    return Dispatch::Done;
+                }
+                info!("conv. id inconsistent. close session");
+                session.close(CloseReason::LinkLost);
+            }
+            self.sessions.remove(&key);
+            return Dispatch::Done;
+        }
+
+        // If an active session already exists from this peer, reuse its
+        // conv. This handles KCP retransmissions of the initial SYN
+        // packet (which still carry conv=0) that arrive before the server
+        // has had a chance to send a response.
+        if let Some((&(_, reuse_conv), session)) = self
+            .sessions
+            .iter()
+            .find(|((p, _), s)| *p == peer && !s.closed())
+        {
+            kcp::set_conv(pkt, reuse_conv);
+            trace!("input session {reuse_conv} {n} bytes");
+            let _ = session.input(pkt);
+            return Dispatch::Done;
+        }
+
+        if self.sessions.len() >= self.max_sessions {
+            info!(
+                "listen: {} sessions active, {peer} is busy",
+                self.sessions.len()
+            );
+            return Dispatch::Reply(Control::Busy.encode(0).to_vec());
+        }
+
+        conv = self.unused_conv(rand::random);
+        trace!("set conv ={conv}");
+        kcp::set_conv(pkt, conv);
+        trace!("accept new session from peer = {peer} input {n} bytes");
+        Dispatch::Accept(conv)
+    }
+
+    // Resume と ResumeRequired は conv だけで引くので、どのアドレスとも重ねない
+    fn unused_conv(&self, mut random: impl FnMut() -> u32) -> u32 {
+        loop {
+            let conv = random();
+            if conv != 0 && !self.sessions.keys().any(|(_, c)| *c == conv) {
+                return conv;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_core_exchanges_messages_without_io() {
+        let config = WkSessionConfig::default();
+        let server_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let client_addr: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let mut client = WkSessionCore::new(&config, server_addr, Tick::from_ms(0)).unwrap();
+        let mut server = WkSessionCore::new(&config, client_addr, Tick::from_ms(0)).unwrap();
+
+        client.send(b"hello", Tick::from_ms(1)).unwrap();
+        // listener が割り当てる conv を模す
+        let mut syn = client.poll_transmit().unwrap().data;
+        kcp::set_conv(&mut syn, 1234);
+        server.input(&syn, Tick::from_ms(1)).unwrap();
+
+        let mut buf = [0u8; 16];
+        assert_eq!(server.recv(&mut buf, Tick::from_ms(2)).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        server.send(b"world", Tick::from_ms(3)).unwrap();
+        while let Some(t) = server.poll_transmit() {
+            assert_eq!(t.peer, client_addr);
+            let mut data = t.data;
+            client
+                .handle_datagram(server_addr, &mut data, Tick::from_ms(3))
+                .unwrap();
+        }
+        assert_eq!(client.conv(), 1234);
+        assert_eq!(client.recv(&mut buf, Tick::from_ms(4)).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+    }
+
+    #[test]
+    fn test_core_refused_message_keeps_nonce() {
+        let config = WkSessionConfig::default();
+        let mut client =
+            WkSessionCore::new(&config, "127.0.0.1:1".parse().unwrap(), Tick::from_ms(0)).unwrap();
+        let mut server =
+            WkSessionCore::new(&config, "127.0.0.1:2".parse().unwrap(), Tick::from_ms(0)).unwrap();
+        client.set_cipher(SessionCipher::client(&[4u8; 32]));
+        server.set_cipher(SessionCipher::server(&[4u8; 32]));
+        // KCP の受信窓に収まらない大きさは送る前に断られる
+        assert!(client.send(&[0u8; 1 << 20], Tick::from_ms(1)).is_err());
+        client.send(b"hello", Tick::from_ms(1)).unwrap();
+        let mut syn = client.poll_transmit().unwrap().data;
+        kcp::set_conv(&mut syn, 7);
+        server.input(&syn, Tick::from_ms(1)).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(server.recv(&mut buf, Tick::from_ms(2)).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert!(!server.closed());
+    }
+
+    #[test]
+    fn test_core_idle_timeout() {
+        let config = WkSessionConfig::default();
+        let peer: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut core = WkSessionCore::new(&config, peer, Tick::from_ms(0)).unwrap();
+        let idle = config.idle_timeout.as_millis() as u32;
+        core.handle_timeout(Tick::from_ms(idle)).unwrap();
+        assert!(!core.closed());
+        core.handle_timeout(Tick::from_ms(idle + 1)).unwrap();
+        assert!(core.closed());
+        let err = core.send(b"x", Tick::from_ms(idle + 2)).unwrap_err();
+        assert!(matches!(
+            WkError::from(err),
+            WkError::Closed {
+                reason: Some(CloseReason::LinkLost)
+            }
+        ));
+    }
+
+    #[test]
+    fn test_core_idle_timeout_across_wrap() {
+        let config = WkSessionConfig::default();
+        let peer: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        // tick_count() が一周する直前に作られたセッション
+        let start = Tick::from_ms(u32::MAX - 100);
+        let mut core = WkSessionCore::new(&config, peer, start).unwrap();
+        core.send(b"x", start + 50).unwrap();
+        let idle = config.idle_timeout.as_millis() as u32;
+        core.handle_timeout(start + 200).unwrap();
+        assert!(!core.closed());
+        core.handle_timeout(start + 50 + idle).unwrap();
+        assert!(!core.closed());
+        core.handle_timeout(start + 51 + idle).unwrap();
+        assert!(core.closed());
+    }
+
+    #[test]
+    fn test_core_ignores_segments_from_other_addresses() {
+        let config = WkSessionConfig::default();
+        let server_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut client = WkSessionCore::new(&config, server_addr, Tick::from_ms(0)).unwrap();
+        let mut server =
+            WkSessionCore::new(&config, "127.0.0.1:2".parse().unwrap(), Tick::from_ms(0)).unwrap();
+        client.set_cipher(SessionCipher::client(&[4u8; 32]));
+        server.set_cipher(SessionCipher::server(&[4u8; 32]));
+        client.send(b"hi", Tick::from_ms(1)).unwrap();
+        let mut syn = client.poll_transmit().unwrap().data;
+        kcp::set_conv(&mut syn, 7);
+        server.input(&syn, Tick::from_ms(1)).unwrap();
+        server.send(b"ok", Tick::from_ms(2)).unwrap();
+        let segments: Vec<_> = std::iter::from_fn(|| server.poll_transmit()).collect();
+        let mut buf = [0u8; 16];
+        // 正しい conv と sn のセグメントでも、サーバー以外からなら受け取らない
+        let forged: SocketAddr = "192.0.2.1:1".parse().unwrap();
+        for t in &segments {
+            let mut data = t.data.clone();
+            client
+                .handle_datagram(forged, &mut data, Tick::from_ms(2))
+                .unwrap();
+        }
+        assert!(client.waiting_conv());
+        assert_eq!(client.recv(&mut buf, Tick::from_ms(3)).unwrap(), 0);
+        for t in segments {
+            let mut data = t.data;
+            client
+                .handle_datagram(server_addr, &mut data, Tick::from_ms(3))
+                .unwrap();
+        }
+        assert_eq!(client.recv(&mut buf, Tick::from_ms(4)).unwrap(), 2);
+        assert_eq!(&buf[..2], b"ok");
+    }
+
+    #[test]
+    fn test_core_busy_closes() {
+        let config = WkSessionConfig::default();
+        let peer: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut core = WkSessionCore::new(&config, peer, Tick::from_ms(0)).unwrap();
+        let mut busy = Control::Busy.encode(0).to_vec();
+        // サーバー以外からの Busy は無視する
+        let other: SocketAddr = "192.0.2.1:1".parse().unwrap();
+        core.handle_datagram(other, &mut busy, Tick::from_ms(1))
+            .unwrap();
+        assert!(!core.closed());
+        core.handle_datagram(peer, &mut busy, Tick::from_ms(1))
+            .unwrap();
+        assert!(core.closed());
+        assert_eq!(core.close_reason(), Some(CloseReason::ServerBusy));
+    }
+
+    #[test]
+    fn test_core_busy_ignored_once_established() {
+        let config = WkSessionConfig::default();
+        let server_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut client = WkSessionCore::new(&config, server_addr, Tick::from_ms(0)).unwrap();
+        let mut server =
+            WkSessionCore::new(&config, "127.0.0.1:2".parse().unwrap(), Tick::from_ms(0)).unwrap();
+        client.send(b"hi", Tick::from_ms(1)).unwrap();
+        let mut syn = client.poll_transmit().unwrap().data;
+        kcp::set_conv(&mut syn, 5);
+        server.input(&syn, Tick::from_ms(1)).unwrap();
+        while let Some(t) = server.poll_transmit() {
+            let mut data = t.data;
+            client
+                .handle_datagram(server_addr, &mut data, Tick::from_ms(1))
+                .unwrap();
+        }
+        assert_eq!(client.conv(), 5);
+        let mut busy = Control::Busy.encode(0).to_vec();
+        client
+            .handle_datagram(server_addr, &mut busy, Tick::from_ms(2))
+            .unwrap();
+        assert!(!client.closed());
+    }
+
+    #[test]
+    fn test_core_close_reaches_peer() {
+        let config = WkSessionConfig::default();
+        let server_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut client = WkSessionCore::new(&config, server_addr, Tick::from_ms(0)).unwrap();
+        let mut server =
+            WkSessionCore::new(&config, "127.0.0.1:2".parse().unwrap(), Tick::from_ms(0)).unwrap();
+        client.send(b"hi", Tick::from_ms(1)).unwrap();
+        let mut syn = client.poll_transmit().unwrap().data;
+        kcp::set_conv(&mut syn, 99);
+        server.input(&syn, Tick::from_ms(1)).unwrap();
+        // ACK を受けてクライアントが conv を知る
+        while let Some(t) = server.poll_transmit() {
+            let mut data = t.data;
+            client
+                .handle_datagram(server_addr, &mut data, Tick::from_ms(1))
+                .unwrap();
+        }
+        assert_eq!(client.conv(), 99);
+
+        server.close_with(CloseReason::EmergencyStop);
+        server.close_with(CloseReason::Shutdown);
+        let mut sent = 0;
+        while let Some(t) = server.poll_transmit() {
+            let mut data = t.data;
+            client
+                .handle_datagram(server_addr, &mut data, Tick::from_ms(2))
+                .unwrap();
+            sent += 1;
+        }
+        // 2 回目の close は何も送らない
+        assert_eq!(sent, CLOSE_REPEATS);
+        assert_eq!(server.close_reason(), Some(CloseReason::EmergencyStop));
+        assert!(client.closed());
+        assert_eq!(client.close_reason(), Some(CloseReason::EmergencyStop));
+    }
+
+    #[test]
+    fn test_core_unauthenticated_close_ignored() {
+        let config = WkSessionConfig::default();
+        let server_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut client = WkSessionCore::new(&config, server_addr, Tick::from_ms(0)).unwrap();
+        let mut server =
+            WkSessionCore::new(&config, "127.0.0.1:2".parse().unwrap(), Tick::from_ms(0)).unwrap();
+        client.set_cipher(SessionCipher::client(&[4u8; 32]));
+        server.set_cipher(SessionCipher::server(&[4u8; 32]));
+        client.send(b"hi", Tick::from_ms(1)).unwrap();
+        let mut syn = client.poll_transmit().unwrap().data;
+        kcp::set_conv(&mut syn, 9);
+        server.input(&syn, Tick::from_ms(1)).unwrap();
+        while let Some(t) = server.poll_transmit() {
+            let mut data = t.data;
+            client
+                .handle_datagram(server_addr, &mut data, Tick::from_ms(1))
+                .unwrap();
+        }
+        assert_eq!(client.conv(), 9);
+
+        // 送り元と conv を偽装しても、タグのない Close では切れない
+        let mut forged = Control::Close {
+            reason: CloseReason::Shutdown,
+            tag: None,
+        }
+        .encode(9)
+        .to_vec();
+        client
+            .handle_datagram(server_addr, &mut forged, Tick::from_ms(2))
+            .unwrap();
+        assert!(!client.closed());
+        assert!(!server.closed_by_peer(CloseReason::UserQuit, None));
+        assert!(!server.closed());
+
+        server.close_with(CloseReason::Shutdown);
+        let close = server.poll_transmit().unwrap().data;
+        // 理由を書き換えるとタグが合わない
+        let mut tampered = close.clone();
+        tampered[6] = CloseReason::UserQuit.code();
+        client
+            .handle_datagram(server_addr, &mut tampered, Tick::from_ms(3))
+            .unwrap();
+        assert!(!client.closed());
+        let mut close = close;
+        client
+            .handle_datagram(server_addr, &mut close, Tick::from_ms(3))
+            .unwrap();
+        assert_eq!(client.close_reason(), Some(CloseReason::Shutdown));
+    }
+
+    struct Idle;
+
+    impl ListenerSession for Idle {
+        fn closed(&self) -> bool {
+            false
+        }
+        fn handle_timeout(&self) -> Option<u32> {
+            None
+        }
+        fn input(&self, _pkt: &[u8]) -> Result<()> {
+            Ok(())
+        }
+        fn close(&self, _reason: CloseReason) {}
+        fn closed_by_peer(&self, _reason: CloseReason, _tag: Option<&[u8; CLOSE_TAG_LEN]>) -> bool {
+            false
+        }
+        fn migrate(&self, _: SocketAddr, _: u32, _: u64, _: &[u8; RESUME_TAG_LEN]) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_new_conv_unused_by_any_peer() {
+        let mut table = SessionTable::new(4);
+        table.insert("127.0.0.1:1".parse().unwrap(), 7, Idle);
+        table.insert("127.0.0.1:2".parse().unwrap(), 8, Idle);
+        // 別のアドレスの conv も、0 も使わない
+        let mut candidates = [0, 7, 8, 9].into_iter();
+        assert_eq!(table.unused_conv(|| candidates.next().unwrap()), 9);
+    }
+}
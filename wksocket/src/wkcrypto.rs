@@ -0,0 +1,313 @@
+use anyhow::{anyhow, bail, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+const CLIENT_TO_SERVER: &[u8] = b"wifikey2 session key c2s";
+const SERVER_TO_CLIENT: &[u8] = b"wifikey2 session key s2c";
+const STREAM_AAD: &[u8] = b"wifikey2 kcp";
+const DATAGRAM_CLIENT_TO_SERVER: &[u8] = b"wifikey2 datagram key c2s";
+const DATAGRAM_SERVER_TO_CLIENT: &[u8] = b"wifikey2 datagram key s2c";
+const RESUME_KEY: &[u8] = b"wifikey2 resume key";
+const CLOSE_CLIENT_TO_SERVER: &[u8] = b"wifikey2 close key c2s";
+const CLOSE_SERVER_TO_CLIENT: &[u8] = b"wifikey2 close key s2c";
+/// Length of the truncated HMAC carried by a resume request
+pub(crate) const RESUME_TAG_LEN: usize = 16;
+/// Length of the truncated HMAC carried by the close of an encrypted session
+pub(crate) const CLOSE_TAG_LEN: usize = 16;
+
+/// One direction of an encrypted session.
+///
+/// KCP delivers each message exactly once and in order, so the nonce is an
+/// implicit per-direction counter. A replayed, reordered or injected message
+/// uses the wrong nonce and fails authentication.
+struct CipherState {
+    aead: ChaCha20Poly1305,
+    counter: u64,
+}
+
+fn derive_aead(shared: &[u8; 32], label: &[u8]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(&derive_key(shared, label)))
+}
+
+fn derive_key(shared: &[u8; 32], label: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, shared)
+        .expand(label, &mut key)
+        .expect("valid HKDF length");
+    key
+}
+
+// 順序が保証されないデータグラムは送信側の連番をそのまま nonce にする
+fn datagram_nonce(seq: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&seq.to_le_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+impl CipherState {
+    fn new(shared: &[u8; 32], label: &[u8]) -> Self {
+        Self {
+            aead: derive_aead(shared, label),
+            counter: 0,
+        }
+    }
+
+    // 次の nonce。使ったら advance() で進める
+    fn nonce(&self) -> Result<Nonce> {
+        if self.counter == u64::MAX {
+            bail!("nonce exhausted");
+        }
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_le_bytes());
+        Ok(*Nonce::from_slice(&nonce))
+    }
+
+    fn advance(&mut self) {
+        self.counter += 1;
+    }
+}
+
+/// ChaCha20-Poly1305 state for the KCP payloads and datagrams of an authenticated session
+pub(crate) struct SessionCipher {
+    tx: CipherState,
+    rx: CipherState,
+    // データグラム用は別の鍵。nonce はパケットに載せた連番
+    datagram_tx: ChaCha20Poly1305,
+    datagram_rx: ChaCha20Poly1305,
+    // Close は KCP の外で届くので、方向ごとの鍵の HMAC を付ける
+    close_tx: [u8; 32],
+    close_rx: [u8; 32],
+}
+
+fn close_tag(key: &[u8; 32], conv: u32, reason: u8) -> [u8; CLOSE_TAG_LEN] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(&conv.to_le_bytes());
+    mac.update(&[reason]);
+    let mut tag = [0u8; CLOSE_TAG_LEN];
+    tag.copy_from_slice(&mac.finalize().into_bytes()[..CLOSE_TAG_LEN]);
+    tag
+}
+
+impl SessionCipher {
+    pub(crate) fn client(shared: &[u8; 32]) -> Self {
+        Self {
+            tx: CipherState::new(shared, CLIENT_TO_SERVER),
+            rx: CipherState::new(shared, SERVER_TO_CLIENT),
+            datagram_tx: derive_aead(shared, DATAGRAM_CLIENT_TO_SERVER),
+            datagram_rx: derive_aead(shared, DATAGRAM_SERVER_TO_CLIENT),
+            close_tx: derive_key(shared, CLOSE_CLIENT_TO_SERVER),
+            close_rx: derive_key(shared, CLOSE_SERVER_TO_CLIENT),
+        }
+    }
+
+    pub(crate) fn server(shared: &[u8; 32]) -> Self {
+        Self {
+            tx: CipherState::new(shared, SERVER_TO_CLIENT),
+            rx: CipherState::new(shared, CLIENT_TO_SERVER),
+            datagram_tx: derive_aead(shared, DATAGRAM_SERVER_TO_CLIENT),
+            datagram_rx: derive_aead(shared, DATAGRAM_CLIENT_TO_SERVER),
+            close_tx: derive_key(shared, CLOSE_SERVER_TO_CLIENT),
+            close_rx: derive_key(shared, CLOSE_CLIENT_TO_SERVER),
+        }
+    }
+
+    /// Seal `msg` and hand it to `send`. The nonce is only used up if
+    /// `send` succeeds, so a message KCP refuses leaves the peer in step.
+    pub(crate) fn seal_with<T>(
+        &mut self,
+        msg: &[u8],
+        send: impl FnOnce(&[u8]) -> Result<T>,
+    ) -> Result<T> {
+        let nonce = self.tx.nonce()?;
+        let sealed = self
+            .tx
+            .aead
+            .encrypt(&nonce, Payload { msg, aad: STREAM_AAD })
+            .map_err(|_| anyhow!("encryption failed"))?;
+        let sent = send(&sealed)?;
+        self.tx.advance();
+        Ok(sent)
+    }
+
+    pub(crate) fn open(&mut self, msg: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.rx.nonce()?;
+        self.rx.advance();
+        self.rx
+            .aead
+            .decrypt(&nonce, Payload { msg, aad: STREAM_AAD })
+            .map_err(|_| anyhow!("message authentication failed"))
+    }
+
+    /// Seal a datagram. `seq` must never repeat; replays are the caller's to reject.
+    pub(crate) fn seal_datagram(&self, seq: u64, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        self.datagram_tx
+            .encrypt(&datagram_nonce(seq), Payload { msg, aad })
+            .map_err(|_| anyhow!("encryption failed"))
+    }
+
+    pub(crate) fn open_datagram(&self, seq: u64, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        self.datagram_rx
+            .decrypt(&datagram_nonce(seq), Payload { msg, aad })
+            .map_err(|_| anyhow!("datagram authentication failed"))
+    }
+
+    /// Tag for a close datagram telling the peer `reason`.
+    pub(crate) fn close_tag(&self, conv: u32, reason: u8) -> [u8; CLOSE_TAG_LEN] {
+        close_tag(&self.close_tx, conv, reason)
+    }
+
+    /// Whether a close datagram came from the peer. A close carries no
+    /// counter: once accepted the session is gone, and a new session has a new key.
+    pub(crate) fn verify_close(&self, conv: u32, reason: u8, tag: &[u8; CLOSE_TAG_LEN]) -> bool {
+        bool::from(close_tag(&self.close_rx, conv, reason).ct_eq(tag))
+    }
+}
+
+/// Resumption ticket of an authenticated session.
+///
+/// `ticket` is the token returned by `challenge`/`response`. A resume request
+/// carries it with a strictly increasing counter and an HMAC under a key only
+/// the two peers know, so a captured request can't be replayed.
+pub(crate) struct ResumeTicket {
+    ticket: u32,
+    key: [u8; 32],
+    counter: u64,
+}
+
+impl ResumeTicket {
+    pub(crate) fn new(ticket: u32, shared: &[u8; 32]) -> Self {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, shared)
+            .expand(RESUME_KEY, &mut key)
+            .expect("valid HKDF length");
+        Self {
+            ticket,
+            key,
+            counter: 0,
+        }
+    }
+
+    fn tag(&self, conv: u32, counter: u64) -> [u8; RESUME_TAG_LEN] {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(&conv.to_le_bytes());
+        mac.update(&self.ticket.to_be_bytes());
+        mac.update(&counter.to_be_bytes());
+        let mut tag = [0u8; RESUME_TAG_LEN];
+        tag.copy_from_slice(&mac.finalize().into_bytes()[..RESUME_TAG_LEN]);
+        tag
+    }
+
+    /// Client side: next (ticket, counter, tag) to send from a new address.
+    pub(crate) fn next(&mut self, conv: u32) -> (u32, u64, [u8; RESUME_TAG_LEN]) {
+        self.counter += 1;
+        (self.ticket, self.counter, self.tag(conv, self.counter))
+    }
+
+    /// Server side: accept a request only once and only with a valid tag.
+    pub(crate) fn verify(
+        &mut self,
+        conv: u32,
+        ticket: u32,
+        counter: u64,
+        tag: &[u8; RESUME_TAG_LEN],
+    ) -> bool {
+        if ticket != self.ticket || counter <= self.counter {
+            return false;
+        }
+        if !bool::from(self.tag(conv, counter).ct_eq(tag)) {
+            return false;
+        }
+        self.counter = counter;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seal(cipher: &mut SessionCipher, msg: &[u8]) -> Vec<u8> {
+        cipher.seal_with(msg, |sealed| Ok(sealed.to_vec())).unwrap()
+    }
+
+    #[test]
+    fn test_seal_open_roundtrip() {
+        let shared = [3u8; 32];
+        let mut client = SessionCipher::client(&shared);
+        let mut server = SessionCipher::server(&shared);
+
+        let sealed = seal(&mut client, b"keydown");
+        // Poly1305 tag (16 bytes) is appended
+        assert_eq!(sealed.len(), 7 + 16);
+        assert_eq!(server.open(&sealed).unwrap(), b"keydown");
+
+        let sealed = seal(&mut server, b"pong");
+        assert_eq!(client.open(&sealed).unwrap(), b"pong");
+    }
+
+    #[test]
+    fn test_replay_rejected() {
+        let shared = [3u8; 32];
+        let mut client = SessionCipher::client(&shared);
+        let mut server = SessionCipher::server(&shared);
+
+        let first = seal(&mut client, b"edge");
+        server.open(&first).unwrap();
+        assert!(server.open(&first).is_err());
+    }
+
+    #[test]
+    fn test_tamper_and_reflection_rejected() {
+        let shared = [3u8; 32];
+        let mut client = SessionCipher::client(&shared);
+        let mut server = SessionCipher::server(&shared);
+
+        let mut sealed = seal(&mut client, b"edge");
+        // 自分宛てに反射されたメッセージは方向鍵が違うので開けない
+        assert!(client.open(&sealed).is_err());
+        sealed[0] ^= 1;
+        assert!(server.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_close_tag() {
+        let client = SessionCipher::client(&[3u8; 32]);
+        let server = SessionCipher::server(&[3u8; 32]);
+        let tag = server.close_tag(7, 5);
+        assert!(client.verify_close(7, 5, &tag));
+        // conv や理由を書き換えたもの、反射されたものは通らない
+        assert!(!client.verify_close(8, 5, &tag));
+        assert!(!client.verify_close(7, 1, &tag));
+        assert!(!server.verify_close(7, 5, &tag));
+        let other = SessionCipher::server(&[4u8; 32]);
+        assert!(!client.verify_close(7, 5, &other.close_tag(7, 5)));
+    }
+
+    #[test]
+    fn test_resume_ticket() {
+        let shared = [5u8; 32];
+        let mut client = ResumeTicket::new(42, &shared);
+        let mut server = ResumeTicket::new(42, &shared);
+
+        let (ticket, counter, tag) = client.next(7);
+        assert!(!server.verify(8, ticket, counter, &tag));
+        assert!(server.verify(7, ticket, counter, &tag));
+        // 同じリクエストの再送（リプレイ）は受け付けない
+        assert!(!server.verify(7, ticket, counter, &tag));
+
+        let (ticket, counter, tag) = client.next(7);
+        assert!(!server.verify(7, ticket + 1, counter, &tag));
+        assert!(server.verify(7, ticket, counter, &tag));
+
+        // 別の鍵で作ったリクエストはカウンタが進んでいても拒否
+        let mut other = ResumeTicket::new(42, &[6u8; 32]);
+        other.counter = 10;
+        let (ticket, counter, tag) = other.next(7);
+        assert!(!server.verify(7, ticket, counter, &tag));
+    }
+}
@@ -0,0 +1,197 @@
+use crate::wkcrypto::SessionCipher;
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut};
+use std::collections::VecDeque;
+
+// 制御パケット (0xF0) と同じく KCP が使わないコマンド値で識別する
+const DATAGRAM_CMD: u8 = 0xF1;
+// conv(4) + cmd(1) + seq(8)
+const DATAGRAM_HEADER: usize = 13;
+// Poly1305 タグ
+const TAG_LEN: usize = 16;
+
+/// True if `pkt` is a frame of the unreliable channel rather than a KCP segment.
+pub(crate) fn is_datagram(pkt: &[u8]) -> bool {
+    pkt.len() >= DATAGRAM_HEADER + TAG_LEN && pkt[4] == DATAGRAM_CMD
+}
+
+/// Sending half of the unreliable channel.
+///
+/// Each frame carries a sequence number and repeats the previous payloads,
+/// newest first, so an isolated loss is covered by the next frame instead
+/// of stalling later payloads behind a retransmission.
+pub(crate) struct DatagramSender {
+    seq: u64,
+    redundancy: usize,
+    history: VecDeque<Vec<u8>>,
+}
+
+impl DatagramSender {
+    /// `redundancy` is the number of payloads per frame, including the new one.
+    pub(crate) fn new(redundancy: usize) -> Self {
+        Self {
+            seq: 0,
+            redundancy: redundancy.clamp(1, u8::MAX as usize),
+            history: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn frame(
+        &mut self,
+        conv: u32,
+        payload: &[u8],
+        mtu: usize,
+        cipher: &SessionCipher,
+    ) -> Result<Vec<u8>> {
+        // count(1) + len(1) + payload が 1 つも入らなければ送れない
+        let budget = mtu.saturating_sub(DATAGRAM_HEADER + TAG_LEN);
+        if payload.len() > u8::MAX as usize || 2 + payload.len() > budget {
+            bail!("datagram too large: {} bytes", payload.len());
+        }
+        self.history.push_front(payload.to_vec());
+        self.history.truncate(self.redundancy);
+
+        // 新しい順に詰め、MTU に収まらない古いものは落とす
+        let mut plain = vec![0u8];
+        let mut count = 0u8;
+        for p in &self.history {
+            if plain.len() + 1 + p.len() > budget {
+                break;
+            }
+            plain.put_u8(p.len() as u8);
+            plain.put_slice(p);
+            count += 1;
+        }
+        plain[0] = count;
+
+        self.seq += 1;
+        let mut frame = Vec::with_capacity(DATAGRAM_HEADER + plain.len() + TAG_LEN);
+        frame.put_u32_le(conv);
+        frame.put_u8(DATAGRAM_CMD);
+        frame.put_u64(self.seq);
+        // ヘッダーも認証して conv や連番の書き換えを防ぐ
+        let sealed = cipher.seal_datagram(self.seq, &plain, &frame)?;
+        frame.extend_from_slice(&sealed);
+        Ok(frame)
+    }
+}
+
+/// Receiving half of the unreliable channel: opens frames and drops
+/// payloads that were already delivered.
+#[derive(Default)]
+pub(crate) struct DatagramReceiver {
+    last: u64,
+}
+
+impl DatagramReceiver {
+    /// Payloads of `pkt` not delivered before, oldest first.
+    ///
+    /// Frames older than the newest one seen are dropped whole, so duplicates,
+    /// replays and late arrivals never reorder what was delivered.
+    pub(crate) fn accept(&mut self, pkt: &[u8], cipher: &SessionCipher) -> Result<Vec<Vec<u8>>> {
+        if !is_datagram(pkt) {
+            bail!("not a datagram");
+        }
+        let (header, sealed) = pkt.split_at(DATAGRAM_HEADER);
+        let seq = (&header[5..]).get_u64();
+        if seq <= self.last {
+            return Ok(Vec::new());
+        }
+        let plain = cipher.open_datagram(seq, sealed, header)?;
+
+        let mut buf = &plain[..];
+        if !buf.has_remaining() {
+            bail!("empty datagram");
+        }
+        let count = buf.get_u8() as u64;
+        let mut payloads = Vec::new();
+        for i in 0..count {
+            if !buf.has_remaining() {
+                bail!("truncated datagram");
+            }
+            let len = buf.get_u8() as usize;
+            if buf.remaining() < len {
+                bail!("truncated datagram");
+            }
+            if seq.checked_sub(i).is_some_and(|s| s > self.last) {
+                payloads.push(buf[..len].to_vec());
+            }
+            buf.advance(len);
+        }
+        payloads.reverse();
+        self.last = seq;
+        Ok(payloads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (SessionCipher, SessionCipher) {
+        let shared = [4u8; 32];
+        (
+            SessionCipher::client(&shared),
+            SessionCipher::server(&shared),
+        )
+    }
+
+    #[test]
+    fn test_redundancy_covers_a_lost_frame() {
+        let (client, server) = pair();
+        let mut tx = DatagramSender::new(3);
+        let mut rx = DatagramReceiver::default();
+
+        let first = tx.frame(1, b"a", 512, &client).unwrap();
+        let _lost = tx.frame(1, b"b", 512, &client).unwrap();
+        let third = tx.frame(1, b"c", 512, &client).unwrap();
+        assert_eq!(rx.accept(&first, &server).unwrap(), vec![b"a".to_vec()]);
+        // 失われた b は次のフレームで届く
+        assert_eq!(
+            rx.accept(&third, &server).unwrap(),
+            vec![b"b".to_vec(), b"c".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_duplicates_and_late_frames_dropped() {
+        let (client, server) = pair();
+        let mut tx = DatagramSender::new(2);
+        let mut rx = DatagramReceiver::default();
+
+        let first = tx.frame(1, b"a", 512, &client).unwrap();
+        let second = tx.frame(1, b"b", 512, &client).unwrap();
+        assert_eq!(rx.accept(&second, &server).unwrap().len(), 2);
+        assert!(rx.accept(&second, &server).unwrap().is_empty());
+        assert!(rx.accept(&first, &server).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_mtu_drops_old_payloads() {
+        let (client, server) = pair();
+        let mut tx = DatagramSender::new(3);
+        let mut rx = DatagramReceiver::default();
+        let mtu = DATAGRAM_HEADER + TAG_LEN + 1 + 2 * 101;
+
+        tx.frame(1, &[1u8; 100], mtu, &client).unwrap();
+        tx.frame(1, &[2u8; 100], mtu, &client).unwrap();
+        let frame = tx.frame(1, &[3u8; 100], mtu, &client).unwrap();
+        assert!(frame.len() <= mtu);
+        assert_eq!(rx.accept(&frame, &server).unwrap().len(), 2);
+        assert!(tx.frame(1, &[0u8; 255], mtu, &client).is_err());
+    }
+
+    #[test]
+    fn test_tampered_and_reflected_frames_rejected() {
+        let (client, server) = pair();
+        let mut tx = DatagramSender::new(1);
+        let mut rx = DatagramReceiver::default();
+
+        let mut frame = tx.frame(1, b"a", 512, &client).unwrap();
+        // 自分宛てに反射されたフレームは方向鍵が違う
+        assert!(DatagramReceiver::default().accept(&frame, &client).is_err());
+        // 連番を書き換えると認証に失敗する（古い連番にすると認証の前に捨てられる）
+        frame[11] ^= 1;
+        assert!(rx.accept(&frame, &server).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt;
+use std::io;
+use wkproto::{AuthError, CloseReason, EncodeError};
+
+/// Why a call on a session, listener or the handshake failed.
+///
+/// The variants are what callers need to choose a retry policy: wait and try
+/// again after a `Timeout`, back off or lock out after `AuthFailed`, start a
+/// new session after `Closed`. Internals still use `anyhow`; a `WkError`
+/// raised inside comes back out unchanged, and any other error surfaces as
+/// `Protocol`.
+#[derive(Debug)]
+pub enum WkError {
+    /// Nothing arrived in time
+    Timeout,
+    /// The password didn't match, or the server couldn't prove it knows it
+    AuthFailed,
+    /// The session is closed. `reason` is None if nobody said why
+    Closed { reason: Option<CloseReason> },
+    /// The peer sent something malformed or not allowed here
+    Protocol(String),
+    /// The UDP socket failed
+    Io(io::Error),
+    /// More key edges than one legacy packet holds
+    TooManySlots { slots: usize, max: usize },
+    /// A message larger than the buffer or channel it is meant for
+    TooLarge(usize),
+    /// A thread panicked while holding the session
+    Poisoned,
+    /// The listener has stopped accepting sessions
+    ListenerClosed,
+}
+
+/// `Result` of the public wksocket API
+pub type WkResult<T> = std::result::Result<T, WkError>;
+
+impl WkError {
+    pub(crate) fn protocol(msg: impl Into<String>) -> Self {
+        WkError::Protocol(msg.into())
+    }
+}
+
+impl fmt::Display for WkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WkError::Timeout => write!(f, "timed out"),
+            WkError::AuthFailed => write!(f, "authentication failed"),
+            WkError::Closed {
+                reason: Some(reason),
+            } => write!(f, "session closed: {reason:?}"),
+            WkError::Closed { reason: None } => write!(f, "session closed"),
+            WkError::Protocol(msg) => write!(f, "protocol error: {msg}"),
+            WkError::Io(e) => write!(f, "I/O error: {e}"),
+            WkError::TooManySlots { slots, max } => write!(f, "too many slots: {slots} > {max}"),
+            WkError::TooLarge(len) => write!(f, "message too large: {len} bytes"),
+            WkError::Poisoned => write!(f, "mutex poisoned"),
+            WkError::ListenerClosed => write!(f, "listener closed"),
+        }
+    }
+}
+
+impl std::error::Error for WkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WkError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WkError {
+    fn from(e: io::Error) -> Self {
+        WkError::Io(e)
+    }
+}
+
+impl From<EncodeError> for WkError {
+    fn from(e: EncodeError) -> Self {
+        match e {
+            EncodeError::TooManySlots { slots, max } => WkError::TooManySlots { slots, max },
+            EncodeError::FieldTooLong { len, .. } => WkError::TooLarge(len),
+        }
+    }
+}
+
+impl From<AuthError> for WkError {
+    fn from(e: AuthError) -> Self {
+        WkError::Protocol(e.to_string())
+    }
+}
+
+impl From<anyhow::Error> for WkError {
+    fn from(e: anyhow::Error) -> Self {
+        // 内部で立てた WkError はそのまま取り出す
+        let e = match e.downcast::<WkError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<EncodeError>() {
+            Ok(e) => return e.into(),
+            Err(e) => e,
+        };
+        match e.downcast::<io::Error>() {
+            Ok(e) => WkError::Io(e),
+            Err(e) => WkError::Protocol(format!("{e:#}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_survives_anyhow() {
+        let inner: anyhow::Error = WkError::Closed {
+            reason: Some(CloseReason::ServerBusy),
+        }
+        .into();
+        assert!(matches!(
+            WkError::from(inner.context("recv")),
+            WkError::Closed {
+                reason: Some(CloseReason::ServerBusy)
+            }
+        ));
+        let io: anyhow::Error = io::Error::from(io::ErrorKind::ConnectionRefused).into();
+        assert!(matches!(WkError::from(io), WkError::Io(_)));
+        let other = WkError::from(anyhow::anyhow!("malformed hello"));
+        assert_eq!(other.to_string(), "protocol error: malformed hello");
+    }
+}
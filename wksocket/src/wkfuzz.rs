@@ -0,0 +1,94 @@
+//! Entry points for the cargo-fuzz targets in `wksocket/fuzz`. Not a stable API.
+
+use crate::wkauth::{challenge_on, response_on, AuthChannel};
+use crate::wkconfig::WkSessionConfig;
+use crate::wkcrypto::{ResumeTicket, SessionCipher};
+use crate::wkerror::{WkError, WkResult};
+use crate::wkhello::Hello;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+use wkproto::{decode_message, AuthVerifier};
+
+const PASSWD: &str = "fuzz";
+// 相手が指定する PBKDF2 の回数はこれに丸め、1 回の実行を速く保つ
+const MAX_FUZZ_ITERATIONS: u32 = 16;
+
+// 入力を相手からのメッセージ列として返し、送信は捨てる認証路
+struct Scripted {
+    msgs: RefCell<VecDeque<Vec<u8>>>,
+    config: WkSessionConfig,
+}
+
+impl Scripted {
+    // [len(1)][msg] の繰り返しに分ける
+    fn new(mut data: &[u8]) -> Self {
+        let mut msgs = VecDeque::new();
+        while let Some((&len, rest)) = data.split_first() {
+            let len = (len as usize).min(rest.len());
+            msgs.push_back(rest[..len].to_vec());
+            data = &rest[len..];
+        }
+        Self {
+            msgs: RefCell::new(msgs),
+            config: WkSessionConfig::default(),
+        }
+    }
+}
+
+impl AuthChannel for Scripted {
+    fn send(&self, buf: &[u8]) -> WkResult<usize> {
+        Ok(buf.len())
+    }
+
+    fn recv_timeout(&self, buf: &mut [u8], _timeout: u32) -> WkResult<usize> {
+        let Some(msg) = self.msgs.borrow_mut().pop_front() else {
+            return Err(WkError::Timeout);
+        };
+        let n = msg.len().min(buf.len());
+        buf[..n].copy_from_slice(&msg[..n]);
+        Ok(n)
+    }
+
+    fn set_cipher(&self, _cipher: SessionCipher) -> WkResult<()> {
+        Ok(())
+    }
+
+    fn set_resume(&self, _ticket: ResumeTicket) -> WkResult<()> {
+        Ok(())
+    }
+
+    fn set_peer_hello(&self, _hello: Hello) -> WkResult<()> {
+        Ok(())
+    }
+
+    fn config(&self) -> &WkSessionConfig {
+        &self.config
+    }
+}
+
+/// Decode one received packet; must never panic.
+pub fn decode(data: &[u8]) {
+    let _ = decode_message(data);
+}
+
+/// Run the server side of authentication against client messages in `data`.
+pub fn challenge(data: &[u8]) {
+    static VERIFIER: OnceLock<AuthVerifier> = OnceLock::new();
+    let verifier =
+        VERIFIER.get_or_init(|| AuthVerifier::with_salt(PASSWD, [0u8; 16], MAX_FUZZ_ITERATIONS));
+    let _ = challenge_on(&Scripted::new(data), verifier, Some(PASSWD));
+}
+
+/// Run the client side of authentication against server messages in `data`.
+pub fn response(data: &[u8]) {
+    let channel = Scripted::new(data);
+    if let Some(params) = channel.msgs.borrow_mut().front_mut() {
+        // [magic][version][iterations(4)]...
+        if let Some(iterations) = params.get_mut(2..6) {
+            let n = u32::from_be_bytes(iterations.try_into().unwrap());
+            iterations.copy_from_slice(&n.min(MAX_FUZZ_ITERATIONS).to_be_bytes());
+        }
+    }
+    let _ = response_on(&channel, PASSWD, true);
+}
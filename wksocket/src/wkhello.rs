@@ -0,0 +1,117 @@
+use crate::wkauth::AuthChannel;
+use crate::wkerror::WkResult;
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use log::info;
+use wkproto::{Capabilities, PKT_SIZE, PROTOCOL_VERSION};
+
+// 認証要求と認証結果の末尾に付けて、hello を交換できることを互いに知らせる。
+// 旧版は末尾の余分なバイトを読まないので影響しない
+pub(crate) const HELLO_OFFER: u8 = 0x48;
+const HELLO_MAGIC: u8 = 0x48;
+// magic(1) + protocol(2) + capabilities(4) + software length(1)
+const HELLO_HEADER: usize = 8;
+const MAX_SOFTWARE_LEN: usize = 32;
+
+/// What a peer announced about itself right after authentication.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol: u16,
+    /// Firmware or server name and version, e.g. "wifikey 0.3.9"
+    pub software: String,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub(crate) fn encode(&self) -> BytesMut {
+        // 長すぎる名前は切り詰める（文字の途中では切らない）
+        let mut len = self.software.len().min(MAX_SOFTWARE_LEN);
+        while !self.software.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut buf = BytesMut::with_capacity(HELLO_HEADER + len);
+        buf.put_u8(HELLO_MAGIC);
+        buf.put_u16(self.protocol);
+        buf.put_u32(self.capabilities.bits());
+        buf.put_u8(len as u8);
+        buf.put_slice(&self.software.as_bytes()[..len]);
+        buf
+    }
+
+    /// Trailing bytes are left for fields added by later protocol versions.
+    pub(crate) fn decode(pkt: &[u8]) -> Result<Self> {
+        if pkt.len() < HELLO_HEADER || pkt[0] != HELLO_MAGIC {
+            bail!("malformed hello");
+        }
+        let mut buf = &pkt[1..];
+        let protocol = buf.get_u16();
+        let capabilities = Capabilities::from_bits(buf.get_u32());
+        let len = buf.get_u8() as usize;
+        if buf.remaining() < len {
+            bail!("truncated hello");
+        }
+        let software = String::from_utf8_lossy(&buf[..len]).into_owned();
+        Ok(Self {
+            protocol,
+            software,
+            capabilities,
+        })
+    }
+}
+
+/// Send our hello and wait for the peer's. Both sides call this once the
+/// session is encrypted, before any other message.
+pub(crate) fn exchange(session: &impl AuthChannel) -> WkResult<Hello> {
+    let config = session.config();
+    let ours = Hello {
+        protocol: PROTOCOL_VERSION,
+        software: config.software.clone(),
+        capabilities: config.capabilities,
+    };
+    session.send(&ours.encode())?;
+
+    let mut buf = [0u8; PKT_SIZE];
+    let timeout = config.auth_timeout.as_millis() as u32;
+    let n = session.recv_timeout(&mut buf, timeout)?;
+    let peer = Hello::decode(&buf[..n])?;
+    info!(
+        "peer {} protocol {} capabilities {:?}",
+        peer.software, peer.protocol, peer.capabilities
+    );
+    session.set_peer_hello(peer.clone())?;
+    Ok(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello_roundtrip() {
+        let hello = Hello {
+            protocol: PROTOCOL_VERSION,
+            software: "wifikey 0.3.9".to_string(),
+            capabilities: Capabilities::BASELINE | Capabilities::ENCODER_EVENTS,
+        };
+        let pkt = hello.encode();
+        assert_eq!(Hello::decode(&pkt).unwrap(), hello);
+        // 後の版が足したフィールドは読み飛ばす
+        let mut longer = pkt.to_vec();
+        longer.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(Hello::decode(&longer).unwrap(), hello);
+        assert!(Hello::decode(&pkt[..pkt.len() - 1]).is_err());
+        assert!(Hello::decode(&[0u8; HELLO_HEADER]).is_err());
+    }
+
+    #[test]
+    fn test_hello_truncates_long_software() {
+        let hello = Hello {
+            protocol: PROTOCOL_VERSION,
+            software: "é".repeat(MAX_SOFTWARE_LEN),
+            capabilities: Capabilities::empty(),
+        };
+        let decoded = Hello::decode(&hello.encode()).unwrap();
+        assert!(decoded.software.len() <= MAX_SOFTWARE_LEN);
+        assert!(hello.software.starts_with(&decoded.software));
+    }
+}
@@ -0,0 +1,402 @@
+use anyhow::Result;
+use log::{info, trace};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{Ordering as CmpOrdering, Reverse};
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap};
+use std::f64::consts::PI;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// UDP の最大ペイロード
+const MAX_DATAGRAM: usize = 65536;
+
+/// One-way delay added to every datagram passing an `ImpairProxy`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+    /// Normal distribution, clamped at zero
+    Normal {
+        mean: Duration,
+        std_dev: Duration,
+    },
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        match *self {
+            Latency::Fixed(delay) => delay,
+            Latency::Uniform { min, max } => {
+                min + max.saturating_sub(min).mul_f64(rng.random::<f64>())
+            }
+            Latency::Normal { mean, std_dev } => {
+                // Box-Muller 変換
+                let u1 = rng.random::<f64>().max(f64::MIN_POSITIVE);
+                let u2 = rng.random::<f64>();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+                let secs = mean.as_secs_f64() + z * std_dev.as_secs_f64();
+                Duration::from_secs_f64(secs.max(0.0))
+            }
+        }
+    }
+}
+
+/// Impairments applied by an `ImpairProxy` to both directions.
+///
+/// The default passes everything through untouched. Probabilities are
+/// fractions between 0.0 and 1.0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImpairConfig {
+    pub(crate) loss: f64,
+    pub(crate) latency: Latency,
+    pub(crate) reorder: f64,
+    pub(crate) reorder_window: Duration,
+    pub(crate) duplicate: f64,
+    pub(crate) bandwidth: Option<u64>,
+    pub(crate) seed: u64,
+}
+
+impl Default for ImpairConfig {
+    fn default() -> Self {
+        Self {
+            loss: 0.0,
+            latency: Latency::Fixed(Duration::ZERO),
+            reorder: 0.0,
+            reorder_window: Duration::ZERO,
+            duplicate: 0.0,
+            bandwidth: None,
+            seed: 0,
+        }
+    }
+}
+
+impl ImpairConfig {
+    /// Drop this fraction of datagrams
+    pub fn loss(mut self, loss: f64) -> Self {
+        self.loss = loss.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn latency(mut self, latency: Latency) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Hold back this fraction of datagrams for up to `window` more, so later ones overtake them
+    pub fn reorder(mut self, reorder: f64, window: Duration) -> Self {
+        self.reorder = reorder.clamp(0.0, 1.0);
+        self.reorder_window = window;
+        self
+    }
+
+    /// Deliver this fraction of datagrams twice
+    pub fn duplicate(mut self, duplicate: f64) -> Self {
+        self.duplicate = duplicate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Serialize each direction at this many bytes per second
+    pub fn bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        self.bandwidth = Some(bytes_per_sec.max(1));
+        self
+    }
+
+    /// Seed of the random decisions, so a failing run can be repeated
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// What an `ImpairProxy` did so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImpairStats {
+    pub forwarded: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    ToServer,
+    ToClient,
+}
+
+struct Pending {
+    due: Instant,
+    // 同じ時刻なら到着順に送る
+    order: u64,
+    direction: Direction,
+    client: SocketAddr,
+    data: Vec<u8>,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.order) == (other.due, other.order)
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (self.due, self.order).cmp(&(other.due, other.order))
+    }
+}
+
+struct Impairer {
+    config: ImpairConfig,
+    rng: StdRng,
+    queue: BinaryHeap<Reverse<Pending>>,
+    order: u64,
+    // 帯域制限で次の送信を始められる時刻（方向ごと）
+    link_free: [Instant; 2],
+    stats: ImpairStats,
+}
+
+impl Impairer {
+    fn new(config: ImpairConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            queue: BinaryHeap::new(),
+            order: 0,
+            link_free: [now; 2],
+            stats: ImpairStats::default(),
+        }
+    }
+
+    fn set_config(&mut self, config: ImpairConfig) {
+        self.rng = StdRng::seed_from_u64(config.seed);
+        self.config = config;
+    }
+
+    fn schedule(&mut self, direction: Direction, client: SocketAddr, data: &[u8], now: Instant) {
+        if self.rng.random_bool(self.config.loss) {
+            self.stats.dropped += 1;
+            return;
+        }
+        let copies = if self.rng.random_bool(self.config.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut depart = now;
+            if let Some(bandwidth) = self.config.bandwidth {
+                let link_free = &mut self.link_free[direction as usize];
+                let start = (*link_free).max(now);
+                depart = start + Duration::from_secs_f64(data.len() as f64 / bandwidth as f64);
+                *link_free = depart;
+            }
+            let mut due = depart + self.config.latency.sample(&mut self.rng);
+            if self.rng.random_bool(self.config.reorder) {
+                self.stats.reordered += 1;
+                due += self.config.reorder_window.mul_f64(self.rng.random::<f64>());
+            }
+            self.order += 1;
+            self.queue.push(Reverse(Pending {
+                due,
+                order: self.order,
+                direction,
+                client,
+                data: data.to_vec(),
+            }));
+        }
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Option<Pending> {
+        if self.queue.peek()?.0.due > now {
+            return None;
+        }
+        self.stats.forwarded += 1;
+        self.queue.pop().map(|Reverse(p)| p)
+    }
+}
+
+/// UDP proxy on localhost that loses, delays, reorders, duplicates and
+/// throttles datagrams between clients and a server.
+///
+/// Clients talk to `addr()` instead of the server; each client gets its own
+/// upstream socket, so the server still sees one address per client.
+/// Meant for tests of sessions on a bad link without real bad WiFi.
+pub struct ImpairProxy {
+    addr: SocketAddr,
+    impairer: Arc<Mutex<Impairer>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for ImpairProxy {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl ImpairProxy {
+    pub fn start(server: SocketAddr, config: ImpairConfig) -> Result<Self> {
+        let local: SocketAddr = if server.is_ipv4() {
+            "127.0.0.1:0".parse()?
+        } else {
+            "[::1]:0".parse()?
+        };
+        let front = UdpSocket::bind(local)?;
+        front.set_nonblocking(true)?;
+        let addr = front.local_addr()?;
+        let impairer = Arc::new(Mutex::new(Impairer::new(config)));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let impairer = impairer.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut buf = vec![0u8; MAX_DATAGRAM];
+                let mut upstream: HashMap<SocketAddr, UdpSocket> = HashMap::new();
+                while !stop.load(Ordering::Relaxed) {
+                    let Ok(mut imp) = impairer.lock() else {
+                        break;
+                    };
+                    while let Ok((n, client)) = front.recv_from(&mut buf) {
+                        if let Entry::Vacant(e) = upstream.entry(client) {
+                            let Ok(back) = UdpSocket::bind(local) else {
+                                continue;
+                            };
+                            let _ = back.set_nonblocking(true);
+                            trace!("impair: new client {client}");
+                            e.insert(back);
+                        }
+                        imp.schedule(Direction::ToServer, client, &buf[..n], Instant::now());
+                    }
+                    for (client, back) in &upstream {
+                        while let Ok((n, src)) = back.recv_from(&mut buf) {
+                            if src == server {
+                                imp.schedule(
+                                    Direction::ToClient,
+                                    *client,
+                                    &buf[..n],
+                                    Instant::now(),
+                                );
+                            }
+                        }
+                    }
+                    while let Some(p) = imp.pop_due(Instant::now()) {
+                        let res = match p.direction {
+                            Direction::ToServer => match upstream.get(&p.client) {
+                                Some(back) => back.send_to(&p.data, server),
+                                None => continue,
+                            },
+                            Direction::ToClient => front.send_to(&p.data, p.client),
+                        };
+                        if let Err(e) = res {
+                            trace!("impair: send error {e}");
+                        }
+                    }
+                    drop(imp);
+                    thread::sleep(Duration::from_millis(1));
+                }
+                info!("impair proxy stopped.");
+            })
+        };
+
+        Ok(Self {
+            addr,
+            impairer,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// Address clients should send to instead of the server's
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Change the impairments, e.g. to start an outage. Datagrams already
+    /// in flight keep their schedule; the RNG is reseeded.
+    pub fn set_config(&self, config: ImpairConfig) {
+        if let Ok(mut imp) = self.impairer.lock() {
+            imp.set_config(config);
+        }
+    }
+
+    pub fn stats(&self) -> ImpairStats {
+        self.impairer
+            .lock()
+            .map(|imp| imp.stats)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_samples_in_range() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let uniform = Latency::Uniform {
+            min: Duration::from_millis(10),
+            max: Duration::from_millis(20),
+        };
+        let normal = Latency::Normal {
+            mean: Duration::from_millis(5),
+            std_dev: Duration::from_millis(10),
+        };
+        for _ in 0..1000 {
+            let d = uniform.sample(&mut rng);
+            assert!(d >= Duration::from_millis(10) && d <= Duration::from_millis(20));
+            // 負の遅延は 0 に丸める
+            let _ = normal.sample(&mut rng);
+        }
+    }
+
+    #[test]
+    fn test_schedule_is_reproducible_and_throttled() {
+        let client: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let config = ImpairConfig::default()
+            .loss(0.3)
+            .duplicate(0.2)
+            .bandwidth(1000)
+            .seed(7);
+        let now = Instant::now();
+        let run = || {
+            let mut imp = Impairer::new(config.clone());
+            // new() が取った現在時刻ではなく now から帯域を数える
+            imp.link_free = [now; 2];
+            for _ in 0..100 {
+                imp.schedule(Direction::ToServer, client, &[0u8; 10], now);
+            }
+            imp
+        };
+        let (a, b) = (run(), run());
+        assert_eq!(a.stats, b.stats);
+        assert!(a.stats.dropped > 0 && a.stats.duplicated > 0);
+        // 1000 B/s で 10 バイトずつなので 1 個あたり 10ms ずつ後ろにずれる
+        let sent = a.queue.len() as u32;
+        assert_eq!(
+            a.link_free[Direction::ToServer as usize] - now,
+            Duration::from_millis(10) * sent
+        );
+    }
+}
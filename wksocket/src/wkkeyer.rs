@@ -0,0 +1,661 @@
+use crate::wkerror::WkError;
+use crate::wkmessage::WkReceiver;
+use crate::wksched::{EdgeErrorHistogram, KeyScheduler, Played};
+use crate::wktime::SharedClock;
+use log::{info, trace};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use wkproto::{
+    CloseReason, Jitter, JitterBuffer, JitterBufferConfig, MessageRCV, Playout, SpeedEstimate,
+    SpeedEstimator, Tick, MAX_ASSERT_DURATION, RESYNC_INTERVAL,
+};
+
+// ウォッチドッグの見回り間隔
+const WATCHDOG_INTERVAL: u32 = 1000;
+// メッセージが来なくても、鳴らした打鍵を知らせ tick を呼び停止に気づく間隔
+const POLL_INTERVAL: u32 = 20;
+// ESP32 でも載る大きさ。ホストでは最小値に切り上げられる
+const WATCHDOG_STACK: usize = 4096;
+
+/// The line a `KeyPlayer` keys, such as a GPIO pin or the rig's serial port.
+pub trait KeyOutput: Send {
+    fn set_key(&mut self, down: bool);
+}
+
+/// Keying statistics, reported at each resync.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct KeyerStats {
+    /// Character speed of the recent keying, 0 if the key has not moved
+    /// since the previous resync
+    pub wpm: f32,
+    /// Speed, weighting and spacing of the recent keying, None if the key
+    /// has not moved since the previous resync
+    pub speed: Option<SpeedEstimate>,
+    /// Messages per second since the previous resync, assuming the client's
+    /// 3 s Sync interval
+    pub pkt: usize,
+    /// Interarrival jitter of the Syncs in milliseconds
+    pub jitter_ms: u32,
+    /// Shortest round trip of the Ping/Pong exchanges in milliseconds,
+    /// None until the client answers with its own timestamps
+    pub delay_ms: Option<u32>,
+    /// How much faster the client's clock runs, in parts per million
+    pub skew_ppm: i32,
+    /// Playout delay of the jitter buffer in milliseconds
+    pub playout_delay_ms: u32,
+    /// Edges that arrived after they were due, since the session started
+    pub late_edges: u32,
+    /// How late the edges were played against their schedule, since the
+    /// session started
+    pub edge_error: EdgeErrorHistogram,
+}
+
+/// What a `KeyPlayer` reports besides driving the key. Every method does
+/// nothing by default.
+pub trait KeyerHooks {
+    /// A Sync arrived at `now`. Called at most every `RESYNC_INTERVAL`,
+    /// with the stats since the previous call.
+    fn resynced(&mut self, _stats: &KeyerStats, _now: Tick) {}
+
+    /// The key went down or up at `now`. Edges are reported in order,
+    /// within a few tens of milliseconds of being played.
+    fn keyed(&mut self, _down: bool, _now: Tick) {}
+
+    /// Called every few tens of milliseconds while the player runs, after
+    /// the edges played so far have been reported, whether or not anything
+    /// arrived. Suits work that depends on time passing, such as ending a
+    /// decoded character after a gap.
+    fn tick(&mut self, _now: Tick) {}
+
+    /// A message that is not keying, such as StartATU, Pong or an encoder
+    /// event. `rx_port` lets the hook drain the messages that follow.
+    fn message(&mut self, _msg: MessageRCV, _rx_port: &WkReceiver) {}
+}
+
+impl KeyerHooks for () {}
+
+/// Plays received keying out on a `KeyOutput`.
+///
+/// Edges are played on the session clock at the client's spacing, see
+/// `Playout`, behind a `JitterBuffer`, by a scheduler thread of their own.
+/// A watchdog releases the key if it
+/// has been held longer than `MAX_ASSERT_DURATION`, and the key is released
+/// when the player stops or is dropped.
+pub struct KeyPlayer<O: KeyOutput> {
+    line: Arc<Mutex<KeyLine<O>>>,
+    stop: Arc<AtomicBool>,
+    buffer: JitterBufferConfig,
+    realtime: bool,
+}
+
+impl<O: KeyOutput + 'static> KeyPlayer<O> {
+    pub fn new(output: O) -> Self {
+        Self {
+            line: Arc::new(Mutex::new(KeyLine::new(output))),
+            stop: Arc::new(AtomicBool::new(false)),
+            buffer: JitterBufferConfig::default(),
+            realtime: false,
+        }
+    }
+
+    /// Ask for real-time priority for the thread that plays the edges.
+    /// Without the privilege it runs at normal priority.
+    pub fn realtime_priority(mut self, on: bool) -> Self {
+        self.realtime = on;
+        self
+    }
+
+    /// Playout delay and late-edge policy
+    pub fn jitter_buffer(mut self, config: JitterBufferConfig) -> Self {
+        self.buffer = config;
+        self
+    }
+
+    /// Share the stop flag with other threads of the keyer
+    pub fn stop_flag(mut self, stop: Arc<AtomicBool>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    /// Play the messages of `rx_port` until the session closes or the
+    /// player is stopped. Returns the reason the peer gave for closing.
+    pub fn run(&self, rx_port: &WkReceiver, hooks: &mut impl KeyerHooks) -> Option<CloseReason> {
+        let clock = rx_port.clock().clone();
+        self.spawn_watchdog(clock.clone());
+        let line = self.line.clone();
+        let scheduler = match KeyScheduler::spawn(clock.clone(), self.realtime, move |down, now| {
+            line.lock().unwrap().set(down, now)
+        }) {
+            Ok(scheduler) => scheduler,
+            Err(e) => {
+                log::warn!("keying scheduler not started: {e}");
+                self.stop();
+                return None;
+            }
+        };
+
+        let mut playback = Playback::new(clock, self.buffer);
+        let mut reason = None;
+        'restart: loop {
+            if rx_port.closed() || self.stopped() {
+                info!("session closed");
+                break;
+            }
+            let msgs = match rx_port.recv_timeout(POLL_INTERVAL) {
+                Ok(msgs) => msgs,
+                Err(WkError::Timeout) => Vec::new(),
+                Err(_) => {
+                    info!("receive error, session closed");
+                    break;
+                }
+            };
+            for m in msgs {
+                playback.msgs += 1;
+                let (tm, sub_us, down) = match m {
+                    MessageRCV::Sync(rmt) => {
+                        if let Some((now, mut stats)) = playback.sync(rmt) {
+                            stats.edge_error = scheduler.errors();
+                            trace!(
+                                "Sync rmt={} local={} jitter~{}ms delay={:?}ms skew={}ppm",
+                                rmt,
+                                now,
+                                stats.jitter_ms,
+                                stats.delay_ms,
+                                stats.skew_ppm
+                            );
+                            hooks.resynced(&stats, now);
+                        }
+                        continue;
+                    }
+                    MessageRCV::Keydown { at, sub_us } => (at, sub_us, true),
+                    MessageRCV::Keyup { at, sub_us } => (at, sub_us, false),
+                    MessageRCV::SessionClosed(r) => {
+                        info!("Session closed: {r:?}");
+                        reason = Some(r);
+                        break 'restart;
+                    }
+                    m => {
+                        if let MessageRCV::Pong {
+                            origin,
+                            peer: Some((received, sent)),
+                        } = m
+                        {
+                            playback.exchange(origin, received, sent);
+                        }
+                        hooks.message(m, rx_port);
+                        continue;
+                    }
+                };
+                let Some(at) = playback.schedule(tm, down) else {
+                    trace!("late edge dropped");
+                    continue;
+                };
+                // 相手の 1ms 未満の間隔もそのまま鳴らす
+                scheduler.push(at, sub_us, down);
+            }
+            for edge in scheduler.played() {
+                playback.played(&edge);
+                trace!("{}", if edge.down { "down" } else { "up" });
+                hooks.keyed(edge.down, edge.at);
+            }
+            hooks.tick(rx_port.clock().now());
+        }
+        self.stop();
+        drop(scheduler);
+        self.line.lock().unwrap().release();
+        reason
+    }
+
+    fn spawn_watchdog(&self, clock: SharedClock) {
+        let line = self.line.clone();
+        let stop = self.stop.clone();
+        let spawned = thread::Builder::new()
+            .name("keyer_wdg".into())
+            .stack_size(WATCHDOG_STACK)
+            .spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    if line.lock().unwrap().watchdog(clock.now()) {
+                        info!("Watchdog: key asserted too long, released");
+                    }
+                    clock.sleep(WATCHDOG_INTERVAL);
+                }
+            });
+        if let Err(e) = spawned {
+            log::warn!("keyer watchdog not started: {e}");
+        }
+    }
+}
+
+impl<O: KeyOutput> Drop for KeyPlayer<O> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Ok(mut line) = self.line.lock() {
+            line.release();
+        }
+    }
+}
+
+// キー出力と、押している間はその時刻
+struct KeyLine<O> {
+    output: O,
+    down_at: Option<Tick>,
+}
+
+impl<O: KeyOutput> KeyLine<O> {
+    fn new(output: O) -> Self {
+        Self {
+            output,
+            down_at: None,
+        }
+    }
+
+    // 離したときは押していた長さを返す
+    fn set(&mut self, down: bool, now: Tick) -> Option<u32> {
+        self.output.set_key(down);
+        if down {
+            self.down_at = Some(now);
+            None
+        } else {
+            self.down_at.take().map(|t| now.since(t))
+        }
+    }
+
+    fn release(&mut self) {
+        self.output.set_key(false);
+        self.down_at = None;
+    }
+
+    // 押しっぱなしなら離して true
+    fn watchdog(&mut self, now: Tick) -> bool {
+        if self
+            .down_at
+            .is_some_and(|t| now.since(t) > MAX_ASSERT_DURATION)
+        {
+            self.release();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// 時刻合わせと統計
+struct Playback {
+    clock: SharedClock,
+    playout: Playout,
+    buffer: JitterBuffer,
+    jitter: Jitter,
+    // 最後に統計を出した Sync の相手の時刻
+    reported: Option<Tick>,
+    msgs: usize,
+    speed: SpeedEstimator,
+    // 前回の合わせ直しからのマークの数と、最後に離した時刻
+    marks: usize,
+    up_at: Option<Tick>,
+}
+
+impl Playback {
+    fn new(clock: SharedClock, buffer: JitterBufferConfig) -> Self {
+        Self {
+            clock,
+            playout: Playout::new(),
+            buffer: JitterBuffer::new(buffer),
+            jitter: Jitter::new(),
+            reported: None,
+            msgs: 0,
+            speed: SpeedEstimator::new(),
+            marks: 0,
+            up_at: None,
+        }
+    }
+
+    // 速く届いた Sync なら時計を合わせ直す。RESYNC_INTERVAL ごとに、
+    // その時刻と前回からの統計を返す
+    fn sync(&mut self, rmt: Tick) -> Option<(Tick, KeyerStats)> {
+        let now = self.clock.now();
+        if self.playout.sync(rmt, now) {
+            trace!("paired rmt={rmt} with local={now}");
+        }
+        if self
+            .reported
+            .is_some_and(|last| rmt.delta(last) <= RESYNC_INTERVAL)
+        {
+            return None;
+        }
+        self.reported = Some(rmt);
+        // 一方向遅延変動（ジッター）の計測
+        self.jitter.update(rmt, now);
+        let speed = if self.marks > 0 {
+            self.speed.estimate()
+        } else {
+            None
+        };
+        let stats = KeyerStats {
+            wpm: speed.map_or(0.0, |s| s.wpm),
+            speed,
+            pkt: self.msgs / 3,
+            jitter_ms: self.jitter.ms(),
+            delay_ms: self.playout.clock_sync().delay(),
+            skew_ppm: self.playout.clock_sync().skew_ppm() as i32,
+            playout_delay_ms: self.buffer.delay(),
+            late_edges: self.buffer.late(),
+            // スケジューラが鳴らした分は呼び出し側で入れる
+            edge_error: EdgeErrorHistogram::default(),
+        };
+        self.msgs = 0;
+        self.marks = 0;
+        Some((now, stats))
+    }
+
+    // 4 つの時刻から相手の時計を推定し、以後の打鍵はそれで合わせる
+    fn exchange(&mut self, origin: Tick, received: Tick, sent: Tick) {
+        let arrived = self.clock.now();
+        if !self.playout.exchange(origin, received, sent, arrived) {
+            trace!("Pong dropped origin={origin} arrived={arrived}");
+        }
+    }
+
+    // 打鍵を鳴らすこちらの時刻。捨てる打鍵は None
+    fn schedule(&mut self, rmt: Tick, down: bool) -> Option<Tick> {
+        let now = self.clock.now();
+        let due = self.playout.due(rmt, now);
+        self.buffer.schedule(due, down, now)
+    }
+
+    // 鳴らした打鍵のマークとスペースの長さを速度の推定に入れる
+    fn played(&mut self, edge: &Played) {
+        if edge.down {
+            if let Some(up_at) = self.up_at.take() {
+                self.speed.space(edge.at.since(up_at));
+            }
+        } else {
+            self.up_at = Some(edge.at);
+        }
+        if let Some(mark) = edge.mark {
+            self.speed.mark(mark);
+            self.marks += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wkcapture::{replay, CaptureKind, CaptureRecord};
+    use crate::wktime::SimClock;
+    use bytes::BytesMut;
+    use std::time::Duration;
+    use wkproto::{decode_message, encode_message, Clock, Codec, LatePolicy, Packet, PacketKind};
+
+    #[derive(Default)]
+    struct Recorder(Arc<Mutex<Vec<bool>>>);
+
+    impl KeyOutput for Recorder {
+        fn set_key(&mut self, down: bool) {
+            self.0.lock().unwrap().push(down);
+        }
+    }
+
+    #[test]
+    fn test_playback_instants() {
+        let sim = SimClock::auto_advance(Tick::from_ms(50_000));
+        let mut playback = Playback::new(SharedClock::new(sim.clone()), JitterBufferConfig::FIXED);
+        // 相手の時計はこちらと無関係で、途中で一周する
+        let rmt = Tick::from_ms(u32::MAX - 100);
+        let (now, _) = playback.sync(rmt).unwrap();
+        assert_eq!(now, Tick::from_ms(50_000));
+
+        // 20 ms 後に届いた打鍵を相手と同じ間隔で鳴らす
+        sim.advance(20);
+        assert_eq!(
+            playback.schedule(rmt + 60, true),
+            Some(Tick::from_ms(50_060))
+        );
+        assert_eq!(
+            playback.schedule(rmt + 120, true),
+            Some(Tick::from_ms(50_120))
+        );
+
+        // 遅れて届いた打鍵はすぐ鳴らす
+        sim.advance(600);
+        assert_eq!(
+            playback.schedule(rmt + 200, true),
+            Some(Tick::from_ms(50_620))
+        );
+
+        // 3 秒以内の Sync では統計を出さない
+        sim.advance(400);
+        assert!(playback.sync(rmt + 1000).is_none());
+        // 遅れて届いた Sync では合わせ直さず、相手と同じ間隔のまま
+        sim.advance(2040);
+        let (now, _) = playback.sync(rmt + 3001).unwrap();
+        assert_eq!(now, Tick::from_ms(53_060));
+        assert_eq!(
+            playback.schedule(rmt + 3100, true),
+            Some(Tick::from_ms(53_100))
+        );
+    }
+
+    #[test]
+    fn test_playback_without_pong() {
+        let sim = SimClock::new(Tick::from_ms(1000));
+        let mut playback = Playback::new(SharedClock::new(sim.clone()), JitterBufferConfig::FIXED);
+        // 相手は Pong を返さず、3 秒ごとの Sync の届き方は揺れる
+        let rmt = Tick::from_ms(70_000);
+        playback.sync(rmt).unwrap();
+        for (i, delayed) in [30, 0, 55, 10].into_iter().enumerate() {
+            let sent = (i as u32 + 1) * 3001;
+            sim.advance((Tick::from_ms(1000 + sent + delayed)).since(sim.now()));
+            assert!(playback.sync(rmt + sent).is_some());
+        }
+        // 最初の Sync の対応のまま、打った間隔で鳴らす
+        assert_eq!(
+            playback.schedule(rmt + 12_104, true),
+            Some(Tick::from_ms(13_104))
+        );
+    }
+
+    #[test]
+    fn test_playback_edge_before_sync() {
+        let sim = SimClock::auto_advance(Tick::from_ms(7));
+        let mut playback = Playback::new(SharedClock::new(sim.clone()), JitterBufferConfig::FIXED);
+        // Sync より先に届いた打鍵が基準になる
+        assert_eq!(
+            playback.schedule(Tick::from_ms(900), true),
+            Some(Tick::from_ms(7))
+        );
+        assert_eq!(
+            playback.schedule(Tick::from_ms(955), true),
+            Some(Tick::from_ms(62))
+        );
+        // 打鍵より遅れて届いた Sync では合わせ直さない
+        sim.advance(200);
+        assert!(playback.sync(Tick::from_ms(1000)).is_some());
+        assert_eq!(
+            playback.schedule(Tick::from_ms(1300), true),
+            Some(Tick::from_ms(407))
+        );
+    }
+
+    #[test]
+    fn test_playback_follows_pong() {
+        let sim = SimClock::auto_advance(Tick::from_ms(1000));
+        let mut playback = Playback::new(SharedClock::new(sim.clone()), JitterBufferConfig::FIXED);
+        // 相手の時計はこちらより 5000 ms 進んでいて、往復 12 ms
+        let origin = Tick::from_ms(1000);
+        sim.advance(12);
+        playback.exchange(origin, origin + 5006, origin + 5006);
+        let (_, stats) = playback.sync(Tick::from_ms(6100)).unwrap();
+        assert_eq!(stats.delay_ms, Some(12));
+        // 打った 2000 ms 後、片道 6 ms で鳴らす
+        assert_eq!(
+            playback.schedule(Tick::from_ms(8000), true),
+            Some(Tick::from_ms(3006))
+        );
+    }
+
+    #[test]
+    fn test_replay_matches_player() {
+        let start = Tick::from_ms(50_000);
+        let keyer = |tm: u32, slots: &[u8]| {
+            let mut buf = BytesMut::new();
+            encode_message(&mut buf, PacketKind::KeyerMessage, tm, slots).unwrap();
+            buf.to_vec()
+        };
+        // 相手の時計はこちらより 5000 ms 進んでいて、往復 12 ms
+        let mut pong = BytesMut::new();
+        let peer = Some((Tick::from_ms(54_996), Tick::from_ms(54_996)));
+        let packet = Packet::Pong {
+            origin: Tick::from_ms(49_990),
+            peer,
+        };
+        Codec::Tlv.encode(&mut pong, &packet).unwrap();
+        let records: Vec<_> = [
+            (2, pong.to_vec()),
+            (10, keyer(55_000, &[])),
+            (120, keyer(55_100, &[0x80, 0x3c])),
+            // 遅れて届き、後ろの打鍵もずれる
+            (300, keyer(55_200, &[0x80, 0x3c])),
+            (340, keyer(55_330, &[0x80, 0x3c])),
+        ]
+        .into_iter()
+        .map(|(at, data)| CaptureRecord {
+            at: Duration::from_millis(at),
+            kind: CaptureKind::Datagram,
+            conv: 7,
+            peer: "192.0.2.1:5000".parse().unwrap(),
+            data,
+        })
+        .collect();
+        let config = JitterBufferConfig::default();
+        let keys = replay(&records, 7, Duration::from_millis(50_000), config);
+
+        // KeyPlayer と同じく、届いた時刻に Playback へ渡す
+        let sim = SimClock::new(start);
+        let mut playback = Playback::new(SharedClock::new(sim.clone()), config);
+        let mut played = Vec::new();
+        for record in &records {
+            sim.advance(record.at.as_millis() as u32 - sim.now().since(start));
+            for m in decode_message(&record.data).unwrap() {
+                let (rmt, down) = match m {
+                    MessageRCV::Sync(rmt) => {
+                        playback.sync(rmt);
+                        continue;
+                    }
+                    MessageRCV::Pong {
+                        origin,
+                        peer: Some((received, sent)),
+                    } => {
+                        playback.exchange(origin, received, sent);
+                        continue;
+                    }
+                    MessageRCV::Keydown { at, .. } => (at, true),
+                    MessageRCV::Keyup { at, .. } => (at, false),
+                    _ => continue,
+                };
+                played.extend(playback.schedule(rmt, down).map(|at| (down, at)));
+            }
+        }
+        let replayed: Vec<_> = keys
+            .iter()
+            .map(|k| (k.down, start + k.played.as_millis() as u32))
+            .collect();
+        assert_eq!(replayed, played);
+        let played: Vec<_> = keys.iter().map(|k| k.played.as_millis()).collect();
+        assert_eq!(played, vec![120, 180, 300, 360, 430, 490]);
+        // Pong で合わせた時計で、片道 6 ms 後に鳴る予定だった
+        assert_eq!(keys[0].due, Duration::from_millis(106));
+    }
+
+    #[test]
+    fn test_playback_drops_late_edges() {
+        let sim = SimClock::auto_advance(Tick::from_ms(1000));
+        let config = JitterBufferConfig {
+            late: LatePolicy::Drop,
+            ..JitterBufferConfig::default()
+        };
+        let mut playback = Playback::new(SharedClock::new(sim.clone()), config);
+        let rmt = Tick::from_ms(0);
+        playback.sync(rmt).unwrap();
+        assert_eq!(
+            playback.schedule(rmt + 100, true),
+            Some(Tick::from_ms(1100))
+        );
+        // 遅れた離しはすぐ鳴らし、遅れた押しは離しごと捨てる
+        sim.advance(600);
+        assert_eq!(
+            playback.schedule(rmt + 160, false),
+            Some(Tick::from_ms(1600))
+        );
+        assert_eq!(playback.schedule(rmt + 200, true), None);
+        assert_eq!(playback.schedule(rmt + 260, false), None);
+        let (_, stats) = playback.sync(rmt + 3001).unwrap();
+        assert_eq!(stats.late_edges, 2);
+    }
+
+    #[test]
+    fn test_playback_stats() {
+        let sim = SimClock::new(Tick::from_ms(1000));
+        let mut playback = Playback::new(SharedClock::new(sim), JitterBufferConfig::FIXED);
+        playback.sync(Tick::from_ms(0)).unwrap();
+        // 20 WPM の短点は 60 ms
+        let mut at = Tick::from_ms(1000);
+        for _ in 0..8 {
+            playback.played(&Played {
+                down: true,
+                at,
+                mark: None,
+            });
+            at += 60;
+            playback.played(&Played {
+                down: false,
+                at,
+                mark: Some(60),
+            });
+            at += 60;
+        }
+        playback.msgs = 30;
+        let (_, stats) = playback.sync(Tick::from_ms(3001)).unwrap();
+        assert_eq!(stats.wpm, 20.0);
+        assert_eq!(stats.speed.unwrap().weight, Some(50.0));
+        assert_eq!(stats.pkt, 10);
+        // 打鍵がなければ 0
+        let (_, stats) = playback.sync(Tick::from_ms(6002)).unwrap();
+        assert_eq!(stats.wpm, 0.0);
+        assert_eq!(stats.speed, None);
+    }
+
+    #[test]
+    fn test_watchdog_releases_key() {
+        let recorder = Recorder::default();
+        let edges = recorder.0.clone();
+        let mut line = KeyLine::new(recorder);
+        let t = Tick::from_ms(u32::MAX - 10);
+        assert_eq!(line.set(true, t), None);
+        assert!(!line.watchdog(t + MAX_ASSERT_DURATION));
+        assert!(line.watchdog(t + MAX_ASSERT_DURATION + 1));
+        // 離したあとは何もしない
+        assert!(!line.watchdog(t + 2 * MAX_ASSERT_DURATION));
+        assert_eq!(line.set(false, t + 100), None);
+        assert_eq!(*edges.lock().unwrap(), [true, false, false]);
+    }
+
+    #[test]
+    fn test_player_releases_on_drop() {
+        let recorder = Recorder::default();
+        let edges = recorder.0.clone();
+        let player = KeyPlayer::new(recorder);
+        player.line.lock().unwrap().set(true, Tick::ZERO);
+        drop(player);
+        assert_eq!(*edges.lock().unwrap(), [true, false]);
+    }
+}
@@ -0,0 +1,309 @@
+use crate::wktime::SharedClock;
+use crate::wkutil::raise_priority;
+use log::info;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use wkproto::Tick;
+
+// 締め切りが遠いときはこれだけ寝るごとに先頭を見直す (ms)
+const NAP: u32 = 10;
+const SCHEDULER_STACK: usize = 4096;
+
+/// Upper bounds of the `EdgeErrorHistogram` buckets in microseconds. The
+/// last bucket takes the rest.
+pub const EDGE_ERROR_BOUNDS_US: [u32; 7] = [50, 100, 250, 500, 1000, 2000, 5000];
+
+/// How long after their scheduled time key edges were played.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EdgeErrorHistogram {
+    buckets: [u32; EDGE_ERROR_BOUNDS_US.len() + 1],
+    max_us: u32,
+    sum_us: u64,
+}
+
+impl EdgeErrorHistogram {
+    pub fn record(&mut self, error_us: u32) {
+        let i = EDGE_ERROR_BOUNDS_US.partition_point(|&bound| bound < error_us);
+        self.buckets[i] += 1;
+        self.max_us = self.max_us.max(error_us);
+        self.sum_us += error_us as u64;
+    }
+
+    /// Edges per bucket of `EDGE_ERROR_BOUNDS_US`
+    pub fn buckets(&self) -> &[u32; EDGE_ERROR_BOUNDS_US.len() + 1] {
+        &self.buckets
+    }
+
+    pub fn count(&self) -> u32 {
+        self.buckets.iter().sum()
+    }
+
+    pub fn max_us(&self) -> u32 {
+        self.max_us
+    }
+
+    pub fn mean_us(&self) -> u32 {
+        match self.count() {
+            0 => 0,
+            n => (self.sum_us / n as u64) as u32,
+        }
+    }
+
+    /// Upper bound of the bucket the `percentile`th edge falls in, or the
+    /// largest error if that is the last bucket. 0 before any edge.
+    pub fn percentile_us(&self, percentile: u8) -> u32 {
+        let rank = (self.count() as u64 * percentile.min(100) as u64).div_ceil(100);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n as u64;
+            if n > 0 && seen >= rank {
+                return EDGE_ERROR_BOUNDS_US
+                    .get(i)
+                    .map_or(self.max_us, |&bound| bound.min(self.max_us));
+            }
+        }
+        0
+    }
+}
+
+/// An edge the scheduler has played, at `at` on the session clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Played {
+    pub down: bool,
+    pub at: Tick,
+    /// Length of the mark an up edge ends
+    pub mark: Option<u32>,
+}
+
+// 締め切りが同じなら積んだ順
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Pending {
+    deadline_us: u64,
+    seq: u64,
+    down: bool,
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: BinaryHeap<Reverse<Pending>>,
+    seq: u64,
+    played: VecDeque<Played>,
+    errors: EdgeErrorHistogram,
+    stop: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    wake: Condvar,
+}
+
+/// Plays key edges at their deadlines on a thread of its own.
+///
+/// Waits are left to `Clock::sleep_until_us`, which on the system clock
+/// sleeps to just before the deadline and spins the rest. Every edge's
+/// lateness goes into an `EdgeErrorHistogram`.
+pub(crate) struct KeyScheduler {
+    shared: Arc<Shared>,
+    clock: SharedClock,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl KeyScheduler {
+    /// `fire` drives the key and returns the length of the mark an up edge
+    /// ends. With `realtime` the thread asks for real-time priority and
+    /// runs at normal priority if it can't have it.
+    pub(crate) fn spawn(
+        clock: SharedClock,
+        realtime: bool,
+        mut fire: impl FnMut(bool, Tick) -> Option<u32> + Send + 'static,
+    ) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            wake: Condvar::new(),
+        });
+        let handle = {
+            let shared = shared.clone();
+            let clock = clock.clone();
+            thread::Builder::new()
+                .name("keyer_sched".into())
+                .stack_size(SCHEDULER_STACK)
+                .spawn(move || {
+                    if realtime {
+                        match raise_priority() {
+                            Ok(()) => info!("keying scheduler at real-time priority"),
+                            Err(e) => info!("keying scheduler at normal priority: {e}"),
+                        }
+                    }
+                    Self::serve(&shared, &clock, &mut fire);
+                })?
+        };
+        Ok(Self {
+            shared,
+            clock,
+            handle: Some(handle),
+        })
+    }
+
+    fn serve(
+        shared: &Shared,
+        clock: &SharedClock,
+        fire: &mut impl FnMut(bool, Tick) -> Option<u32>,
+    ) {
+        let mut queue = shared.queue.lock().unwrap();
+        while !queue.stop {
+            let Some(Reverse(next)) = queue.pending.peek() else {
+                queue = shared.wake.wait(queue).unwrap();
+                continue;
+            };
+            let (deadline_us, down) = (next.deadline_us, next.down);
+            let now_us = clock.now_us();
+            if now_us < deadline_us {
+                drop(queue);
+                // 先頭より早い打鍵が積まれても気づけるよう、遠い締め切りへは刻んで寝る
+                if deadline_us - now_us > NAP as u64 * 1000 {
+                    clock.sleep(NAP);
+                } else {
+                    clock.sleep_until_us(deadline_us);
+                }
+                queue = shared.queue.lock().unwrap();
+                continue;
+            }
+            queue.pending.pop();
+            drop(queue);
+            let at = Tick::from_ms((now_us / 1000) as u32);
+            let mark = fire(down, at);
+            queue = shared.queue.lock().unwrap();
+            let error = (now_us - deadline_us).min(u32::MAX as u64) as u32;
+            queue.errors.record(error);
+            queue.played.push_back(Played { down, at, mark });
+        }
+    }
+
+    /// Play an edge `sub_us` microseconds after the session clock reaches
+    /// `at`, or at once if that has passed
+    pub(crate) fn push(&self, at: Tick, sub_us: u16, down: bool) {
+        let now_us = self.clock.now_us();
+        let ahead = at.delta(Tick::from_ms((now_us / 1000) as u32)) as i64 * 1000 + sub_us as i64;
+        let deadline_us = (now_us - now_us % 1000).saturating_add_signed(ahead);
+        let mut queue = self.shared.queue.lock().unwrap();
+        let seq = queue.seq;
+        queue.seq += 1;
+        queue.pending.push(Reverse(Pending {
+            deadline_us,
+            seq,
+            down,
+        }));
+        self.shared.wake.notify_one();
+    }
+
+    /// Edges played since the last call, in order
+    pub(crate) fn played(&self) -> Vec<Played> {
+        self.shared.queue.lock().unwrap().played.drain(..).collect()
+    }
+
+    pub(crate) fn errors(&self) -> EdgeErrorHistogram {
+        self.shared.queue.lock().unwrap().errors
+    }
+}
+
+impl Drop for KeyScheduler {
+    // まだ鳴らしていない打鍵は捨てる
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().stop = true;
+        self.shared.wake.notify_one();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wktime::SimClock;
+    use std::time::Duration;
+    use wkproto::Clock;
+
+    #[test]
+    fn test_histogram() {
+        let mut h = EdgeErrorHistogram::default();
+        assert_eq!(h.percentile_us(99), 0);
+        for e in [10, 20, 30, 40, 60, 70, 80, 90, 300, 8000] {
+            h.record(e);
+        }
+        assert_eq!(h.count(), 10);
+        assert_eq!(h.buckets(), &[4, 4, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(h.percentile_us(50), 100);
+        assert_eq!(h.percentile_us(90), 500);
+        // 最後のバケツは上限がないので最大値
+        assert_eq!(h.percentile_us(99), 8000);
+        assert_eq!(h.mean_us(), 870);
+        // 上限は実際の最大値を超えない
+        let mut h = EdgeErrorHistogram::default();
+        h.record(3);
+        assert_eq!(h.percentile_us(50), 3);
+    }
+
+    #[test]
+    fn test_plays_in_deadline_order() {
+        let sim = SimClock::new(Tick::from_ms(1000));
+        let down_at = Arc::new(Mutex::new(None));
+        let scheduler = {
+            let down_at = down_at.clone();
+            KeyScheduler::spawn(SharedClock::new(sim.clone()), false, move |down, at| {
+                let mut down_at = down_at.lock().unwrap();
+                if down {
+                    *down_at = Some(at);
+                    None
+                } else {
+                    down_at.take().map(|t| at.since(t))
+                }
+            })
+            .unwrap()
+        };
+        // 後の打鍵を先に積んでも締め切り順に鳴らす
+        scheduler.push(Tick::from_ms(1060), 0, false);
+        scheduler.push(Tick::from_ms(1020), 0, true);
+        let mut played = Vec::new();
+        for _ in 0..1000 {
+            if played.len() == 2 {
+                break;
+            }
+            sim.advance_us(500);
+            thread::sleep(Duration::from_millis(1));
+            played.extend(scheduler.played());
+        }
+        assert_eq!(played.len(), 2);
+        assert!(played[0].down && !played[1].down);
+        assert!(!played[0].at.is_before(Tick::from_ms(1020)));
+        assert!(played[1].mark.is_some_and(|mark| mark >= 40));
+        assert_eq!(scheduler.errors().count(), 2);
+        // 過ぎた時刻の打鍵はすぐ鳴らす
+        scheduler.push(sim.now() - 50, 0, true);
+        for _ in 0..1000 {
+            if !scheduler.played().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(scheduler.errors().count(), 3);
+        // 同じミリ秒の中でもマイクロ秒の順に鳴らす
+        let at = sim.now() + 10;
+        scheduler.push(at, 600, false);
+        scheduler.push(at, 200, true);
+        let mut played = Vec::new();
+        for _ in 0..1000 {
+            if played.len() == 2 {
+                break;
+            }
+            sim.advance_us(100);
+            thread::sleep(Duration::from_millis(1));
+            played.extend(scheduler.played());
+        }
+        assert_eq!(played.len(), 2);
+        assert!(played[0].down && !played[1].down);
+    }
+}
@@ -1,182 +1,187 @@
-use crate::wkutil::{sleep, tick_count};
-use anyhow::{bail, Result};
-use bytes::{Buf, BufMut, BytesMut};
-use kcp::Kcp;
+use crate::wkconfig::WkSessionConfig;
+use crate::wkcore::{Dispatch, ListenerSession, SessionTable, WkSessionCore};
+use crate::wkcrypto::{ResumeTicket, SessionCipher, CLOSE_TAG_LEN, RESUME_TAG_LEN};
+use crate::wkerror::{WkError, WkResult};
+use crate::wkhello::Hello;
+use crate::wkstats::WkSessionStats;
+use crate::wktime::SharedClock;
+use anyhow::Result;
 use log::{info, trace};
-use md5::{Digest, Md5};
-use rand::random;
-use std::io::{self, Cursor, Write};
 use std::net::{SocketAddr, UdpSocket};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
 use std::time::Duration;
+use wkproto::{Capabilities, CloseReason, Tick};
 
-pub const MTU_SIZE: usize = 512;
-pub const SESSION_TIMEOUT: u64 = 15;
-pub const PKT_SIZE: usize = 128;
+/// Default limit of concurrent sessions per `WkListener`
+pub const MAX_SESSIONS: usize = 4;
+// セッションがなくても停止フラグはこの間隔で見る
+const LISTEN_WAIT_MAX: u32 = 1000;
 
-struct UDPOutput {
-    socket: Arc<UdpSocket>,
-    peer: SocketAddr,
-}
-
-impl UDPOutput {
-    fn new(socket: Arc<UdpSocket>, peer: SocketAddr) -> Self {
-        UDPOutput { socket, peer }
-    }
+/// Blocking I/O around `WkSessionCore`: every operation sends what the core queued.
+pub struct KcpSocket {
+    core: WkSessionCore,
+    udp: Arc<UdpSocket>,
+    clock: SharedClock,
 }
 
-impl Write for UDPOutput {
-    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
-        match self.socket.send_to(data, self.peer) {
-            Ok(n) => {
-                trace!("{n} byte packet sent");
-                Ok(n)
-            }
-            Err(e) => {
-                // Log at warn level since UDP send errors may indicate network issues
-                log::warn!("UDP send error to {}: {}", self.peer, e);
-                // Return error to let KCP handle retransmission
-                Err(e)
+impl KcpSocket {
+    pub fn new(config: &WkSessionConfig, udp: Arc<UdpSocket>, peer: SocketAddr) -> Result<Self> {
+        let clock = config.clock.clone();
+        let core = WkSessionCore::new(config, peer, clock.now())?;
+        Ok(Self { core, udp, clock })
+    }
+
+    fn transmit(&mut self) {
+        while let Some(t) = self.core.poll_transmit() {
+            match self.udp.send_to(&t.data, t.peer) {
+                Ok(n) => trace!("{n} byte packet sent"),
+                // UDP 一時エラーで session が閉じないよう、再送は KCP に任せる
+                Err(e) => log::warn!("UDP send error to {}: {}", t.peer, e),
             }
         }
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+    pub fn handle_datagram(&mut self, src: SocketAddr, pkt: &mut [u8]) -> Result<()> {
+        let res = self.core.handle_datagram(src, pkt, self.clock.now());
+        self.transmit();
+        res
     }
-}
 
-#[allow(dead_code)]
-pub enum KcpMode {
-    Default,
-    Normal,
-    Fast,
-    FastNB,
-}
+    pub fn input(&mut self, buf: &[u8]) -> Result<()> {
+        let res = self.core.input(buf, self.clock.now());
+        self.transmit();
+        res
+    }
 
-pub struct KcpSocket {
-    kcp: Kcp<UDPOutput>,
-    last_update: u32,
-    closed: bool,
-}
+    pub fn send(&mut self, buf: &[u8]) -> Result<usize> {
+        let res = self.core.send(buf, self.clock.now());
+        self.transmit();
+        res
+    }
 
-impl KcpSocket {
-    pub fn new(mode: KcpMode, socket: Arc<UdpSocket>, peer: SocketAddr) -> Result<Self> {
-        let output = UDPOutput::new(socket.clone(), peer);
-        let conv = 0;
-        let mut kcp = Kcp::new(conv, output);
-
-        match mode {
-            KcpMode::Default => kcp.set_nodelay(false, 10, 0, false),
-            KcpMode::Normal => kcp.set_nodelay(false, 10, 0, true),
-            KcpMode::Fast => {
-                kcp.set_mtu(MTU_SIZE)?;
-                kcp.set_nodelay(true, 10, 1, true);
-                kcp.set_maximum_resend_times(10);
-            }
-            KcpMode::FastNB => {
-                kcp.set_mtu(MTU_SIZE)?;
-                kcp.set_nodelay(true, 10, 1, true);
-                kcp.set_maximum_resend_times(10);
-                socket.set_nonblocking(true)?;
-            }
-        }
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.core.recv(buf, self.clock.now())
+    }
 
-        if conv == 0 {
-            kcp.input_conv();
-        }
+    pub fn send_datagram(&mut self, buf: &[u8]) -> Result<usize> {
+        let res = self.core.send_datagram(buf, self.clock.now());
+        self.transmit();
+        res
+    }
 
-        let last_update = tick_count();
-        kcp.update(last_update)?;
+    pub fn recv_datagram(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.core.recv_datagram(buf)
+    }
 
-        Ok(Self {
-            kcp,
-            last_update,
-            closed: false,
-        })
+    pub(crate) fn set_cipher(&mut self, cipher: SessionCipher) {
+        self.core.set_cipher(cipher);
     }
 
-    pub fn input(&mut self, buf: &[u8]) -> Result<()> {
-        match self.kcp.input(buf) {
-            Ok(_) => {}
-            Err(err) => return Err(err.into()),
-        }
-        self.last_update = tick_count();
-        // flush_ack() は内部バッファを送信せずにエンコードだけする（kcp-0.5.3 のバグ）。
-        // flush() を呼ぶことで ACK を即座に UDP 送信する。
-        // UDP 一時エラーで session が閉じないようエラーは無視する。
-        let _ = self.kcp.flush();
-        Ok(())
+    pub fn encrypted(&self) -> bool {
+        self.core.encrypted()
     }
 
-    pub fn send(&mut self, buf: &[u8]) -> Result<usize> {
-        if self.closed || self.kcp.is_dead_link() {
-            self.closed = true;
-            bail!("connection closed.");
-        }
-        let n = self.kcp.send(buf)?;
-        self.last_update = tick_count();
-        self.kcp.flush()?;
-        Ok(n)
+    pub(crate) fn set_resume(&mut self, ticket: ResumeTicket) {
+        self.core.set_resume(ticket);
     }
 
-    pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
-        if self.closed {
-            bail!("connection closed.");
-        }
-        match self.kcp.recv(buf) {
-            Ok(n) => {
-                self.last_update = tick_count();
-                Ok(n)
-            }
-            Err(kcp::Error::RecvQueueEmpty) => Ok(0),
-            Err(e) => Err(e.into()),
-        }
+    pub(crate) fn set_peer_hello(&mut self, hello: Hello) {
+        self.core.set_peer_hello(hello);
+    }
+
+    pub fn peer_hello(&self) -> Option<&Hello> {
+        self.core.peer_hello()
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.core.peer()
+    }
+
+    /// Client side: ask the server to move this session to our current address.
+    pub(crate) fn send_resume(&mut self) -> Result<()> {
+        let res = self.core.request_resume();
+        self.transmit();
+        res
+    }
+
+    /// Server side: move the session to `peer` if the request is valid.
+    pub(crate) fn migrate(
+        &mut self,
+        peer: SocketAddr,
+        ticket: u32,
+        counter: u64,
+        tag: &[u8; RESUME_TAG_LEN],
+    ) -> bool {
+        let now = self.clock.now();
+        let ok = self.core.migrate(peer, ticket, counter, tag, now);
+        self.transmit();
+        ok
     }
 
     #[allow(dead_code)]
     pub fn flush(&mut self) -> Result<()> {
-        self.kcp.flush()?;
-        self.last_update = tick_count();
-        Ok(())
+        let res = self.core.flush(self.clock.now());
+        self.transmit();
+        res
     }
 
+    /// Run KCP timers. Returns milliseconds until the next call.
     pub fn update(&mut self) -> Result<u32> {
-        let current = tick_count();
-        self.kcp.update(current)?;
-        Ok(self.kcp.check(current))
+        let now = self.clock.now();
+        let res = self.core.handle_timeout(now);
+        self.transmit();
+        res?;
+        Ok(self.core.next_timeout(now))
+    }
+
+    pub fn stats(&self) -> WkSessionStats {
+        self.core.stats(self.clock.now())
     }
 
     #[allow(dead_code)]
     pub fn conv(&mut self) -> u32 {
-        self.kcp.conv()
+        self.core.conv()
     }
 
     #[allow(dead_code)]
     pub fn waiting_conv(&mut self) -> bool {
-        self.kcp.waiting_conv()
+        self.core.waiting_conv()
     }
 
-    #[allow(dead_code)]
-    pub fn close(&mut self) {
-        self.closed = true;
+    /// Close and tell the peer why.
+    pub fn close_with(&mut self, reason: CloseReason) {
+        self.core.close_with(reason);
+        self.transmit();
+    }
+
+    pub(crate) fn closed_by_peer(
+        &mut self,
+        reason: CloseReason,
+        tag: Option<&[u8; CLOSE_TAG_LEN]>,
+    ) -> bool {
+        self.core.closed_by_peer(reason, tag)
     }
 
     #[allow(dead_code)]
     pub fn closed(&mut self) -> bool {
-        self.closed
+        self.core.closed()
+    }
+
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.core.close_reason()
     }
 
     #[allow(dead_code)]
-    pub fn last_update(&mut self) -> u32 {
-        self.last_update
+    pub fn last_update(&mut self) -> Tick {
+        self.core.last_update()
     }
 }
 
 pub struct WkSession {
     socket: Arc<Mutex<KcpSocket>>,
+    config: WkSessionConfig,
     closed: AtomicBool,
     // data_available はデータ到着時に WkReceiver をブロック解除するための Condvar
     data_available: (Mutex<bool>, Condvar),
@@ -185,6 +190,9 @@ pub struct WkSession {
 impl Drop for WkSession {
     fn drop(&mut self) {
         info!("session dropped. stop thread.");
+        if let Ok(mut socket) = self.socket.lock() {
+            socket.close_with(CloseReason::UserQuit);
+        }
         self.closed.store(true, Ordering::Relaxed);
         if let Ok(mut flag) = self.data_available.0.lock() {
             *flag = true;
@@ -197,94 +205,84 @@ impl WkSession {
     fn new(
         udp: Arc<UdpSocket>,
         peer: SocketAddr,
-        mode: KcpMode,
-        expire: Duration,
-    ) -> Result<Arc<WkSession>> {
-        let kcp = KcpSocket::new(mode, udp.clone(), peer)?;
-        let socket = Arc::new(Mutex::new(kcp));
-        let server = socket.clone();
-        let expire = expire.as_millis() as u32;
-        let closed = AtomicBool::new(false);
-
-        thread::spawn(move || loop {
-            let Ok(mut s) = server.lock() else {
-                info!("mutex poisoned, exiting update thread");
-                break;
-            };
-            if s.closed() {
-                break;
-            }
-            match s.update() {
-                Ok(n) => {
-                    if tick_count() - s.last_update() > expire {
-                        s.close();
-                        break;
-                    }
-                    drop(s);
-                    sleep(n)
-                }
-                Err(e) => {
-                    info!("kcp update failed. {e}");
-                    drop(s);
-                    sleep(1000);
-                }
-            }
-        });
+        config: WkSessionConfig,
+    ) -> WkResult<Arc<WkSession>> {
+        let kcp = KcpSocket::new(&config, udp, peer)?;
         Ok(Arc::new(WkSession {
-            socket,
-            closed,
+            socket: Arc::new(Mutex::new(kcp)),
+            config,
+            closed: AtomicBool::new(false),
             data_available: (Mutex::new(false), Condvar::new()),
         }))
     }
 
-    pub fn connect(peer: SocketAddr, udp: UdpSocket) -> Result<Arc<WkSession>> {
+    /// Run KCP timers. Returns milliseconds until the next call, or None once the session has closed.
+    fn handle_timeout(&self) -> Option<u32> {
+        let mut socket = self.socket.lock().ok()?;
+        let res = socket.update();
+        // アイドルタイムアウトは core が判定する
+        if socket.closed() {
+            drop(socket);
+            self.wake_closed();
+            return None;
+        }
+        match res {
+            Ok(delay) => Some(delay),
+            Err(e) => {
+                info!("kcp update failed. {e}");
+                Some(1000)
+            }
+        }
+    }
+
+    pub fn connect(peer: SocketAddr, udp: UdpSocket) -> WkResult<Arc<WkSession>> {
+        WkSession::connect_with_config(peer, udp, WkSessionConfig::default())
+    }
+
+    pub fn connect_with_config(
+        peer: SocketAddr,
+        udp: UdpSocket,
+        config: WkSessionConfig,
+    ) -> WkResult<Arc<WkSession>> {
+        // 受信は次のタイマーの期限までブロックして待つ
+        udp.set_nonblocking(false)?;
         let udp = Arc::new(udp);
         let client_udp = udp.clone();
-        let session = WkSession::new(
-            udp,
-            peer,
-            KcpMode::FastNB,
-            Duration::from_secs(SESSION_TIMEOUT),
-        )?;
+        let mtu = config.mtu;
+        let session = WkSession::new(udp, peer, config)?;
         let client_socket = session.socket.clone();
         let client_session = session.clone();
 
         let _handle = thread::spawn(move || {
-            let buf = &mut [0u8; PKT_SIZE];
-            loop {
-                if client_session.closed() {
+            let buf = &mut vec![0u8; mtu];
+            while let Some(delay) = client_session.handle_timeout() {
+                let timeout = Duration::from_millis(delay.max(1) as u64);
+                if let Err(e) = client_udp.set_read_timeout(Some(timeout)) {
+                    info!("connect: can't set read timeout, exiting client thread: {e}");
                     break;
                 }
-                sleep(1);
-                if let Ok((n, src)) = client_udp.recv_from(buf) {
-                    let pkt = &mut buf[..n];
-                    if pkt.len() < kcp::KCP_OVERHEAD {
-                        trace!("connect: packet too short {n} bytes received from {src}");
-                        continue;
-                    }
-                    let Ok(mut s) = client_socket.lock() else {
-                        info!("mutex poisoned, exiting client thread");
-                        break;
-                    };
-                    if s.waiting_conv() {
-                        let conv = kcp::get_conv(pkt);
-                        kcp::set_conv(pkt, conv);
-                    }
-                    if s.closed() {
-                        break;
-                    } else {
-                        let ok = s.input(pkt).is_ok();
-                        drop(s);
-                        if ok {
-                            if let Ok(mut flag) = client_session.data_available.0.lock() {
-                                *flag = true;
-                                client_session.data_available.1.notify_one();
-                            }
-                        } else {
-                            trace!("conv. id inconsistent.");
-                        }
-                        continue;
-                    }
+                let Ok((n, src)) = client_udp.recv_from(buf) else {
+                    continue;
+                };
+                let Ok(mut s) = client_socket.lock() else {
+                    info!("mutex poisoned, exiting client thread");
+                    break;
+                };
+                if s.closed() {
+                    break;
+                }
+                let ok = s.handle_datagram(src, &mut buf[..n]).is_ok();
+                let closed = s.closed();
+                drop(s);
+                if closed {
+                    // ビジー応答やサーバーからの Close で core が閉じた
+                    client_session.wake_closed();
+                    break;
+                }
+                if ok {
+                    client_session.notify_data();
+                } else {
+                    trace!("conv. id inconsistent.");
                 }
             }
         });
@@ -292,119 +290,267 @@ impl WkSession {
         Ok(session)
     }
 
-    pub fn input(&self, buf: &[u8]) -> Result<()> {
-        let mut socket = self
-            .socket
-            .lock()
-            .map_err(|_| anyhow::anyhow!("mutex poisoned"))?;
+    pub fn input(&self, buf: &[u8]) -> WkResult<()> {
+        let mut socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
         socket.input(buf)?;
         drop(socket);
-        // KCP キューにデータが積まれたことを recv_wait に通知
+        self.notify_data();
+        Ok(())
+    }
+
+    // KCP キューにデータが積まれたことを recv_wait と recv_timeout に通知
+    fn notify_data(&self) {
         if let Ok(mut flag) = self.data_available.0.lock() {
             *flag = true;
             self.data_available.1.notify_one();
         }
-        Ok(())
     }
 
-    pub fn send(&self, buf: &[u8]) -> Result<usize> {
-        let mut socket = self
-            .socket
-            .lock()
-            .map_err(|_| anyhow::anyhow!("mutex poisoned"))?;
-        socket.send(buf)
+    // 通知か timeout_ms ms の経過まで待つ（フラグで通知見逃しを防ぐ）
+    fn wait_data(&self, timeout_ms: u32) {
+        let (lock, cvar) = &self.data_available;
+        let mut flag = lock.lock().unwrap();
+        if !*flag {
+            let (f, _) = cvar
+                .wait_timeout(flag, Duration::from_millis(timeout_ms as u64))
+                .unwrap();
+            flag = f;
+        }
+        *flag = false;
     }
 
-    pub fn recv(&self, buf: &mut [u8]) -> Result<usize> {
-        let mut socket = self
-            .socket
-            .lock()
-            .map_err(|_| anyhow::anyhow!("mutex poisoned"))?;
-        socket.recv(buf)
+    pub fn send(&self, buf: &[u8]) -> WkResult<usize> {
+        let mut socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
+        Ok(socket.send(buf)?)
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> WkResult<usize> {
+        let mut socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
+        Ok(socket.recv(buf)?)
+    }
+
+    /// Send on the unreliable channel, see `WkSessionCore::send_datagram`.
+    ///
+    /// Check that the peer announced `Capabilities::KEYING_DATAGRAMS` first;
+    /// older peers can't tell datagrams from KCP segments.
+    pub fn send_datagram(&self, buf: &[u8]) -> WkResult<usize> {
+        let mut socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
+        Ok(socket.send_datagram(buf)?)
+    }
+
+    /// Next payload from the unreliable channel. Returns 0 if none has arrived.
+    pub fn recv_datagram(&self, buf: &mut [u8]) -> WkResult<usize> {
+        let mut socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
+        Ok(socket.recv_datagram(buf)?)
     }
 
     /// データが届くまでブロック（最大 timeout_ms ms）して recv する。
     /// sleep(1) ポーリングの代替。condvar で通知されるまで待機するため CPU を消費しない。
-    pub fn recv_wait(&self, buf: &mut [u8], timeout_ms: u32) -> Result<usize> {
+    pub fn recv_wait(&self, buf: &mut [u8], timeout_ms: u32) -> WkResult<usize> {
         // まず即時に試みる
         {
-            let mut socket = self
-                .socket
-                .lock()
-                .map_err(|_| anyhow::anyhow!("mutex poisoned"))?;
+            let mut socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
             match socket.recv(buf) {
                 Ok(n) if n > 0 => return Ok(n),
-                Err(e) => return Err(e),
+                Err(e) => return Err(e.into()),
                 Ok(_) => {}
             }
         }
-        // condvar でデータ到着通知を待つ（フラグで通知見逃しを防ぐ）
-        let (lock, cvar) = &self.data_available;
-        let mut flag = lock.lock().unwrap();
-        if !*flag {
-            let (f, _) = cvar
-                .wait_timeout(flag, Duration::from_millis(timeout_ms as u64))
-                .unwrap();
-            flag = f;
-        }
-        *flag = false;
-        drop(flag);
+        self.wait_data(timeout_ms);
         // 起床後に再試行
-        let mut socket = self
-            .socket
-            .lock()
-            .map_err(|_| anyhow::anyhow!("mutex poisoned"))?;
-        socket.recv(buf)
-    }
-
-    pub fn recv_timeout(&self, buf: &mut [u8], timeout: u32) -> Result<usize> {
-        let now = tick_count();
-        while tick_count() - now < timeout {
-            let mut socket = self
-                .socket
-                .lock()
-                .map_err(|_| anyhow::anyhow!("mutex poisoned"))?;
-            if let Ok(n) = socket.recv(buf) {
-                if n > 0 {
-                    return Ok(n);
-                }
-                drop(socket);
-                sleep(1);
-                continue;
+        let mut socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
+        Ok(socket.recv(buf)?)
+    }
+
+    /// Receive within `timeout` ms of the session clock, or fail with `WkError::Timeout`.
+    pub fn recv_timeout(&self, buf: &mut [u8], timeout: u32) -> WkResult<usize> {
+        let clock = &self.config.clock;
+        let start = clock.now();
+        loop {
+            // 閉じたセッションはタイムアウトまで待たずにエラーを返す
+            let n = self.recv(buf)?;
+            if n > 0 {
+                return Ok(n);
             }
+            let elapsed = clock.now().since(start);
+            if elapsed >= timeout {
+                return Err(WkError::Timeout);
+            }
+            // 差し替えた時計でも起きるたびに経過を測り直す
+            self.wait_data(timeout - elapsed);
         }
-        bail!("recv timeout")
     }
 
-    pub fn close(&self) -> Result<()> {
-        let mut socket = self
+    pub fn close(&self) -> WkResult<()> {
+        self.close_with(CloseReason::UserQuit)
+    }
+
+    /// Close the session and tell the peer why. The close is sent a few
+    /// times but not acknowledged; a peer that misses it times out as before.
+    pub fn close_with(&self, reason: CloseReason) -> WkResult<()> {
+        let mut socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
+        socket.close_with(reason);
+        drop(socket);
+        self.wake_closed();
+        Ok(())
+    }
+
+    fn closed_by_peer(&self, reason: CloseReason, tag: Option<&[u8; CLOSE_TAG_LEN]>) -> bool {
+        let closed = self
             .socket
             .lock()
-            .map_err(|_| anyhow::anyhow!("mutex poisoned"))?;
-        socket.close();
+            .map(|mut socket| socket.closed_by_peer(reason, tag))
+            .unwrap_or(false);
+        if closed {
+            self.wake_closed();
+        }
+        closed
+    }
+
+    fn wake_closed(&self) {
         self.closed.store(true, Ordering::Relaxed);
-        drop(socket);
         // 待機中の recv_wait を起こす
         if let Ok(mut flag) = self.data_available.0.lock() {
             *flag = true;
             self.data_available.1.notify_all();
         }
-        Ok(())
     }
 
     pub fn closed(&self) -> bool {
         self.closed.load(Ordering::Relaxed)
     }
 
-    pub fn conv(&self) -> Result<u32> {
-        let mut socket = self
-            .socket
+    /// Why the session closed, locally or by the peer. None while it is open.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.socket
             .lock()
-            .map_err(|_| anyhow::anyhow!("mutex poisoned"))?;
+            .ok()
+            .and_then(|socket| socket.close_reason())
+    }
+
+    /// Encrypt all further payloads. Called once authentication succeeds.
+    pub(crate) fn set_cipher(&self, cipher: SessionCipher) -> WkResult<()> {
+        let mut socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
+        socket.set_cipher(cipher);
+        Ok(())
+    }
+
+    /// True once the session has switched to ChaCha20-Poly1305 sealed payloads
+    pub fn encrypted(&self) -> bool {
+        self.socket
+            .lock()
+            .map(|socket| socket.encrypted())
+            .unwrap_or(false)
+    }
+
+    /// Allow the peer to resume this session from another address.
+    pub(crate) fn set_resume(&self, ticket: ResumeTicket) -> WkResult<()> {
+        let mut socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
+        socket.set_resume(ticket);
+        Ok(())
+    }
+
+    pub(crate) fn set_peer_hello(&self, hello: Hello) -> WkResult<()> {
+        let mut socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
+        socket.set_peer_hello(hello);
+        Ok(())
+    }
+
+    /// Protocol version, software and capabilities the peer announced after
+    /// authentication. None for peers that predate the hello.
+    pub fn peer_hello(&self) -> Option<Hello> {
+        self.socket
+            .lock()
+            .ok()
+            .and_then(|socket| socket.peer_hello().cloned())
+    }
+
+    /// Message kinds the peer understands, `Capabilities::BASELINE` if it sent no hello
+    pub fn peer_capabilities(&self) -> Capabilities {
+        self.peer_hello()
+            .map_or(Capabilities::BASELINE, |hello| hello.capabilities)
+    }
+
+    /// Tell the server our address has changed, e.g. after switching networks.
+    ///
+    /// Only sessions authenticated with SPAKE2+ hold a resume ticket.
+    pub fn request_resume(&self) -> WkResult<()> {
+        let mut socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
+        Ok(socket.send_resume()?)
+    }
+
+    fn migrate(
+        &self,
+        peer: SocketAddr,
+        ticket: u32,
+        counter: u64,
+        tag: &[u8; RESUME_TAG_LEN],
+    ) -> bool {
+        self.socket
+            .lock()
+            .map(|mut socket| socket.migrate(peer, ticket, counter, tag))
+            .unwrap_or(false)
+    }
+
+    /// Current address of the peer. Changes when a client resumes from elsewhere.
+    pub fn peer(&self) -> WkResult<SocketAddr> {
+        let socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
+        Ok(socket.peer())
+    }
+
+    /// Snapshot of RTT, retransmissions, queue depths and traffic counters
+    pub fn stats(&self) -> WkResult<WkSessionStats> {
+        let socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
+        Ok(socket.stats())
+    }
+
+    pub fn config(&self) -> &WkSessionConfig {
+        &self.config
+    }
+
+    /// Clock the session runs on, see `WkSessionConfig::clock`
+    pub fn clock(&self) -> &SharedClock {
+        &self.config.clock
+    }
+
+    pub fn conv(&self) -> WkResult<u32> {
+        let mut socket = self.socket.lock().map_err(|_| WkError::Poisoned)?;
         Ok(socket.conv())
     }
 }
 
+impl ListenerSession for Arc<WkSession> {
+    fn closed(&self) -> bool {
+        WkSession::closed(self)
+    }
+
+    fn handle_timeout(&self) -> Option<u32> {
+        WkSession::handle_timeout(self)
+    }
+
+    fn input(&self, pkt: &[u8]) -> Result<()> {
+        Ok(WkSession::input(self, pkt)?)
+    }
+
+    fn close(&self, reason: CloseReason) {
+        let _ = WkSession::close_with(self, reason);
+    }
+
+    fn closed_by_peer(&self, reason: CloseReason, tag: Option<&[u8; CLOSE_TAG_LEN]>) -> bool {
+        WkSession::closed_by_peer(self, reason, tag)
+    }
+
+    fn migrate(
+        &self,
+        peer: SocketAddr,
+        ticket: u32,
+        counter: u64,
+        tag: &[u8; RESUME_TAG_LEN],
+    ) -> bool {
+        WkSession::migrate(self, peer, ticket, counter, tag)
+    }
+}
+
 pub struct WkListener {
     stop: Arc<AtomicBool>,
     rx: mpsc::Receiver<(Arc<WkSession>, SocketAddr)>,
@@ -418,228 +564,105 @@ impl Drop for WkListener {
 }
 
 impl WkListener {
-    pub fn bind(udp: UdpSocket) -> Result<Self> {
-        udp.set_read_timeout(Some(Duration::from_secs(1)))?;
+    pub fn bind(udp: UdpSocket) -> WkResult<Self> {
+        WkListener::bind_with_limit(udp, MAX_SESSIONS)
+    }
+
+    /// Bind with at most `max_sessions` concurrent sessions. Further peers get a busy reply.
+    pub fn bind_with_limit(udp: UdpSocket, max_sessions: usize) -> WkResult<Self> {
+        WkListener::bind_with_config(udp, max_sessions, WkSessionConfig::default())
+    }
+
+    /// Bind with at most `max_sessions` sessions, each created with `config`.
+    pub fn bind_with_config(
+        udp: UdpSocket,
+        max_sessions: usize,
+        config: WkSessionConfig,
+    ) -> WkResult<Self> {
+        udp.set_read_timeout(Some(Duration::from_millis(LISTEN_WAIT_MAX as u64)))?;
         let udp = Arc::new(udp);
         let (tx, rx) = mpsc::channel();
         let stop = Arc::new(AtomicBool::new(false));
         {
             let stop = stop.clone();
             thread::spawn(move || {
-                let mut buf = [0u8; 256];
-                let mut sessions: Option<(Arc<WkSession>, SocketAddr)> = None;
+                let mut buf = vec![0u8; config.mtu];
+                let mut sessions = SessionTable::new(max_sessions);
                 loop {
                     if stop.load(Ordering::Relaxed) {
                         info!("stop listner thread");
                         break;
                     }
-                    match udp.recv_from(&mut buf) {
-                        Err(_) => continue,
-                        Ok((n, peer)) => {
-                            let pkt = &mut buf[..n];
-
-                            trace!("received {n}bytes from {peer}");
-
-                            if pkt.len() < kcp::KCP_OVERHEAD {
-                                info!("listen: packet too short {n} bytes received from {peer}");
-                                continue;
-                            }
-
-                            let mut conv = kcp::get_conv(pkt);
-                            if conv == 0 {
-                                // If an active session already exists from this peer, reuse its
-                                // conv. This handles KCP retransmissions of the initial SYN
-                                // packet (which still carry conv=0) that arrive before the server
-                                // has had a chance to send a response.
-                                let reuse_conv =
-                                    sessions.as_ref().and_then(|(session, current_peer)| {
-                                        if !session.closed() && *current_peer == peer {
-                                            session.conv().ok()
-                                        } else {
-                                            None
-                                        }
-                                    });
-                                conv = reuse_conv.unwrap_or_else(rand::random);
-                                trace!("set conv ={conv}");
-                                kcp::set_conv(pkt, conv);
-                            }
-
-                            if let Some((ref session, current_peer)) = sessions {
-                                if !session.closed() {
-                                    if peer == current_peer {
-                                        trace!("input current session {n} bytes");
-                                        if session.input(pkt).is_ok() {
-                                            continue;
-                                        }
-                                        info!("conv. id inconsistent. close session");
-                                        let _ = session.close();
-                                    } else {
-                                        trace!("discard packet");
-                                        continue;
-                                    }
-                                }
-                                trace!("session closed.");
-                            }
-                            trace!("accept new session from peer = {peer} input {n} bytes");
-                            let session = match WkSession::new(
-                                udp.clone(),
-                                peer,
-                                KcpMode::Fast,
-                                Duration::from_secs(SESSION_TIMEOUT),
-                            ) {
-                                Ok(s) => s,
-                                Err(e) => {
-                                    info!("failed to create session: {e}");
-                                    continue;
-                                }
-                            };
-                            if let Err(e) = session.input(pkt) {
-                                info!("failed to input packet: {e}");
-                                continue;
-                            }
-
-                            let client_session = session.clone();
-                            if tx.send((client_session, peer)).is_err() {
-                                info!("listener channel closed");
-                                break;
-                            }
-                            sessions = Some((session, peer));
+                    // 全セッションのタイマーをこのスレッドで回し、次の期限まで受信を待つ
+                    let delay = sessions
+                        .handle_timeouts()
+                        .map_or(LISTEN_WAIT_MAX, |delay| delay.min(LISTEN_WAIT_MAX));
+                    sessions.evict_closed();
+                    let timeout = Duration::from_millis(delay.max(1) as u64);
+                    if let Err(e) = udp.set_read_timeout(Some(timeout)) {
+                        info!("listen: can't set read timeout: {e}");
+                    }
+                    let Ok((n, peer)) = udp.recv_from(&mut buf) else {
+                        continue;
+                    };
+                    let pkt = &mut buf[..n];
+                    let conv = match sessions.dispatch(peer, pkt) {
+                        Dispatch::Done => continue,
+                        Dispatch::Reply(reply) => {
+                            let _ = udp.send_to(&reply, peer);
+                            continue;
                         }
+                        Dispatch::Accept(conv) => conv,
+                    };
+                    let session = match WkSession::new(udp.clone(), peer, config.clone()) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            info!("failed to create session: {e}");
+                            continue;
+                        }
+                    };
+                    if let Err(e) = session.input(pkt) {
+                        info!("failed to input packet: {e}");
+                        continue;
+                    }
+
+                    let client_session = session.clone();
+                    if tx.send((client_session, peer)).is_err() {
+                        info!("listener channel closed");
+                        break;
                     }
+                    sessions.insert(peer, conv, session);
                 }
             });
         }
         Ok(WkListener { stop, rx })
     }
 
-    pub fn accept(&mut self) -> Result<(Arc<WkSession>, SocketAddr)> {
+    /// Wait at most `timeout` for a new session. Returns None on timeout.
+    pub fn accept_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> WkResult<Option<(Arc<WkSession>, SocketAddr)>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok((s, addr)) => Ok(Some((s, addr))),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(WkError::ListenerClosed),
+        }
+    }
+
+    pub fn accept(&mut self) -> WkResult<(Arc<WkSession>, SocketAddr)> {
         loop {
             match self.rx.recv_timeout(Duration::from_secs(1)) {
                 Ok((s, addr)) => return Ok((s, addr)),
                 Err(mpsc::RecvTimeoutError::Timeout) => {
                     if self.stop.load(Ordering::Relaxed) {
-                        return Err(anyhow::anyhow!("listener stopped"));
+                        return Err(WkError::ListenerClosed);
                     }
                 }
                 Err(mpsc::RecvTimeoutError::Disconnected) => {
-                    return Err(anyhow::anyhow!("listener closed"));
+                    return Err(WkError::ListenerClosed);
                 }
             }
         }
     }
 }
-
-pub(crate) fn hashstr(buf: &mut [u8], passwd: &str) {
-    let mut hasher = Md5::new();
-    hasher.update(passwd);
-    let result = hasher.finalize();
-    let buf = &mut buf[..16];
-    buf.copy_from_slice(&result);
-}
-
-pub fn response(session: Arc<WkSession>, passwd: &str) -> Result<u32> {
-    let mut buf = [0u8; PKT_SIZE];
-
-    // Request challenge from server
-    session.send(&[0u8; 1])?;
-
-    if session.recv_timeout(&mut buf, 1000).is_err() {
-        bail!("auth response time out");
-    }
-
-    let mut rcvbuf = Cursor::new(buf);
-    let salt = rcvbuf.get_u32();
-    hashstr(&mut buf, &format!("{passwd}{salt}"));
-    session.send(&buf)?;
-
-    if session.recv_timeout(&mut buf, 1000).is_err() {
-        bail!("auth response time out");
-    }
-    let mut rcvbuf = Cursor::new(buf);
-    let res = rcvbuf.get_u32();
-    if res == 0 {
-        bail!("auth failed")
-    } else {
-        Ok(res)
-    }
-}
-
-pub fn challenge(session: Arc<WkSession>, passwd: &str) -> Result<u32> {
-    let mut sendbuf = BytesMut::with_capacity(PKT_SIZE);
-    let mut buf = [0u8; PKT_SIZE];
-
-    // Wait for client to initiate authentication
-    if session.recv_timeout(&mut buf, 1000).is_err() {
-        info!("auth challenge timeout: no init request");
-        bail!("auth challenge timeout: no init request");
-    }
-
-    let chl = random();
-    sendbuf.put_u32(chl);
-    session.send(&sendbuf)?;
-
-    if session.recv_timeout(&mut buf, 1000).is_err() {
-        info!("auth challenge timeout3");
-        bail!("auth challenge timeout3");
-    };
-
-    let response = &buf[..16];
-    let mut challenge = [0u8; 16];
-    hashstr(&mut challenge, &format!("{passwd}{chl}"));
-    // Use constant-time comparison to prevent timing attacks
-    use subtle::ConstantTimeEq;
-    let ok = response.ct_eq(&challenge).into();
-    let res = if ok { random::<u32>() + 1u32 } else { 0u32 };
-    sendbuf.clear();
-    sendbuf.put_u32(res);
-    session.send(&sendbuf)?;
-
-    if ok {
-        info!("auth challenge success {res}");
-        Ok(res)
-    } else {
-        info!("auth challenge failed {response:?} {challenge:?}");
-        bail!("auth challenge failed")
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_hashstr_basic() {
-        let mut buf = [0u8; 16];
-        hashstr(&mut buf, "test");
-        // MD5("test") = 098f6bcd4621d373cade4e832627b4f6
-        assert_eq!(
-            buf,
-            [
-                0x09, 0x8f, 0x6b, 0xcd, 0x46, 0x21, 0xd3, 0x73, 0xca, 0xde, 0x4e, 0x83, 0x26, 0x27,
-                0xb4, 0xf6
-            ]
-        );
-    }
-
-    #[test]
-    fn test_hashstr_empty() {
-        let mut buf = [0u8; 16];
-        hashstr(&mut buf, "");
-        // MD5("") = d41d8cd98f00b204e9800998ecf8427e
-        assert_eq!(
-            buf,
-            [
-                0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04, 0xe9, 0x80, 0x09, 0x98, 0xec, 0xf8,
-                0x42, 0x7e
-            ]
-        );
-    }
-
-    #[test]
-    fn test_hashstr_with_salt() {
-        let mut buf = [0u8; 16];
-        hashstr(&mut buf, "password12345");
-        // Verify it produces a valid 16-byte hash
-        assert_ne!(buf, [0u8; 16]);
-    }
-}
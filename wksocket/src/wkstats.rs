@@ -0,0 +1,252 @@
+use crate::wkconfig::WkSessionConfig;
+use bytes::Buf;
+use std::collections::BTreeSet;
+use std::time::Duration;
+use wkproto::Tick;
+
+const KCP_CMD_PUSH: u8 = 81;
+const KCP_CMD_ACK: u8 = 82;
+const KCP_CMD_WINS: u8 = 84;
+const KCP_RTO_MAX: u32 = 60000;
+// 受信側で保持する欠番待ちセグメントの上限（異常なピアでメモリを食わないように）
+const MAX_TRACKED: usize = 1024;
+
+/// Snapshot of a session's link quality, see `WkSession::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WkSessionStats {
+    /// Smoothed round-trip time
+    pub srtt_ms: u32,
+    /// Round-trip time variation
+    pub rttvar_ms: u32,
+    /// Current retransmission timeout
+    pub rto_ms: u32,
+    /// Segments sent more than once
+    pub retransmissions: u64,
+    /// Segments waiting to be sent or acknowledged
+    pub send_queue: usize,
+    /// Segments received ahead of a gap, waiting for the missing one
+    pub recv_queue: usize,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Segments received again after they were already delivered
+    pub duplicates: u64,
+    /// Segments received ahead of a gap
+    pub out_of_order: u64,
+    /// Time since the last datagram from the peer
+    pub since_last_recv: Duration,
+}
+
+struct Segment {
+    cmd: u8,
+    ts: u32,
+    sn: u32,
+}
+
+// 1 つのデータグラムに KCP セグメントが複数詰め込まれることがある
+fn segments(mut pkt: &[u8]) -> impl Iterator<Item = Segment> + '_ {
+    std::iter::from_fn(move || {
+        if pkt.len() < kcp::KCP_OVERHEAD {
+            return None;
+        }
+        let mut hdr = &pkt[..kcp::KCP_OVERHEAD];
+        hdr.advance(4);
+        let cmd = hdr.get_u8();
+        // 制御パケットやデータグラムは数えるだけで中を見ない
+        if !(KCP_CMD_PUSH..=KCP_CMD_WINS).contains(&cmd) {
+            return None;
+        }
+        hdr.advance(3);
+        let ts = hdr.get_u32_le();
+        let sn = hdr.get_u32_le();
+        hdr.advance(4);
+        let len = hdr.get_u32_le() as usize;
+        let end = kcp::KCP_OVERHEAD.checked_add(len)?;
+        if end > pkt.len() {
+            return None;
+        }
+        pkt = &pkt[end..];
+        Some(Segment { cmd, ts, sn })
+    })
+}
+
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Watches the KCP segments entering and leaving a session.
+///
+/// KCP keeps its RTT estimate and queues private, so the monitor rebuilds
+/// them from the segment headers using the same estimator as KCP.
+pub(crate) struct LinkMonitor {
+    interval: u32,
+    min_rto: u32,
+    srtt: u32,
+    rttvar: u32,
+    rto: u32,
+    snd_next: u32,
+    rcv_next: u32,
+    ahead: BTreeSet<u32>,
+    last_recv: Tick,
+    stats: WkSessionStats,
+}
+
+impl LinkMonitor {
+    pub(crate) fn new(config: &WkSessionConfig, now: Tick) -> Self {
+        Self {
+            interval: config.interval.max(0) as u32,
+            // KCP の最小 RTO。nodelay では 30ms、通常は 100ms
+            min_rto: if config.nodelay { 30 } else { 100 },
+            srtt: 0,
+            rttvar: 0,
+            rto: 200,
+            snd_next: 0,
+            rcv_next: 0,
+            ahead: BTreeSet::new(),
+            last_recv: now,
+            stats: WkSessionStats::default(),
+        }
+    }
+
+    pub(crate) fn on_transmit(&mut self, pkt: &[u8]) {
+        self.stats.tx_packets += 1;
+        self.stats.tx_bytes += pkt.len() as u64;
+        for seg in segments(pkt).filter(|s| s.cmd == KCP_CMD_PUSH) {
+            if before(seg.sn, self.snd_next) {
+                self.stats.retransmissions += 1;
+            } else {
+                self.snd_next = seg.sn.wrapping_add(1);
+            }
+        }
+    }
+
+    pub(crate) fn on_receive(&mut self, pkt: &[u8], now: Tick) {
+        self.stats.rx_packets += 1;
+        self.stats.rx_bytes += pkt.len() as u64;
+        self.last_recv = now;
+        for seg in segments(pkt) {
+            // ACK の ts はこちらが送ったときの tick_count()
+            let sent = Tick::from_ms(seg.ts);
+            match seg.cmd {
+                KCP_CMD_ACK if !now.is_before(sent) => self.update_rtt(now.since(sent)),
+                KCP_CMD_PUSH => self.on_push(seg.sn),
+                _ => {}
+            }
+        }
+    }
+
+    fn on_push(&mut self, sn: u32) {
+        if before(sn, self.rcv_next) || self.ahead.contains(&sn) {
+            self.stats.duplicates += 1;
+        } else if sn == self.rcv_next {
+            self.rcv_next = sn.wrapping_add(1);
+            while self.ahead.remove(&self.rcv_next) {
+                self.rcv_next = self.rcv_next.wrapping_add(1);
+            }
+        } else {
+            self.stats.out_of_order += 1;
+            if self.ahead.len() < MAX_TRACKED {
+                self.ahead.insert(sn);
+            }
+        }
+    }
+
+    // ikcp_update_ack と同じ推定
+    fn update_rtt(&mut self, rtt: u32) {
+        if self.srtt == 0 {
+            self.srtt = rtt;
+            self.rttvar = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(self.srtt);
+            self.rttvar = (3 * self.rttvar + delta) / 4;
+            self.srtt = ((7 * self.srtt + rtt) / 8).max(1);
+        }
+        let rto = self.srtt + self.interval.max(4 * self.rttvar);
+        self.rto = rto.clamp(self.min_rto, KCP_RTO_MAX);
+    }
+
+    pub(crate) fn snapshot(&self, send_queue: usize, now: Tick) -> WkSessionStats {
+        WkSessionStats {
+            srtt_ms: self.srtt,
+            rttvar_ms: self.rttvar,
+            rto_ms: self.rto,
+            send_queue,
+            recv_queue: self.ahead.len(),
+            since_last_recv: Duration::from_millis(now.since(self.last_recv) as u64),
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+
+    fn segment(cmd: u8, ts: u32, sn: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.put_u32_le(1);
+        buf.put_u8(cmd);
+        buf.put_u8(0);
+        buf.put_u16_le(128);
+        buf.put_u32_le(ts);
+        buf.put_u32_le(sn);
+        buf.put_u32_le(0);
+        buf.put_u32_le(2);
+        buf.put_slice(b"ok");
+        buf
+    }
+
+    #[test]
+    fn test_counts_retransmissions() {
+        let mut mon = LinkMonitor::new(&WkSessionConfig::default(), Tick::from_ms(0));
+        mon.on_transmit(&segment(KCP_CMD_PUSH, 0, 0));
+        mon.on_transmit(&segment(KCP_CMD_PUSH, 0, 1));
+        mon.on_transmit(&segment(KCP_CMD_PUSH, 50, 0));
+        let stats = mon.snapshot(0, Tick::from_ms(0));
+        assert_eq!(stats.retransmissions, 1);
+        assert_eq!(stats.tx_packets, 3);
+    }
+
+    #[test]
+    fn test_counts_duplicates_and_reordering() {
+        let mut mon = LinkMonitor::new(&WkSessionConfig::default(), Tick::from_ms(0));
+        // 2 つのセグメントを 1 つのデータグラムで受け取る
+        let mut pkt = segment(KCP_CMD_PUSH, 0, 0);
+        pkt.extend(segment(KCP_CMD_PUSH, 0, 2));
+        mon.on_receive(&pkt, Tick::from_ms(10));
+        assert_eq!(mon.snapshot(0, Tick::from_ms(10)).recv_queue, 1);
+        mon.on_receive(&segment(KCP_CMD_PUSH, 0, 1), Tick::from_ms(20));
+        mon.on_receive(&segment(KCP_CMD_PUSH, 0, 2), Tick::from_ms(30));
+        let stats = mon.snapshot(0, Tick::from_ms(100));
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.recv_queue, 0);
+        assert_eq!(stats.rx_packets, 3);
+        assert_eq!(stats.since_last_recv, Duration::from_millis(70));
+    }
+
+    #[test]
+    fn test_rtt_from_acks() {
+        let mut mon = LinkMonitor::new(&WkSessionConfig::default(), Tick::from_ms(0));
+        mon.on_receive(&segment(KCP_CMD_ACK, 1000, 0), Tick::from_ms(1040));
+        let stats = mon.snapshot(0, Tick::from_ms(1040));
+        assert_eq!(stats.srtt_ms, 40);
+        assert_eq!(stats.rttvar_ms, 20);
+        assert_eq!(stats.rto_ms, 120);
+        // 未来の時刻をエコーする ACK は無視
+        mon.on_receive(&segment(KCP_CMD_ACK, 5000, 1), Tick::from_ms(1050));
+        assert_eq!(mon.snapshot(0, Tick::from_ms(1050)).srtt_ms, 40);
+    }
+
+    #[test]
+    fn test_rtt_across_tick_wrap() {
+        let mut mon = LinkMonitor::new(&WkSessionConfig::default(), Tick::from_ms(u32::MAX - 10));
+        // u32::MAX - 10 に送ったセグメントの ACK が一周後の 20 に届く
+        mon.on_receive(&segment(KCP_CMD_ACK, u32::MAX - 10, 0), Tick::from_ms(20));
+        let stats = mon.snapshot(0, Tick::from_ms(25));
+        assert_eq!(stats.srtt_ms, 31);
+        assert_eq!(stats.since_last_recv, Duration::from_millis(5));
+    }
+}
@@ -0,0 +1,189 @@
+use crate::wkutil::{self, tick_us};
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex};
+use wkproto::{Clock, Tick};
+
+/// The monotonic clock of the platform
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_us(&self) -> u64 {
+        tick_us()
+    }
+
+    fn sleep(&self, ms: u32) {
+        wkutil::sleep(ms)
+    }
+
+    fn sleep_until_us(&self, deadline_us: u64) {
+        wkutil::sleep_until_us(deadline_us)
+    }
+}
+
+/// Virtual clock for tests.
+///
+/// Made with `new`, `sleep` blocks until other threads have `advance`d the
+/// clock past the wake-up time. Made with `auto_advance`, `sleep` moves the
+/// clock forward itself and returns at once, which suits code driven from a
+/// single thread.
+#[derive(Clone)]
+pub struct SimClock {
+    inner: Arc<SimInner>,
+}
+
+struct SimInner {
+    now_us: Mutex<u64>,
+    moved: Condvar,
+    auto: bool,
+}
+
+impl SimClock {
+    pub fn new(start: Tick) -> Self {
+        SimClock::with_mode(start, false)
+    }
+
+    pub fn auto_advance(start: Tick) -> Self {
+        SimClock::with_mode(start, true)
+    }
+
+    fn with_mode(start: Tick, auto: bool) -> Self {
+        SimClock {
+            inner: Arc::new(SimInner {
+                now_us: Mutex::new(start.as_ms() as u64 * 1000),
+                moved: Condvar::new(),
+                auto,
+            }),
+        }
+    }
+
+    /// Move the clock forward and wake the sleepers that are due
+    pub fn advance(&self, ms: u32) {
+        self.advance_us(ms as u64 * 1000);
+    }
+
+    pub fn advance_us(&self, us: u64) {
+        *self.inner.now_us.lock().unwrap() += us;
+        self.inner.moved.notify_all();
+    }
+}
+
+impl Clock for SimClock {
+    fn now_us(&self) -> u64 {
+        *self.inner.now_us.lock().unwrap()
+    }
+
+    fn sleep(&self, ms: u32) {
+        let until = self.now_us() + ms as u64 * 1000;
+        self.sleep_until_us(until);
+    }
+
+    fn sleep_until_us(&self, deadline_us: u64) {
+        let mut now = self.inner.now_us.lock().unwrap();
+        if self.inner.auto {
+            *now = deadline_us.max(*now);
+            self.inner.moved.notify_all();
+            return;
+        }
+        while *now < deadline_us {
+            now = self.inner.moved.wait(now).unwrap();
+        }
+    }
+}
+
+impl fmt::Debug for SimClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimClock")
+            .field("now_us", &self.now_us())
+            .field("auto", &self.inner.auto)
+            .finish()
+    }
+}
+
+/// A `Clock` shared by a session and everything built on it.
+///
+/// Two handles are equal when they point at the same clock.
+#[derive(Clone)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub fn new(clock: impl Clock + 'static) -> Self {
+        SharedClock(Arc::new(clock))
+    }
+}
+
+impl<C: Clock + 'static> From<C> for SharedClock {
+    fn from(clock: C) -> Self {
+        SharedClock::new(clock)
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        SharedClock::new(SystemClock)
+    }
+}
+
+impl Deref for SharedClock {
+    type Target = dyn Clock;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedClock").finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for SharedClock {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SharedClock {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sim_clock_sleep_waits_for_advance() {
+        let clock = SimClock::new(Tick::from_ms(u32::MAX - 5));
+        let sleeper = clock.clone();
+        let handle = thread::spawn(move || {
+            sleeper.sleep(10);
+            sleeper.now()
+        });
+        // 眠りに入るのを待ってから進める
+        thread::sleep(Duration::from_millis(20));
+        clock.advance(6);
+        thread::sleep(Duration::from_millis(20));
+        assert!(!handle.is_finished());
+        clock.advance(4);
+        // 一周した先で起きる
+        assert_eq!(handle.join().unwrap(), Tick::from_ms(4));
+    }
+
+    #[test]
+    fn test_sim_clock_auto_advance() {
+        let clock = SimClock::auto_advance(Tick::from_ms(1000));
+        clock.sleep(250);
+        clock.advance_us(1500);
+        assert_eq!(clock.now(), Tick::from_ms(1251));
+        assert_eq!(clock.now_us(), 1_251_500);
+    }
+
+    #[test]
+    fn test_shared_clock_equality() {
+        let clock = SharedClock::new(SimClock::new(Tick::ZERO));
+        assert_eq!(clock, clock.clone());
+        assert_ne!(clock, SharedClock::default());
+    }
+}
@@ -6,7 +6,13 @@ use std::time::{Duration, Instant};
 
 // ESP32 platforms (xtensa, riscv32)
 #[cfg(any(target_arch = "xtensa", target_arch = "riscv32"))]
-use esp_idf_hal::delay::FreeRtos;
+use esp_idf_hal::delay::{Ets, FreeRtos};
+
+// 締め切りのこれだけ手前までは OS に寝かせ、残りはスピンで詰める (us)
+const SPIN_US: u64 = 2000;
+// リアルタイム優先度。ほかのリアルタイムスレッドを邪魔しない低めの値
+#[cfg(target_os = "linux")]
+const REALTIME_PRIORITY: i32 = 10;
 
 /// 起動からの経過ミリ秒を返す（単調クロック）
 ///
@@ -14,17 +20,23 @@ use esp_idf_hal::delay::FreeRtos;
 /// - Desktop: std::time::Instant を使用（NTP等による時刻逆行の影響なし）
 #[inline]
 pub fn tick_count() -> u32 {
+    (tick_us() / 1000) as u32
+}
+
+/// 起動からの経過マイクロ秒を返す。tick_count() と同じクロック
+#[inline]
+pub fn tick_us() -> u64 {
     #[cfg(any(target_arch = "xtensa", target_arch = "riscv32"))]
     {
         // esp_timer_get_time() は常にマイクロ秒を返す（FreeRTOSティックレートに非依存）
-        unsafe { (esp_idf_sys::esp_timer_get_time() / 1000) as u32 }
+        unsafe { esp_idf_sys::esp_timer_get_time() as u64 }
     }
     #[cfg(not(any(target_arch = "xtensa", target_arch = "riscv32")))]
     {
         use std::sync::OnceLock;
         static START: OnceLock<Instant> = OnceLock::new();
         let start = START.get_or_init(Instant::now);
-        start.elapsed().as_millis() as u32
+        start.elapsed().as_micros() as u64
     }
 }
 
@@ -35,3 +47,58 @@ pub fn sleep(ms: u32) {
     #[cfg(not(any(target_arch = "xtensa", target_arch = "riscv32")))]
     thread::sleep(Duration::from_millis(ms as u64));
 }
+
+/// tick_us() が deadline_us になるまで待つ
+///
+/// OS のスリープは寝過ごすので、締め切りの少し手前で起きて残りはスピンする。
+pub fn sleep_until_us(deadline_us: u64) {
+    let now = tick_us();
+    if now >= deadline_us {
+        return;
+    }
+    let left = deadline_us - now;
+    #[cfg(any(target_arch = "xtensa", target_arch = "riscv32"))]
+    {
+        if left > SPIN_US {
+            FreeRtos::delay_ms(((left - SPIN_US) / 1000) as u32);
+        }
+        let now = tick_us();
+        if now < deadline_us {
+            Ets::delay_us((deadline_us - now) as u32);
+        }
+    }
+    #[cfg(not(any(target_arch = "xtensa", target_arch = "riscv32")))]
+    {
+        if left > SPIN_US {
+            thread::sleep(Duration::from_micros(left - SPIN_US));
+        }
+        while tick_us() < deadline_us {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+/// 呼んだスレッドをリアルタイム優先度 (SCHED_FIFO) にする
+///
+/// 権限がなければ Err を返すので、呼び出し側はそのまま通常の優先度で続ける。
+/// Linux 以外では Unsupported。
+pub fn raise_priority() -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let param = libc::sched_param {
+            sched_priority: REALTIME_PRIORITY,
+        };
+        // SAFETY: pthread_self() は常に有効で、param は呼び出しの間生きている
+        let r =
+            unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::from_raw_os_error(r))
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
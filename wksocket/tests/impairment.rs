@@ -0,0 +1,314 @@
+//! Sessions over a localhost link impaired by `ImpairProxy`.
+//!
+//! Seeds are fixed, but thread scheduling is not, so the bounds are loose
+//! enough for a busy CI machine.
+
+use std::collections::HashMap;
+use std::net::UdpSocket;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use wksocket::{
+    challenge, response, AuthVerifier, Capabilities, ImpairConfig, ImpairProxy, KeyOutput,
+    KeyPlayer, KeyerHooks, Latency, MessageRCV, MessageSND, Tick, WkListener, WkReceiver, WkSender,
+    WkSession, WkSessionConfig,
+};
+
+const PASSWD: &str = "impaired";
+
+struct Link {
+    proxy: ImpairProxy,
+    client: Arc<WkSession>,
+    server: Arc<WkSession>,
+    _listener: WkListener,
+}
+
+/// Authenticate a client with a listener through a proxy with `impair`.
+fn establish(impair: ImpairConfig, server_config: WkSessionConfig) -> Link {
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = udp.local_addr().unwrap();
+    let mut listener = WkListener::bind_with_config(udp, 1, server_config).unwrap();
+    let proxy = ImpairProxy::start(server_addr, impair).unwrap();
+
+    let server = thread::spawn(move || {
+        let (session, _) = listener
+            .accept_timeout(Duration::from_secs(10))
+            .unwrap()
+            .expect("no session accepted");
+        let verifier = AuthVerifier::with_salt(PASSWD, [7u8; 16], 16);
+        challenge(session.clone(), &verifier, None).unwrap();
+        (session, listener)
+    });
+
+    let config = WkSessionConfig::default().auth_timeout(Duration::from_secs(3));
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = WkSession::connect_with_config(proxy.addr(), udp, config).unwrap();
+    response(client.clone(), PASSWD, false).unwrap();
+    let (server, listener) = server.join().unwrap();
+    Link {
+        proxy,
+        client,
+        server,
+        _listener: listener,
+    }
+}
+
+fn datagram_server() -> WkSessionConfig {
+    WkSessionConfig::default()
+        .auth_timeout(Duration::from_secs(3))
+        .capabilities(Capabilities::BASELINE | Capabilities::KEYING_DATAGRAMS)
+}
+
+/// Collect keyer messages until `done` says so or `timeout` passes.
+fn collect(
+    receiver: &WkReceiver,
+    timeout: Duration,
+    mut done: impl FnMut(&[(Instant, MessageRCV)]) -> bool,
+) -> Vec<(Instant, MessageRCV)> {
+    let start = Instant::now();
+    let mut got = Vec::new();
+    while !done(&got) && start.elapsed() < timeout {
+        match receiver.try_recv() {
+            Ok(msgs) => {
+                let now = Instant::now();
+                got.extend(msgs.into_iter().map(|m| (now, m)));
+            }
+            Err(_) => thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    got
+}
+
+fn edges(got: &[(Instant, MessageRCV)]) -> Vec<&MessageRCV> {
+    got.iter()
+        .map(|(_, m)| m)
+        .filter(|m| matches!(m, MessageRCV::Keydown { .. } | MessageRCV::Keyup { .. }))
+        .collect()
+}
+
+#[test]
+fn test_auth_over_lossy_link() {
+    let impair = ImpairConfig::default()
+        .loss(0.1)
+        .latency(Latency::Uniform {
+            min: Duration::from_millis(5),
+            max: Duration::from_millis(25),
+        })
+        .reorder(0.1, Duration::from_millis(20))
+        .duplicate(0.05)
+        .seed(1);
+    let link = establish(impair, datagram_server());
+
+    assert!(link.client.encrypted() && link.server.encrypted());
+    let hello = link.client.peer_hello().expect("no hello from server");
+    assert!(hello.capabilities.contains(Capabilities::KEYING_DATAGRAMS));
+    assert!(link.server.peer_hello().is_some());
+    assert!(link.proxy.stats().forwarded > 0);
+}
+
+#[test]
+fn test_edges_delivered_in_order_over_kcp() {
+    let impair = ImpairConfig::default()
+        .loss(0.05)
+        .latency(Latency::Normal {
+            mean: Duration::from_millis(20),
+            std_dev: Duration::from_millis(5),
+        })
+        .reorder(0.05, Duration::from_millis(30))
+        .duplicate(0.05)
+        .seed(2);
+    let link = establish(impair, WkSessionConfig::default());
+    let sender = WkSender::new(link.client.clone()).unwrap();
+    let receiver = WkReceiver::new(link.server.clone()).unwrap();
+
+    let mut expected = Vec::new();
+    for i in 0..50u32 {
+        let tm = Tick::from_ms(i * 100);
+        sender.send(MessageSND::NegEdge(10)).unwrap();
+        sender.send(MessageSND::PosEdge(60)).unwrap();
+        sender.send(MessageSND::SendPacket(tm)).unwrap();
+        expected.push(MessageRCV::Keydown {
+            at: tm + 10,
+            sub_us: 0,
+        });
+        expected.push(MessageRCV::Keyup {
+            at: tm + 60,
+            sub_us: 0,
+        });
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    let got = collect(&receiver, Duration::from_secs(10), |got| {
+        edges(got).len() >= expected.len()
+    });
+    // KCP は順序を保って全部届ける
+    assert_eq!(edges(&got), expected.iter().collect::<Vec<_>>());
+    assert!(link.proxy.stats().dropped > 0);
+}
+
+#[test]
+fn test_datagram_keying_survives_loss() {
+    let impair = ImpairConfig::default()
+        .loss(0.1)
+        .latency(Latency::Fixed(Duration::from_millis(10)))
+        .duplicate(0.05)
+        .seed(3);
+    let link = establish(impair, datagram_server());
+    let sender = WkSender::new(link.client.clone()).unwrap();
+    let receiver = WkReceiver::new(link.server.clone()).unwrap();
+
+    let count = 200u32;
+    for i in 0..count {
+        sender.send(MessageSND::NegEdge(0)).unwrap();
+        sender
+            .send(MessageSND::SendPacket(Tick::from_ms(i * 10)))
+            .unwrap();
+        thread::sleep(Duration::from_millis(2));
+    }
+    // 最後のキーイングも冗長コピーで守られるよう同期パケットを続ける
+    for i in 0..5 {
+        sender
+            .send(MessageSND::SendPacket(Tick::from_ms((count + i) * 10)))
+            .unwrap();
+        thread::sleep(Duration::from_millis(2));
+    }
+
+    let got = collect(&receiver, Duration::from_secs(5), |got| {
+        edges(got).len() >= count as usize
+    });
+    let times: Vec<Tick> = edges(&got)
+        .into_iter()
+        .map(|m| match m {
+            MessageRCV::Keydown { at, .. } | MessageRCV::Keyup { at, .. } => *at,
+            _ => unreachable!(),
+        })
+        .collect();
+    // 重複も並べ替えもなく、冗長化で損失の大半が埋まる
+    assert!(times.windows(2).all(|w| w[1].is_after(w[0])));
+    assert!(
+        times.len() as u32 >= count * 98 / 100,
+        "only {} of {count} edges arrived",
+        times.len()
+    );
+    assert!(link.proxy.stats().dropped > 0);
+}
+
+#[test]
+fn test_keying_delay_within_link_latency() {
+    let min = Duration::from_millis(20);
+    let max = Duration::from_millis(40);
+    let impair = ImpairConfig::default()
+        .latency(Latency::Uniform { min, max })
+        .seed(4);
+    let link = establish(impair, datagram_server());
+    let sender = WkSender::new(link.client.clone()).unwrap();
+    let receiver = WkReceiver::new(link.server.clone()).unwrap();
+
+    let count = 20u32;
+    let receiving = thread::spawn(move || {
+        collect(&receiver, Duration::from_secs(5), |got| {
+            got.len() >= count as usize
+        })
+    });
+    let mut sent = HashMap::new();
+    for i in 0..count {
+        let tm = Tick::from_ms(i * 50);
+        sent.insert(tm, Instant::now());
+        sender.send(MessageSND::SendPacket(tm)).unwrap();
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let got = receiving.join().unwrap();
+    assert_eq!(got.len(), count as usize);
+    // スレッドの起床遅れぶんの余裕だけ上限に足す
+    let slack = Duration::from_millis(40);
+    for (arrived, msg) in &got {
+        let MessageRCV::Sync(tm) = msg else {
+            panic!("unexpected {msg:?}");
+        };
+        let delay = *arrived - sent[tm];
+        assert!(
+            delay >= min && delay <= max + slack,
+            "sync {tm} took {delay:?}"
+        );
+    }
+}
+
+struct NoKey;
+
+impl KeyOutput for NoKey {
+    fn set_key(&mut self, _down: bool) {}
+}
+
+struct Keyed(Sender<bool>);
+
+impl KeyerHooks for Keyed {
+    fn keyed(&mut self, down: bool, _now: Tick) {
+        let _ = self.0.send(down);
+    }
+}
+
+#[test]
+fn test_player_reports_edges_without_further_messages() {
+    let impair = ImpairConfig::default().latency(Latency::Fixed(Duration::from_millis(10)));
+    let link = establish(impair, datagram_server());
+    let sender = WkSender::new(link.client.clone()).unwrap();
+    let receiver = WkReceiver::new(link.server.clone()).unwrap();
+    let player = Arc::new(KeyPlayer::new(NoKey));
+    let (tx, rx) = mpsc::channel();
+    let playing = {
+        let player = player.clone();
+        thread::spawn(move || player.run(&receiver, &mut Keyed(tx)))
+    };
+
+    // 1 打だけ送り、その後は Sync も送らない
+    sender.send(MessageSND::NegEdge(0)).unwrap();
+    sender.send(MessageSND::PosEdge(60)).unwrap();
+    sender
+        .send(MessageSND::SendPacket(Tick::from_ms(0)))
+        .unwrap();
+    let timeout = Duration::from_secs(2);
+    assert_eq!(rx.recv_timeout(timeout), Ok(true));
+    assert_eq!(rx.recv_timeout(timeout), Ok(false));
+
+    // メッセージを待っている間でも止められる
+    player.stop();
+    let start = Instant::now();
+    assert_eq!(playing.join().unwrap(), None);
+    assert!(start.elapsed() < timeout);
+}
+
+struct Ticks(Sender<Tick>);
+
+impl KeyerHooks for Ticks {
+    fn tick(&mut self, now: Tick) {
+        let _ = self.0.send(now);
+    }
+}
+
+#[test]
+fn test_player_ticks_without_messages() {
+    let link = establish(ImpairConfig::default(), datagram_server());
+    let receiver = WkReceiver::new(link.server.clone()).unwrap();
+    let player = Arc::new(KeyPlayer::new(NoKey));
+    let (tx, rx) = mpsc::channel();
+    let playing = {
+        let player = player.clone();
+        thread::spawn(move || player.run(&receiver, &mut Ticks(tx)))
+    };
+
+    // 何も送らなくても、Sync の周期よりずっと短い間隔で呼ばれる
+    let timeout = Duration::from_secs(1);
+    let first = rx.recv_timeout(timeout).unwrap();
+    let mut last = first;
+    for _ in 0..5 {
+        let now = rx.recv_timeout(timeout).unwrap();
+        assert!(now.since(last) < 500);
+        last = now;
+    }
+    assert!(last.since(first) > 0);
+
+    player.stop();
+    assert_eq!(playing.join().unwrap(), None);
+}
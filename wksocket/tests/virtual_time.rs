@@ -0,0 +1,93 @@
+//! Sessions on a `SimClock`: timeouts are stepped through instead of waited for.
+//!
+//! Only the virtual clock decides when a timeout fires; real sleeps below just
+//! give the session threads a chance to run.
+
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use wksocket::{CloseReason, SimClock, Tick, WkListener, WkSession, WkSessionConfig};
+
+/// A client whose peer never answers. Keep the returned socket alive.
+fn silent_client(clock: &SimClock) -> (Arc<WkSession>, UdpSocket) {
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let config = WkSessionConfig::default().clock(clock.clone());
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let session = WkSession::connect_with_config(peer.local_addr().unwrap(), udp, config).unwrap();
+    (session, peer)
+}
+
+fn settle() {
+    thread::sleep(Duration::from_millis(50));
+}
+
+fn wait_until(done: impl Fn() -> bool) -> bool {
+    for _ in 0..200 {
+        if done() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+#[test]
+fn test_idle_timeout_on_virtual_time() {
+    let clock = SimClock::new(Tick::from_ms(u32::MAX - 1000));
+    let (session, _peer) = silent_client(&clock);
+
+    // 既定のアイドルタイムアウト 15 秒の手前
+    clock.advance(14_900);
+    settle();
+    assert!(!session.closed());
+
+    clock.advance(200);
+    assert!(wait_until(|| session.closed()));
+    assert_eq!(session.close_reason(), Some(CloseReason::LinkLost));
+}
+
+#[test]
+fn test_recv_timeout_on_virtual_time() {
+    let clock = SimClock::new(Tick::from_ms(5000));
+    let (session, _peer) = silent_client(&clock);
+    let waiter = session.clone();
+    let handle: JoinHandle<bool> = thread::spawn(move || {
+        let mut buf = [0u8; 16];
+        waiter.recv_timeout(&mut buf, 1000).is_err()
+    });
+    // recv_timeout が開始時刻を読むのを待つ
+    settle();
+
+    clock.advance(999);
+    settle();
+    assert!(!handle.is_finished());
+
+    clock.advance(2);
+    assert!(wait_until(|| handle.is_finished()));
+    assert!(handle.join().unwrap());
+}
+
+#[test]
+fn test_listener_idle_timeout_on_virtual_time() {
+    let clock = SimClock::new(Tick::from_ms(1000));
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = udp.local_addr().unwrap();
+    let config = WkSessionConfig::default().clock(clock.clone());
+    let mut listener = WkListener::bind_with_config(udp, 1, config).unwrap();
+    let client = WkSession::connect(addr, UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap();
+    client.send(b"hi").unwrap();
+    let (session, _) = listener
+        .accept_timeout(Duration::from_secs(2))
+        .unwrap()
+        .expect("no session accepted");
+
+    // サーバー側のタイマーはリスナーのスレッドが回す
+    clock.advance(14_900);
+    settle();
+    assert!(!session.closed());
+
+    clock.advance(200);
+    assert!(wait_until(|| session.closed()));
+    assert_eq!(session.close_reason(), Some(CloseReason::LinkLost));
+}